@@ -0,0 +1,147 @@
+// cache throughput under concurrent clients, `cargo bench --bench cache`
+//
+// Clients read a set of hot keys that stay cached, and every MISS_EVERY-th
+// read is a key never seen before that costs an upstream round trip of
+// UPSTREAM_LATENCY. The global lock run holds one mutex across the round trip
+// the way the cache used to, the sharded run is rengo's cache.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bson::{doc, Document};
+use rengo::cache::flight::Join;
+use rengo::cache::policy::CachePolicy;
+use rengo::cache::reads::CacheableRead;
+use rengo::cache::{Cache, Lookup};
+
+const NAMESPACES: usize = 8;
+const HOT_KEYS: usize = 512;
+const MISS_EVERY: u64 = 10;
+const UPSTREAM_LATENCY: Duration = Duration::from_millis(2);
+const RUN: Duration = Duration::from_secs(2);
+const CLIENTS: [usize; 5] = [1, 4, 16, 64, 256];
+
+fn hot(n: usize) -> CacheableRead {
+    CacheableRead {
+        namespace: format!("bench.c{}", n % NAMESPACES),
+        dependencies: vec![],
+        key: format!("hot{}", n),
+    }
+}
+
+fn read(client: usize, op: u64) -> CacheableRead {
+    if !op.is_multiple_of(MISS_EVERY) {
+        // a cheap spread over the hot keys that differs per client
+        return hot((op.wrapping_mul(2654435761) as usize + client * 7919) % HOT_KEYS);
+    }
+    CacheableRead {
+        namespace: format!("bench.c{}", op as usize % NAMESPACES),
+        dependencies: vec![],
+        key: format!("cold{}.{}", client, op),
+    }
+}
+
+fn upstream(read: &CacheableRead) -> Document {
+    thread::sleep(UPSTREAM_LATENCY);
+    doc! { "cursor": { "firstBatch": [{ "key": &read.key }], "id": 0i64, "ns": &read.namespace }, "ok": 1.0 }
+}
+
+trait Target: Send + Sync + 'static {
+    fn get(&self, read: CacheableRead) -> Document;
+}
+
+// the cache before it was sharded: one lock, held while the miss is fetched
+#[derive(Default)]
+struct GlobalLock {
+    entries: Mutex<HashMap<String, (String, Document)>>,
+}
+
+impl Target for GlobalLock {
+    fn get(&self, read: CacheableRead) -> Document {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((_, reply)) = entries.get(&read.key) {
+            return reply.clone();
+        }
+        let reply = upstream(&read);
+        entries.insert(read.key.clone(), (read.namespace, reply.clone()));
+        reply
+    }
+}
+
+struct Sharded {
+    cache: Arc<Cache>,
+    policy: CachePolicy,
+}
+
+impl Target for Sharded {
+    fn get(&self, read: CacheableRead) -> Document {
+        if let Lookup::Fresh(reply) = self.cache.lookup(&read.key) {
+            return reply;
+        }
+        let generation = self.cache.generation(&read);
+        match self.cache.join(&read.key) {
+            Join::Leader(guard) => {
+                let reply = upstream(&read);
+                let command = doc! { "find": "c", "$db": "bench" };
+                self.cache
+                    .store_read("", &command, read, &reply, &self.policy, generation);
+                guard.complete(reply.clone());
+                reply
+            }
+            Join::Follower(flight) => flight.wait().unwrap_or_default(),
+        }
+    }
+}
+
+// reads per second with `clients` threads reading for RUN
+fn run<T: Target>(target: Arc<T>, clients: usize) -> f64 {
+    let done = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicU64::new(0));
+    // the hot keys are cached before the clock starts
+    for n in 0..HOT_KEYS {
+        target.get(hot(n));
+    }
+    let threads: Vec<_> = (0..clients)
+        .map(|client| {
+            let target = target.clone();
+            let done = done.clone();
+            let reads = reads.clone();
+            thread::spawn(move || {
+                let mut op = 0;
+                while !done.load(Ordering::Relaxed) {
+                    op += 1;
+                    target.get(read(client, op));
+                }
+                reads.fetch_add(op, Ordering::Relaxed);
+            })
+        })
+        .collect();
+    let started = Instant::now();
+    thread::sleep(RUN);
+    done.store(true, Ordering::Relaxed);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    reads.load(Ordering::Relaxed) as f64 / started.elapsed().as_secs_f64()
+}
+
+fn main() {
+    println!(
+        "{} hot keys, 1 in {} reads misses with a {:?} upstream round trip",
+        HOT_KEYS, MISS_EVERY, UPSTREAM_LATENCY
+    );
+    println!("{:>8} {:>16} {:>16}", "clients", "global lock/s", "sharded/s");
+    for clients in CLIENTS {
+        let global = run(Arc::new(GlobalLock::default()), clients);
+        let sharded = run(
+            Arc::new(Sharded {
+                cache: Arc::new(Cache::new()),
+                policy: CachePolicy::default(),
+            }),
+            clients,
+        );
+        println!("{:>8} {:>16.0} {:>16.0}", clients, global, sharded);
+    }
+}
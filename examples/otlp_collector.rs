@@ -0,0 +1,68 @@
+// a stand-in for an OTLP collector that prints the spans rengo exports,
+// `cargo run --example otlp_collector [address]`
+//
+// Point rengo at it with `--otlp-endpoint http://127.0.0.1:4318`. Each span
+// is printed on a line of its own with its trace, parent and duration.
+use std::thread;
+
+use rengo::http::{self, HttpRequest, HttpResponse};
+use serde_json::Value;
+
+fn attribute(attributes: &Value) -> String {
+    let pairs: Vec<String> = attributes
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|attribute| {
+            let value = &attribute["value"]["stringValue"];
+            format!("{}={}", attribute["key"].as_str().unwrap_or(""), value.as_str().unwrap_or(""))
+        })
+        .collect();
+    pairs.join(" ")
+}
+
+fn print_spans(request: &Value) -> usize {
+    let mut count = 0;
+    let resources = request["resourceSpans"].as_array().into_iter().flatten();
+    let scopes = resources.flat_map(|resource| resource["scopeSpans"].as_array().into_iter().flatten());
+    for span in scopes.flat_map(|scope| scope["spans"].as_array().into_iter().flatten()) {
+        let nanos = |field: &str| span[field].as_str().and_then(|nanos| nanos.parse::<u64>().ok()).unwrap_or(0);
+        let duration = nanos("endTimeUnixNano").saturating_sub(nanos("startTimeUnixNano"));
+        println!(
+            "{} {} parent={} {:<20} {:>9.3}ms {}",
+            span["traceId"].as_str().unwrap_or(""),
+            span["spanId"].as_str().unwrap_or(""),
+            span["parentSpanId"].as_str().filter(|parent| !parent.is_empty()).unwrap_or("-"),
+            span["name"].as_str().unwrap_or(""),
+            duration as f64 / 1_000_000.0,
+            attribute(&span["attributes"]),
+        );
+        count += 1;
+    }
+    count
+}
+
+fn collect(request: &HttpRequest) -> HttpResponse {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/v1/traces") => match serde_json::from_slice::<Value>(&request.body) {
+            Ok(body) => {
+                print_spans(&body);
+                HttpResponse::new(200, "application/json", "{}")
+            }
+            Err(e) => HttpResponse::text(400, format!("{}\n", e)),
+        },
+        _ => HttpResponse::not_found(),
+    }
+}
+
+fn main() {
+    let addr = std::env::args().nth(1).unwrap_or_else(|| "127.0.0.1:4318".to_string());
+    if let Err(e) = http::serve(&addr, collect) {
+        eprintln!("Error: {}: {}", addr, e);
+        std::process::exit(1);
+    }
+    println!("Collecting spans on http://{}/v1/traces", addr);
+    loop {
+        thread::park();
+    }
+}
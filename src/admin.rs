@@ -0,0 +1,553 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Instant, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+
+use crate::cache::policy::{CachePolicy, PolicyRule};
+use crate::cache::Entry;
+use crate::clients;
+use crate::config::{self, Config};
+use crate::handler::{InnerData, Storage};
+use crate::http::{self, HttpRequest, HttpResponse};
+use crate::metrics::metrics;
+use crate::reload;
+
+// entries listed when the request sets no limit
+const DEFAULT_LIMIT: usize = 100;
+
+fn respond(status: u16, body: Value) -> HttpResponse {
+    HttpResponse::new(status, "application/json", format!("{}\n", body))
+}
+
+fn bad_request(message: &str) -> HttpResponse {
+    respond(400, json!({ "error": message }))
+}
+
+fn seconds(value: Option<std::time::Duration>) -> Value {
+    value.map_or(Value::Null, |value| json!(value.as_secs_f64()))
+}
+
+fn policy_json(policy: &CachePolicy) -> Value {
+    json!({
+        "enabled": policy.enabled,
+        "ttl": seconds(policy.ttl),
+        "stale_while_revalidate": seconds(policy.stale_while_revalidate),
+        "max_entries": policy.max_entries,
+        "cache_empty": policy.cache_empty,
+        "invalidate_on_write": policy.invalidate_on_write,
+        "local": policy.local,
+    })
+}
+
+fn rule_json(rule: &PolicyRule) -> Value {
+    json!({
+        "namespace": rule.namespace,
+        "enabled": rule.enabled,
+        "ttl": rule.ttl,
+        "stale_while_revalidate": rule.stale_while_revalidate,
+        "max_entries": rule.max_entries,
+        "cache_empty": rule.cache_empty,
+        "invalidate_on_write": rule.invalidate_on_write,
+        "local": rule.local,
+    })
+}
+
+// ages and expiries in seconds, negative once the entry has expired
+fn entry_json(key: &str, entry: &Entry, now: Instant) -> Value {
+    let remaining = |at: Option<Instant>| match at {
+        Some(at) if at >= now => json!((at - now).as_secs_f64()),
+        Some(at) => json!(-(now - at).as_secs_f64()),
+        None => Value::Null,
+    };
+    json!({
+        "key": key,
+        "namespace": entry.namespace,
+        "dependencies": entry.dependencies,
+        "kind": match entry.data {
+            InnerData::Document(_) => "reply",
+            InnerData::Documents(_) => "cursor",
+        },
+        "age": now.duration_since(entry.inserted).as_secs_f64(),
+        "expires_in": remaining(entry.expires),
+        "stale_until": remaining(entry.stale_until),
+        "expired": entry.is_expired(now),
+        "size": entry.size,
+    })
+}
+
+// entries and size by namespace, shared with `rengoCacheStats`
+pub fn cache_stats(storage: &Storage) -> Value {
+    let mut namespaces: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    storage.for_each(|_, entry| {
+        let (entries, size) = namespaces.entry(entry.namespace.clone()).or_default();
+        *entries += 1;
+        *size += entry.size;
+    });
+    let (replies, documents, collections) = storage.memory();
+    let namespaces: Vec<Value> = namespaces
+        .into_iter()
+        .map(|(namespace, (entries, size))| json!({ "namespace": namespace, "entries": entries, "size": size }))
+        .collect();
+    json!({
+        "entries": storage.len(),
+        "memory": { "replies": replies, "documents": documents, "collections": collections },
+        "namespaces": namespaces,
+    })
+}
+
+// `?namespace=` narrows the list, `?key=` picks out one entry
+fn entries(storage: &Storage, request: &HttpRequest) -> HttpResponse {
+    let limit = match request.query.get("limit").map(|limit| limit.parse::<usize>()) {
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return bad_request("limit must be a number"),
+        None => DEFAULT_LIMIT,
+    };
+    let namespace = request.query.get("namespace");
+    let key = request.query.get("key");
+    let now = Instant::now();
+    let mut matched = 0;
+    let mut listed = vec![];
+    storage.for_each(|entry_key, entry| {
+        if namespace.is_some_and(|namespace| *namespace != entry.namespace) || key.is_some_and(|key| key != entry_key) {
+            return;
+        }
+        matched += 1;
+        if listed.len() < limit {
+            listed.push(entry_json(entry_key, entry, now));
+        }
+    });
+    match key {
+        Some(_) if listed.is_empty() => respond(404, json!({ "error": "no entry with that key" })),
+        Some(_) => respond(200, listed.remove(0)),
+        None => respond(200, json!({ "matched": matched, "entries": listed })),
+    }
+}
+
+// one namespace or, with None, everything
+pub fn flush(storage: &Storage, namespace: Option<&str>) -> usize {
+    let flushed = match namespace {
+        Some(namespace) => storage.flush_namespace(namespace),
+        None => storage.clear(),
+    };
+    info!("Flushed {} entries from {}", flushed, namespace.unwrap_or("the cache"));
+    flushed
+}
+
+fn name(value: impl Debug) -> String {
+    format!("{:?}", value).to_lowercase()
+}
+
+// the settings in effect by config key, without credentials
+pub fn settings(config: &Config, storage: &Storage) -> Value {
+    json!({
+        "listen.address": config.listen_addr,
+        "listen.port": config.port,
+        "listen.workers": config.workers,
+        "listen.topology": name(config.topology),
+        "listen.tls": config.tls_cert_file.is_some(),
+        "limits.commands_per_second": config.rate_limit,
+        "upstream.hosts": config.upstream.hosts,
+        "upstream.user": config.upstream.username,
+        "auth.mode": name(config.auth_mode),
+        "auth.users_file": config.users_file,
+        "cache.backend": name(config.cache_backend),
+        "cache": policy_json(&config.cache_default),
+        "cache.policy": config.cache_policies.iter().map(rule_json).collect::<Vec<Value>>(),
+        "cache.runtime_policy": storage.policies().iter().map(rule_json).collect::<Vec<Value>>(),
+        "cache.watch.databases": config.watch_databases,
+        "cache.persist.file": config.persist_file,
+        "metrics.address": config.metrics_addr,
+        "admin.address": config.admin_addr,
+        "admin.users": config.admin_users,
+        "slow_log.file": config.slow_log_file,
+        "slow_log.threshold": config.slow_log_threshold.as_millis() as u64,
+        "slow_log.audit": config.audit_writes,
+        "otlp.endpoint": config.otlp_endpoint,
+        "otlp.sample_ratio": config.otlp_sample_ratio,
+        "log.level": name(config.log_level),
+        "log.format": name(config.log_format),
+    })
+}
+
+fn policies(config: &Config, storage: &Storage, request: &HttpRequest) -> HttpResponse {
+    let mut body = json!({
+        "default": policy_json(&config.cache_default),
+        "configured": config.cache_policies.iter().map(rule_json).collect::<Vec<Value>>(),
+        "runtime": storage.policies().iter().map(rule_json).collect::<Vec<Value>>(),
+    });
+    // how the rules resolve for one namespace
+    if let Some(namespace) = request.query.get("namespace") {
+        body["effective"] = policy_json(&storage.policy(config, namespace));
+    }
+    respond(200, body)
+}
+
+// a runtime rule from `?namespace=` and the fields it overrides, parsed as
+// `[[cache.policy]]` fields are
+fn parse_rule(request: &HttpRequest) -> Result<PolicyRule, String> {
+    let mut rule = PolicyRule {
+        namespace: match request.query.get("namespace") {
+            Some(namespace) if !namespace.is_empty() => namespace.clone(),
+            _ => return Err("namespace is required".to_string()),
+        },
+        ..PolicyRule::default()
+    };
+    let number = |field: &str, value: &str| {
+        value
+            .parse::<u64>()
+            .map_err(|_| format!("{}: '{}' is not a valid number", field, value))
+    };
+    let flag = |field: &str, value: &str| match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("{}: expected true or false, got '{}'", field, value)),
+    };
+    for (field, value) in request.query.iter() {
+        match field.as_str() {
+            "namespace" => {}
+            "ttl" => rule.ttl = Some(number(field, value)?),
+            "stale_while_revalidate" => rule.stale_while_revalidate = Some(number(field, value)?),
+            "max_entries" => rule.max_entries = Some(number(field, value)? as usize),
+            "enabled" => rule.enabled = Some(flag(field, value)?),
+            "cache_empty" => rule.cache_empty = Some(flag(field, value)?),
+            "invalidate_on_write" => rule.invalidate_on_write = Some(flag(field, value)?),
+            "local" => rule.local = Some(flag(field, value)?),
+            _ => return Err(format!("unknown policy field '{}'", field)),
+        }
+    }
+    Ok(rule)
+}
+
+fn set_policy(storage: &Storage, request: &HttpRequest) -> HttpResponse {
+    match parse_rule(request) {
+        Ok(rule) => {
+            info!("Cache policy for {} set through the admin api", rule.namespace);
+            let body = rule_json(&rule);
+            storage.set_policy(rule);
+            respond(200, body)
+        }
+        Err(e) => bad_request(&e),
+    }
+}
+
+fn remove_policy(storage: &Storage, request: &HttpRequest) -> HttpResponse {
+    let namespace = match request.query.get("namespace") {
+        Some(namespace) => namespace,
+        None => return bad_request("namespace is required"),
+    };
+    match storage.remove_policy(namespace) {
+        true => {
+            info!("Cache policy for {} removed through the admin api", namespace);
+            respond(200, json!({ "removed": namespace }))
+        }
+        false => respond(404, json!({ "error": "no runtime policy for that namespace" })),
+    }
+}
+
+fn connections(config: &Config, upstream: &str) -> HttpResponse {
+    let now = Instant::now();
+    let clients = clients::list();
+    let queued = clients.iter().filter(|client| client.upstream.is_none()).count();
+    let listed: Vec<Value> = clients
+        .iter()
+        .map(|client| {
+            json!({
+                "id": client.id,
+                "peer": client.peer.map(|peer| peer.to_string()),
+                "connected": client.connected.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()),
+                "upstream": client.upstream.map(|upstream| upstream.to_string()),
+                "user": Some(&client.user).filter(|user| !user.is_empty()),
+                "requests": client.requests,
+                "busy": client.busy,
+                "idle": client.last_request.map(|last| now.duration_since(last).as_secs_f64()),
+            })
+        })
+        .collect();
+    respond(
+        200,
+        json!({
+            "clients": listed,
+            "upstream": {
+                "address": upstream,
+            },
+            // a busy worker holds the upstream connection of its client
+            "workers": {
+                "size": config.workers,
+                "busy": metrics().upstream_connections.get(),
+                // accepted, waiting for a worker to free up
+                "queued": queued,
+            },
+        }),
+    )
+}
+
+// compares every byte so the time taken doesn't tell how much of a guess
+// was right
+fn same(given: &str, token: &str) -> bool {
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// reads the configuration again, 400 with the error when it's invalid
+fn reload_config() -> HttpResponse {
+    match reload::reload() {
+        Ok(report) => respond(200, reloaded(&report)),
+        Err(e) => bad_request(&e.to_string()),
+    }
+}
+
+pub fn reloaded(report: &reload::Report) -> Value {
+    json!({ "applied": report.applied, "restart_required": report.restart_required })
+}
+
+fn authorized(config: &Config, request: &HttpRequest) -> bool {
+    match &config.admin_token {
+        Some(token) => request
+            .headers
+            .get("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| same(given, token)),
+        None => true,
+    }
+}
+
+fn route(config: &Config, storage: &Storage, upstream: &str, request: &HttpRequest) -> HttpResponse {
+    if !authorized(config, request) {
+        return respond(401, json!({ "error": "missing or wrong bearer token" }));
+    }
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/cache") => respond(200, cache_stats(storage)),
+        ("GET", "/cache/entries") => entries(storage, request),
+        ("POST", "/cache/flush") => {
+            let flushed = flush(storage, request.query.get("namespace").map(|namespace| namespace.as_str()));
+            respond(200, json!({ "flushed": flushed }))
+        }
+        ("GET", "/cache/policies") => policies(config, storage, request),
+        ("PUT", "/cache/policies") => set_policy(storage, request),
+        ("DELETE", "/cache/policies") => remove_policy(storage, request),
+        ("GET", "/connections") => connections(config, upstream),
+        ("GET", "/config") => respond(200, settings(config, storage)),
+        ("POST", "/config/reload") => reload_config(),
+        (
+            _,
+            "/cache" | "/cache/entries" | "/cache/flush" | "/cache/policies" | "/connections" | "/config"
+            | "/config/reload",
+        ) => {
+            respond(405, json!({ "error": "method not allowed" }))
+        }
+        _ => HttpResponse::not_found(),
+    }
+}
+
+// serves the admin api on `admin.address`
+pub fn start(config: Arc<Config>, storage: Storage, upstream: Arc<String>) {
+    let addr = match &config.admin_addr {
+        Some(addr) => addr.clone(),
+        None => return,
+    };
+    if config.admin_token.is_none() {
+        warn!("The admin api on {} accepts requests without a token", addr);
+    }
+    let handler = move |request: &HttpRequest| route(&config::current(), &storage, &upstream, request);
+    match http::serve(&addr, handler) {
+        Ok(()) => info!("Admin api on http://{}", addr),
+        Err(e) => error!("admin listener on {}: {}", addr, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bson::doc;
+
+    use super::*;
+    use crate::cache::Cache;
+
+    fn request(method: &str, target: &str) -> HttpRequest {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            headers: HashMap::new(),
+            body: vec![],
+        }
+    }
+
+    fn call(config: &Config, storage: &Storage, method: &str, target: &str) -> (u16, Value) {
+        let response = route(config, storage, "db1:27017", &request(method, target));
+        let body = serde_json::from_slice(&response.body).unwrap_or(Value::Null);
+        (response.status, body)
+    }
+
+    fn storage() -> Storage {
+        let storage = Arc::new(Cache::new());
+        for (key, namespace) in [("a", "shop.orders"), ("b", "shop.orders"), ("c", "shop.carts")] {
+            let generation = storage.namespace_generation(namespace);
+            let data = InnerData::Document(doc! { "ok": 1.0 });
+            storage.insert(key.to_string(), namespace, data, &CachePolicy::default(), generation);
+        }
+        storage
+    }
+
+    #[test]
+    fn checks_the_bearer_token() {
+        let config = Config {
+            admin_token: Some("secret".to_string()),
+            ..Config::default()
+        };
+        let storage = storage();
+        assert_eq!(call(&config, &storage, "GET", "/cache").0, 401);
+        let mut authorized = request("GET", "/cache");
+        authorized.headers.insert("authorization".to_string(), "Bearer secret".to_string());
+        assert_eq!(route(&config, &storage, "", &authorized).status, 200);
+        authorized.headers.insert("authorization".to_string(), "Bearer secreT".to_string());
+        assert_eq!(route(&config, &storage, "", &authorized).status, 401);
+        authorized.headers.insert("authorization".to_string(), "secret".to_string());
+        assert_eq!(route(&config, &storage, "", &authorized).status, 401);
+        assert!(same("secret", "secret"));
+        assert!(!same("secret", "secre"));
+    }
+
+    #[test]
+    fn reports_cache_stats() {
+        let (status, body) = call(&Config::default(), &storage(), "GET", "/cache");
+        assert_eq!(status, 200);
+        assert_eq!(body["entries"], 3);
+        assert_eq!(body["namespaces"][0]["namespace"], "shop.carts");
+        assert_eq!(body["namespaces"][0]["entries"], 1);
+        assert_eq!(body["namespaces"][1]["namespace"], "shop.orders");
+        assert_eq!(body["namespaces"][1]["entries"], 2);
+        assert!(body["memory"]["replies"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn lists_entries() {
+        let config = Config::default();
+        let storage = storage();
+        let (_, body) = call(&config, &storage, "GET", "/cache/entries?namespace=shop.orders");
+        assert_eq!(body["matched"], 2);
+        assert_eq!(body["entries"].as_array().unwrap().len(), 2);
+        let (_, body) = call(&config, &storage, "GET", "/cache/entries?limit=1");
+        assert_eq!(body["matched"], 3);
+        assert_eq!(body["entries"].as_array().unwrap().len(), 1);
+        let (status, body) = call(&config, &storage, "GET", "/cache/entries?key=c");
+        assert_eq!(status, 200);
+        assert_eq!(body["namespace"], "shop.carts");
+        assert_eq!(body["kind"], "reply");
+        assert_eq!(body["expires_in"], Value::Null);
+        assert_eq!(body["expired"], false);
+        assert_eq!(call(&config, &storage, "GET", "/cache/entries?key=z").0, 404);
+        assert_eq!(call(&config, &storage, "GET", "/cache/entries?limit=all").0, 400);
+    }
+
+    #[test]
+    fn flushes() {
+        let config = Config::default();
+        let storage = storage();
+        let (status, body) = call(&config, &storage, "POST", "/cache/flush?namespace=shop.orders");
+        assert_eq!((status, body["flushed"].clone()), (200, json!(2)));
+        assert_eq!(storage.len(), 1);
+        let (_, body) = call(&config, &storage, "POST", "/cache/flush");
+        assert_eq!(body["flushed"], 1);
+        assert!(storage.is_empty());
+    }
+
+    #[test]
+    fn sets_and_removes_runtime_policies() {
+        let config = Config {
+            cache_policies: vec![PolicyRule {
+                namespace: "shop.*".to_string(),
+                ttl: Some(60),
+                ..PolicyRule::default()
+            }],
+            ..Config::default()
+        };
+        let storage = storage();
+        let target = "/cache/policies?namespace=shop.products&ttl=5&max_entries=10&cache_empty=false&local=true";
+        let (status, body) = call(&config, &storage, "PUT", target);
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(body["local"], true);
+        assert_eq!(body["enabled"], Value::Null);
+        let (_, body) = call(&config, &storage, "GET", "/cache/policies?namespace=shop.products");
+        assert_eq!(body["configured"][0]["namespace"], "shop.*");
+        assert_eq!(body["runtime"][0]["namespace"], "shop.products");
+        assert_eq!(body["effective"]["ttl"], 5.0);
+        assert_eq!(body["effective"]["max_entries"], 10);
+        assert_eq!(body["effective"]["cache_empty"], false);
+        assert_eq!(body["effective"]["local"], true);
+        let (status, _) = call(&config, &storage, "DELETE", "/cache/policies?namespace=shop.products");
+        assert_eq!(status, 200);
+        let (_, body) = call(&config, &storage, "GET", "/cache/policies?namespace=shop.products");
+        assert_eq!(body["effective"]["ttl"], 60.0);
+        assert_eq!(body["effective"]["local"], false);
+        assert_eq!(call(&config, &storage, "DELETE", "/cache/policies?namespace=shop.products").0, 404);
+    }
+
+    #[test]
+    fn refuses_invalid_policies() {
+        let config = Config::default();
+        let storage = storage();
+        for (target, error) in [
+            ("/cache/policies?ttl=5", "namespace is required"),
+            ("/cache/policies?namespace=shop.*&ttl=soon", "ttl: 'soon' is not a valid number"),
+            ("/cache/policies?namespace=shop.*&local=yes", "local: expected true or false, got 'yes'"),
+            ("/cache/policies?namespace=shop.*&size=5", "unknown policy field 'size'"),
+        ] {
+            let (status, body) = call(&config, &storage, "PUT", target);
+            assert_eq!(status, 400, "{}", target);
+            assert_eq!(body["error"], error);
+        }
+        assert!(storage.policies().is_empty());
+    }
+
+    #[test]
+    fn shows_settings_without_credentials() {
+        let mut config = Config::default();
+        config.upstream.username = Some("proxy".to_string());
+        config.upstream.password = Some("hunter2".to_string());
+        config.admin_token = Some("secret".to_string());
+        let mut request = request("GET", "/config");
+        request.headers.insert("authorization".to_string(), "Bearer secret".to_string());
+        let response = route(&config, &storage(), "", &request);
+        let body = String::from_utf8(response.body).unwrap();
+        let settings: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(settings["upstream.user"], "proxy");
+        assert_eq!(settings["listen.port"], config.port);
+        assert!(!body.contains("hunter2"));
+        assert!(!body.contains("secret"));
+    }
+
+    #[test]
+    fn reports_connections() {
+        let config = Config {
+            workers: 4,
+            ..Config::default()
+        };
+        let (status, body) = call(&config, &storage(), "GET", "/connections");
+        assert_eq!(status, 200);
+        assert!(body["clients"].is_array());
+        assert_eq!(body["upstream"]["address"], "db1:27017");
+        assert_eq!(body["workers"]["size"], 4);
+        assert!(body["workers"]["busy"].is_u64());
+        assert!(body["upstream"].get("pool_size").is_none());
+    }
+
+    #[test]
+    fn routes_by_method_and_path() {
+        let config = Config::default();
+        let storage = storage();
+        assert_eq!(call(&config, &storage, "DELETE", "/cache").0, 405);
+        assert_eq!(call(&config, &storage, "GET", "/cache/flush").0, 405);
+        assert_eq!(call(&config, &storage, "GET", "/config/reload").0, 405);
+        assert_eq!(call(&config, &storage, "GET", "/nowhere").0, 404);
+        assert_eq!(storage.len(), 3);
+    }
+}
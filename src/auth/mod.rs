@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::fs;
+
+use bson::{doc, Bson, Document};
+use serde_json::Value;
+
+use self::scram::{Credential, Mechanism, ScramError, ScramServer};
+pub mod passthrough;
+pub mod scram;
+
+// commands a client may run before it has authenticated, mirrors mongod
+const UNAUTHENTICATED_COMMANDS: [&str; 10] = [
+    "hello",
+    "isMaster",
+    "ismaster",
+    "saslStart",
+    "saslContinue",
+    "buildInfo",
+    "buildinfo",
+    "ping",
+    "getnonce",
+    "endSessions",
+];
+
+pub fn requires_auth(command: &str) -> bool {
+    !UNAUTHENTICATED_COMMANDS.contains(&command)
+}
+
+pub fn unauthorized(command: &str) -> Document {
+    doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": Bson::String(format!("command {} requires authentication", command)),
+        "code": Bson::Int32(13),
+        "codeName": "Unauthorized",
+    }
+}
+
+pub fn authentication_failed(message: &str) -> Document {
+    doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": Bson::String(message.to_string()),
+        "code": Bson::Int32(18),
+        "codeName": "AuthenticationFailed",
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct User {
+    pub user: String,
+    pub db: String,
+    pub credentials: Vec<Credential>,
+}
+
+impl User {
+    pub fn credential(&self, mechanism: Mechanism) -> Option<&Credential> {
+        self.credentials.iter().find(|c| c.mechanism == mechanism)
+    }
+}
+
+// the local users clients authenticate against, keyed by `db.user`
+#[derive(Debug, Clone, Default)]
+pub struct UserStore {
+    users: HashMap<String, User>,
+}
+
+impl UserStore {
+    // reads a json array of `{user, db, password, mechanisms}` entries, the
+    // passwords are only used to derive SCRAM keys and then dropped
+    pub fn load(path: &str) -> Result<UserStore, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let entries: Value = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+        let entries = match entries.as_array() {
+            Some(entries) => entries,
+            None => return Err(format!("{}: expected an array of users", path)),
+        };
+        let mut users = HashMap::new();
+        for (index, entry) in entries.iter().enumerate() {
+            let field = |name: &str| {
+                entry
+                    .get(name)
+                    .and_then(|value| value.as_str())
+                    .map(|value| value.to_string())
+                    .ok_or_else(|| format!("{}: users[{}].{} is required", path, index, name))
+            };
+            let user = field("user")?;
+            let password = field("password")?;
+            let db = field("db").unwrap_or("admin".to_string());
+            let mechanisms = match entry.get("mechanisms").and_then(|m| m.as_array()) {
+                Some(names) => names
+                    .iter()
+                    .map(|name| {
+                        name.as_str().and_then(Mechanism::parse).ok_or_else(|| {
+                            format!("{}: users[{}].mechanisms has unknown mechanism {}", path, index, name)
+                        })
+                    })
+                    .collect::<Result<Vec<Mechanism>, String>>()?,
+                None => vec![Mechanism::ScramSha1, Mechanism::ScramSha256],
+            };
+            let credentials = mechanisms
+                .iter()
+                .map(|mechanism| Credential::derive(*mechanism, &user, &password))
+                .collect::<Result<Vec<Credential>, ScramError>>()
+                .map_err(|e| format!("{}: users[{}].password: {}", path, index, e))?;
+            users.insert(format!("{}.{}", db, user), User { user, db, credentials });
+        }
+        Ok(UserStore { users })
+    }
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty()
+    }
+    pub fn get(&self, db: &str, user: &str) -> Option<&User> {
+        self.users.get(&format!("{}.{}", db, user))
+    }
+    // answer to `saslSupportedMechs: "db.user"` in the handshake
+    pub fn mechanisms(&self, namespaced_user: &str) -> Option<Vec<Bson>> {
+        let user = self.users.get(namespaced_user)?;
+        Some(
+            user.credentials
+                .iter()
+                .map(|c| Bson::String(c.mechanism.name().to_string()))
+                .collect(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+    pub user: String,
+    pub db: String,
+}
+
+// per client connection authentication state
+#[derive(Debug, Default)]
+pub struct Session {
+    pub principal: Option<Principal>,
+    // who a relayed conversation is for until the upstream accepts it
+    pub pending: Option<Principal>,
+    pub conversation: Option<ScramServer>,
+    pub conversation_db: String,
+    pub skip_empty_exchange: bool,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session::default()
+    }
+    pub fn is_authenticated(&self) -> bool {
+        self.principal.is_some()
+    }
+    // cache entries are only shared between connections of the same user
+    pub fn partition(&self) -> String {
+        match &self.principal {
+            Some(principal) => format!("{}.{}", principal.db, principal.user),
+            None => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(json: &str) -> Result<UserStore, String> {
+        static FILES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let file = FILES.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("rengo-users-{}-{}.json", std::process::id(), file));
+        fs::write(&path, json).unwrap();
+        let users = UserStore::load(path.to_str().unwrap());
+        let _ = fs::remove_file(path);
+        users
+    }
+
+    #[test]
+    fn loads_users_with_their_mechanisms() {
+        let users = load(
+            r#"[{"user": "app", "db": "shop", "password": "secret", "mechanisms": ["SCRAM-SHA-256"]},
+                {"user": "ops", "password": "secret"}]"#,
+        )
+        .unwrap();
+        assert!(users.is_enabled());
+        let app = users.get("shop", "app").unwrap();
+        assert!(app.credential(Mechanism::ScramSha256).is_some());
+        assert!(app.credential(Mechanism::ScramSha1).is_none());
+        // db defaults to admin and both mechanisms
+        assert_eq!(users.mechanisms("admin.ops").unwrap().len(), 2);
+        assert!(users.mechanisms("shop.ops").is_none());
+    }
+
+    #[test]
+    fn rejects_bad_user_files() {
+        let error = load(r#"[{"user": "app"}]"#).unwrap_err();
+        assert!(error.ends_with("users[0].password is required"), "{}", error);
+        let error = load(r#"[{"user": "app", "password": "x", "mechanisms": ["PLAIN"]}]"#).unwrap_err();
+        assert!(error.contains("unknown mechanism"), "{}", error);
+        let error = load(r#"{"user": "app"}"#).unwrap_err();
+        assert!(error.ends_with("expected an array of users"), "{}", error);
+        let error = load(r#"[{"user": "app", "password": "a\u0007"}]"#).unwrap_err();
+        assert!(error.contains("users[0].password"), "{}", error);
+    }
+
+    #[test]
+    fn sessions_partition_by_principal() {
+        let mut session = Session::new();
+        assert_eq!(session.partition(), "");
+        session.principal = Some(Principal {
+            user: "app".to_string(),
+            db: "shop".to_string(),
+        });
+        assert_eq!(session.partition(), "shop.app");
+    }
+
+    #[test]
+    fn handshake_commands_need_no_auth() {
+        assert!(!requires_auth("hello"));
+        assert!(!requires_auth("saslContinue"));
+        assert!(requires_auth("find"));
+    }
+}
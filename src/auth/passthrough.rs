@@ -0,0 +1,139 @@
+use bson::{Bson, Document};
+
+use super::{Principal, Session};
+
+fn payload(doc: &Document) -> Vec<u8> {
+    match doc.get("payload") {
+        Some(Bson::Binary(binary)) => binary.bytes.clone(),
+        Some(Bson::String(payload)) => payload.clone().into_bytes(),
+        _ => vec![],
+    }
+}
+
+// the user a relayed conversation authenticates as, when the mechanism does
+// not tell us we fall back to a name unique to the connection so its cache
+// partition is never shared
+fn principal_name(mechanism: &str, payload: &[u8], connection: &str) -> String {
+    let payload = String::from_utf8_lossy(payload);
+    let name = match mechanism {
+        "SCRAM-SHA-1" | "SCRAM-SHA-256" => payload
+            .split(',')
+            .find_map(|part| part.strip_prefix("n="))
+            .map(|name| name.replace("=2C", ",").replace("=3D", "=")),
+        // authzid \0 authcid \0 password
+        "PLAIN" => payload.split('\0').nth(1).map(|name| name.to_string()),
+        _ => None,
+    };
+    match name {
+        Some(name) if !name.is_empty() => name,
+        _ => format!("#{}", connection),
+    }
+}
+
+fn is_ok(reply: &Document) -> bool {
+    match reply.get("ok") {
+        Some(Bson::Double(ok)) => *ok == 1.0,
+        Some(Bson::Int32(ok)) => *ok == 1,
+        _ => false,
+    }
+}
+
+// remembers who a relayed saslStart / authenticate is for before it goes to
+// the upstream
+pub fn observe_request(session: &mut Session, command: &str, doc: &Document, connection: &str) {
+    let db = doc.get_str("$db").unwrap_or("admin").to_string();
+    match command {
+        "saslStart" => {
+            let mechanism = doc.get_str("mechanism").unwrap_or("");
+            session.pending = Some(Principal {
+                user: principal_name(mechanism, &payload(doc), connection),
+                db,
+            });
+        }
+        "authenticate" => {
+            let user = match doc.get_str("user") {
+                Ok(user) if !user.is_empty() => user.to_string(),
+                _ => format!("#{}", connection),
+            };
+            session.pending = Some(Principal { user, db });
+        }
+        _ => {}
+    }
+}
+
+// promotes the pending principal once the upstream says the conversation is done
+pub fn observe_reply(session: &mut Session, command: &str, reply: &Document) {
+    match command {
+        "saslStart" | "saslContinue" | "authenticate" => {
+            if !is_ok(reply) {
+                session.pending = None;
+            } else if reply.get_bool("done").unwrap_or(true) {
+                // authenticate has no done field, it is a single round trip
+                if let Some(principal) = session.pending.take() {
+                    session.principal = Some(principal);
+                }
+            }
+        }
+        "logout" => {
+            session.principal = None;
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{doc, spec::BinarySubtype, Binary};
+
+    use super::*;
+
+    fn sasl_start(mechanism: &str, payload: &[u8]) -> Document {
+        doc! {
+            "saslStart": 1,
+            "mechanism": mechanism,
+            "payload": Bson::Binary(Binary { subtype: BinarySubtype::Generic, bytes: payload.to_vec() }),
+            "$db": "shop",
+        }
+    }
+
+    #[test]
+    fn names_the_principal_from_the_payload() {
+        assert_eq!(principal_name("SCRAM-SHA-256", b"n,,n=app,r=abc", "7"), "app");
+        assert_eq!(principal_name("SCRAM-SHA-1", b"n,,n=a=2Cb=3Dc,r=abc", "7"), "a,b=c");
+        assert_eq!(principal_name("PLAIN", b"\0app\0secret", "7"), "app");
+        // unknown mechanisms never share a partition with another connection
+        assert_eq!(principal_name("GSSAPI", b"\x60\x82", "7"), "#7");
+        assert_eq!(principal_name("SCRAM-SHA-256", b"n,,r=abc", "7"), "#7");
+    }
+
+    #[test]
+    fn authenticates_once_the_upstream_is_done() {
+        let mut session = Session::new();
+        observe_request(&mut session, "saslStart", &sasl_start("SCRAM-SHA-256", b"n,,n=app,r=abc"), "1");
+        observe_reply(&mut session, "saslStart", &doc! { "ok": 1.0, "done": false });
+        assert!(!session.is_authenticated());
+        observe_reply(&mut session, "saslContinue", &doc! { "ok": 1.0, "done": true });
+        assert_eq!(session.partition(), "shop.app");
+        observe_reply(&mut session, "logout", &doc! { "ok": 1.0 });
+        assert!(!session.is_authenticated());
+    }
+
+    #[test]
+    fn a_failed_conversation_authenticates_nobody() {
+        let mut session = Session::new();
+        observe_request(&mut session, "saslStart", &sasl_start("SCRAM-SHA-256", b"n,,n=app,r=abc"), "1");
+        observe_reply(&mut session, "saslContinue", &doc! { "ok": 0.0, "code": 18 });
+        assert!(session.pending.is_none());
+        observe_reply(&mut session, "saslContinue", &doc! { "ok": 1.0, "done": true });
+        assert!(!session.is_authenticated());
+    }
+
+    #[test]
+    fn authenticate_is_a_single_round_trip() {
+        let mut session = Session::new();
+        let request = doc! { "authenticate": 1, "mechanism": "MONGODB-X509", "user": "CN=app", "$db": "$external" };
+        observe_request(&mut session, "authenticate", &request, "1");
+        observe_reply(&mut session, "authenticate", &doc! { "ok": 1 });
+        assert_eq!(session.partition(), "$external.CN=app");
+    }
+}
@@ -0,0 +1,553 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use rand::{distributions::Alphanumeric, Rng};
+use sha1::Sha1;
+use sha2::Sha256;
+
+// mongod's default iteration counts for newly created users
+pub const SHA1_ITERATIONS: u32 = 10000;
+pub const SHA256_ITERATIONS: u32 = 15000;
+// the lowest count RFC 7677 allows, a server asking for fewer is refused
+pub const MIN_ITERATIONS: u32 = 4096;
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mechanism {
+    ScramSha1,
+    ScramSha256,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScramError {
+    pub message: String,
+}
+impl std::error::Error for ScramError {}
+impl std::fmt::Display for ScramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl ScramError {
+    pub fn new(message: &str) -> Self {
+        ScramError {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Mechanism {
+    pub fn parse(name: &str) -> Option<Mechanism> {
+        match name {
+            "SCRAM-SHA-1" => Some(Mechanism::ScramSha1),
+            "SCRAM-SHA-256" => Some(Mechanism::ScramSha256),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mechanism::ScramSha1 => "SCRAM-SHA-1",
+            Mechanism::ScramSha256 => "SCRAM-SHA-256",
+        }
+    }
+    pub fn default_iterations(&self) -> u32 {
+        match self {
+            Mechanism::ScramSha1 => SHA1_ITERATIONS,
+            Mechanism::ScramSha256 => SHA256_ITERATIONS,
+        }
+    }
+    // SCRAM-SHA-1 in mongodb runs over the legacy MONGODB-CR digest instead
+    // of the raw password, SCRAM-SHA-256 over its SASLprep form (RFC 4013)
+    fn prepare_password(&self, username: &str, password: &str) -> Result<String, ScramError> {
+        match self {
+            Mechanism::ScramSha1 => {
+                let digest = Md5::digest(format!("{}:mongo:{}", username, password).as_bytes());
+                Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+            }
+            Mechanism::ScramSha256 => stringprep::saslprep(password)
+                .map(|password| password.into_owned())
+                .map_err(|e| ScramError::new(&format!("password is not valid for SCRAM-SHA-256: {}", e))),
+        }
+    }
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Mechanism::ScramSha1 => Sha1::digest(data).to_vec(),
+            Mechanism::ScramSha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+    fn hmac(&self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            Mechanism::ScramSha1 => {
+                let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            Mechanism::ScramSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+    fn salted_password(&self, password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+        match self {
+            Mechanism::ScramSha1 => {
+                let mut out = [0u8; 20];
+                pbkdf2::pbkdf2_hmac::<Sha1>(password.as_bytes(), salt, iterations, &mut out);
+                out.to_vec()
+            }
+            Mechanism::ScramSha256 => {
+                let mut out = [0u8; 32];
+                pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut out);
+                out.to_vec()
+            }
+        }
+    }
+}
+
+// what the server keeps for a user, the password itself is never stored
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub mechanism: Mechanism,
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl Credential {
+    pub fn derive(mechanism: Mechanism, username: &str, password: &str) -> Result<Credential, ScramError> {
+        let salt: Vec<u8> = (0..SALT_LEN).map(|_| rand::random::<u8>()).collect();
+        Credential::derive_with_salt(mechanism, username, password, salt, mechanism.default_iterations())
+    }
+    pub fn derive_with_salt(
+        mechanism: Mechanism,
+        username: &str,
+        password: &str,
+        salt: Vec<u8>,
+        iterations: u32,
+    ) -> Result<Credential, ScramError> {
+        let password = mechanism.prepare_password(username, password)?;
+        let salted = mechanism.salted_password(&password, &salt, iterations);
+        let client_key = mechanism.hmac(&salted, b"Client Key");
+        Ok(Credential {
+            mechanism,
+            stored_key: mechanism.hash(&client_key),
+            server_key: mechanism.hmac(&salted, b"Server Key"),
+            salt,
+            iterations,
+        })
+    }
+}
+
+fn nonce() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(NONCE_LEN)
+        .map(char::from)
+        .collect()
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
+
+// compares keys in time that doesn't depend on where they differ
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// splits `a=1,b=2` into its attributes, values may contain `=`. Keys are a
+// single ascii letter, anything else is skipped.
+fn attributes(message: &str) -> Vec<(char, &str)> {
+    message
+        .split(',')
+        .filter_map(|part| {
+            let (key, value) = part.split_once('=')?;
+            let mut chars = key.chars();
+            match (chars.next(), chars.next()) {
+                (Some(key), None) if key.is_ascii_alphabetic() => Some((key, value)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn attribute(message: &str, key: char) -> Result<&str, ScramError> {
+    attributes(message)
+        .into_iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+        .ok_or_else(|| ScramError::new(&format!("missing '{}' attribute in SCRAM message", key)))
+}
+
+// usernames escape ',' and '=' as =2C and =3D
+fn decode_username(name: &str) -> String {
+    name.replace("=2C", ",").replace("=3D", "=")
+}
+fn encode_username(name: &str) -> String {
+    name.replace('=', "=3D").replace(',', "=2C")
+}
+
+// server side of a single SCRAM conversation with a client
+#[derive(Debug, Clone)]
+pub struct ScramServer {
+    pub mechanism: Mechanism,
+    pub username: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+    credential: Option<Credential>,
+    pub done: bool,
+}
+
+impl ScramServer {
+    // parses client-first and returns the username it asks for
+    pub fn start(mechanism: Mechanism, client_first: &[u8]) -> Result<ScramServer, ScramError> {
+        let client_first = std::str::from_utf8(client_first)
+            .map_err(|_| ScramError::new("SCRAM client-first message is not utf-8"))?;
+        // gs2 header: we never support channel binding
+        let bare = match client_first.strip_prefix("n,,") {
+            Some(bare) => bare,
+            None => return Err(ScramError::new("SCRAM channel binding is not supported")),
+        };
+        let username = decode_username(attribute(bare, 'n')?);
+        let client_nonce = attribute(bare, 'r')?;
+        Ok(ScramServer {
+            mechanism,
+            username,
+            client_first_bare: bare.to_string(),
+            server_first: String::new(),
+            nonce: format!("{}{}", client_nonce, nonce()),
+            credential: None,
+            done: false,
+        })
+    }
+    // builds server-first, an unknown user still gets a fake salt so the
+    // reply does not reveal whether the user exists
+    pub fn server_first(&mut self, credential: Option<Credential>) -> Vec<u8> {
+        let (salt, iterations) = match &credential {
+            Some(credential) => (credential.salt.clone(), credential.iterations),
+            None => (
+                self.mechanism.hash(self.username.as_bytes())[..SALT_LEN].to_vec(),
+                self.mechanism.default_iterations(),
+            ),
+        };
+        self.credential = credential;
+        self.server_first = format!("r={},s={},i={}", self.nonce, STANDARD.encode(salt), iterations);
+        self.server_first.clone().into_bytes()
+    }
+    // verifies client-final and returns server-final carrying our signature
+    pub fn server_final(&mut self, client_final: &[u8]) -> Result<Vec<u8>, ScramError> {
+        let client_final = std::str::from_utf8(client_final)
+            .map_err(|_| ScramError::new("SCRAM client-final message is not utf-8"))?;
+        // `biws` is `n,,` in base64, the gs2 header without channel binding
+        if attribute(client_final, 'c')? != "biws" {
+            return Err(ScramError::new("SCRAM channel binding is not supported"));
+        }
+        if attribute(client_final, 'r')? != self.nonce {
+            return Err(ScramError::new("SCRAM nonce mismatch"));
+        }
+        let proof = STANDARD
+            .decode(attribute(client_final, 'p')?)
+            .map_err(|_| ScramError::new("SCRAM proof is not base64"))?;
+        let without_proof = match client_final.rfind(",p=") {
+            Some(index) => &client_final[..index],
+            None => return Err(ScramError::new("SCRAM proof must be the last attribute")),
+        };
+        let auth_message = format!("{},{},{}", self.client_first_bare, self.server_first, without_proof);
+        let credential = match &self.credential {
+            Some(credential) => credential,
+            None => return Err(ScramError::new("Authentication failed.")),
+        };
+        let client_signature = self.mechanism.hmac(&credential.stored_key, auth_message.as_bytes());
+        let client_key = xor(&proof, &client_signature);
+        if proof.len() != client_signature.len() || !same(&self.mechanism.hash(&client_key), &credential.stored_key) {
+            return Err(ScramError::new("Authentication failed."));
+        }
+        let server_signature = self.mechanism.hmac(&credential.server_key, auth_message.as_bytes());
+        self.done = true;
+        Ok(format!("v={}", STANDARD.encode(server_signature)).into_bytes())
+    }
+}
+
+// client side of a SCRAM conversation, used to log rengo into the upstream
+pub struct ScramClient {
+    mechanism: Mechanism,
+    username: String,
+    password: String,
+    client_first_bare: String,
+    server_signature: Vec<u8>,
+}
+
+impl ScramClient {
+    pub fn new(mechanism: Mechanism, username: &str, password: &str) -> ScramClient {
+        ScramClient {
+            mechanism,
+            username: username.to_string(),
+            password: password.to_string(),
+            client_first_bare: format!("n={},r={}", encode_username(username), nonce()),
+            server_signature: vec![],
+        }
+    }
+    pub fn client_first(&self) -> Vec<u8> {
+        format!("n,,{}", self.client_first_bare).into_bytes()
+    }
+    pub fn client_final(&mut self, server_first: &[u8]) -> Result<Vec<u8>, ScramError> {
+        let server_first = std::str::from_utf8(server_first)
+            .map_err(|_| ScramError::new("SCRAM server-first message is not utf-8"))?;
+        let nonce = attribute(server_first, 'r')?;
+        if !nonce.starts_with(attribute(&self.client_first_bare, 'r')?) {
+            return Err(ScramError::new("SCRAM server nonce does not extend ours"));
+        }
+        let salt = STANDARD
+            .decode(attribute(server_first, 's')?)
+            .map_err(|_| ScramError::new("SCRAM salt is not base64"))?;
+        let iterations: u32 = attribute(server_first, 'i')?
+            .parse()
+            .map_err(|_| ScramError::new("SCRAM iteration count is not a number"))?;
+        if iterations < MIN_ITERATIONS {
+            return Err(ScramError::new("SCRAM iteration count is too low"));
+        }
+        let password = self.mechanism.prepare_password(&self.username, &self.password)?;
+        let salted = self.mechanism.salted_password(&password, &salt, iterations);
+        let client_key = self.mechanism.hmac(&salted, b"Client Key");
+        let stored_key = self.mechanism.hash(&client_key);
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!("{},{},{}", self.client_first_bare, server_first, without_proof);
+        let client_signature = self.mechanism.hmac(&stored_key, auth_message.as_bytes());
+        let server_key = self.mechanism.hmac(&salted, b"Server Key");
+        self.server_signature = self.mechanism.hmac(&server_key, auth_message.as_bytes());
+        let proof = STANDARD.encode(xor(&client_key, &client_signature));
+        Ok(format!("{},p={}", without_proof, proof).into_bytes())
+    }
+    pub fn verify_server_final(&self, server_final: &[u8]) -> Result<(), ScramError> {
+        let server_final = std::str::from_utf8(server_final)
+            .map_err(|_| ScramError::new("SCRAM server-final message is not utf-8"))?;
+        if let Ok(error) = attribute(server_final, 'e') {
+            return Err(ScramError::new(error));
+        }
+        let signature = STANDARD
+            .decode(attribute(server_final, 'v')?)
+            .map_err(|_| ScramError::new("SCRAM server signature is not base64"))?;
+        if !same(&signature, &self.server_signature) {
+            return Err(ScramError::new("SCRAM server signature mismatch"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a server conversation whose nonce is fixed to the one in the vector
+    fn server(mechanism: Mechanism, client_first: &str, nonce: &str, credential: Credential) -> (ScramServer, String) {
+        let mut server = ScramServer::start(mechanism, client_first.as_bytes()).unwrap();
+        server.nonce = nonce.to_string();
+        let server_first = String::from_utf8(server.server_first(Some(credential))).unwrap();
+        (server, server_first)
+    }
+
+    // the keys of a plain SCRAM user, without mongodb's password digest
+    fn raw_credential(mechanism: Mechanism, password: &str, salt: &str, iterations: u32) -> Credential {
+        let salt = STANDARD.decode(salt).unwrap();
+        let salted = mechanism.salted_password(password, &salt, iterations);
+        let client_key = mechanism.hmac(&salted, b"Client Key");
+        Credential {
+            mechanism,
+            stored_key: mechanism.hash(&client_key),
+            server_key: mechanism.hmac(&salted, b"Server Key"),
+            salt,
+            iterations,
+        }
+    }
+
+    fn client(mechanism: Mechanism, password: &str, client_nonce: &str) -> ScramClient {
+        ScramClient {
+            mechanism,
+            username: "user".to_string(),
+            password: password.to_string(),
+            client_first_bare: format!("n=user,r={}", client_nonce),
+            server_signature: vec![],
+        }
+    }
+
+    #[test]
+    fn rfc5802_server() {
+        let nonce = "fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j";
+        let credential = raw_credential(Mechanism::ScramSha1, "pencil", "QSXCR+Q6sek8bf92", 4096);
+        let (mut server, server_first) =
+            server(Mechanism::ScramSha1, "n,,n=user,r=fyko+d2lbbFgONRv9qkxdawL", nonce, credential);
+        assert_eq!(server.username, "user");
+        assert_eq!(server_first, format!("r={},s=QSXCR+Q6sek8bf92,i=4096", nonce));
+        let client_final = format!("c=biws,r={},p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts=", nonce);
+        let server_final = server.server_final(client_final.as_bytes()).unwrap();
+        assert_eq!(server_final, b"v=rmF9pqV8S7suAoZWja4dJRkFsKQ=");
+        assert!(server.done);
+    }
+
+    #[test]
+    fn rfc7677_server() {
+        let nonce = "rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+        let credential = Credential::derive_with_salt(
+            Mechanism::ScramSha256,
+            "user",
+            "pencil",
+            STANDARD.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap(),
+            4096,
+        )
+        .unwrap();
+        let (mut server, server_first) =
+            server(Mechanism::ScramSha256, "n,,n=user,r=rOprNGfwEbeRWgbNEkqO", nonce, credential);
+        assert_eq!(server_first, format!("r={},s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096", nonce));
+        let client_final = format!("c=biws,r={},p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=", nonce);
+        let server_final = server.server_final(client_final.as_bytes()).unwrap();
+        assert_eq!(server_final, b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+    }
+
+    #[test]
+    fn rfc7677_client() {
+        let mut client = client(Mechanism::ScramSha256, "pencil", "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(client.client_first(), b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let client_final = client.client_final(server_first.as_bytes()).unwrap();
+        assert_eq!(
+            String::from_utf8(client_final).unwrap(),
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        assert!(client
+            .verify_server_final(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")
+            .is_ok());
+        assert!(client.verify_server_final(b"v=rmF9pqV8S7suAoZWja4dJRkFsKQ=").is_err());
+    }
+
+    // mongodb's SCRAM-SHA-1 runs over the MONGODB-CR digest of the password,
+    // the vector from its authentication spec
+    #[test]
+    fn mongodb_sha1_client() {
+        let mut client = client(Mechanism::ScramSha1, "pencil", "fyko+d2lbbFgONRv9qkxdawL");
+        let server_first =
+            "r=fyko+d2lbbFgONRv9qkxdawLHo+Vgk7qvUOKUwuWLIWg4l/9SraGMHEE,s=rQ9ZY3MntBeuP3E1TDVC4w==,i=10000";
+        let client_final = client.client_final(server_first.as_bytes()).unwrap();
+        assert_eq!(
+            String::from_utf8(client_final).unwrap(),
+            "c=biws,r=fyko+d2lbbFgONRv9qkxdawLHo+Vgk7qvUOKUwuWLIWg4l/9SraGMHEE,p=MC2T8BvbmWRckDw8oWl5IVghwCY="
+        );
+        assert!(client.verify_server_final(b"v=UMWeI25JD1yNYZRMpZ4VHvhZ9e0=").is_ok());
+    }
+
+    fn round_trip(mechanism: Mechanism, stored: &str, given: &str) -> Result<(), ScramError> {
+        let credential = Credential::derive(mechanism, "app", stored).unwrap();
+        let mut client = ScramClient::new(mechanism, "app", given);
+        let mut server = ScramServer::start(mechanism, &client.client_first())?;
+        assert_eq!(server.username, "app");
+        let server_first = server.server_first(Some(credential));
+        let client_final = client.client_final(&server_first)?;
+        let server_final = server.server_final(&client_final)?;
+        client.verify_server_final(&server_final)
+    }
+
+    #[test]
+    fn client_and_server_agree() {
+        assert!(round_trip(Mechanism::ScramSha1, "secret", "secret").is_ok());
+        assert!(round_trip(Mechanism::ScramSha256, "secret", "secret").is_ok());
+    }
+
+    #[test]
+    fn wrong_password_fails() {
+        for mechanism in [Mechanism::ScramSha1, Mechanism::ScramSha256] {
+            let error = round_trip(mechanism, "secret", "guess").unwrap_err();
+            assert_eq!(error.message, "Authentication failed.");
+        }
+    }
+
+    #[test]
+    fn unknown_user_fails_without_revealing_it() {
+        let mut client = ScramClient::new(Mechanism::ScramSha256, "nobody", "secret");
+        let mut server = ScramServer::start(Mechanism::ScramSha256, &client.client_first()).unwrap();
+        let server_first = server.server_first(None);
+        assert!(String::from_utf8(server_first.clone()).unwrap().contains(",i=15000"));
+        let client_final = client.client_final(&server_first).unwrap();
+        assert!(server.server_final(&client_final).is_err());
+        assert!(!server.done);
+    }
+
+    #[test]
+    fn low_iteration_counts_are_refused() {
+        let mut client = ScramClient::new(Mechanism::ScramSha256, "app", "secret");
+        let nonce = attribute(&client.client_first_bare, 'r').unwrap().to_string();
+        let error = client.client_final(format!("r={}x,s=QSXCR+Q6sek8bf92,i=1", nonce).as_bytes()).unwrap_err();
+        assert_eq!(error.message, "SCRAM iteration count is too low");
+        assert!(client.client_final(format!("r={}x,s=QSXCR+Q6sek8bf92,i=4096", nonce).as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn keys_are_compared_whole() {
+        assert!(same(b"stored", b"stored"));
+        assert!(!same(b"stored", b"storeD"));
+        assert!(!same(b"stored", b"store"));
+    }
+
+    #[test]
+    fn nonce_mismatch_fails() {
+        let credential = Credential::derive(Mechanism::ScramSha256, "app", "secret").unwrap();
+        let mut client = ScramClient::new(Mechanism::ScramSha256, "app", "secret");
+        let mut server = ScramServer::start(Mechanism::ScramSha256, &client.client_first()).unwrap();
+        let server_first = server.server_first(Some(credential));
+        let client_final = String::from_utf8(client.client_final(&server_first).unwrap()).unwrap();
+        let tampered = client_final.replacen(",r=", ",r=x", 1);
+        let error = server.server_final(tampered.as_bytes()).unwrap_err();
+        assert_eq!(error.message, "SCRAM nonce mismatch");
+        // and a server nonce that doesn't start with the client's
+        let mut client = ScramClient::new(Mechanism::ScramSha256, "app", "secret");
+        let error = client.client_final(b"r=other,s=QSXCR+Q6sek8bf92,i=4096").unwrap_err();
+        assert_eq!(error.message, "SCRAM server nonce does not extend ours");
+    }
+
+    #[test]
+    fn channel_binding_is_rejected() {
+        assert!(ScramServer::start(Mechanism::ScramSha256, b"p=tls-unique,,n=user,r=abc").is_err());
+        let credential = Credential::derive(Mechanism::ScramSha256, "app", "secret").unwrap();
+        let mut client = ScramClient::new(Mechanism::ScramSha256, "app", "secret");
+        let mut server = ScramServer::start(Mechanism::ScramSha256, &client.client_first()).unwrap();
+        let server_first = server.server_first(Some(credential));
+        let client_final = String::from_utf8(client.client_final(&server_first).unwrap()).unwrap();
+        let bound = client_final.replacen("c=biws", "c=eSws", 1);
+        let error = server.server_final(bound.as_bytes()).unwrap_err();
+        assert_eq!(error.message, "SCRAM channel binding is not supported");
+    }
+
+    #[test]
+    fn attributes_need_a_single_ascii_key() {
+        assert_eq!(attributes("n=user,r=a=b"), vec![('n', "user"), ('r', "a=b")]);
+        assert_eq!(attributes("€=x,nn=y,=z,r"), vec![]);
+        assert!(ScramServer::start(Mechanism::ScramSha256, "n,,€=x,n=user".as_bytes()).is_err());
+        let mut server = ScramServer::start(Mechanism::ScramSha256, b"n,,n=user,r=abc").unwrap();
+        server.server_first(None);
+        assert!(server.server_final("€=x,c=biws".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn usernames_are_escaped() {
+        assert_eq!(encode_username("a,b=c"), "a=2Cb=3Dc");
+        assert_eq!(decode_username("a=2Cb=3Dc"), "a,b=c");
+        let server = ScramServer::start(Mechanism::ScramSha1, b"n,,n=a=2Cb,r=abc").unwrap();
+        assert_eq!(server.username, "a,b");
+    }
+
+    #[test]
+    fn sha256_passwords_are_saslprepped() {
+        let salt = vec![1; SALT_LEN];
+        let plain = Credential::derive_with_salt(Mechanism::ScramSha256, "user", "IX", salt.clone(), 4096).unwrap();
+        // a soft hyphen maps to nothing
+        let mapped =
+            Credential::derive_with_salt(Mechanism::ScramSha256, "user", "I\u{00AD}X", salt.clone(), 4096).unwrap();
+        assert_eq!(plain.stored_key, mapped.stored_key);
+        // a control character is prohibited
+        assert!(Credential::derive_with_salt(Mechanism::ScramSha256, "user", "a\u{0007}", salt.clone(), 4096).is_err());
+        // SCRAM-SHA-1 hashes the password as given
+        assert!(Credential::derive_with_salt(Mechanism::ScramSha1, "user", "a\u{0007}", salt, 4096).is_ok());
+    }
+}
@@ -0,0 +1,726 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use bson::{doc, Bson, Document};
+
+use super::policy::{is_ok, CachePolicy};
+use super::reads::VOLATILE_FIELDS;
+
+// find fields a point lookup may carry, anything else (collation, hint,
+// min/max, returnKey...) can change what `{_id: X}` returns
+const POINT_LOOKUP_FIELDS: [&str; 7] = ["find", "filter", "limit", "singleBatch", "batchSize", "sort", "skip"];
+
+// the key of an `_id` value, relaxed extended json keeps 1 and 1L together
+pub fn id_key(id: &Bson) -> String {
+    id.clone().into_relaxed_extjson().to_string()
+}
+
+// `X` out of `{_id: X}` or `{_id: {$eq: X}}`
+fn equality_id(filter: &Document) -> Option<&Bson> {
+    if filter.len() != 1 {
+        return None;
+    }
+    let id = filter.get("_id")?;
+    match id {
+        Bson::Document(id) if id.keys().any(|key| key.starts_with('$')) => {
+            if id.len() != 1 {
+                return None;
+            }
+            match id.get("$eq")? {
+                Bson::Document(_) | Bson::Array(_) | Bson::RegularExpression(_) => None,
+                id => Some(id),
+            }
+        }
+        Bson::Array(_) | Bson::RegularExpression(_) => None,
+        id => Some(id),
+    }
+}
+
+// the namespace and `_id` of a `find({_id: X})` the document cache can answer
+pub fn point_lookup(command: &str, doc: &Document) -> Option<(String, Bson)> {
+    if command != "find" || doc.contains_key("txnNumber") {
+        return None;
+    }
+    for (key, value) in doc {
+        match key.as_str() {
+            "skip" if value.as_i64().or(value.as_i32().map(i64::from)) != Some(0) => return None,
+            "projection" if value.as_document().is_some_and(|projection| projection.is_empty()) => {}
+            key if POINT_LOOKUP_FIELDS.contains(&key) || VOLATILE_FIELDS.contains(&key) => {}
+            _ => return None,
+        }
+    }
+    let id = equality_id(doc.get_document("filter").ok()?)?;
+    let db = doc.get_str("$db").unwrap_or("admin");
+    let namespace = format!("{}.{}", db, doc.get_str("find").ok()?);
+    Some((namespace, id.clone()))
+}
+
+// whether a find reply carries whole documents, projected ones can't answer
+// other lookups
+pub fn returns_documents(command: &str, doc: &Document) -> bool {
+    command == "find"
+        && doc
+            .get_document("projection")
+            .map_or(true, |projection| projection.is_empty())
+        && !doc.get_bool("returnKey").unwrap_or(false)
+        && !doc.get_bool("showRecordId").unwrap_or(false)
+}
+
+// the reply a find would have got for a cached document
+pub fn reply(namespace: &str, document: Document) -> Document {
+    doc! {
+        "cursor": {
+            "firstBatch": [document],
+            "id": 0_i64,
+            "ns": namespace,
+        },
+        "ok": 1.0,
+    }
+}
+
+pub fn batch(reply: &Document) -> Vec<&Document> {
+    let cursor = match reply.get_document("cursor") {
+        Ok(cursor) => cursor,
+        Err(_) => return vec![],
+    };
+    let batch = cursor
+        .get_array("firstBatch")
+        .or_else(|_| cursor.get_array("nextBatch"));
+    match batch {
+        Ok(batch) => batch.iter().filter_map(|doc| doc.as_document()).collect(),
+        Err(_) => vec![],
+    }
+}
+
+// what a write through rengo does to the cached documents of a namespace
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    // inserted or replaced as a whole
+    Put(Document),
+    // `$set` / `$unset` applied to an existing document
+    Patch(Bson, Document),
+    Remove(Bson),
+    // the write did not name its documents by `_id`
+    All,
+}
+
+fn update_change(update: &Document) -> Change {
+    let id = match update.get_document("q").ok().and_then(equality_id) {
+        Some(id) => id.clone(),
+        None => return Change::All,
+    };
+    if update.contains_key("collation") || update.contains_key("arrayFilters") {
+        return Change::Remove(id);
+    }
+    match update.get("u") {
+        Some(Bson::Document(u)) if !u.keys().any(|key| key.starts_with('$')) => {
+            let mut document = doc! { "_id": id };
+            for (key, value) in u {
+                if key != "_id" {
+                    document.insert(key, value.clone());
+                }
+            }
+            Change::Put(document)
+        }
+        Some(Bson::Document(u))
+            if u.keys().all(|key| key == "$set" || key == "$unset")
+                && u.values().all(|fields| {
+                    fields
+                        .as_document()
+                        .is_some_and(|fields| fields.keys().all(|path| !path.contains('$') && path != "_id"))
+                }) =>
+        {
+            Change::Patch(id, u.clone())
+        }
+        // other operators and pipelines are refetched
+        _ => Change::Remove(id),
+    }
+}
+
+fn count(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(value) => Some(i64::from(*value)),
+        Bson::Int64(value) => Some(*value),
+        Bson::Double(value) => Some(*value as i64),
+        _ => None,
+    }
+}
+
+// the changes a write command makes, Put and Patch only stand when the
+// upstream accepted every write
+pub fn changes(command: &str, request: &Document, reply: &Document) -> Vec<Change> {
+    let entries = |field: &str| -> Vec<Document> {
+        match request.get_array(field) {
+            Ok(entries) => entries.iter().filter_map(|entry| entry.as_document().cloned()).collect(),
+            Err(_) => vec![],
+        }
+    };
+    let mut changes: Vec<Change> = match command {
+        "insert" => entries("documents")
+            .into_iter()
+            .map(|document| match document.contains_key("_id") {
+                true => Change::Put(document),
+                false => Change::All,
+            })
+            .collect(),
+        "update" => entries("updates").iter().map(update_change).collect(),
+        "delete" => entries("deletes")
+            .iter()
+            .map(|delete| match delete.get_document("q").ok().and_then(equality_id) {
+                Some(id) => Change::Remove(id.clone()),
+                None => Change::All,
+            })
+            .collect(),
+        "findAndModify" | "findandmodify" => match request.get_document("query").ok().and_then(equality_id) {
+            Some(id) => vec![Change::Remove(id.clone())],
+            None => vec![Change::All],
+        },
+        _ => vec![Change::All],
+    };
+    if command == "update" {
+        // a replacement only leaves a document behind when it matched one or
+        // upserted it
+        let upserted: Vec<i64> = match reply.get_array("upserted") {
+            Ok(upserted) => upserted
+                .iter()
+                .filter_map(|upsert| count(upsert.as_document()?.get("index")?))
+                .collect(),
+            Err(_) => vec![],
+        };
+        // statements without `multi` match one document at most
+        let single = entries("updates").iter().all(|update| !update.get_bool("multi").unwrap_or(false));
+        let all_matched = single && reply.get("n").and_then(count) == Some(changes.len() as i64);
+        for (index, change) in changes.iter_mut().enumerate() {
+            if let Change::Put(document) = change {
+                if !all_matched && !upserted.contains(&(index as i64)) {
+                    *change = Change::Remove(document.get("_id").cloned().unwrap_or_default());
+                }
+            }
+        }
+    }
+    if !is_ok(reply) || reply.contains_key("writeErrors") || reply.contains_key("writeConcernError") {
+        for change in changes.iter_mut() {
+            match change {
+                Change::Put(document) => *change = Change::Remove(document.get("_id").cloned().unwrap_or_default()),
+                Change::Patch(id, _) => *change = Change::Remove(id.clone()),
+                _ => {}
+            }
+        }
+    }
+    changes
+}
+
+// sets or removes a dotted path, false when it runs into a non document
+fn patch_path(document: &mut Document, path: &str, value: Option<&Bson>) -> bool {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if !document.contains_key(head) {
+                if value.is_none() {
+                    return true;
+                }
+                document.insert(head, Document::new());
+            }
+            match document.get_mut(head) {
+                Some(Bson::Document(inner)) => patch_path(inner, rest, value),
+                _ => false,
+            }
+        }
+        None => {
+            match value {
+                Some(value) => {
+                    document.insert(path, value.clone());
+                }
+                None => {
+                    document.remove(path);
+                }
+            }
+            true
+        }
+    }
+}
+
+fn patch(document: &mut Document, update: &Document) -> bool {
+    for (operator, fields) in update {
+        let fields = match fields.as_document() {
+            Some(fields) => fields,
+            None => return false,
+        };
+        for (path, value) in fields {
+            let value = if operator == "$set" { Some(value) } else { None };
+            if !patch_path(document, path, value) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+struct CachedDocument {
+    document: Document,
+    expires: Option<Instant>,
+    size: usize,
+    // insertion order, used for max_entries eviction
+    seq: u64,
+}
+
+impl CachedDocument {
+    fn new(document: Document, expires: Option<Instant>, seq: u64) -> CachedDocument {
+        CachedDocument {
+            size: super::document_size(&document),
+            document,
+            expires,
+            seq,
+        }
+    }
+    fn replace(&mut self, document: Document) {
+        self.size = super::document_size(&document);
+        self.document = document;
+    }
+    fn patch(&mut self, update: &Document) -> bool {
+        let patched = patch(&mut self.document, update);
+        self.size = super::document_size(&self.document);
+        patched
+    }
+}
+
+// documents by namespace, then `_id`, then the cache partition that read them
+#[derive(Default)]
+pub struct DocumentCache {
+    namespaces: HashMap<String, HashMap<String, HashMap<String, CachedDocument>>>,
+    seq: u64,
+}
+
+impl DocumentCache {
+    pub fn len(&self) -> usize {
+        self.namespaces
+            .values()
+            .flat_map(|ids| ids.values())
+            .map(|copies| copies.len())
+            .sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.namespaces.is_empty()
+    }
+    // bson bytes of every cached copy
+    pub fn size(&self) -> usize {
+        self.namespaces
+            .values()
+            .flat_map(|ids| ids.values())
+            .flat_map(|copies| copies.values())
+            .map(|cached| cached.size)
+            .sum()
+    }
+    pub fn get(&mut self, partition: &str, namespace: &str, id: &Bson) -> Option<Document> {
+        let ids = self.namespaces.get_mut(namespace)?;
+        let key = id_key(id);
+        let copies = ids.get_mut(&key)?;
+        let cached = copies.get(partition)?;
+        if cached.expires.is_some_and(|expires| expires <= Instant::now()) {
+            copies.remove(partition);
+            if copies.is_empty() {
+                ids.remove(&key);
+            }
+            if ids.is_empty() {
+                self.namespaces.remove(namespace);
+            }
+            return None;
+        }
+        Some(cached.document.clone())
+    }
+    // documents without an `_id` are skipped, once max_entries ids are cached
+    // for the namespace a new one evicts the oldest, every partition's copy of it
+    pub fn insert(&mut self, partition: &str, namespace: &str, document: &Document, policy: &CachePolicy) {
+        let id = match document.get("_id") {
+            Some(id) => id_key(id),
+            None => return,
+        };
+        if policy.max_entries == Some(0) {
+            return;
+        }
+        self.seq += 1;
+        let ids = self.namespaces.entry(namespace.to_string()).or_default();
+        if policy.max_entries.is_some_and(|max| ids.len() >= max) && !ids.contains_key(&id) {
+            let oldest = ids
+                .iter()
+                .min_by_key(|(_, copies)| copies.values().map(|cached| cached.seq).min())
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                ids.remove(&oldest);
+            }
+        }
+        ids.entry(id).or_default().insert(
+            partition.to_string(),
+            CachedDocument::new(document.clone(), policy.ttl.map(|ttl| Instant::now() + ttl), self.seq),
+        );
+    }
+    // (namespace, partition, document, expires) of every cached copy
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String, &Document, Option<Instant>)> {
+        self.namespaces.iter().flat_map(|(namespace, ids)| {
+            ids.values().flat_map(move |copies| {
+                copies
+                    .iter()
+                    .map(move |(partition, cached)| (namespace, partition, &cached.document, cached.expires))
+            })
+        })
+    }
+    pub fn restore(&mut self, partition: &str, namespace: &str, document: Document, expires: Option<Instant>) {
+        let id = match document.get("_id") {
+            Some(id) => id_key(id),
+            None => return,
+        };
+        self.seq += 1;
+        self.namespaces
+            .entry(namespace.to_string())
+            .or_default()
+            .entry(id)
+            .or_default()
+            .insert(partition.to_string(), CachedDocument::new(document, expires, self.seq));
+    }
+    pub fn remove(&mut self, namespace: &str, id: &Bson) {
+        if let Some(ids) = self.namespaces.get_mut(namespace) {
+            ids.remove(&id_key(id));
+            if ids.is_empty() {
+                self.namespaces.remove(namespace);
+            }
+        }
+    }
+    pub fn invalidate_namespace(&mut self, namespace: &str) -> usize {
+        self.namespaces
+            .remove(namespace)
+            .map_or(0, |ids| ids.values().map(|copies| copies.len()).sum())
+    }
+    pub fn invalidate_database(&mut self, db: &str) -> usize {
+        let prefix = format!("{}.", db);
+        let namespaces: Vec<String> = self
+            .namespaces
+            .keys()
+            .filter(|namespace| namespace.starts_with(&prefix))
+            .cloned()
+            .collect();
+        namespaces
+            .iter()
+            .map(|namespace| self.invalidate_namespace(namespace))
+            .sum()
+    }
+    pub fn clear(&mut self) {
+        self.namespaces.clear();
+    }
+    // a replacement updates every partition's copy and is cached for the
+    // writer, a patch only touches copies that are already cached
+    pub fn apply(&mut self, partition: &str, namespace: &str, changes: Vec<Change>, policy: &CachePolicy) {
+        for change in changes {
+            match change {
+                Change::Put(document) => {
+                    let id = document.get("_id").map(id_key).unwrap_or_default();
+                    if let Some(copies) = self.namespaces.get_mut(namespace).and_then(|ids| ids.get_mut(&id)) {
+                        for cached in copies.values_mut() {
+                            cached.replace(document.clone());
+                        }
+                    }
+                    self.insert(partition, namespace, &document, policy);
+                }
+                Change::Patch(id, update) => {
+                    let key = id_key(&id);
+                    let patched = match self.namespaces.get_mut(namespace).and_then(|ids| ids.get_mut(&key)) {
+                        Some(copies) => copies.values_mut().all(|cached| cached.patch(&update)),
+                        None => true,
+                    };
+                    if !patched {
+                        self.remove(namespace, &id);
+                    }
+                }
+                Change::Remove(id) => self.remove(namespace, &id),
+                Change::All => {
+                    self.invalidate_namespace(namespace);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn ok(n: i32) -> Document {
+        doc! { "n": n, "ok": 1.0 }
+    }
+
+    fn find(filter: Document) -> Document {
+        doc! { "find": "orders", "filter": filter, "$db": "shop" }
+    }
+
+    fn replace(id: i32, upsert: bool) -> Document {
+        doc! { "q": { "_id": id }, "u": { "total": 5 }, "upsert": upsert }
+    }
+
+    fn update(updates: Vec<Document>) -> Document {
+        doc! { "update": "orders", "updates": updates, "$db": "shop" }
+    }
+
+    fn put(id: i32) -> Change {
+        Change::Put(doc! { "_id": id, "total": 5 })
+    }
+
+    fn remove(id: i32) -> Change {
+        Change::Remove(Bson::Int32(id))
+    }
+
+    #[test]
+    fn point_lookups_name_a_single_id() {
+        let order = Some(("shop.orders".to_string(), Bson::Int32(1)));
+        assert_eq!(point_lookup("find", &find(doc! { "_id": 1 })), order);
+        assert_eq!(point_lookup("find", &find(doc! { "_id": { "$eq": 1 } })), order);
+        let mut extra = find(doc! { "_id": 1 });
+        extra.insert("limit", 1);
+        extra.insert("singleBatch", true);
+        extra.insert("skip", 0);
+        extra.insert("projection", Document::new());
+        extra.insert("lsid", doc! { "id": 1 });
+        assert_eq!(point_lookup("find", &extra), order);
+
+        for filter in [
+            doc! { "_id": 1, "status": "A" },
+            doc! { "_id": { "$gt": 1 } },
+            doc! { "_id": { "$eq": 1, "$ne": 2 } },
+            doc! { "_id": { "$eq": { "a": 1 } } },
+            doc! { "_id": [1, 2] },
+            doc! { "status": "A" },
+        ] {
+            assert_eq!(point_lookup("find", &find(filter.clone())), None, "{}", filter);
+        }
+        for (field, value) in [
+            ("skip", Bson::Int32(1)),
+            ("projection", Bson::Document(doc! { "total": 1 })),
+            ("collation", Bson::Document(doc! { "locale": "fr" })),
+            ("txnNumber", Bson::Int64(1)),
+        ] {
+            let mut doc = find(doc! { "_id": 1 });
+            doc.insert(field, value);
+            assert_eq!(point_lookup("find", &doc), None, "{}", field);
+        }
+        assert_eq!(point_lookup("count", &find(doc! { "_id": 1 })), None);
+    }
+
+    #[test]
+    fn only_whole_documents_fill_the_cache() {
+        assert!(returns_documents("find", &find(doc! {})));
+        let mut projected = find(doc! {});
+        projected.insert("projection", doc! { "total": 1 });
+        assert!(!returns_documents("find", &projected));
+        let mut keys = find(doc! {});
+        keys.insert("returnKey", true);
+        assert!(!returns_documents("find", &keys));
+        assert!(!returns_documents("aggregate", &doc! { "aggregate": "orders" }));
+    }
+
+    #[test]
+    fn inserts_and_deletes() {
+        let insert = doc! { "insert": "orders", "documents": [{ "_id": 1, "total": 5 }, { "total": 6 }] };
+        assert_eq!(changes("insert", &insert, &ok(2)), [put(1), Change::All]);
+        let delete = doc! { "delete": "orders", "deletes": [{ "q": { "_id": 1 }, "limit": 1 }, { "q": { "a": 1 }, "limit": 0 }] };
+        assert_eq!(changes("delete", &delete, &ok(1)), [remove(1), Change::All]);
+        let modify = doc! { "findAndModify": "orders", "query": { "_id": 1 }, "update": { "$inc": { "n": 1 } } };
+        assert_eq!(changes("findAndModify", &modify, &ok(1)), [remove(1)]);
+        assert_eq!(changes("findAndModify", &doc! { "query": {} }, &ok(1)), [Change::All]);
+    }
+
+    #[test]
+    fn updates() {
+        let patch = doc! { "q": { "_id": 1 }, "u": { "$set": { "a.b": 1 }, "$unset": { "c": "" } } };
+        let pipeline = doc! { "q": { "_id": 2 }, "u": [{ "$set": { "a": 1 } }] };
+        let increment = doc! { "q": { "_id": 3 }, "u": { "$inc": { "a": 1 } } };
+        let positional = doc! { "q": { "_id": 4 }, "u": { "$set": { "items.$.a": 1 } } };
+        let id = doc! { "q": { "_id": 5 }, "u": { "$set": { "_id": 6 } } };
+        let filters = doc! { "q": { "_id": 6 }, "u": { "$set": { "a": 1 } }, "arrayFilters": [] };
+        let many = doc! { "q": { "a": 1 }, "u": { "$set": { "a": 2 } }, "multi": true };
+        let request = update(vec![patch, pipeline, increment, positional, id, filters, many]);
+        assert_eq!(
+            changes("update", &request, &ok(3)),
+            [
+                Change::Patch(Bson::Int32(1), doc! { "$set": { "a.b": 1 }, "$unset": { "c": "" } }),
+                remove(2),
+                remove(3),
+                remove(4),
+                remove(5),
+                remove(6),
+                Change::All,
+            ]
+        );
+    }
+
+    #[test]
+    fn replacements_stand_only_when_they_matched_or_upserted() {
+        let request = update(vec![replace(1, false)]);
+        assert_eq!(changes("update", &request, &ok(1)), [put(1)]);
+        assert_eq!(changes("update", &request, &ok(0)), [remove(1)]);
+        // the replacement's own `_id` doesn't override the filter's
+        let request = update(vec![doc! { "q": { "_id": 1 }, "u": { "_id": 1, "total": 5 } }]);
+        assert_eq!(changes("update", &request, &ok(1)), [put(1)]);
+
+        let upserted = doc! { "n": 1, "upserted": [{ "index": 0, "_id": 1 }], "ok": 1.0 };
+        assert_eq!(changes("update", &update(vec![replace(1, true)]), &upserted), [put(1)]);
+
+        // in a batch, only the upserted statement is known to have a document
+        // unless every statement matched
+        let batch = update(vec![replace(1, true), replace(2, false)]);
+        let one_upserted = doc! { "n": 1, "upserted": [{ "index": 0, "_id": 1 }], "ok": 1.0 };
+        assert_eq!(changes("update", &batch, &one_upserted), [put(1), remove(2)]);
+        assert_eq!(changes("update", &batch, &ok(2)), [put(1), put(2)]);
+        assert_eq!(changes("update", &batch, &ok(1)), [remove(1), remove(2)]);
+        let mut multi = doc! { "q": { "a": 1 }, "u": { "$set": { "a": 2 } }, "multi": true };
+        let with_multi = update(vec![replace(1, false), multi.clone()]);
+        assert_eq!(changes("update", &with_multi, &ok(2)), [remove(1), Change::All]);
+        multi.insert("multi", false);
+        let without_multi = update(vec![replace(1, false), multi]);
+        assert_eq!(changes("update", &without_multi, &ok(2)), [put(1), Change::All]);
+    }
+
+    #[test]
+    fn failed_writes_only_remove() {
+        let insert = doc! { "insert": "orders", "documents": [{ "_id": 1, "total": 5 }] };
+        let request = update(vec![
+            replace(2, false),
+            doc! { "q": { "_id": 3 }, "u": { "$set": { "a": 1 } } },
+        ]);
+        for reply in [
+            doc! { "ok": 0.0, "errmsg": "boom" },
+            doc! { "n": 2, "writeErrors": [{ "index": 1, "code": 11000 }], "ok": 1.0 },
+            doc! { "n": 2, "writeConcernError": { "code": 64 }, "ok": 1.0 },
+        ] {
+            assert_eq!(changes("insert", &insert, &reply), [remove(1)], "{}", reply);
+            assert_eq!(changes("update", &request, &reply), [remove(2), remove(3)], "{}", reply);
+        }
+    }
+
+    fn cache(policy: &CachePolicy) -> DocumentCache {
+        let mut cache = DocumentCache::default();
+        cache.insert(
+            "a",
+            "shop.orders",
+            &doc! { "_id": 1, "total": 5, "c": { "d": 1 } },
+            policy,
+        );
+        cache.insert(
+            "b",
+            "shop.orders",
+            &doc! { "_id": 1, "total": 5, "c": { "d": 1 } },
+            policy,
+        );
+        cache.insert("a", "shop.orders", &doc! { "_id": 2 }, policy);
+        cache
+    }
+
+    #[test]
+    fn replacements_update_every_copy() {
+        let policy = CachePolicy::default();
+        let mut cache = cache(&policy);
+        cache.apply(
+            "c",
+            "shop.orders",
+            vec![Change::Put(doc! { "_id": 1, "total": 7 })],
+            &policy,
+        );
+        for partition in ["a", "b", "c"] {
+            assert_eq!(
+                cache.get(partition, "shop.orders", &Bson::Int32(1)),
+                Some(doc! { "_id": 1, "total": 7 })
+            );
+        }
+        // 1 and 1L are the same `_id`
+        assert!(cache.get("a", "shop.orders", &Bson::Int64(2)).is_some());
+        assert_eq!(cache.len(), 4);
+    }
+
+    #[test]
+    fn patches_touch_only_cached_copies() {
+        let policy = CachePolicy::default();
+        let mut cache = cache(&policy);
+        let update = doc! { "$set": { "total": 6, "c.e": 2 }, "$unset": { "c.d": "", "missing.path": "" } };
+        cache.apply(
+            "a",
+            "shop.orders",
+            vec![Change::Patch(Bson::Int32(1), update.clone())],
+            &policy,
+        );
+        assert_eq!(
+            cache.get("b", "shop.orders", &Bson::Int32(1)),
+            Some(doc! { "_id": 1, "total": 6, "c": { "e": 2 } })
+        );
+        cache.apply("a", "shop.orders", vec![Change::Patch(Bson::Int32(3), update)], &policy);
+        assert_eq!(cache.get("a", "shop.orders", &Bson::Int32(3)), None);
+
+        // a path through a value that isn't a document is refetched
+        let through = doc! { "$set": { "total.x": 1 } };
+        cache.apply(
+            "a",
+            "shop.orders",
+            vec![Change::Patch(Bson::Int32(1), through)],
+            &policy,
+        );
+        assert_eq!(cache.get("a", "shop.orders", &Bson::Int32(1)), None);
+        assert_eq!(cache.get("b", "shop.orders", &Bson::Int32(1)), None);
+    }
+
+    #[test]
+    fn removes_and_unnamed_writes() {
+        let policy = CachePolicy::default();
+        let mut cache = cache(&policy);
+        cache.apply("a", "shop.orders", vec![remove(1)], &policy);
+        assert_eq!(cache.get("b", "shop.orders", &Bson::Int32(1)), None);
+        assert_eq!(cache.len(), 1);
+        cache.insert("a", "shop.carts", &doc! { "_id": 1 }, &policy);
+        cache.apply("a", "shop.orders", vec![Change::All, put(9)], &policy);
+        assert_eq!(cache.get("a", "shop.orders", &Bson::Int32(2)), None);
+        // nothing after the namespace is dropped is applied
+        assert_eq!(cache.get("a", "shop.orders", &Bson::Int32(9)), None);
+        assert!(cache.get("a", "shop.carts", &Bson::Int32(1)).is_some());
+    }
+
+    #[test]
+    fn honours_max_entries_and_ttl() {
+        let policy = CachePolicy {
+            max_entries: Some(2),
+            ttl: Some(Duration::from_millis(20)),
+            ..CachePolicy::default()
+        };
+        let mut cache = cache(&policy);
+        // a third id evicts the oldest, in every partition
+        cache.insert("a", "shop.orders", &doc! { "_id": 3 }, &policy);
+        assert!(cache.get("a", "shop.orders", &Bson::Int32(3)).is_some());
+        assert_eq!(cache.get("b", "shop.orders", &Bson::Int32(1)), None);
+        // another partition's copy of a cached id still fits
+        cache.insert("c", "shop.orders", &doc! { "_id": 2 }, &policy);
+        assert!(cache.get("c", "shop.orders", &Bson::Int32(2)).is_some());
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(cache.get("a", "shop.orders", &Bson::Int32(1)), None);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn empty_namespaces_are_dropped() {
+        let policy = CachePolicy {
+            ttl: Some(Duration::from_millis(20)),
+            ..CachePolicy::default()
+        };
+        let mut cache = DocumentCache::default();
+        cache.insert("a", "shop.orders", &doc! { "_id": 1 }, &policy);
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(cache.get("a", "shop.orders", &Bson::Int32(1)), None);
+        assert!(cache.is_empty());
+        cache.insert("a", "shop.orders", &doc! { "_id": 1 }, &CachePolicy::default());
+        cache.remove("shop.orders", &Bson::Int32(1));
+        assert!(cache.is_empty());
+        // nothing fits with max_entries = 0
+        let none = CachePolicy {
+            max_entries: Some(0),
+            ..CachePolicy::default()
+        };
+        cache.insert("a", "shop.orders", &doc! { "_id": 1 }, &none);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use bson::Document;
+
+use crate::handler::Storage;
+
+// how long a follower waits for the leader before going upstream itself
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// one upstream request for a cache key that other clients wait on
+#[derive(Default)]
+pub struct Flight {
+    // Some once finished, holding None when the leader gave up
+    result: Mutex<Option<Option<Document>>>,
+    done: Condvar,
+}
+
+impl Flight {
+    // the leader's reply, None when it failed or is taking too long and the
+    // caller should go upstream itself
+    pub fn wait(&self) -> Option<Document> {
+        self.wait_for(WAIT_TIMEOUT)
+    }
+    pub fn wait_for(&self, timeout: Duration) -> Option<Document> {
+        let result = self.result.lock().unwrap();
+        let (result, _) = self.done.wait_timeout_while(result, timeout, |result| result.is_none()).unwrap();
+        result.clone().flatten()
+    }
+    fn finish(&self, document: Option<Document>) {
+        *self.result.lock().unwrap() = Some(document);
+        self.done.notify_all();
+    }
+}
+
+pub enum Join {
+    // nobody is fetching the key, the caller does and completes the guard
+    Leader(FlightGuard),
+    Follower(Arc<Flight>),
+}
+
+// the keys being fetched from upstream right now
+#[derive(Default)]
+pub struct Flights {
+    flights: HashMap<String, Arc<Flight>>,
+}
+
+impl Flights {
+    pub fn join(&mut self, key: &str, storage: &Storage) -> Join {
+        if let Some(flight) = self.flights.get(key) {
+            return Join::Follower(flight.clone());
+        }
+        let flight = Arc::new(Flight::default());
+        self.flights.insert(key.to_string(), flight.clone());
+        Join::Leader(FlightGuard {
+            storage: storage.clone(),
+            key: key.to_string(),
+            flight,
+            document: None,
+        })
+    }
+    fn remove(&mut self, key: &str, flight: &Arc<Flight>) {
+        if self.flights.get(key).is_some_and(|current| Arc::ptr_eq(current, flight)) {
+            self.flights.remove(key);
+        }
+    }
+}
+
+// held by the leader, followers are woken when it is dropped so a panicking
+// leader can't leave them waiting
+pub struct FlightGuard {
+    storage: Storage,
+    key: String,
+    flight: Arc<Flight>,
+    document: Option<Document>,
+}
+
+impl FlightGuard {
+    pub fn complete(mut self, document: Document) {
+        self.document = Some(document);
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        let mut flights = self.storage.flights.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        flights.remove(&self.key, &self.flight);
+        drop(flights);
+        self.flight.finish(self.document.take());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use bson::doc;
+
+    use super::*;
+    use crate::cache::Cache;
+
+    fn leader(storage: &Storage, key: &str) -> FlightGuard {
+        match storage.join(key) {
+            Join::Leader(guard) => guard,
+            Join::Follower(_) => panic!("{} already has a leader", key),
+        }
+    }
+
+    fn follower(storage: &Storage, key: &str) -> Arc<Flight> {
+        match storage.join(key) {
+            Join::Follower(flight) => flight,
+            Join::Leader(_) => panic!("{} has no leader", key),
+        }
+    }
+
+    #[test]
+    fn followers_wait_for_the_leader() {
+        let storage: Storage = Arc::new(Cache::new());
+        let guard = leader(&storage, "a");
+        let followers: Vec<_> = (0..3)
+            .map(|_| {
+                let flight = follower(&storage, "a");
+                thread::spawn(move || flight.wait())
+            })
+            .collect();
+        // other keys don't wait
+        drop(leader(&storage, "b"));
+        thread::sleep(Duration::from_millis(20));
+        guard.complete(doc! { "n": 1 });
+        for follower in followers {
+            assert_eq!(follower.join().unwrap(), Some(doc! { "n": 1 }));
+        }
+        // the flight is over once the leader is done
+        drop(leader(&storage, "a"));
+    }
+
+    #[test]
+    fn a_leader_that_gives_up_sends_followers_upstream() {
+        let storage: Storage = Arc::new(Cache::new());
+        let guard = leader(&storage, "a");
+        let flight = follower(&storage, "a");
+        let leader = thread::spawn(move || {
+            let _guard = guard;
+            panic!("upstream went away");
+        });
+        assert!(leader.join().is_err());
+        assert_eq!(flight.wait(), None);
+    }
+
+    #[test]
+    fn followers_stop_waiting_for_a_slow_leader() {
+        let storage: Storage = Arc::new(Cache::new());
+        let guard = leader(&storage, "a");
+        let flight = follower(&storage, "a");
+        assert_eq!(flight.wait_for(Duration::from_millis(20)), None);
+        // the leader still completes for later followers
+        let later = follower(&storage, "a");
+        guard.complete(doc! { "n": 1 });
+        assert_eq!(later.wait(), Some(doc! { "n": 1 }));
+    }
+}
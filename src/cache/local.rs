@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bson::{doc, Document};
+
+use super::documents;
+use super::policy::{is_ok, CachePolicy};
+
+// collections are only held locally up to this many documents unless the
+// policy's max_entries says otherwise
+pub const MAX_DOCUMENTS: usize = 10_000;
+
+// how long a collection that could not be loaded is answered upstream before
+// loading is tried again
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// replies stay under the 16MB bson limit, bigger results go upstream
+const MAX_REPLY_SIZE: usize = 16 * 1024 * 1024 - 16 * 1024;
+
+// cloned out of the cache so queries run without holding its lock
+#[derive(Clone)]
+pub enum LocalCollection {
+    Loaded {
+        documents: Arc<Vec<Document>>,
+        expires: Option<Instant>,
+        // bson bytes of the documents
+        size: usize,
+    },
+    // too large or the load failed
+    Unavailable { retry: Instant },
+}
+
+impl LocalCollection {
+    pub fn loaded(documents: Vec<Document>, policy: &CachePolicy) -> LocalCollection {
+        LocalCollection::restored(documents, policy.ttl.map(|ttl| Instant::now() + ttl))
+    }
+    // a collection read back from a snapshot, keeping its original expiry
+    pub fn restored(documents: Vec<Document>, expires: Option<Instant>) -> LocalCollection {
+        LocalCollection::Loaded {
+            size: documents.iter().map(super::document_size).sum(),
+            documents: Arc::new(documents),
+            expires,
+        }
+    }
+    pub fn unavailable() -> LocalCollection {
+        LocalCollection::Unavailable {
+            retry: Instant::now() + RETRY_INTERVAL,
+        }
+    }
+    fn is_expired(&self, now: Instant) -> bool {
+        match self {
+            LocalCollection::Loaded { expires, .. } => expires.is_some_and(|expires| expires <= now),
+            LocalCollection::Unavailable { retry } => *retry <= now,
+        }
+    }
+}
+
+// whole collections by namespace and cache partition, any write to the
+// namespace drops them
+#[derive(Default)]
+pub struct LocalCollections {
+    namespaces: HashMap<String, HashMap<String, LocalCollection>>,
+}
+
+impl LocalCollections {
+    pub fn get(&mut self, partition: &str, namespace: &str) -> Option<&LocalCollection> {
+        let partitions = self.namespaces.get_mut(namespace)?;
+        if partitions.get(partition)?.is_expired(Instant::now()) {
+            partitions.remove(partition);
+            return None;
+        }
+        partitions.get(partition)
+    }
+    pub fn insert(&mut self, partition: &str, namespace: &str, collection: LocalCollection) {
+        self.namespaces
+            .entry(namespace.to_string())
+            .or_default()
+            .insert(partition.to_string(), collection);
+    }
+    // (namespace, partition, documents, expires) of every loaded collection
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String, &Vec<Document>, Option<Instant>)> {
+        self.namespaces.iter().flat_map(|(namespace, partitions)| {
+            partitions
+                .iter()
+                .filter_map(move |(partition, collection)| match collection {
+                    LocalCollection::Loaded { documents, expires, .. } => Some((namespace, partition, documents.as_ref(), *expires)),
+                    LocalCollection::Unavailable { .. } => None,
+                })
+        })
+    }
+    // bson bytes held across all loaded collections
+    pub fn size(&self) -> usize {
+        self.namespaces
+            .values()
+            .flat_map(|partitions| partitions.values())
+            .map(|collection| match collection {
+                LocalCollection::Loaded { size, .. } => *size,
+                LocalCollection::Unavailable { .. } => 0,
+            })
+            .sum()
+    }
+    pub fn invalidate_namespace(&mut self, namespace: &str) {
+        self.namespaces.remove(namespace);
+    }
+    pub fn invalidate_database(&mut self, db: &str) {
+        let prefix = format!("{}.", db);
+        self.namespaces.retain(|namespace, _| !namespace.starts_with(&prefix));
+    }
+    pub fn clear(&mut self) {
+        self.namespaces.clear();
+    }
+}
+
+// reads every document of `db.collection`, `run` sends a command upstream and
+// returns its reply
+pub fn load<F>(mut run: F, db: &str, collection: &str, max: Option<usize>) -> Result<Vec<Document>, String>
+where
+    F: FnMut(Document) -> io::Result<Document>,
+{
+    let mut reply = run(doc! { "find": collection, "filter": {}, "$db": db }).map_err(|e| e.to_string())?;
+    let mut loaded = vec![];
+    loop {
+        if !is_ok(&reply) {
+            return Err(reply.get_str("errmsg").unwrap_or("find failed").to_string());
+        }
+        loaded.extend(documents::batch(&reply).into_iter().cloned());
+        let cursor_id = reply
+            .get_document("cursor")
+            .and_then(|cursor| cursor.get_i64("id"))
+            .unwrap_or(0);
+        if let Some(max) = max.filter(|max| loaded.len() > *max) {
+            if cursor_id != 0 {
+                // best effort, the server reaps it after 10 minutes anyway
+                let _ = run(doc! { "killCursors": collection, "cursors": [cursor_id], "$db": db });
+            }
+            return Err(format!("more than {} documents", max));
+        }
+        if cursor_id == 0 {
+            return Ok(loaded);
+        }
+        reply = run(doc! { "getMore": cursor_id, "collection": collection, "$db": db }).map_err(|e| e.to_string())?;
+    }
+}
+
+// the whole result as a single batch, None when it would not fit a reply
+pub fn reply(namespace: &str, documents: Vec<Document>) -> Option<Document> {
+    let size: usize = documents
+        .iter()
+        .map(|doc| bson::to_vec(doc).map_or(usize::MAX, |bytes| bytes.len()))
+        .fold(0, usize::saturating_add);
+    if size > MAX_REPLY_SIZE {
+        return None;
+    }
+    Some(doc! {
+        "cursor": {
+            "firstBatch": documents,
+            "id": 0_i64,
+            "ns": namespace,
+        },
+        "ok": 1.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    // a collection of `total` documents served `batch` at a time
+    fn upstream(total: i32, batch: i32) -> impl FnMut(Document) -> io::Result<Document> {
+        let mut next = 0;
+        move |command| {
+            if command.contains_key("killCursors") {
+                return Ok(doc! { "cursorsKilled": [42i64], "ok": 1.0 });
+            }
+            let documents: Vec<Document> = (next..total.min(next + batch)).map(|n| doc! { "_id": n }).collect();
+            next += batch;
+            let field = if command.contains_key("find") {
+                "firstBatch"
+            } else {
+                "nextBatch"
+            };
+            let id = if next < total { 42i64 } else { 0 };
+            Ok(doc! { "cursor": { "id": id, field: documents }, "ok": 1.0 })
+        }
+    }
+
+    #[test]
+    fn loads_every_batch() {
+        let mut commands = vec![];
+        let mut collection = upstream(5, 2);
+        let run = |command: Document| {
+            commands.push(command.clone());
+            collection(command)
+        };
+        let loaded = load(run, "shop", "orders", Some(5)).unwrap();
+        assert_eq!(loaded.len(), 5);
+        assert_eq!(commands[0], doc! { "find": "orders", "filter": {}, "$db": "shop" });
+        assert_eq!(
+            commands[1],
+            doc! { "getMore": 42i64, "collection": "orders", "$db": "shop" }
+        );
+        assert_eq!(commands.len(), 3);
+    }
+
+    #[test]
+    fn stops_past_the_limit() {
+        let mut commands = vec![];
+        let mut collection = upstream(10, 3);
+        let run = |command: Document| {
+            commands.push(command.clone());
+            collection(command)
+        };
+        let error = load(run, "shop", "orders", Some(4)).unwrap_err();
+        assert_eq!(error, "more than 4 documents");
+        assert_eq!(
+            commands.last(),
+            Some(&doc! { "killCursors": "orders", "cursors": [42i64], "$db": "shop" })
+        );
+    }
+
+    #[test]
+    fn fails_on_an_error_reply() {
+        let run = |_| Ok(doc! { "ok": 0.0, "errmsg": "not authorized" });
+        assert_eq!(load(run, "shop", "orders", None).unwrap_err(), "not authorized");
+        let run = |_| Err(io::Error::other("connection reset"));
+        assert_eq!(load(run, "shop", "orders", None).unwrap_err(), "connection reset");
+    }
+
+    #[test]
+    fn replies_fit_a_single_message() {
+        let found = reply("shop.orders", vec![doc! { "_id": 1 }]).unwrap();
+        assert_eq!(documents::batch(&found).len(), 1);
+        assert_eq!(
+            found.get_document("cursor").unwrap().get_str("ns").unwrap(),
+            "shop.orders"
+        );
+        let big = "x".repeat(1024 * 1024);
+        let documents: Vec<Document> = (0..17).map(|n| doc! { "_id": n, "big": big.as_str() }).collect();
+        assert_eq!(reply("shop.orders", documents), None);
+    }
+
+    #[test]
+    fn collections_expire_and_are_invalidated() {
+        let mut collections = LocalCollections::default();
+        let policy = CachePolicy {
+            ttl: Some(Duration::from_millis(20)),
+            ..CachePolicy::default()
+        };
+        collections.insert(
+            "",
+            "shop.orders",
+            LocalCollection::loaded(vec![doc! { "_id": 1 }], &policy),
+        );
+        collections.insert(
+            "",
+            "shop.carts",
+            LocalCollection::loaded(vec![], &CachePolicy::default()),
+        );
+        collections.insert("", "users.sessions", LocalCollection::unavailable());
+        assert!(collections.get("", "shop.orders").is_some());
+        assert!(collections.get("tenant", "shop.orders").is_none());
+        assert_eq!(collections.iter().count(), 2);
+        assert!(collections.size() > 0);
+
+        thread::sleep(Duration::from_millis(40));
+        assert!(collections.get("", "shop.orders").is_none());
+        assert!(matches!(
+            collections.get("", "users.sessions"),
+            Some(LocalCollection::Unavailable { .. })
+        ));
+        collections.invalidate_database("shop");
+        assert!(collections.get("", "shop.carts").is_none());
+        collections.invalidate_namespace("users.sessions");
+        assert!(collections.get("", "users.sessions").is_none());
+    }
+}
@@ -0,0 +1,849 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+
+use bson::{Bson, Document};
+
+use crate::commands::hash_parts;
+use crate::config::Config;
+use crate::handler::InnerData;
+use crate::metrics::metrics;
+
+use self::documents::{Change, DocumentCache};
+use self::flight::{Flights, Join};
+use self::local::{LocalCollection, LocalCollections};
+use self::policy::{CachePolicy, PolicyRule};
+use self::reads::CacheableRead;
+use self::refresh::Refresh;
+pub mod documents;
+pub mod flight;
+pub mod local;
+pub mod policy;
+pub mod persist;
+pub mod query;
+pub mod reads;
+pub mod refresh;
+pub mod warmup;
+pub mod watch;
+
+// entries are spread over this many locks by key, a hit only takes one
+const SHARDS: usize = 64;
+
+pub struct Entry {
+    pub namespace: String,
+    // collections the entry was computed from besides `namespace`
+    pub dependencies: Vec<String>,
+    pub data: InnerData,
+    pub inserted: Instant,
+    pub expires: Option<Instant>,
+    // past `expires` and before this the entry is served stale while it is
+    // refreshed
+    pub stale_until: Option<Instant>,
+    // bson bytes of `data`
+    pub size: usize,
+    // insertion order within the namespace, used for max_entries eviction
+    seq: u64,
+}
+
+impl Entry {
+    fn new(namespace: &str, dependencies: &[String], data: InnerData, policy: &CachePolicy) -> Entry {
+        let now = Instant::now();
+        let expires = policy.ttl.map(|ttl| now + ttl);
+        Entry {
+            namespace: namespace.to_string(),
+            dependencies: dependencies.to_vec(),
+            size: data.size(),
+            data,
+            inserted: now,
+            expires,
+            stale_until: expires
+                .zip(policy.stale_while_revalidate)
+                .map(|(expires, stale)| expires + stale),
+            seq: 0,
+        }
+    }
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+    fn is_dead(&self, now: Instant) -> bool {
+        match self.stale_until {
+            Some(stale_until) => stale_until <= now,
+            None => self.is_expired(now),
+        }
+    }
+}
+
+pub enum Lookup {
+    Fresh(Document),
+    // expired but within the policy's stale_while_revalidate window
+    Stale(Document),
+    Miss,
+}
+
+// which keys belong to which `db.collection`, so writes can drop everything
+// cached for a namespace
+#[derive(Default)]
+struct Index {
+    namespaces: HashMap<String, BTreeMap<u64, String>>,
+    seq: u64,
+    // bumped whenever a namespace is invalidated, a reply fetched while it
+    // changed is not stored
+    generations: HashMap<String, u64>,
+    epoch: u64,
+}
+
+impl Index {
+    fn generation<'a>(&self, namespaces: impl Iterator<Item = &'a String>) -> u64 {
+        let generations: u64 = namespaces
+            .map(|namespace| self.generations.get(namespace).copied().unwrap_or(0))
+            .sum();
+        self.epoch + generations
+    }
+    fn unlink(&mut self, entry: &Entry) {
+        for namespace in entry.dependencies.iter().chain(std::iter::once(&entry.namespace)) {
+            if let Some(keys) = self.namespaces.get_mut(namespace) {
+                keys.remove(&entry.seq);
+                if keys.is_empty() {
+                    self.namespaces.remove(namespace);
+                }
+            }
+        }
+    }
+}
+
+// the cached replies, striped over SHARDS locks so hits on different keys
+// don't wait on each other. Anything that adds or drops entries takes the
+// index lock first and then at most one other lock at a time, and no lock is
+// held across an upstream round trip.
+pub struct Cache {
+    shards: Vec<Mutex<HashMap<String, Entry>>>,
+    index: Mutex<Index>,
+    // single documents by `_id`, filled from find replies and kept up to date
+    // by writes that name their `_id`
+    documents: Mutex<DocumentCache>,
+    // whole collections the local query engine answers finds from
+    collections: Mutex<LocalCollections>,
+    // reads being fetched from upstream, concurrent misses wait on them
+    flights: Mutex<Flights>,
+    // the background refresh of stale entries, unset in passthrough mode
+    refresher: OnceLock<Sender<Refresh>>,
+    // rules set through the admin api, checked before `[[cache.policy]]`
+    policies: Mutex<Vec<PolicyRule>>,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            index: Mutex::default(),
+            documents: Mutex::default(),
+            collections: Mutex::default(),
+            flights: Mutex::default(),
+            refresher: OnceLock::new(),
+            policies: Mutex::default(),
+        }
+    }
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Cache::default()
+    }
+    fn shard(&self, key: &str) -> &Mutex<HashMap<String, Entry>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // bson bytes held as (replies, documents, collections)
+    pub fn memory(&self) -> (usize, usize, usize) {
+        let replies = self
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().values().map(|entry| entry.size).sum::<usize>())
+            .sum();
+        let documents = self.documents.lock().unwrap().size();
+        let collections = self.collections.lock().unwrap().size();
+        (replies, documents, collections)
+    }
+    // entries past their stale window are dropped on the way
+    pub fn lookup(&self, key: &str) -> Lookup {
+        let now = Instant::now();
+        let shard = self.shard(key).lock().unwrap();
+        let entry = match shard.get(key) {
+            Some(entry) => entry,
+            None => return Lookup::Miss,
+        };
+        if entry.is_dead(now) {
+            drop(shard);
+            self.remove_dead(key);
+            return Lookup::Miss;
+        }
+        let reply = match &entry.data {
+            InnerData::Document(reply) => reply.clone(),
+            InnerData::Documents(_) => return Lookup::Miss,
+        };
+        match entry.is_expired(now) {
+            true => Lookup::Stale(reply),
+            false => Lookup::Fresh(reply),
+        }
+    }
+    // runs `update` on a fresh entry under its shard lock, None when there is
+    // none
+    pub fn update<F, R>(&self, key: &str, update: F) -> Option<R>
+    where
+        F: FnOnce(&mut InnerData) -> R,
+    {
+        let now = Instant::now();
+        let mut shard = self.shard(key).lock().unwrap();
+        let entry = shard.get_mut(key)?;
+        if entry.is_dead(now) {
+            drop(shard);
+            self.remove_dead(key);
+            return None;
+        }
+        if entry.is_expired(now) {
+            return None;
+        }
+        let result = update(&mut entry.data);
+        entry.size = entry.data.size();
+        Some(result)
+    }
+    // changes whenever one of the namespaces is invalidated, taken before a
+    // read goes upstream and compared before its reply is stored
+    pub fn generation(&self, read: &CacheableRead) -> u64 {
+        let namespaces = read.dependencies.iter().chain(std::iter::once(&read.namespace));
+        self.index.lock().unwrap().generation(namespaces)
+    }
+    pub fn namespace_generation(&self, namespace: &str) -> u64 {
+        self.index.lock().unwrap().generation(std::iter::once(&namespace.to_string()))
+    }
+    pub fn set_refresher(&self, refresher: Sender<Refresh>) {
+        let _ = self.refresher.set(refresher);
+    }
+    // the thread stale entries are handed to
+    pub fn refresher(&self) -> Option<Sender<Refresh>> {
+        self.refresher.get().cloned()
+    }
+    // the namespace's policy, a rule set at runtime overrides the configured
+    // one field by field
+    pub fn policy(&self, config: &Config, namespace: &str) -> CachePolicy {
+        let configured = config.cache_policy(namespace);
+        let policies = self.policies.lock().unwrap();
+        match policies.iter().find(|rule| rule.matches(namespace)) {
+            Some(rule) => rule.apply(&configured),
+            None => configured,
+        }
+    }
+    pub fn policies(&self) -> Vec<PolicyRule> {
+        self.policies.lock().unwrap().clone()
+    }
+    // replaces the rule for the same pattern, a new one is checked first.
+    // Entries already cached keep the expiry they were stored with.
+    pub fn set_policy(&self, rule: PolicyRule) {
+        let mut policies = self.policies.lock().unwrap();
+        match policies.iter_mut().find(|existing| existing.namespace == rule.namespace) {
+            Some(existing) => *existing = rule,
+            None => policies.insert(0, rule),
+        }
+    }
+    pub fn remove_policy(&self, namespace: &str) -> bool {
+        let mut policies = self.policies.lock().unwrap();
+        let before = policies.len();
+        policies.retain(|rule| rule.namespace != namespace);
+        policies.len() != before
+    }
+    // the first caller for a key fetches it, the others wait for its reply
+    pub fn join(self: &Arc<Self>, key: &str) -> Join {
+        self.flights.lock().unwrap().join(key, self)
+    }
+    // stored unless the namespace changed since `generation`
+    pub fn insert(&self, key: String, namespace: &str, data: InnerData, policy: &CachePolicy, generation: u64) -> bool {
+        let mut index = self.index.lock().unwrap();
+        if index.generation(std::iter::once(&namespace.to_string())) != generation {
+            return false;
+        }
+        let entry = Entry::new(namespace, &[], data, policy);
+        self.insert_entry(&mut index, key, entry, policy.max_entries);
+        true
+    }
+    // an entry read back from a snapshot, keeping its original expiry
+    pub fn restore(
+        &self,
+        key: String,
+        namespace: &str,
+        dependencies: &[String],
+        data: InnerData,
+        expires: Option<Instant>,
+    ) {
+        let mut index = self.index.lock().unwrap();
+        let mut entry = Entry::new(namespace, dependencies, data, &CachePolicy::default());
+        entry.expires = expires;
+        self.insert_entry(&mut index, key, entry, None)
+    }
+    // visits every entry, one shard lock at a time
+    pub fn for_each<F: FnMut(&String, &Entry)>(&self, mut visit: F) {
+        for shard in self.shards.iter() {
+            for (key, entry) in shard.lock().unwrap().iter() {
+                visit(key, entry);
+            }
+        }
+    }
+    fn insert_entry(&self, index: &mut Index, key: String, mut entry: Entry, max_entries: Option<usize>) {
+        self.remove_locked(index, &key);
+        index.seq += 1;
+        entry.seq = index.seq;
+        for dependency in entry.dependencies.iter() {
+            let keys = index.namespaces.entry(dependency.clone()).or_default();
+            keys.insert(entry.seq, key.clone());
+        }
+        let keys = index.namespaces.entry(entry.namespace.clone()).or_default();
+        keys.insert(entry.seq, key.clone());
+        let evict: Vec<String> = match max_entries {
+            Some(max) if keys.len() > max => keys.values().take(keys.len() - max).cloned().collect(),
+            _ => vec![],
+        };
+        let namespace = entry.namespace.clone();
+        self.shard(&key).lock().unwrap().insert(key, entry);
+        let evicted = evict
+            .iter()
+            .filter(|key| self.remove_locked(index, key).is_some())
+            .count();
+        evicted_metric(&namespace, "max_entries", evicted);
+    }
+    // a read's reply, whole documents in it also go to the `_id` cache.
+    // Nothing is stored when the namespaces changed since `generation`.
+    pub fn store_read(
+        &self,
+        partition: &str,
+        doc: &Document,
+        read: CacheableRead,
+        reply: &Document,
+        policy: &CachePolicy,
+        generation: u64,
+    ) -> bool {
+        let mut index = self.index.lock().unwrap();
+        let namespaces = read.dependencies.iter().chain(std::iter::once(&read.namespace));
+        if index.generation(namespaces) != generation {
+            return false;
+        }
+        let command = doc.keys().next().map_or("", |command| command.as_str());
+        if documents::returns_documents(command, doc) {
+            let mut cached = self.documents.lock().unwrap();
+            for found in documents::batch(reply) {
+                cached.insert(partition, &read.namespace, found, policy);
+            }
+        }
+        let data = InnerData::Document(reply.clone());
+        let entry = Entry::new(&read.namespace, &read.dependencies, data, policy);
+        self.insert_entry(&mut index, read.key, entry, policy.max_entries);
+        true
+    }
+    pub fn document(&self, partition: &str, namespace: &str, id: &Bson) -> Option<Document> {
+        self.documents.lock().unwrap().get(partition, namespace, id)
+    }
+    // every document of a collection for the `_id` cache
+    pub fn store_documents(
+        &self,
+        partition: &str,
+        namespace: &str,
+        documents: &[Document],
+        policy: &CachePolicy,
+        generation: u64,
+    ) -> bool {
+        let index = self.index.lock().unwrap();
+        if index.generation(std::iter::once(&namespace.to_string())) != generation {
+            return false;
+        }
+        let mut cached = self.documents.lock().unwrap();
+        for document in documents {
+            cached.insert(partition, namespace, document, policy);
+        }
+        true
+    }
+    pub fn restore_document(&self, partition: &str, namespace: &str, document: Document, expires: Option<Instant>) {
+        self.documents
+            .lock()
+            .unwrap()
+            .restore(partition, namespace, document, expires)
+    }
+    // read only access for snapshots, `read` must not call back into the cache
+    pub fn with_documents<F: FnOnce(&DocumentCache) -> R, R>(&self, read: F) -> R {
+        read(&self.documents.lock().unwrap())
+    }
+    pub fn collection(&self, partition: &str, namespace: &str) -> Option<LocalCollection> {
+        self.collections.lock().unwrap().get(partition, namespace).cloned()
+    }
+    pub fn insert_collection(
+        &self,
+        partition: &str,
+        namespace: &str,
+        collection: LocalCollection,
+        generation: u64,
+    ) -> bool {
+        let index = self.index.lock().unwrap();
+        if index.generation(std::iter::once(&namespace.to_string())) != generation {
+            return false;
+        }
+        self.collections
+            .lock()
+            .unwrap()
+            .insert(partition, namespace, collection);
+        true
+    }
+    pub fn restore_collection(&self, partition: &str, namespace: &str, collection: LocalCollection) {
+        self.collections
+            .lock()
+            .unwrap()
+            .insert(partition, namespace, collection)
+    }
+    pub fn with_collections<F: FnOnce(&LocalCollections) -> R, R>(&self, read: F) -> R {
+        read(&self.collections.lock().unwrap())
+    }
+    pub fn remove(&self, key: &str) -> Option<Entry> {
+        let mut index = self.index.lock().unwrap();
+        self.remove_locked(&mut index, key)
+    }
+    // an entry found past its stale window, unless it was replaced meanwhile
+    fn remove_dead(&self, key: &str) {
+        let mut index = self.index.lock().unwrap();
+        let mut shard = self.shard(key).lock().unwrap();
+        if shard.get(key).is_some_and(|entry| entry.is_dead(Instant::now())) {
+            if let Some(entry) = shard.remove(key) {
+                index.unlink(&entry);
+                evicted_metric(&entry.namespace, "expired", 1);
+            }
+        }
+    }
+    fn remove_locked(&self, index: &mut Index, key: &str) -> Option<Entry> {
+        let entry = self.shard(key).lock().unwrap().remove(key)?;
+        index.unlink(&entry);
+        Some(entry)
+    }
+    fn invalidate_locked(&self, index: &mut Index, namespace: &str, reason: &str) -> usize {
+        *index.generations.entry(namespace.to_string()).or_default() += 1;
+        self.collections.lock().unwrap().invalidate_namespace(namespace);
+        let keys = match index.namespaces.remove(namespace) {
+            Some(keys) => keys,
+            None => return 0,
+        };
+        // unlinking also drops the entries from the other namespaces they
+        // depend on
+        let removed = keys
+            .values()
+            .filter(|key| self.remove_locked(index, key).is_some())
+            .count();
+        evicted_metric(namespace, reason, removed);
+        removed
+    }
+    // drops every entry of `db.collection`, returns how many were dropped
+    pub fn invalidate_namespace(&self, namespace: &str) -> usize {
+        let mut index = self.index.lock().unwrap();
+        self.invalidate_locked(&mut index, namespace, "invalidated")
+    }
+    // a write through rengo or a change event, the `_id` cache takes the
+    // changes and the namespace's replies are dropped
+    pub fn apply_write(&self, partition: &str, namespace: &str, changes: Vec<Change>, policy: &CachePolicy) -> usize {
+        let mut index = self.index.lock().unwrap();
+        self.documents
+            .lock()
+            .unwrap()
+            .apply(partition, namespace, changes, policy);
+        self.invalidate_locked(&mut index, namespace, "invalidated")
+    }
+    // the collection is gone, its single documents go with the replies
+    pub fn drop_namespace(&self, namespace: &str) -> usize {
+        let mut index = self.index.lock().unwrap();
+        self.documents.lock().unwrap().invalidate_namespace(namespace);
+        self.invalidate_locked(&mut index, namespace, "invalidated")
+    }
+    // everything cached for `db.collection`, dropped on request
+    pub fn flush_namespace(&self, namespace: &str) -> usize {
+        let mut index = self.index.lock().unwrap();
+        self.documents.lock().unwrap().invalidate_namespace(namespace);
+        self.invalidate_locked(&mut index, namespace, "flushed")
+    }
+    pub fn clear(&self) -> usize {
+        let mut index = self.index.lock().unwrap();
+        index.epoch += 1;
+        index.namespaces.clear();
+        self.documents.lock().unwrap().clear();
+        self.collections.lock().unwrap().clear();
+        let mut flushed: HashMap<String, usize> = HashMap::new();
+        for shard in self.shards.iter() {
+            for (_, entry) in shard.lock().unwrap().drain() {
+                *flushed.entry(entry.namespace).or_default() += 1;
+            }
+        }
+        for (namespace, count) in flushed.iter() {
+            evicted_metric(namespace, "flushed", *count);
+        }
+        flushed.values().sum()
+    }
+    pub fn invalidate_database(&self, db: &str) -> usize {
+        let mut index = self.index.lock().unwrap();
+        // namespaces of the database nothing is cached for yet are bumped too
+        index.epoch += 1;
+        self.documents.lock().unwrap().invalidate_database(db);
+        self.collections.lock().unwrap().invalidate_database(db);
+        let prefix = format!("{}.", db);
+        let namespaces: Vec<String> = index
+            .namespaces
+            .keys()
+            .filter(|namespace| namespace.starts_with(&prefix))
+            .cloned()
+            .collect();
+        namespaces
+            .iter()
+            .map(|namespace| self.invalidate_locked(&mut index, namespace, "invalidated"))
+            .sum()
+    }
+}
+
+// approximate memory a document takes, its bson size
+pub fn document_size(document: &Document) -> usize {
+    bson::to_vec(document).map_or(0, |bytes| bytes.len())
+}
+
+// where the batches after a partition's cursor `id` are cached
+pub fn cursor_key(partition: &str, id: i64) -> String {
+    hash_parts(&[partition, &id.to_string()])
+}
+
+// the id of a cursor reply, 0 once the cursor is exhausted
+pub fn cursor_id(reply: &Document) -> i64 {
+    reply
+        .get_document("cursor")
+        .and_then(|cursor| cursor.get_i64("id"))
+        .unwrap_or(0)
+}
+
+fn evicted_metric(namespace: &str, reason: &str, count: usize) {
+    if count > 0 {
+        metrics().cache_evictions.with(&[namespace, reason]).add(count as u64);
+    }
+}
+
+// what a command that changes data makes stale
+#[derive(Debug, Clone, PartialEq)]
+pub enum Invalidation {
+    // insert / update / delete / findAndModify, subject to the namespace's
+    // invalidate_on_write policy
+    Write(String),
+    // the collection or database is gone, always invalidated
+    Drop(String),
+    DropDatabase(String),
+    Rename(String, String),
+}
+
+pub fn invalidation(command: &str, doc: &Document) -> Option<Invalidation> {
+    let db = doc.get_str("$db").unwrap_or("admin");
+    let namespace = || doc.get_str(command).ok().map(|collection| format!("{}.{}", db, collection));
+    match command {
+        "insert" | "update" | "delete" | "findAndModify" | "findandmodify" => namespace().map(Invalidation::Write),
+        "drop" => namespace().map(Invalidation::Drop),
+        "dropDatabase" => Some(Invalidation::DropDatabase(db.to_string())),
+        "renameCollection" => {
+            let from = doc.get_str("renameCollection").ok()?;
+            let to = doc.get_str("to").ok()?;
+            Some(Invalidation::Rename(from.to_string(), to.to_string()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use bson::doc;
+
+    use super::*;
+
+    fn rule(namespace: &str, max_entries: Option<usize>) -> PolicyRule {
+        PolicyRule {
+            namespace: namespace.to_string(),
+            max_entries,
+            ..PolicyRule::default()
+        }
+    }
+
+    fn read(namespace: &str, key: &str) -> CacheableRead {
+        CacheableRead {
+            namespace: namespace.to_string(),
+            dependencies: vec![],
+            key: key.to_string(),
+        }
+    }
+
+    fn reply(n: i32) -> InnerData {
+        InnerData::Document(doc! { "n": n, "ok": 1.0 })
+    }
+
+    fn insert(cache: &Cache, key: &str, namespace: &str, policy: &CachePolicy) -> bool {
+        let generation = cache.namespace_generation(namespace);
+        cache.insert(key.to_string(), namespace, reply(1), policy, generation)
+    }
+
+    fn is_fresh(cache: &Cache, key: &str) -> bool {
+        matches!(cache.lookup(key), Lookup::Fresh(_))
+    }
+
+    #[test]
+    fn the_first_matching_rule_applies() {
+        let config = Config {
+            cache_policies: vec![rule("shop.orders", Some(1)), rule("shop.*", Some(2))],
+            ..Config::default()
+        };
+        let cache = Cache::new();
+        assert_eq!(cache.policy(&config, "shop.orders").max_entries, Some(1));
+        assert_eq!(cache.policy(&config, "shop.carts").max_entries, Some(2));
+        assert_eq!(cache.policy(&config, "users.sessions"), config.cache_default);
+    }
+
+    #[test]
+    fn runtime_rules_override_the_configured_ones() {
+        let config = Config {
+            cache_policies: vec![PolicyRule {
+                ttl: Some(30),
+                ..rule("shop.*", Some(2))
+            }],
+            ..Config::default()
+        };
+        let cache = Cache::new();
+        cache.set_policy(rule("shop.*", Some(5)));
+        cache.set_policy(PolicyRule {
+            enabled: Some(false),
+            ..rule("shop.orders", None)
+        });
+
+        // the newer rule is checked first, unset fields keep the configured
+        // policy's
+        let orders = cache.policy(&config, "shop.orders");
+        assert!(!orders.enabled);
+        assert_eq!(orders.max_entries, Some(2));
+        let carts = cache.policy(&config, "shop.carts");
+        assert_eq!(carts.max_entries, Some(5));
+        assert_eq!(carts.ttl, Some(Duration::from_secs(30)));
+
+        // setting the same pattern again replaces the rule in place
+        cache.set_policy(rule("shop.*", Some(7)));
+        assert_eq!(cache.policies().len(), 2);
+        assert_eq!(cache.policy(&config, "shop.carts").max_entries, Some(7));
+
+        assert!(cache.remove_policy("shop.*"));
+        assert!(!cache.remove_policy("shop.*"));
+        assert_eq!(cache.policy(&config, "shop.carts").max_entries, Some(2));
+    }
+
+    #[test]
+    fn max_entries_evicts_the_oldest_of_the_namespace() {
+        let cache = Cache::new();
+        let policy = CachePolicy {
+            max_entries: Some(2),
+            ..CachePolicy::default()
+        };
+        for key in ["a", "b", "c"] {
+            assert!(insert(&cache, key, "shop.orders", &policy));
+        }
+        insert(&cache, "other", "shop.carts", &policy);
+        assert!(matches!(cache.lookup("a"), Lookup::Miss));
+        assert!(is_fresh(&cache, "b"));
+        assert!(is_fresh(&cache, "c"));
+        assert!(is_fresh(&cache, "other"));
+
+        // storing a key again makes it the newest
+        insert(&cache, "b", "shop.orders", &policy);
+        insert(&cache, "d", "shop.orders", &policy);
+        assert!(matches!(cache.lookup("c"), Lookup::Miss));
+        assert!(is_fresh(&cache, "b"));
+        assert!(is_fresh(&cache, "d"));
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn entries_expire_after_the_stale_window() {
+        let cache = Cache::new();
+        let policy = CachePolicy {
+            ttl: Some(Duration::from_millis(20)),
+            stale_while_revalidate: Some(Duration::from_millis(200)),
+            ..CachePolicy::default()
+        };
+        insert(&cache, "stale", "shop.orders", &policy);
+        let short = CachePolicy {
+            ttl: Some(Duration::from_millis(20)),
+            ..CachePolicy::default()
+        };
+        insert(&cache, "gone", "shop.orders", &short);
+        assert!(is_fresh(&cache, "stale"));
+        thread::sleep(Duration::from_millis(40));
+        assert!(matches!(cache.lookup("stale"), Lookup::Stale(_)));
+        assert!(matches!(cache.lookup("gone"), Lookup::Miss));
+        // a stale entry isn't updated in place
+        assert!(cache.update("stale", |_| ()).is_none());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn invalidating_drops_the_namespace_and_its_dependents() {
+        let cache = Cache::new();
+        let policy = CachePolicy::default();
+        insert(&cache, "orders", "shop.orders", &policy);
+        insert(&cache, "carts", "shop.carts", &policy);
+        let joined = CacheableRead {
+            dependencies: vec!["shop.products".to_string()],
+            ..read("shop.orders", "joined")
+        };
+        let generation = cache.generation(&joined);
+        assert!(cache.store_read(
+            "",
+            &doc! { "aggregate": "orders" },
+            joined,
+            &doc! { "ok": 1.0 },
+            &policy,
+            generation
+        ));
+
+        assert_eq!(cache.invalidate_namespace("shop.products"), 1);
+        assert!(matches!(cache.lookup("joined"), Lookup::Miss));
+        assert!(is_fresh(&cache, "orders"));
+        assert_eq!(cache.invalidate_namespace("shop.orders"), 1);
+        assert!(is_fresh(&cache, "carts"));
+        assert_eq!(cache.invalidate_database("shop"), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn replies_fetched_across_an_invalidation_are_not_stored() {
+        let cache = Cache::new();
+        let policy = CachePolicy::default();
+        let generation = cache.namespace_generation("shop.orders");
+        cache.invalidate_namespace("shop.orders");
+        assert!(!cache.insert("a".to_string(), "shop.orders", reply(1), &policy, generation));
+
+        let generation = cache.namespace_generation("shop.orders");
+        cache.clear();
+        assert!(!cache.insert("a".to_string(), "shop.orders", reply(1), &policy, generation));
+
+        let generation = cache.namespace_generation("shop.orders");
+        cache.invalidate_namespace("shop.carts");
+        assert!(cache.insert("a".to_string(), "shop.orders", reply(1), &policy, generation));
+    }
+
+    #[test]
+    fn finds_fill_the_id_cache() {
+        let cache = Cache::new();
+        let policy = CachePolicy::default();
+        let find = doc! { "find": "orders", "$db": "shop" };
+        let found = doc! { "cursor": { "id": 0i64, "firstBatch": [{ "_id": 1, "total": 5 }] }, "ok": 1.0 };
+        let read = read("shop.orders", "find");
+        let generation = cache.generation(&read);
+        assert!(cache.store_read("tenant", &find, read, &found, &policy, generation));
+        assert_eq!(
+            cache.document("tenant", "shop.orders", &Bson::Int32(1)),
+            Some(doc! { "_id": 1, "total": 5 })
+        );
+        assert_eq!(cache.document("", "shop.orders", &Bson::Int32(1)), None);
+        cache.drop_namespace("shop.orders");
+        assert_eq!(cache.document("tenant", "shop.orders", &Bson::Int32(1)), None);
+    }
+
+    #[test]
+    fn cursor_keys_are_kept_apart_by_partition() {
+        assert_ne!(cursor_key("app.a", 12), cursor_key("app.a1", 2));
+        assert_ne!(cursor_key("", 12), cursor_key("1", 2));
+        assert_eq!(cursor_key("app.a", 12), cursor_key("app.a", 12));
+    }
+
+    #[test]
+    fn names_what_a_command_invalidates() {
+        let write = |command: &str| invalidation(command, &doc! { command: "orders", "$db": "shop" });
+        for command in ["insert", "update", "delete", "findAndModify", "findandmodify"] {
+            assert_eq!(write(command), Some(Invalidation::Write("shop.orders".to_string())));
+        }
+        assert_eq!(write("drop"), Some(Invalidation::Drop("shop.orders".to_string())));
+        assert_eq!(write("find"), None);
+        assert_eq!(
+            invalidation("dropDatabase", &doc! { "dropDatabase": 1, "$db": "shop" }),
+            Some(Invalidation::DropDatabase("shop".to_string()))
+        );
+        assert_eq!(
+            invalidation(
+                "renameCollection",
+                &doc! { "renameCollection": "shop.a", "to": "shop.b", "$db": "admin" }
+            ),
+            Some(Invalidation::Rename("shop.a".to_string(), "shop.b".to_string()))
+        );
+    }
+
+    #[test]
+    fn concurrent_clients_keep_the_index_consistent() {
+        let cache = Arc::new(Cache::new());
+        let policy = CachePolicy {
+            max_entries: Some(50),
+            ..CachePolicy::default()
+        };
+        let clients: Vec<_> = (0..8)
+            .map(|client| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for n in 0..500 {
+                        let namespace = format!("shop.c{}", n % 4);
+                        let key = format!("{}-{}", client, n % 120);
+                        insert(&cache, &key, &namespace, &policy);
+                        cache.lookup(&format!("{}-{}", (client + 1) % 8, n % 120));
+                        if n % 97 == 0 {
+                            cache.invalidate_namespace(&namespace);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        // every entry is still reachable from its namespace, and no
+        // namespace kept more than max_entries
+        let cached = cache.len();
+        let mut dropped = 0;
+        for namespace in 0..4 {
+            let removed = cache.invalidate_namespace(&format!("shop.c{}", namespace));
+            assert!(removed <= 50, "{}", removed);
+            dropped += removed;
+        }
+        assert_eq!(dropped, cached);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn a_read_being_fetched_blocks_no_other_key() {
+        let cache = Arc::new(Cache::new());
+        let policy = CachePolicy::default();
+        insert(&cache, "hot", "shop.orders", &policy);
+        // a slow upstream read holds its flight, not a cache lock
+        let slow = match cache.join("slow") {
+            Join::Leader(guard) => guard,
+            Join::Follower(_) => unreachable!(),
+        };
+        let (done, finished) = std::sync::mpsc::channel();
+        let other = cache.clone();
+        thread::spawn(move || {
+            let hit = is_fresh(&other, "hot");
+            insert(&other, "new", "shop.carts", &CachePolicy::default());
+            other.invalidate_namespace("shop.orders");
+            done.send(hit).unwrap();
+        });
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok(true));
+        slow.complete(doc! { "ok": 1.0 });
+        assert!(is_fresh(&cache, "new"));
+    }
+}
@@ -0,0 +1,480 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bson::Document;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
+
+use super::local::LocalCollection;
+use crate::config::Config;
+use crate::handler::{GetMore, InnerData, Storage};
+
+const MAGIC: [u8; 4] = *b"RNGC";
+// bump when the layout below changes, older snapshots are then ignored
+const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    magic: [u8; 4],
+    version: u32,
+    // unix millis the snapshot was taken, ttls below are relative to it
+    taken: u64,
+}
+
+// documents are kept as bson bytes, bincode can't encode bson values
+#[derive(Serialize, Deserialize)]
+enum SavedData {
+    Document(Vec<u8>),
+    // the batches of a cursor read to the end
+    Cursor(Vec<Vec<u8>>),
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedEntry {
+    key: String,
+    namespace: String,
+    dependencies: Vec<String>,
+    data: SavedData,
+    // millis left when the snapshot was taken
+    ttl: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedDocument {
+    namespace: String,
+    partition: String,
+    document: Vec<u8>,
+    ttl: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedCollection {
+    namespace: String,
+    partition: String,
+    documents: Vec<Vec<u8>>,
+    ttl: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Snapshot {
+    entries: Vec<SavedEntry>,
+    documents: Vec<SavedDocument>,
+    collections: Vec<SavedCollection>,
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn bytes(document: &Document) -> Vec<u8> {
+    bson::to_vec(document).unwrap_or_default()
+}
+
+fn document(bytes: &[u8]) -> io::Result<Document> {
+    Document::from_reader(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn ttl(expires: Option<Instant>, now: Instant) -> Option<u64> {
+    expires.map(|expires| expires.saturating_duration_since(now).as_millis() as u64)
+}
+
+fn invalid(e: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+// copies the cache a lock at a time, writing happens after they are released
+fn snapshot(storage: &Storage) -> Snapshot {
+    let now = Instant::now();
+    let mut snapshot = Snapshot::default();
+    storage.for_each(|key, entry| {
+        if entry.is_expired(now) {
+            return;
+        }
+        let data = match &entry.data {
+            InnerData::Document(document) => SavedData::Document(bytes(document)),
+            InnerData::Documents(get_more) => match get_more.batches() {
+                Some(batches) => SavedData::Cursor(batches.iter().map(bytes).collect()),
+                // still being paged through, not worth keeping
+                None => return,
+            },
+        };
+        snapshot.entries.push(SavedEntry {
+            key: key.clone(),
+            namespace: entry.namespace.clone(),
+            dependencies: entry.dependencies.clone(),
+            data,
+            ttl: ttl(entry.expires, now),
+        });
+    });
+    storage.with_documents(|documents| {
+        for (namespace, partition, document, expires) in documents.iter() {
+            if expires.is_some_and(|expires| expires <= now) {
+                continue;
+            }
+            snapshot.documents.push(SavedDocument {
+                namespace: namespace.clone(),
+                partition: partition.clone(),
+                document: bytes(document),
+                ttl: ttl(expires, now),
+            });
+        }
+    });
+    storage.with_collections(|collections| {
+        for (namespace, partition, documents, expires) in collections.iter() {
+            if expires.is_some_and(|expires| expires <= now) {
+                continue;
+            }
+            snapshot.collections.push(SavedCollection {
+                namespace: namespace.clone(),
+                partition: partition.clone(),
+                documents: documents.iter().map(bytes).collect(),
+                ttl: ttl(expires, now),
+            });
+        }
+    });
+    snapshot
+}
+
+// written next to the target and renamed so a crash never leaves half a file
+pub fn save(path: &str, storage: &Storage) -> io::Result<usize> {
+    let snapshot = snapshot(storage);
+    let count = snapshot.entries.len() + snapshot.documents.len() + snapshot.collections.len();
+    let tmp = format!("{}.tmp", path);
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    let header = Header {
+        magic: MAGIC,
+        version: SNAPSHOT_VERSION,
+        taken: unix_millis(),
+    };
+    bincode::serialize_into(&mut file, &header).map_err(invalid)?;
+    bincode::serialize_into(&mut file, &snapshot).map_err(invalid)?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)?;
+    Ok(count)
+}
+
+// how long an entry has left now, None when it expired while rengo was down
+fn remaining(ttl: Option<u64>, age: u64, now: Instant) -> Option<Option<Instant>> {
+    match ttl {
+        None => Some(None),
+        Some(ttl) if ttl > age => Some(Some(now + Duration::from_millis(ttl - age))),
+        Some(_) => None,
+    }
+}
+
+// loads a snapshot into the cache, returns how many entries were restored
+pub fn restore(path: &str, storage: &Storage, config: &Config) -> io::Result<usize> {
+    let mut file = BufReader::new(File::open(path)?);
+    let header: Header = bincode::deserialize_from(&mut file).map_err(invalid)?;
+    if header.magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a rengo cache snapshot"));
+    }
+    if header.version != SNAPSHOT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("snapshot version {} is not supported", header.version),
+        ));
+    }
+    let age = unix_millis().saturating_sub(header.taken);
+    if config.persist_max_age.is_some_and(|max_age| age > max_age.as_millis() as u64) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("snapshot is {}s old, older than cache.persist.max_age", age / 1000),
+        ));
+    }
+    let snapshot: Snapshot = bincode::deserialize_from(&mut file).map_err(invalid)?;
+    // everything is decoded before the cache is touched, a corrupt snapshot
+    // restores nothing rather than part of it
+    let now = Instant::now();
+    let mut entries = vec![];
+    for entry in snapshot.entries {
+        let expires = match remaining(entry.ttl, age, now) {
+            Some(expires) => expires,
+            None => continue,
+        };
+        let data = match entry.data {
+            SavedData::Document(bytes) => InnerData::Document(document(&bytes)?),
+            SavedData::Cursor(batches) => {
+                let batches = batches.iter().map(|bytes| document(bytes)).collect::<io::Result<_>>()?;
+                InnerData::Documents(GetMore::from_batches(batches))
+            }
+        };
+        entries.push((entry.key, entry.namespace, entry.dependencies, data, expires));
+    }
+    let mut documents = vec![];
+    for saved in snapshot.documents {
+        if let Some(expires) = remaining(saved.ttl, age, now) {
+            documents.push((saved.partition, saved.namespace, document(&saved.document)?, expires));
+        }
+    }
+    let mut collections = vec![];
+    for saved in snapshot.collections {
+        if let Some(expires) = remaining(saved.ttl, age, now) {
+            let documents = saved.documents.iter().map(|bytes| document(bytes)).collect::<io::Result<_>>()?;
+            collections.push((saved.partition, saved.namespace, LocalCollection::restored(documents, expires)));
+        }
+    }
+    let restored = entries.len() + documents.len() + collections.len();
+    for (key, namespace, dependencies, data, expires) in entries {
+        storage.restore(key, &namespace, &dependencies, data, expires);
+    }
+    for (partition, namespace, document, expires) in documents {
+        storage.restore_document(&partition, &namespace, document, expires);
+    }
+    for (partition, namespace, collection) in collections {
+        storage.restore_collection(&partition, &namespace, collection);
+    }
+    Ok(restored)
+}
+
+// writes the snapshot every `persist_interval`
+pub fn start(config: &Config, storage: Storage) {
+    let (path, interval) = match (&config.persist_file, config.persist_interval) {
+        (Some(path), Some(interval)) => (path.clone(), interval),
+        _ => return,
+    };
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        match save(&path, &storage) {
+            Ok(count) => debug!("Saved {} cache entries to {}", count, path),
+            Err(e) => error!("could not save the cache to {}: {}", path, e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::sync::Arc;
+
+    use bson::{doc, Bson};
+
+    use super::*;
+    use crate::cache::policy::CachePolicy;
+    use crate::cache::{Cache, Lookup};
+
+    fn path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("rengo-persist-{}-{}", std::process::id(), name));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn ttl(seconds: u64) -> CachePolicy {
+        CachePolicy {
+            ttl: Some(Duration::from_secs(seconds)),
+            ..CachePolicy::default()
+        }
+    }
+
+    fn cursor(id: i64, field: &str, n: i32) -> Document {
+        doc! { "cursor": { "id": id, field: [{ "_id": n }] }, "ok": 1.0 }
+    }
+
+    // rewrites a saved snapshot, e.g. to make it older
+    fn rewrite(path: &str, change: impl FnOnce(&mut Header, &mut Snapshot)) {
+        let mut file = BufReader::new(File::open(path).unwrap());
+        let mut header: Header = bincode::deserialize_from(&mut file).unwrap();
+        let mut snapshot: Snapshot = bincode::deserialize_from(&mut file).unwrap();
+        change(&mut header, &mut snapshot);
+        let mut bytes = bincode::serialize(&header).unwrap();
+        bytes.extend(bincode::serialize(&snapshot).unwrap());
+        fs::write(path, bytes).unwrap();
+    }
+
+    fn expiry(storage: &Storage, wanted: &str) -> Option<Instant> {
+        let mut expires = None;
+        storage.for_each(|key, entry| {
+            if key == wanted {
+                expires = entry.expires;
+            }
+        });
+        expires
+    }
+
+    #[test]
+    fn round_trips_the_cache() {
+        let storage: Storage = Arc::new(Cache::new());
+        let policy = CachePolicy::default();
+        let reply = doc! { "n": 3, "ok": 1.0 };
+        storage.restore(
+            "count".to_string(),
+            "shop.orders",
+            &["shop.items".to_string()],
+            InnerData::Document(reply.clone()),
+            None,
+        );
+        let done = GetMore::from_batches(vec![cursor(5, "nextBatch", 2), cursor(0, "nextBatch", 3)]);
+        storage.insert(
+            "done".to_string(),
+            "shop.orders",
+            InnerData::Documents(done),
+            &policy,
+            0,
+        );
+        let mut paging = GetMore::new();
+        paging.add_document(cursor(5, "nextBatch", 2));
+        storage.insert(
+            "paging".to_string(),
+            "shop.orders",
+            InnerData::Documents(paging),
+            &policy,
+            0,
+        );
+        storage.restore_document("app.alice", "shop.orders", doc! { "_id": 1 }, None);
+        let collection = LocalCollection::loaded(vec![doc! { "_id": "fr" }], &ttl(60));
+        storage.restore_collection("", "app.countries", collection);
+
+        let file = path("round-trip");
+        // the cursor still being paged through is left out
+        assert_eq!(save(&file, &storage).unwrap(), 4);
+        let restored: Storage = Arc::new(Cache::new());
+        assert_eq!(restore(&file, &restored, &Config::default()).unwrap(), 4);
+
+        assert!(matches!(restored.lookup("count"), Lookup::Fresh(found) if found == reply));
+        let batches: Vec<Option<Document>> = (0..2)
+            .map(|_| {
+                restored
+                    .update("done", |data| match data {
+                        InnerData::Documents(get_more) => get_more.get_document(),
+                        InnerData::Document(_) => None,
+                    })
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            batches,
+            [Some(cursor(5, "nextBatch", 2)), Some(cursor(0, "nextBatch", 3))]
+        );
+        assert!(matches!(restored.lookup("paging"), Lookup::Miss));
+        assert_eq!(
+            restored.document("app.alice", "shop.orders", &Bson::Int32(1)),
+            Some(doc! { "_id": 1 })
+        );
+        assert!(matches!(
+            restored.collection("", "app.countries"),
+            Some(LocalCollection::Loaded { documents, expires: Some(_), .. }) if documents[0] == doc! { "_id": "fr" }
+        ));
+        // dependencies survive, a write to them still evicts
+        assert_eq!(restored.invalidate_namespace("shop.items"), 1);
+        assert!(matches!(restored.lookup("count"), Lookup::Miss));
+    }
+
+    #[test]
+    fn ttls_count_the_time_rengo_was_down() {
+        let storage: Storage = Arc::new(Cache::new());
+        let reply = || InnerData::Document(doc! { "ok": 1.0 });
+        storage.insert("short".to_string(), "shop.orders", reply(), &ttl(10), 0);
+        storage.insert("long".to_string(), "shop.orders", reply(), &ttl(60), 0);
+        storage.insert(
+            "forever".to_string(),
+            "shop.orders",
+            reply(),
+            &CachePolicy::default(),
+            0,
+        );
+        storage.restore_document(
+            "",
+            "shop.orders",
+            doc! { "_id": 1 },
+            Some(Instant::now() + Duration::from_secs(10)),
+        );
+        let file = path("ttl");
+        save(&file, &storage).unwrap();
+        // taken 20s ago
+        rewrite(&file, |header, _| header.taken -= 20_000);
+
+        let restored: Storage = Arc::new(Cache::new());
+        assert_eq!(restore(&file, &restored, &Config::default()).unwrap(), 2);
+        assert!(matches!(restored.lookup("short"), Lookup::Miss));
+        assert_eq!(restored.document("", "shop.orders", &Bson::Int32(1)), None);
+        assert_eq!(expiry(&restored, "forever"), None);
+        let left = expiry(&restored, "long")
+            .unwrap()
+            .saturating_duration_since(Instant::now());
+        assert!(
+            left > Duration::from_secs(35) && left <= Duration::from_secs(40),
+            "{:?}",
+            left
+        );
+    }
+
+    #[test]
+    fn refuses_other_formats_and_old_snapshots() {
+        let storage: Storage = Arc::new(Cache::new());
+        storage.insert(
+            "a".to_string(),
+            "shop.orders",
+            InnerData::Document(doc! { "ok": 1.0 }),
+            &CachePolicy::default(),
+            0,
+        );
+        let file = path("formats");
+        save(&file, &storage).unwrap();
+
+        rewrite(&file, |header, _| header.version = SNAPSHOT_VERSION + 1);
+        let restored: Storage = Arc::new(Cache::new());
+        let error = restore(&file, &restored, &Config::default()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("snapshot version {} is not supported", SNAPSHOT_VERSION + 1)
+        );
+
+        rewrite(&file, |header, _| {
+            header.version = SNAPSHOT_VERSION;
+            header.magic = *b"NOPE";
+        });
+        let error = restore(&file, &restored, &Config::default()).unwrap_err();
+        assert_eq!(error.to_string(), "not a rengo cache snapshot");
+
+        rewrite(&file, |header, _| {
+            header.magic = MAGIC;
+            header.taken -= 120_000;
+        });
+        let config = Config {
+            persist_max_age: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        let error = restore(&file, &restored, &config).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "snapshot is 120s old, older than cache.persist.max_age"
+        );
+        assert!(restored.is_empty());
+        assert_eq!(restore(&file, &restored, &Config::default()).unwrap(), 1);
+
+        fs::write(&file, b"RNGC").unwrap();
+        assert!(restore(&file, &Arc::new(Cache::new()), &Config::default()).is_err());
+    }
+
+    #[test]
+    fn a_corrupt_snapshot_restores_nothing() {
+        let storage: Storage = Arc::new(Cache::new());
+        storage.insert(
+            "a".to_string(),
+            "shop.orders",
+            InnerData::Document(doc! { "ok": 1.0 }),
+            &CachePolicy::default(),
+            0,
+        );
+        storage.restore_document("", "shop.orders", doc! { "_id": 1 }, None);
+        let file = path("corrupt");
+        save(&file, &storage).unwrap();
+        rewrite(&file, |_, snapshot| {
+            snapshot.documents.push(SavedDocument {
+                namespace: "shop.orders".to_string(),
+                partition: String::new(),
+                document: b"not bson".to_vec(),
+                ttl: None,
+            })
+        });
+        let restored: Storage = Arc::new(Cache::new());
+        assert!(restore(&file, &restored, &Config::default()).is_err());
+        assert!(restored.is_empty());
+        assert_eq!(restored.document("", "shop.orders", &Bson::Int32(1)), None);
+    }
+}
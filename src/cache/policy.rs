@@ -0,0 +1,261 @@
+use std::time::Duration;
+
+use bson::{Bson, Document};
+
+// how a namespace is cached, resolved from the `[cache]` defaults and the
+// first matching `[[cache.policy]]` rule
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CachePolicy {
+    pub enabled: bool,
+    // None keeps entries until they are invalidated
+    pub ttl: Option<Duration>,
+    // how long past the ttl an entry is still served while it is refreshed
+    pub stale_while_revalidate: Option<Duration>,
+    // per namespace, the oldest entry is evicted first
+    pub max_entries: Option<usize>,
+    pub cache_empty: bool,
+    pub invalidate_on_write: bool,
+    // load the whole collection and answer finds from it
+    pub local: bool,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        CachePolicy {
+            enabled: true,
+            ttl: None,
+            stale_while_revalidate: None,
+            max_entries: None,
+            cache_empty: true,
+            invalidate_on_write: true,
+            local: false,
+        }
+    }
+}
+
+pub fn is_ok(reply: &Document) -> bool {
+    match reply.get("ok") {
+        Some(Bson::Double(ok)) => *ok == 1.0,
+        Some(Bson::Int32(ok)) => *ok == 1,
+        Some(Bson::Int64(ok)) => *ok == 1,
+        _ => false,
+    }
+}
+
+// the documents of a cursor reply, or the single value of a command reply
+fn is_empty(reply: &Document) -> bool {
+    if let Ok(cursor) = reply.get_document("cursor") {
+        for batch in ["firstBatch", "nextBatch"] {
+            if let Ok(batch) = cursor.get_array(batch) {
+                return batch.is_empty();
+            }
+        }
+    }
+    if let Ok(values) = reply.get_array("values") {
+        return values.is_empty();
+    }
+    false
+}
+
+impl CachePolicy {
+    // errors are never cached, empty results only when the policy allows it
+    pub fn should_store(&self, reply: &Document) -> bool {
+        self.enabled && is_ok(reply) && (self.cache_empty || !is_empty(reply))
+    }
+}
+
+// a `[[cache.policy]]` entry, unset fields fall back to the `[cache]` defaults
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PolicyRule {
+    // `db.collection` where either side may use `*`, e.g. `reference.*`
+    pub namespace: String,
+    pub enabled: Option<bool>,
+    // seconds, 0 means entries never expire
+    pub ttl: Option<u64>,
+    // seconds, 0 turns it off
+    pub stale_while_revalidate: Option<u64>,
+    pub max_entries: Option<usize>,
+    pub cache_empty: Option<bool>,
+    pub invalidate_on_write: Option<bool>,
+    pub local: Option<bool>,
+}
+
+// `*` matches any run of characters
+pub fn glob(pattern: &str, value: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == value;
+    }
+    let mut rest = value;
+    for (index, part) in parts.iter().enumerate() {
+        if index == 0 {
+            match rest.strip_prefix(part) {
+                Some(tail) => rest = tail,
+                None => return false,
+            }
+        } else if index == parts.len() - 1 {
+            return rest.ends_with(part);
+        } else {
+            match rest.find(part) {
+                Some(position) => rest = &rest[position + part.len()..],
+                None => return false,
+            }
+        }
+    }
+    true
+}
+
+impl PolicyRule {
+    pub fn matches(&self, namespace: &str) -> bool {
+        glob(&self.namespace, namespace)
+    }
+    pub fn apply(&self, default: &CachePolicy) -> CachePolicy {
+        CachePolicy {
+            enabled: self.enabled.unwrap_or(default.enabled),
+            ttl: match self.ttl {
+                Some(0) => None,
+                Some(seconds) => Some(Duration::from_secs(seconds)),
+                None => default.ttl,
+            },
+            stale_while_revalidate: match self.stale_while_revalidate {
+                Some(0) => None,
+                Some(seconds) => Some(Duration::from_secs(seconds)),
+                None => default.stale_while_revalidate,
+            },
+            max_entries: self.max_entries.or(default.max_entries),
+            cache_empty: self.cache_empty.unwrap_or(default.cache_empty),
+            invalidate_on_write: self.invalidate_on_write.unwrap_or(default.invalidate_on_write),
+            local: self.local.unwrap_or(default.local),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::doc;
+
+    use super::*;
+
+    fn rule(namespace: &str) -> PolicyRule {
+        PolicyRule {
+            namespace: namespace.to_string(),
+            ..PolicyRule::default()
+        }
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("shop.orders", "shop.orders"));
+        assert!(!glob("shop.orders", "shop.orders2"));
+        assert!(glob("shop.*", "shop.orders"));
+        assert!(!glob("shop.*", "shopping.orders"));
+        assert!(glob("*.sessions", "users.sessions"));
+        assert!(!glob("*.sessions", "users.sessions.old"));
+        assert!(glob("*.*", "a.b"));
+        assert!(glob("*", "anything"));
+        assert!(glob("a*c*e", "abcde"));
+        assert!(!glob("a*c*e", "abde"));
+        // the parts must not overlap
+        assert!(!glob("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn matches_the_namespace_pattern() {
+        assert!(rule("reference.*").matches("reference.countries"));
+        assert!(!rule("reference.*").matches("shop.countries"));
+        assert!(rule("*.audit").matches("shop.audit"));
+    }
+
+    #[test]
+    fn unset_fields_fall_back_to_the_defaults() {
+        let default = CachePolicy {
+            ttl: Some(Duration::from_secs(60)),
+            stale_while_revalidate: Some(Duration::from_secs(10)),
+            max_entries: Some(100),
+            ..CachePolicy::default()
+        };
+        assert_eq!(rule("shop.*").apply(&default), default);
+
+        let overridden = PolicyRule {
+            enabled: Some(false),
+            ttl: Some(5),
+            stale_while_revalidate: Some(1),
+            max_entries: Some(3),
+            cache_empty: Some(false),
+            invalidate_on_write: Some(false),
+            local: Some(true),
+            ..rule("shop.*")
+        };
+        assert_eq!(
+            overridden.apply(&default),
+            CachePolicy {
+                enabled: false,
+                ttl: Some(Duration::from_secs(5)),
+                stale_while_revalidate: Some(Duration::from_secs(1)),
+                max_entries: Some(3),
+                cache_empty: false,
+                invalidate_on_write: false,
+                local: true,
+            }
+        );
+    }
+
+    #[test]
+    fn zero_turns_the_ttl_and_stale_window_off() {
+        let default = CachePolicy {
+            ttl: Some(Duration::from_secs(60)),
+            stale_while_revalidate: Some(Duration::from_secs(10)),
+            ..CachePolicy::default()
+        };
+        let policy = PolicyRule {
+            ttl: Some(0),
+            stale_while_revalidate: Some(0),
+            ..rule("shop.*")
+        }
+        .apply(&default);
+        assert_eq!(policy.ttl, None);
+        assert_eq!(policy.stale_while_revalidate, None);
+    }
+
+    #[test]
+    fn stores_only_ok_replies() {
+        let policy = CachePolicy::default();
+        assert!(policy.should_store(&doc! { "n": 1, "ok": 1.0 }));
+        assert!(policy.should_store(&doc! { "n": 1, "ok": 1 }));
+        assert!(policy.should_store(&doc! { "n": 1, "ok": 1i64 }));
+        assert!(!policy.should_store(&doc! { "ok": 0.0, "errmsg": "boom" }));
+        assert!(!policy.should_store(&doc! { "n": 1 }));
+
+        let disabled = CachePolicy {
+            enabled: false,
+            ..CachePolicy::default()
+        };
+        assert!(!disabled.should_store(&doc! { "n": 1, "ok": 1.0 }));
+    }
+
+    #[test]
+    fn empty_results_follow_cache_empty() {
+        let empty = [
+            doc! { "cursor": { "id": 0i64, "firstBatch": [] }, "ok": 1.0 },
+            doc! { "cursor": { "id": 0i64, "nextBatch": [] }, "ok": 1.0 },
+            doc! { "values": [], "ok": 1.0 },
+        ];
+        let found = [
+            doc! { "cursor": { "id": 0i64, "firstBatch": [{ "_id": 1 }] }, "ok": 1.0 },
+            doc! { "values": ["a"], "ok": 1.0 },
+            // a count of 0 is a value, not an empty result
+            doc! { "n": 0, "ok": 1.0 },
+        ];
+        let skip_empty = CachePolicy {
+            cache_empty: false,
+            ..CachePolicy::default()
+        };
+        for reply in empty.iter() {
+            assert!(CachePolicy::default().should_store(reply), "{}", reply);
+            assert!(!skip_empty.should_store(reply), "{}", reply);
+        }
+        for reply in found.iter() {
+            assert!(skip_empty.should_store(reply), "{}", reply);
+        }
+    }
+}
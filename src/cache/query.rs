@@ -0,0 +1,1083 @@
+use std::cmp::Ordering;
+
+use bson::{Bson, Decimal128, Document};
+use regex::Regex;
+
+use super::reads::VOLATILE_FIELDS;
+
+// find fields the local engine understands, anything else (collation, hint,
+// min/max...) sends the query upstream
+const QUERY_FIELDS: [&str; 8] = ["find", "filter", "sort", "projection", "skip", "limit", "batchSize", "singleBatch"];
+
+// a filter, sort or projection rengo can't evaluate itself, the query goes
+// upstream instead
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unsupported(pub String);
+
+fn unsupported(what: &str) -> Unsupported {
+    Unsupported(what.to_string())
+}
+
+// the canonical bson type order comparisons and sort use
+fn rank(value: &Bson) -> u8 {
+    match value {
+        Bson::MinKey => 0,
+        Bson::Null | Bson::Undefined => 1,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 2,
+        Bson::String(_) | Bson::Symbol(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::DateTime(_) => 9,
+        Bson::Timestamp(_) => 10,
+        Bson::RegularExpression(_) => 11,
+        Bson::MaxKey => 13,
+        _ => 12,
+    }
+}
+
+// IEEE 754 decimal128 (BID encoding) as the nearest f64
+fn decimal(value: &Decimal128) -> f64 {
+    let bits = u128::from_le_bytes(value.bytes());
+    let sign = if bits >> 127 == 1 { -1.0 } else { 1.0 };
+    let (exponent, coefficient) = match (bits >> 125) & 0b11 {
+        0b11 => match (bits >> 122) & 0b11111 {
+            0b11110 => return sign * f64::INFINITY,
+            0b11111 => return f64::NAN,
+            // coefficients past 10^34 are non canonical and read as zero
+            _ => return 0.0,
+        },
+        _ => ((bits >> 113) & 0x3fff, bits & ((1 << 113) - 1)),
+    };
+    sign * coefficient as f64 * 10f64.powi(exponent as i32 - 6176)
+}
+
+fn number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(value) => Some(*value as f64),
+        Bson::Int64(value) => Some(*value as f64),
+        Bson::Double(value) => Some(*value),
+        Bson::Decimal128(value) => Some(decimal(value)),
+        _ => None,
+    }
+}
+
+fn string(value: &Bson) -> Option<&str> {
+    match value {
+        Bson::String(value) | Bson::Symbol(value) => Some(value),
+        _ => None,
+    }
+}
+
+// orders any two values the way the server does without a collation
+pub fn compare(a: &Bson, b: &Bson) -> Ordering {
+    let rank_a = rank(a);
+    if rank_a != rank(b) {
+        return rank_a.cmp(&rank(b));
+    }
+    match (a, b) {
+        _ if rank_a == 2 => number(a).partial_cmp(&number(b)).unwrap_or(Ordering::Equal),
+        _ if rank_a == 3 => string(a).cmp(&string(b)),
+        (Bson::Document(a), Bson::Document(b)) => {
+            for ((key_a, value_a), (key_b, value_b)) in a.iter().zip(b.iter()) {
+                let ordering = rank(value_a)
+                    .cmp(&rank(value_b))
+                    .then_with(|| key_a.cmp(key_b))
+                    .then_with(|| compare(value_a, value_b));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            a.len().cmp(&b.len())
+        }
+        (Bson::Array(a), Bson::Array(b)) => {
+            for (value_a, value_b) in a.iter().zip(b.iter()) {
+                let ordering = compare(value_a, value_b);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            a.len().cmp(&b.len())
+        }
+        (Bson::Binary(a), Bson::Binary(b)) => a
+            .bytes
+            .len()
+            .cmp(&b.bytes.len())
+            .then_with(|| u8::from(a.subtype).cmp(&u8::from(b.subtype)))
+            .then_with(|| a.bytes.cmp(&b.bytes)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => a.bytes().cmp(&b.bytes()),
+        (Bson::Boolean(a), Bson::Boolean(b)) => a.cmp(b),
+        (Bson::DateTime(a), Bson::DateTime(b)) => a.cmp(b),
+        (Bson::Timestamp(a), Bson::Timestamp(b)) => (a.time, a.increment).cmp(&(b.time, b.increment)),
+        (Bson::RegularExpression(a), Bson::RegularExpression(b)) => {
+            (&a.pattern, &a.options).cmp(&(&b.pattern, &b.options))
+        }
+        _ => Ordering::Equal,
+    }
+}
+
+// every value a dotted path reaches, arrays of documents are walked into and
+// numeric parts also index arrays
+fn resolve<'a>(value: &'a Bson, path: &[&str], out: &mut Vec<&'a Bson>) {
+    let (head, rest) = match path.split_first() {
+        Some(parts) => parts,
+        None => {
+            out.push(value);
+            return;
+        }
+    };
+    match value {
+        Bson::Document(doc) => {
+            if let Some(value) = doc.get(*head) {
+                resolve(value, rest, out);
+            }
+        }
+        Bson::Array(values) => {
+            if let Some(value) = head.parse::<usize>().ok().and_then(|index| values.get(index)) {
+                resolve(value, rest, out);
+            }
+            for value in values.iter().filter(|value| matches!(value, Bson::Document(_))) {
+                resolve(value, path, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn values<'a>(doc: &'a Document, path: &str) -> Vec<&'a Bson> {
+    let parts: Vec<&str> = path.split('.').collect();
+    let mut out = vec![];
+    if let Some(value) = doc.get(parts[0]) {
+        resolve(value, &parts[1..], &mut out);
+    }
+    out
+}
+
+// the values themselves plus the elements of arrays, what `{a: 1}` matches
+fn expand<'a>(found: &[&'a Bson]) -> Vec<&'a Bson> {
+    let mut out = vec![];
+    for value in found {
+        out.push(*value);
+        if let Bson::Array(values) = value {
+            out.extend(values.iter());
+        }
+    }
+    out
+}
+
+fn equals(found: &[&Bson], value: &Bson) -> bool {
+    // null also matches a missing field
+    if matches!(value, Bson::Null) && found.is_empty() {
+        return true;
+    }
+    expand(found)
+        .iter()
+        .any(|found| rank(found) == rank(value) && compare(found, value) == Ordering::Equal)
+}
+
+fn regex(pattern: &str, options: &str) -> Result<Regex, Unsupported> {
+    let mut flags = String::new();
+    for option in options.chars() {
+        match option {
+            'i' | 'm' | 's' | 'x' => flags.push(option),
+            'u' => {}
+            _ => return Err(unsupported("$options")),
+        }
+    }
+    let pattern = if flags.is_empty() {
+        pattern.to_string()
+    } else {
+        format!("(?{}){}", flags, pattern)
+    };
+    // lookarounds and backreferences are pcre only
+    Regex::new(&pattern).map_err(|_| unsupported("$regex"))
+}
+
+fn regex_matches(found: &[&Bson], regex: &Regex) -> bool {
+    expand(found)
+        .iter()
+        .any(|found| string(found).is_some_and(|found| regex.is_match(found)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Eq(Bson),
+    Regex(Regex),
+}
+
+impl Value {
+    fn parse(value: &Bson) -> Result<Value, Unsupported> {
+        match value {
+            Bson::RegularExpression(re) => Ok(Value::Regex(regex(&re.pattern, &re.options)?)),
+            value => Ok(Value::Eq(value.clone())),
+        }
+    }
+    fn matches(&self, found: &[&Bson]) -> bool {
+        match self {
+            Value::Eq(value) => equals(found, value),
+            Value::Regex(regex) => regex_matches(found, regex),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    Value(Value),
+    Ne(Bson),
+    Compare(Comparison, Bson),
+    In(Vec<Value>),
+    Nin(Vec<Value>),
+    Exists(bool),
+    Size(usize),
+    All(Vec<Value>),
+    ElemMatch(Box<ElemMatch>),
+    Not(Vec<Condition>),
+}
+
+#[derive(Debug, Clone)]
+enum ElemMatch {
+    Conditions(Vec<Condition>),
+    Filter(Filter),
+}
+
+fn is_operator_document(value: &Bson) -> bool {
+    match value {
+        Bson::Document(doc) => doc.keys().next().is_some_and(|key| key.starts_with('$')),
+        _ => false,
+    }
+}
+
+fn truthy(value: &Bson) -> bool {
+    match value {
+        Bson::Boolean(value) => *value,
+        Bson::Null | Bson::Undefined => false,
+        value => number(value) != Some(0.0),
+    }
+}
+
+fn array<'a>(value: &'a Bson, operator: &str) -> Result<&'a Vec<Bson>, Unsupported> {
+    value.as_array().ok_or_else(|| unsupported(operator))
+}
+
+fn values_of(value: &Bson, operator: &str) -> Result<Vec<Value>, Unsupported> {
+    array(value, operator)?.iter().map(Value::parse).collect()
+}
+
+impl Condition {
+    // the conditions of an operator document such as `{$gt: 1, $lt: 5}`
+    fn parse_all(operators: &Document) -> Result<Vec<Condition>, Unsupported> {
+        let mut conditions = vec![];
+        for (operator, argument) in operators {
+            let condition = match operator.as_str() {
+                "$eq" => Condition::Value(Value::Eq(argument.clone())),
+                "$ne" => Condition::Ne(argument.clone()),
+                "$gt" => Condition::Compare(Comparison::Gt, argument.clone()),
+                "$gte" => Condition::Compare(Comparison::Gte, argument.clone()),
+                "$lt" => Condition::Compare(Comparison::Lt, argument.clone()),
+                "$lte" => Condition::Compare(Comparison::Lte, argument.clone()),
+                "$in" => Condition::In(values_of(argument, operator)?),
+                "$nin" => Condition::Nin(values_of(argument, operator)?),
+                "$exists" => Condition::Exists(truthy(argument)),
+                "$size" => match number(argument) {
+                    Some(size) if size >= 0.0 && size.fract() == 0.0 => Condition::Size(size as usize),
+                    _ => return Err(unsupported(operator)),
+                },
+                "$all" => Condition::All(values_of(argument, operator)?),
+                "$regex" => {
+                    let options = operators.get_str("$options").unwrap_or("");
+                    match argument {
+                        Bson::String(pattern) => Condition::Value(Value::Regex(regex(pattern, options)?)),
+                        Bson::RegularExpression(re) => {
+                            let options = if options.is_empty() { &re.options } else { options };
+                            Condition::Value(Value::Regex(regex(&re.pattern, options)?))
+                        }
+                        _ => return Err(unsupported(operator)),
+                    }
+                }
+                // read together with $regex
+                "$options" if operators.contains_key("$regex") => continue,
+                "$not" => match argument {
+                    Bson::Document(inner) if is_operator_document(argument) => {
+                        Condition::Not(Condition::parse_all(inner)?)
+                    }
+                    Bson::RegularExpression(_) => Condition::Not(vec![Condition::Value(Value::parse(argument)?)]),
+                    _ => return Err(unsupported(operator)),
+                },
+                "$elemMatch" => match argument {
+                    Bson::Document(inner) if is_operator_document(argument) => {
+                        Condition::ElemMatch(Box::new(ElemMatch::Conditions(Condition::parse_all(inner)?)))
+                    }
+                    Bson::Document(inner) => Condition::ElemMatch(Box::new(ElemMatch::Filter(Filter::parse(inner)?))),
+                    _ => return Err(unsupported(operator)),
+                },
+                _ => return Err(unsupported(operator)),
+            };
+            conditions.push(condition);
+        }
+        Ok(conditions)
+    }
+    fn matches(&self, found: &[&Bson]) -> bool {
+        match self {
+            Condition::Value(value) => value.matches(found),
+            Condition::Ne(value) => !equals(found, value),
+            Condition::Compare(comparison, Bson::Null) => match comparison {
+                Comparison::Gte | Comparison::Lte => equals(found, &Bson::Null),
+                Comparison::Gt | Comparison::Lt => false,
+            },
+            Condition::Compare(comparison, value) => expand(found).iter().any(|found| {
+                if rank(found) != rank(value) {
+                    return false;
+                }
+                let ordering = compare(found, value);
+                match comparison {
+                    Comparison::Gt => ordering == Ordering::Greater,
+                    Comparison::Gte => ordering != Ordering::Less,
+                    Comparison::Lt => ordering == Ordering::Less,
+                    Comparison::Lte => ordering != Ordering::Greater,
+                }
+            }),
+            Condition::In(values) => values.iter().any(|value| value.matches(found)),
+            Condition::Nin(values) => !values.iter().any(|value| value.matches(found)),
+            Condition::Exists(exists) => found.is_empty() != *exists,
+            Condition::Size(size) => found
+                .iter()
+                .any(|found| matches!(found, Bson::Array(values) if values.len() == *size)),
+            Condition::All(values) => !values.is_empty() && values.iter().all(|value| value.matches(found)),
+            Condition::ElemMatch(elem_match) => found.iter().any(|found| match found {
+                Bson::Array(elements) => elements.iter().any(|element| match elem_match.as_ref() {
+                    ElemMatch::Conditions(conditions) => {
+                        conditions.iter().all(|condition| condition.matches(&[element]))
+                    }
+                    ElemMatch::Filter(filter) => element.as_document().is_some_and(|element| filter.matches(element)),
+                }),
+                _ => false,
+            }),
+            Condition::Not(conditions) => !conditions.iter().all(|condition| condition.matches(found)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Clause {
+    And(Vec<Clause>),
+    Or(Vec<Clause>),
+    Nor(Vec<Clause>),
+    Field(String, Vec<Condition>),
+}
+
+fn clauses(value: &Bson, operator: &str) -> Result<Vec<Clause>, Unsupported> {
+    let clauses = array(value, operator)?;
+    if clauses.is_empty() {
+        return Err(unsupported(operator));
+    }
+    clauses
+        .iter()
+        .map(|clause| match clause {
+            Bson::Document(clause) => Clause::parse(clause),
+            _ => Err(unsupported(operator)),
+        })
+        .collect()
+}
+
+impl Clause {
+    fn parse(filter: &Document) -> Result<Clause, Unsupported> {
+        let mut filters = vec![];
+        for (key, value) in filter {
+            let filter = match key.as_str() {
+                "$and" => Clause::And(clauses(value, key)?),
+                "$or" => Clause::Or(clauses(value, key)?),
+                "$nor" => Clause::Nor(clauses(value, key)?),
+                "$comment" => continue,
+                // $expr, $where, $text, $jsonSchema...
+                key if key.starts_with('$') => return Err(unsupported(key)),
+                _ => match value {
+                    Bson::Document(operators) if is_operator_document(value) => {
+                        Clause::Field(key.clone(), Condition::parse_all(operators)?)
+                    }
+                    value => Clause::Field(key.clone(), vec![Condition::Value(Value::parse(value)?)]),
+                },
+            };
+            filters.push(filter);
+        }
+        Ok(Clause::And(filters))
+    }
+    fn matches(&self, doc: &Document) -> bool {
+        match self {
+            Clause::And(filters) => filters.iter().all(|filter| filter.matches(doc)),
+            Clause::Or(filters) => filters.iter().any(|filter| filter.matches(doc)),
+            Clause::Nor(filters) => !filters.iter().any(|filter| filter.matches(doc)),
+            Clause::Field(path, conditions) => {
+                let found = values(doc, path);
+                conditions.iter().all(|condition| condition.matches(&found))
+            }
+        }
+    }
+}
+
+// a parsed `filter`, every clause of a document must hold
+#[derive(Debug, Clone)]
+pub struct Filter(Clause);
+
+impl Filter {
+    pub fn parse(filter: &Document) -> Result<Filter, Unsupported> {
+        Clause::parse(filter).map(Filter)
+    }
+    pub fn matches(&self, doc: &Document) -> bool {
+        self.0.matches(doc)
+    }
+}
+
+// an empty array (None) sorts after MinKey and before null
+fn compare_key(a: Option<&Bson>, b: Option<&Bson>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => compare(a, b),
+        (None, None) => Ordering::Equal,
+        (None, Some(Bson::MinKey)) => Ordering::Greater,
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => compare_key(b, a).reverse(),
+    }
+}
+
+// `sort` fields, true for ascending
+#[derive(Debug, Clone, Default)]
+pub struct Sort(Vec<(String, bool)>);
+
+impl Sort {
+    pub fn parse(sort: &Document) -> Result<Sort, Unsupported> {
+        let mut fields = vec![];
+        for (path, direction) in sort {
+            match number(direction) {
+                Some(1.0) => fields.push((path.clone(), true)),
+                Some(-1.0) => fields.push((path.clone(), false)),
+                // {$meta: "textScore"}
+                _ => return Err(unsupported("sort")),
+            }
+        }
+        Ok(Sort(fields))
+    }
+    // arrays sort by their smallest element ascending and largest descending,
+    // None is an empty array
+    fn key(doc: &Document, path: &str, ascending: bool) -> Option<Bson> {
+        let found = values(doc, path);
+        let mut candidates = vec![];
+        for value in found {
+            match value {
+                Bson::Array(values) if values.is_empty() => candidates.push(None),
+                Bson::Array(values) => candidates.extend(values.iter().map(Some)),
+                value => candidates.push(Some(value)),
+            }
+        }
+        let key = if ascending {
+            candidates.into_iter().min_by(|a, b| compare_key(*a, *b))
+        } else {
+            candidates.into_iter().max_by(|a, b| compare_key(*a, *b))
+        };
+        match key {
+            Some(key) => key.cloned(),
+            // a missing field sorts as null
+            None => Some(Bson::Null),
+        }
+    }
+    pub fn apply(&self, documents: &mut [Document]) {
+        if self.0.is_empty() {
+            return;
+        }
+        let mut keyed: Vec<(Vec<Option<Bson>>, Document)> = documents
+            .iter()
+            .map(|doc| {
+                let keys = self.0.iter().map(|(path, ascending)| Sort::key(doc, path, *ascending));
+                (keys.collect(), doc.clone())
+            })
+            .collect();
+        keyed.sort_by(|(a, _), (b, _)| {
+            for ((a, b), (_, ascending)) in a.iter().zip(b.iter()).zip(self.0.iter()) {
+                let ordering = compare_key(a.as_ref(), b.as_ref());
+                if ordering != Ordering::Equal {
+                    return if *ascending { ordering } else { ordering.reverse() };
+                }
+            }
+            Ordering::Equal
+        });
+        for (slot, (_, doc)) in documents.iter_mut().zip(keyed) {
+            *slot = doc;
+        }
+    }
+}
+
+// an inclusion or exclusion `projection` over plain (dotted) field paths
+#[derive(Debug, Clone, PartialEq)]
+pub enum Projection {
+    All,
+    Include(Vec<Vec<String>>),
+    Exclude(Vec<Vec<String>>),
+}
+
+fn split(path: &str) -> Vec<String> {
+    path.split('.').map(|part| part.to_string()).collect()
+}
+
+// the rest of every path that starts with `key`, None when one ends there
+fn below(paths: &[Vec<String>], key: &str) -> Option<Vec<Vec<String>>> {
+    let mut rest = vec![];
+    for path in paths.iter().filter(|path| path[0] == key) {
+        if path.len() == 1 {
+            return None;
+        }
+        rest.push(path[1..].to_vec());
+    }
+    Some(rest)
+}
+
+fn include(doc: &Document, paths: &[Vec<String>]) -> Document {
+    let mut out = Document::new();
+    for (key, value) in doc {
+        if !paths.iter().any(|path| &path[0] == key) {
+            continue;
+        }
+        match below(paths, key) {
+            None => {
+                out.insert(key, value.clone());
+            }
+            Some(rest) => {
+                if let Some(value) = include_value(value, &rest) {
+                    out.insert(key, value);
+                }
+            }
+        }
+    }
+    out
+}
+
+fn include_value(value: &Bson, paths: &[Vec<String>]) -> Option<Bson> {
+    match value {
+        Bson::Document(doc) => Some(Bson::Document(include(doc, paths))),
+        Bson::Array(values) => Some(Bson::Array(
+            values.iter().filter_map(|value| include_value(value, paths)).collect(),
+        )),
+        _ => None,
+    }
+}
+
+fn exclude(doc: &Document, paths: &[Vec<String>]) -> Document {
+    let mut out = Document::new();
+    for (key, value) in doc {
+        match below(paths, key) {
+            None => {}
+            Some(rest) if rest.is_empty() => {
+                out.insert(key, value.clone());
+            }
+            Some(rest) => {
+                out.insert(key, exclude_value(value, &rest));
+            }
+        }
+    }
+    out
+}
+
+fn exclude_value(value: &Bson, paths: &[Vec<String>]) -> Bson {
+    match value {
+        Bson::Document(doc) => Bson::Document(exclude(doc, paths)),
+        Bson::Array(values) => Bson::Array(values.iter().map(|value| exclude_value(value, paths)).collect()),
+        value => value.clone(),
+    }
+}
+
+impl Projection {
+    pub fn parse(projection: &Document) -> Result<Projection, Unsupported> {
+        let mut included = vec![];
+        let mut excluded = vec![];
+        let mut id = true;
+        for (path, value) in projection {
+            // $slice, $elemMatch, $meta, positional and computed fields
+            if path.contains('$') || !matches!(value, Bson::Boolean(_) | Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_))
+            {
+                return Err(unsupported("projection"));
+            }
+            match (path.as_str(), truthy(value)) {
+                ("_id", include) => id = include,
+                (_, true) => included.push(split(path)),
+                (_, false) => excluded.push(split(path)),
+            }
+        }
+        match (included.is_empty(), excluded.is_empty()) {
+            (true, true) if id => Ok(Projection::All),
+            (true, _) => {
+                if !id {
+                    excluded.push(vec!["_id".to_string()]);
+                }
+                Ok(Projection::Exclude(excluded))
+            }
+            (false, true) => {
+                if id {
+                    included.push(vec!["_id".to_string()]);
+                }
+                Ok(Projection::Include(included))
+            }
+            // the server rejects mixing them, let it say so
+            (false, false) => Err(unsupported("projection")),
+        }
+    }
+    pub fn apply(&self, doc: &Document) -> Document {
+        match self {
+            Projection::All => doc.clone(),
+            Projection::Include(paths) => include(doc, paths),
+            Projection::Exclude(paths) => exclude(doc, paths),
+        }
+    }
+}
+
+// a `find` command the local engine can answer
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub filter: Filter,
+    pub sort: Sort,
+    pub skip: usize,
+    pub limit: Option<usize>,
+    pub projection: Projection,
+}
+
+fn count(doc: &Document, field: &str) -> Result<Option<usize>, Unsupported> {
+    match doc.get(field).map(number) {
+        None => Ok(None),
+        Some(Some(value)) if value.fract() == 0.0 => Ok(Some(value.abs() as usize)),
+        Some(_) => Err(unsupported(field)),
+    }
+}
+
+impl Query {
+    pub fn parse(doc: &Document) -> Result<Query, Unsupported> {
+        if doc.contains_key("txnNumber") {
+            return Err(unsupported("txnNumber"));
+        }
+        for key in doc.keys() {
+            if !QUERY_FIELDS.contains(&key.as_str()) && !VOLATILE_FIELDS.contains(&key.as_str()) {
+                return Err(unsupported(key));
+            }
+        }
+        let empty = Document::new();
+        let document = |field: &str| match doc.get(field) {
+            None | Some(Bson::Null) => Ok(&empty),
+            Some(Bson::Document(value)) => Ok(value),
+            Some(_) => Err(unsupported(field)),
+        };
+        Ok(Query {
+            filter: Filter::parse(document("filter")?)?,
+            sort: Sort::parse(document("sort")?)?,
+            skip: count(doc, "skip")?.unwrap_or(0),
+            // 0 means no limit, negative ones close the cursor after one batch
+            limit: count(doc, "limit")?.filter(|limit| *limit > 0),
+            projection: Projection::parse(document("projection")?)?,
+        })
+    }
+    pub fn run(&self, documents: &[Document]) -> Vec<Document> {
+        let mut found: Vec<Document> = documents.iter().filter(|doc| self.filter.matches(doc)).cloned().collect();
+        self.sort.apply(&mut found);
+        found
+            .iter()
+            .skip(self.skip)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|doc| self.projection.apply(doc))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{doc, oid::ObjectId, DateTime, Regex as BsonRegex};
+
+    use super::*;
+
+    fn matches(filter: Document, doc: &Document) -> bool {
+        Filter::parse(&filter).unwrap().matches(doc)
+    }
+
+    // every (filter, document, matches) row
+    fn check(rows: Vec<(Document, Document, bool)>) {
+        for (filter, doc, expected) in rows {
+            assert_eq!(matches(filter.clone(), &doc), expected, "{} on {}", filter, doc);
+        }
+    }
+
+    fn decimal(value: &str) -> Bson {
+        Bson::Decimal128(value.parse().unwrap())
+    }
+
+    fn sorted(sort: Document, documents: Vec<Document>) -> Vec<Bson> {
+        let mut documents = documents;
+        Sort::parse(&sort).unwrap().apply(&mut documents);
+        documents.iter().map(|doc| doc.get("_id").cloned().unwrap()).collect()
+    }
+
+    fn project(projection: Document, doc: Document) -> Document {
+        Projection::parse(&projection).unwrap().apply(&doc)
+    }
+
+    #[test]
+    fn orders_values_by_bson_type() {
+        let ascending = [
+            Bson::MinKey,
+            Bson::Null,
+            Bson::Int32(-1),
+            Bson::Double(0.5),
+            decimal("1.5"),
+            Bson::Int64(2),
+            Bson::String("".to_string()),
+            Bson::String("a".to_string()),
+            // field types before field names
+            Bson::Document(doc! { "a": 1 }),
+            Bson::Document(doc! { "b": 1 }),
+            Bson::Document(doc! { "a": "x" }),
+            Bson::Array(vec![Bson::Int32(1)]),
+            Bson::Array(vec![Bson::Int32(1), Bson::Int32(0)]),
+            Bson::ObjectId(ObjectId::from_bytes([0; 12])),
+            Bson::ObjectId(ObjectId::from_bytes([1; 12])),
+            Bson::Boolean(false),
+            Bson::Boolean(true),
+            Bson::DateTime(DateTime::from_millis(0)),
+            Bson::DateTime(DateTime::from_millis(1)),
+            Bson::RegularExpression(BsonRegex {
+                pattern: "a".to_string(),
+                options: "".to_string(),
+            }),
+            Bson::MaxKey,
+        ];
+        for (index, a) in ascending.iter().enumerate() {
+            assert_eq!(compare(a, a), Ordering::Equal, "{}", a);
+            for b in ascending[index + 1..].iter() {
+                assert_eq!(compare(a, b), Ordering::Less, "{} < {}", a, b);
+                assert_eq!(compare(b, a), Ordering::Greater, "{} > {}", b, a);
+            }
+        }
+        assert_eq!(compare(&Bson::Int32(1), &decimal("1")), Ordering::Equal);
+        assert_eq!(compare(&Bson::Int64(3), &Bson::Double(3.0)), Ordering::Equal);
+        assert_eq!(compare(&Bson::Null, &Bson::Undefined), Ordering::Equal);
+    }
+
+    #[test]
+    fn compares_within_a_type() {
+        let doc = doc! { "n": 5, "s": "b", "d": DateTime::from_millis(10) };
+        check(vec![
+            (doc! { "n": 5.0 }, doc.clone(), true),
+            (doc! { "n": 5i64 }, doc.clone(), true),
+            (doc! { "n": { "$eq": decimal("5") } }, doc.clone(), true),
+            (doc! { "n": { "$gt": 4.5 } }, doc.clone(), true),
+            (doc! { "n": { "$gte": 5 } }, doc.clone(), true),
+            (doc! { "n": { "$lt": 5 } }, doc.clone(), false),
+            (doc! { "n": { "$lte": 5, "$gt": 1 } }, doc.clone(), true),
+            (doc! { "n": { "$ne": 5 } }, doc.clone(), false),
+            (doc! { "s": { "$gt": "a" } }, doc.clone(), true),
+            (doc! { "d": { "$lt": DateTime::from_millis(11) } }, doc.clone(), true),
+            // comparisons don't cross types
+            (doc! { "n": { "$lt": "a" } }, doc.clone(), false),
+            (doc! { "s": { "$gt": 1 } }, doc.clone(), false),
+            (doc! { "n": "5" }, doc.clone(), false),
+            (doc! { "n": { "$lt": Bson::MaxKey } }, doc.clone(), false),
+        ]);
+    }
+
+    #[test]
+    fn null_matches_missing_fields() {
+        let missing = doc! { "a": 1 };
+        let null = doc! { "x": Bson::Null };
+        check(vec![
+            (doc! { "x": Bson::Null }, missing.clone(), true),
+            (doc! { "x": Bson::Null }, null.clone(), true),
+            (doc! { "x": { "$ne": Bson::Null } }, missing.clone(), false),
+            (doc! { "x": { "$gte": Bson::Null } }, missing.clone(), true),
+            (doc! { "x": { "$lt": Bson::Null } }, null.clone(), false),
+            (doc! { "x": { "$in": [Bson::Null, 1] } }, missing.clone(), true),
+            (doc! { "x": { "$nin": [Bson::Null] } }, missing.clone(), false),
+            (doc! { "x": { "$exists": false } }, missing.clone(), true),
+            (doc! { "x": { "$exists": true } }, null.clone(), true),
+            (doc! { "x": { "$exists": 0 } }, null.clone(), false),
+            (doc! { "x": { "$ne": 1 } }, missing, true),
+        ]);
+    }
+
+    #[test]
+    fn matches_arrays() {
+        let doc = doc! { "tags": ["a", "b"], "n": [1, 5], "nested": [[1, 2], 3], "empty": [] };
+        check(vec![
+            // an element or the whole array
+            (doc! { "tags": "a" }, doc.clone(), true),
+            (doc! { "tags": ["a", "b"] }, doc.clone(), true),
+            (doc! { "tags": ["b", "a"] }, doc.clone(), false),
+            (doc! { "nested": [1, 2] }, doc.clone(), true),
+            (doc! { "nested": 1 }, doc.clone(), false),
+            // each element is compared on its own
+            (doc! { "n": { "$gt": 2, "$lt": 4 } }, doc.clone(), true),
+            (
+                doc! { "n": { "$elemMatch": { "$gt": 2, "$lt": 4 } } },
+                doc.clone(),
+                false,
+            ),
+            (
+                doc! { "n": { "$elemMatch": { "$gt": 2, "$lt": 6 } } },
+                doc.clone(),
+                true,
+            ),
+            (doc! { "n": { "$ne": 5 } }, doc.clone(), false),
+            (doc! { "n": { "$in": [3, 5] } }, doc.clone(), true),
+            (doc! { "n": { "$nin": [3, 5] } }, doc.clone(), false),
+            (doc! { "tags": { "$all": ["b", "a"] } }, doc.clone(), true),
+            (doc! { "tags": { "$all": ["a", "c"] } }, doc.clone(), false),
+            (doc! { "tags": { "$all": [] } }, doc.clone(), false),
+            (doc! { "tags": { "$size": 2 } }, doc.clone(), true),
+            (doc! { "tags": { "$size": 1 } }, doc.clone(), false),
+            (doc! { "empty": { "$size": 0 } }, doc.clone(), true),
+            (doc! { "empty": [] }, doc.clone(), true),
+            (doc! { "empty": { "$exists": true } }, doc.clone(), true),
+        ]);
+    }
+
+    #[test]
+    fn follows_dotted_paths() {
+        let doc = doc! {
+            "a": { "b": { "c": 1 } },
+            "items": [{ "sku": "x", "qty": 1 }, { "sku": "y", "qty": 5 }, 7],
+            "grid": [[{ "v": 1 }]],
+        };
+        check(vec![
+            (doc! { "a.b.c": 1 }, doc.clone(), true),
+            (doc! { "a.b": { "c": 1 } }, doc.clone(), true),
+            (doc! { "a.b.d": { "$exists": false } }, doc.clone(), true),
+            (doc! { "a.c.d": Bson::Null }, doc.clone(), true),
+            // into every document of an array
+            (doc! { "items.sku": "y" }, doc.clone(), true),
+            (doc! { "items.qty": { "$gt": 4 } }, doc.clone(), true),
+            (doc! { "items.sku": "z" }, doc.clone(), false),
+            // numeric parts index the array
+            (doc! { "items.0.sku": "x" }, doc.clone(), true),
+            (doc! { "items.1.sku": "x" }, doc.clone(), false),
+            (doc! { "items.2": 7 }, doc.clone(), true),
+            // nested arrays aren't walked into
+            (doc! { "grid.v": 1 }, doc.clone(), false),
+            (doc! { "grid.0.0.v": 1 }, doc.clone(), true),
+            // the conditions may hold on different elements without $elemMatch
+            (doc! { "items.sku": "x", "items.qty": 5 }, doc.clone(), true),
+            (
+                doc! { "items": { "$elemMatch": { "sku": "x", "qty": 5 } } },
+                doc.clone(),
+                false,
+            ),
+            (
+                doc! { "items": { "$elemMatch": { "sku": "y", "qty": { "$gte": 5 } } } },
+                doc.clone(),
+                true,
+            ),
+        ]);
+    }
+
+    #[test]
+    fn matches_regexes_and_negations() {
+        let doc = doc! { "name": "Rengo", "tags": ["alpha", "beta"], "n": 1 };
+        let regex = |pattern: &str, options: &str| BsonRegex {
+            pattern: pattern.to_string(),
+            options: options.to_string(),
+        };
+        check(vec![
+            (doc! { "name": regex("^Ren", "") }, doc.clone(), true),
+            (doc! { "name": regex("^ren", "") }, doc.clone(), false),
+            (doc! { "name": regex("^ren", "i") }, doc.clone(), true),
+            (
+                doc! { "name": { "$regex": "^ren", "$options": "i" } },
+                doc.clone(),
+                true,
+            ),
+            (
+                doc! { "name": { "$regex": regex("^ren", ""), "$options": "i" } },
+                doc.clone(),
+                true,
+            ),
+            (doc! { "tags": { "$regex": "^b" } }, doc.clone(), true),
+            (
+                doc! { "tags": { "$in": [regex("^z", ""), regex("a$", "")] } },
+                doc.clone(),
+                true,
+            ),
+            // only strings match
+            (doc! { "n": { "$regex": "1" } }, doc.clone(), false),
+            (doc! { "name": { "$not": regex("^Ren", "") } }, doc.clone(), false),
+            (doc! { "name": { "$not": { "$regex": "^x" } } }, doc.clone(), true),
+            (doc! { "n": { "$not": { "$gt": 5 } } }, doc.clone(), true),
+            (doc! { "n": { "$not": { "$gt": 0, "$lt": 2 } } }, doc.clone(), false),
+            // $not also matches a missing field
+            (doc! { "missing": { "$not": { "$gt": 5 } } }, doc.clone(), true),
+            (doc! { "$or": [{ "n": 2 }, { "name": "Rengo" }] }, doc.clone(), true),
+            (doc! { "$nor": [{ "n": 2 }, { "name": "Rengo" }] }, doc.clone(), false),
+            (doc! { "$and": [{ "n": 1 }, { "name": "x" }] }, doc.clone(), false),
+            (doc! { "n": 1, "$comment": "why" }, doc.clone(), true),
+        ]);
+    }
+
+    #[test]
+    fn leaves_the_rest_to_the_server() {
+        for filter in [
+            doc! { "$expr": { "$gt": ["$a", 1] } },
+            doc! { "$where": "true" },
+            doc! { "$text": { "$search": "x" } },
+            doc! { "a": { "$type": "string" } },
+            doc! { "a": { "$mod": [2, 0] } },
+            doc! { "a": { "$not": 1 } },
+            doc! { "a": { "$size": 1.5 } },
+            doc! { "a": { "$regex": "(?=x)" } },
+            doc! { "a": { "$regex": "x", "$options": "l" } },
+            doc! { "$or": [] },
+        ] {
+            assert!(Filter::parse(&filter).is_err(), "{}", filter);
+        }
+        assert!(Sort::parse(&doc! { "score": { "$meta": "textScore" } }).is_err());
+        for projection in [
+            doc! { "a": { "$slice": 1 } },
+            doc! { "a.$": 1 },
+            doc! { "a": "$b" },
+            doc! { "a": 1, "b": 0 },
+        ] {
+            assert!(Projection::parse(&projection).is_err(), "{}", projection);
+        }
+        for find in [
+            doc! { "find": "orders", "collation": { "locale": "fr" } },
+            doc! { "find": "orders", "hint": "a_1" },
+            doc! { "find": "orders", "txnNumber": 1i64 },
+            doc! { "find": "orders", "skip": 1.5 },
+            doc! { "find": "orders", "filter": "a" },
+        ] {
+            assert!(Query::parse(&find).is_err(), "{}", find);
+        }
+    }
+
+    #[test]
+    fn sorts_by_type_then_value() {
+        let documents = vec![
+            doc! { "_id": 1, "a": "x" },
+            doc! { "_id": 2, "a": 3 },
+            doc! { "_id": 3 },
+            doc! { "_id": 4, "a": 1.5 },
+            doc! { "_id": 5, "a": Bson::Null },
+        ];
+        assert_eq!(
+            sorted(doc! { "a": 1, "_id": 1 }, documents.clone()),
+            [3, 5, 4, 2, 1].map(Bson::Int32)
+        );
+        assert_eq!(
+            sorted(doc! { "a": -1, "_id": 1 }, documents),
+            [1, 2, 4, 3, 5].map(Bson::Int32)
+        );
+    }
+
+    #[test]
+    fn sorts_arrays_by_their_smallest_or_largest_element() {
+        let documents = vec![
+            doc! { "_id": 1, "a": [1, 10] },
+            doc! { "_id": 2, "a": 5 },
+            doc! { "_id": 3, "a": [] },
+            doc! { "_id": 4, "a": [3, 7] },
+            doc! { "_id": 5 },
+        ];
+        // an empty array sorts below null and a missing field
+        assert_eq!(
+            sorted(doc! { "a": 1 }, documents.clone()),
+            [3, 5, 1, 4, 2].map(Bson::Int32)
+        );
+        assert_eq!(sorted(doc! { "a": -1 }, documents), [1, 4, 2, 5, 3].map(Bson::Int32));
+
+        let nested = vec![
+            doc! { "_id": 1, "items": [{ "qty": 4 }, { "qty": 9 }] },
+            doc! { "_id": 2, "items": [{ "qty": 6 }] },
+        ];
+        assert_eq!(sorted(doc! { "items.qty": 1 }, nested.clone()), [1, 2].map(Bson::Int32));
+        assert_eq!(sorted(doc! { "items.qty": -1 }, nested), [1, 2].map(Bson::Int32));
+    }
+
+    #[test]
+    fn sorts_are_stable_across_keys() {
+        let documents = vec![
+            doc! { "_id": 1, "a": 1, "b": 2 },
+            doc! { "_id": 2, "a": 1, "b": 1 },
+            doc! { "_id": 3, "a": 0, "b": 3 },
+        ];
+        assert_eq!(
+            sorted(doc! { "a": 1, "b": -1 }, documents.clone()),
+            [3, 1, 2].map(Bson::Int32)
+        );
+        assert_eq!(sorted(doc! {}, documents), [1, 2, 3].map(Bson::Int32));
+    }
+
+    #[test]
+    fn projects_included_fields() {
+        let doc = doc! {
+            "_id": 1,
+            "a": 1,
+            "b": { "c": 2, "d": 3 },
+            "items": [{ "sku": "x", "qty": 1 }, 7, { "qty": 2 }],
+        };
+        assert_eq!(project(doc! {}, doc.clone()), doc);
+        assert_eq!(project(doc! { "a": 1 }, doc.clone()), doc! { "_id": 1, "a": 1 });
+        assert_eq!(project(doc! { "a": true, "_id": 0 }, doc.clone()), doc! { "a": 1 });
+        assert_eq!(
+            project(doc! { "b.c": 1 }, doc.clone()),
+            doc! { "_id": 1, "b": { "c": 2 } }
+        );
+        // scalars in an array are dropped, documents keep what is left
+        assert_eq!(
+            project(doc! { "items.sku": 1, "_id": 0 }, doc.clone()),
+            doc! { "items": [{ "sku": "x" }, {}] }
+        );
+        assert_eq!(project(doc! { "a.x": 1, "_id": 0 }, doc.clone()), doc! {});
+        assert_eq!(project(doc! { "missing": 1 }, doc), doc! { "_id": 1 });
+    }
+
+    #[test]
+    fn projects_excluded_fields() {
+        let doc = doc! {
+            "_id": 1,
+            "a": 1,
+            "b": { "c": 2, "d": 3 },
+            "items": [{ "sku": "x", "qty": 1 }, 7],
+        };
+        assert_eq!(
+            project(doc! { "a": 0 }, doc.clone()),
+            doc! { "_id": 1, "b": { "c": 2, "d": 3 }, "items": [{ "sku": "x", "qty": 1 }, 7] }
+        );
+        assert_eq!(
+            project(doc! { "_id": 0, "b.c": 0, "items.qty": false }, doc.clone()),
+            doc! { "a": 1, "b": { "d": 3 }, "items": [{ "sku": "x" }, 7] }
+        );
+        assert_eq!(project(doc! { "a.x": 0 }, doc.clone()), doc);
+    }
+
+    #[test]
+    fn runs_a_find() {
+        let documents: Vec<Document> = (1..=6)
+            .map(|n| doc! { "_id": n, "even": n % 2 == 0, "n": 10 - n })
+            .collect();
+        let query = Query::parse(&doc! {
+            "find": "orders",
+            "filter": { "even": true },
+            "sort": { "n": 1 },
+            "projection": { "n": 1, "_id": 0 },
+            "skip": 1,
+            "limit": 5,
+            "$db": "shop",
+            "lsid": { "id": 1 },
+        })
+        .unwrap();
+        assert_eq!(query.run(&documents), [doc! { "n": 6 }, doc! { "n": 8 }]);
+
+        // a negative limit is a single batch of that many
+        let query = Query::parse(&doc! { "find": "orders", "filter": Bson::Null, "limit": -2 }).unwrap();
+        assert_eq!(query.run(&documents).len(), 2);
+        let query = Query::parse(&doc! { "find": "orders", "limit": 0 }).unwrap();
+        assert_eq!(query.run(&documents).len(), 6);
+    }
+}
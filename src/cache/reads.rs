@@ -0,0 +1,293 @@
+use bson::{Bson, Document};
+
+use crate::commands::hash_parts;
+
+// fields that change per request but not the result, they are left out of the key
+pub const VOLATILE_FIELDS: [&str; 9] = [
+    "$db",
+    "lsid",
+    "$clusterTime",
+    "$readPreference",
+    "readConcern",
+    "maxTimeMS",
+    "comment",
+    "apiVersion",
+    "$audit",
+];
+
+// stages that write, read server state or are not deterministic
+const UNCACHEABLE_STAGES: [&str; 13] = [
+    "$out",
+    "$merge",
+    "$currentOp",
+    "$sample",
+    "$changeStream",
+    "$changeStreamSplitLargeEvent",
+    "$collStats",
+    "$indexStats",
+    "$planCacheStats",
+    "$listSessions",
+    "$listLocalSessions",
+    "$listSearchIndexes",
+    "$querySettings",
+];
+
+// stages that read another collection of the same database
+const FOREIGN_STAGES: [(&str, &str); 3] = [("$lookup", "from"), ("$graphLookup", "from"), ("$unionWith", "coll")];
+
+// a read whose reply can be served from the cache
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheableRead {
+    pub namespace: String,
+    // other collections the result depends on, writes to them evict it too
+    pub dependencies: Vec<String>,
+    pub key: String,
+}
+
+// `$rand` and the `$$NOW` / `$$CLUSTER_TIME` variables make a result differ
+// between identical requests
+fn is_nondeterministic(value: &Bson) -> bool {
+    match value {
+        Bson::Document(doc) => doc
+            .iter()
+            .any(|(key, value)| key == "$rand" || is_nondeterministic(value)),
+        Bson::Array(values) => values.iter().any(is_nondeterministic),
+        Bson::String(value) => value.starts_with("$$NOW") || value.starts_with("$$CLUSTER_TIME"),
+        _ => false,
+    }
+}
+
+// walks the pipeline, nested ones inside $lookup / $unionWith / $facet
+// included, collecting foreign collections
+fn inspect_pipeline(pipeline: &[Bson], db: &str, dependencies: &mut Vec<String>) -> bool {
+    for stage in pipeline {
+        let stage = match stage.as_document() {
+            Some(stage) => stage,
+            None => return false,
+        };
+        for (name, spec) in stage {
+            if UNCACHEABLE_STAGES.contains(&name.as_str()) {
+                return false;
+            }
+            if let Some((_, field)) = FOREIGN_STAGES.iter().find(|(stage, _)| stage == name) {
+                let foreign = match spec {
+                    Bson::String(collection) => Some(collection.as_str()),
+                    Bson::Document(spec) => spec.get_str(field).ok(),
+                    _ => None,
+                };
+                if let Some(foreign) = foreign {
+                    dependencies.push(format!("{}.{}", db, foreign));
+                }
+            }
+            let nested: Vec<&Bson> = match spec {
+                Bson::Document(spec) if name == "$facet" => spec.values().collect(),
+                Bson::Document(spec) => spec.get("pipeline").into_iter().collect(),
+                _ => vec![],
+            };
+            for pipeline in nested {
+                match pipeline.as_array() {
+                    Some(pipeline) if inspect_pipeline(pipeline, db, dependencies) => {}
+                    Some(_) => return false,
+                    None => {}
+                }
+            }
+        }
+    }
+    true
+}
+
+// top level fields are sorted, drivers differ in the order they send them;
+// nested documents keep theirs since it matters for `sort`
+pub fn key(partition: &str, namespace: &str, doc: &Document) -> String {
+    let mut fields: Vec<(&String, &Bson)> = doc
+        .iter()
+        .filter(|(field, _)| !VOLATILE_FIELDS.contains(&field.as_str()))
+        .collect();
+    fields.sort_by_key(|(field, _)| *field);
+    let fields: Document = fields.into_iter().map(|(field, value)| (field.clone(), value.clone())).collect();
+    hash_parts(&[partition, namespace, &fields.to_string()])
+}
+
+// decides whether `doc` is a read rengo may answer from the cache
+pub fn cacheable(command: &str, doc: &Document, partition: &str) -> Option<CacheableRead> {
+    // reads in a transaction see the transaction's snapshot
+    if doc.contains_key("txnNumber") || doc.contains_key("startTransaction") {
+        return None;
+    }
+    let db = doc.get_str("$db").unwrap_or("admin");
+    // db level aggregates (`aggregate: 1`) have no collection to attach to
+    let collection = doc.get_str(command).ok()?;
+    let namespace = format!("{}.{}", db, collection);
+    let mut dependencies = vec![];
+    match command {
+        "find" => {
+            if doc.get_bool("tailable").unwrap_or(false) || doc.get_bool("awaitData").unwrap_or(false) {
+                return None;
+            }
+            if doc.get("filter").is_some_and(is_nondeterministic) {
+                return None;
+            }
+        }
+        "aggregate" => {
+            if doc.get_bool("explain").unwrap_or(false) {
+                return None;
+            }
+            let pipeline = doc.get_array("pipeline").ok()?;
+            if !inspect_pipeline(pipeline, db, &mut dependencies) {
+                return None;
+            }
+            if pipeline.iter().any(is_nondeterministic) {
+                return None;
+            }
+        }
+        "count" | "distinct" => {
+            if doc.get("query").is_some_and(is_nondeterministic) {
+                return None;
+            }
+        }
+        _ => return None,
+    }
+    dependencies.retain(|dependency| dependency != &namespace);
+    dependencies.sort();
+    dependencies.dedup();
+    Some(CacheableRead {
+        key: key(partition, &namespace, doc),
+        namespace,
+        dependencies,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::doc;
+
+    use super::*;
+
+    fn read(doc: &Document) -> Option<CacheableRead> {
+        let command = doc.keys().next().unwrap().clone();
+        cacheable(&command, doc, "")
+    }
+
+    fn aggregate(pipeline: Vec<Document>) -> Option<CacheableRead> {
+        read(&doc! { "aggregate": "orders", "pipeline": pipeline, "cursor": {}, "$db": "shop" })
+    }
+
+    #[test]
+    fn caches_finds_counts_and_distincts() {
+        for doc in [
+            doc! { "find": "orders", "filter": { "status": "A" }, "$db": "shop" },
+            doc! { "count": "orders", "query": { "status": "A" }, "$db": "shop" },
+            doc! { "distinct": "orders", "key": "status", "$db": "shop" },
+        ] {
+            let read = read(&doc).unwrap();
+            assert_eq!(read.namespace, "shop.orders");
+            assert!(read.dependencies.is_empty());
+        }
+        assert_eq!(read(&doc! { "insert": "orders", "$db": "shop" }), None);
+        assert_eq!(read(&doc! { "aggregate": 1, "pipeline": [], "$db": "admin" }), None);
+    }
+
+    #[test]
+    fn skips_transactions_tailable_cursors_and_explains() {
+        assert_eq!(read(&doc! { "find": "orders", "txnNumber": 1i64, "$db": "shop" }), None);
+        assert_eq!(
+            read(&doc! { "find": "orders", "startTransaction": true, "$db": "shop" }),
+            None
+        );
+        assert_eq!(read(&doc! { "find": "orders", "tailable": true, "$db": "shop" }), None);
+        assert_eq!(read(&doc! { "find": "orders", "awaitData": true, "$db": "shop" }), None);
+        assert_eq!(
+            read(&doc! { "aggregate": "orders", "pipeline": [], "explain": true, "$db": "shop" }),
+            None
+        );
+    }
+
+    #[test]
+    fn skips_nondeterministic_reads() {
+        assert_eq!(
+            read(&doc! { "find": "orders", "filter": { "$expr": { "$lt": [{ "$rand": {} }, 0.5] } }, "$db": "shop" }),
+            None
+        );
+        assert_eq!(
+            read(&doc! { "count": "orders", "query": { "$expr": { "$lt": ["$due", "$$NOW"] } }, "$db": "shop" }),
+            None
+        );
+        assert_eq!(aggregate(vec![doc! { "$addFields": { "at": "$$CLUSTER_TIME" } }]), None);
+        assert_eq!(aggregate(vec![doc! { "$sample": { "size": 1 } }]), None);
+        // a field merely named like a variable is fine
+        assert!(read(&doc! { "find": "orders", "filter": { "note": "NOW" }, "$db": "shop" }).is_some());
+    }
+
+    #[test]
+    fn skips_pipelines_that_write_or_read_server_state() {
+        for stage in UNCACHEABLE_STAGES {
+            assert_eq!(aggregate(vec![doc! { stage: {} }]), None, "{}", stage);
+        }
+        // nested in a $lookup or $facet too
+        assert_eq!(
+            aggregate(vec![
+                doc! { "$lookup": { "from": "items", "as": "items", "pipeline": [{ "$sample": { "size": 1 } }] } }
+            ]),
+            None
+        );
+        assert_eq!(
+            aggregate(vec![
+                doc! { "$facet": { "a": [{ "$match": {} }], "b": [{ "$collStats": {} }] } }
+            ]),
+            None
+        );
+    }
+
+    #[test]
+    fn collects_the_collections_a_pipeline_reads() {
+        let read = aggregate(vec![
+            doc! { "$lookup": { "from": "items", "localField": "item", "foreignField": "_id", "as": "items" } },
+            doc! { "$unionWith": "archive" },
+            doc! { "$unionWith": { "coll": "returns", "pipeline": [
+                { "$graphLookup": { "from": "parts", "startWith": "$part", "connectFromField": "p", "connectToField": "_id", "as": "parts" } },
+            ] } },
+            doc! { "$facet": { "self": [{ "$lookup": { "from": "orders", "as": "x", "pipeline": [] } }] } },
+            doc! { "$lookup": { "from": "items", "as": "again", "pipeline": [] } },
+        ])
+        .unwrap();
+        // the collection itself isn't a dependency, each other one is listed once
+        assert_eq!(
+            read.dependencies,
+            ["shop.archive", "shop.items", "shop.parts", "shop.returns"]
+        );
+    }
+
+    #[test]
+    fn keys_ignore_volatile_fields_and_top_level_order() {
+        let plain = doc! { "find": "orders", "filter": { "a": 1 }, "limit": 5 };
+        let mut noisy = doc! { "limit": 5, "find": "orders", "filter": { "a": 1 } };
+        for field in VOLATILE_FIELDS {
+            noisy.insert(field, "x");
+        }
+        assert_eq!(key("", "shop.orders", &plain), key("", "shop.orders", &noisy));
+    }
+
+    #[test]
+    fn partitions_and_namespaces_are_kept_apart() {
+        let find = doc! { "find": "orders", "filter": {} };
+        assert_ne!(key("admin.alice", "shop.orders", &find), key("admin.alices", "hop.orders", &find));
+        assert_ne!(key("admin.bob", "shop.orders", &find), key("admin.bo", "bshop.orders", &find));
+    }
+
+    #[test]
+    fn keys_differ_by_partition_namespace_and_nested_order() {
+        let find = doc! { "find": "orders", "sort": { "a": 1, "b": 1 } };
+        let reordered = doc! { "find": "orders", "sort": { "b": 1, "a": 1 } };
+        let base = key("", "shop.orders", &find);
+        assert_ne!(base, key("tenant", "shop.orders", &find));
+        assert_ne!(base, key("", "shop.carts", &find));
+        assert_ne!(base, key("", "shop.orders", &reordered));
+
+        let pipeline =
+            |stages: Vec<Document>| key("", "shop.orders", &doc! { "aggregate": "orders", "pipeline": stages });
+        assert_ne!(
+            pipeline(vec![doc! { "$match": { "a": 1 } }, doc! { "$limit": 1 }]),
+            pipeline(vec![doc! { "$limit": 1 }, doc! { "$match": { "a": 1 } }])
+        );
+    }
+}
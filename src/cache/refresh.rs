@@ -0,0 +1,188 @@
+use std::io::{self, Read, Write};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+use bson::Document;
+use rustls::ClientConfig;
+use tracing::{debug, error};
+
+use super::flight::FlightGuard;
+use super::reads::CacheableRead;
+use super::{cursor_id, cursor_key, warmup};
+use crate::config::{self, Config};
+use crate::handler::{InnerData, Storage};
+use crate::upstream::{self, UpstreamStream};
+
+// a stale entry to fetch again, followers keep being served the stale reply
+// until the guard completes
+pub struct Refresh {
+    pub guard: FlightGuard,
+    pub partition: String,
+    pub command: Document,
+    pub read: CacheableRead,
+    // the cache generation when the stale entry was served
+    pub generation: u64,
+}
+
+// session fields belong to the client that happened to hit the stale entry
+const SESSION_FIELDS: [&str; 2] = ["lsid", "$clusterTime"];
+
+fn refresh<S: Read + Write>(refresh: Refresh, stream: &mut S, storage: &Storage, config: &Config) -> io::Result<()> {
+    let Refresh {
+        guard,
+        partition,
+        mut command,
+        read,
+        generation,
+    } = refresh;
+    for field in SESSION_FIELDS {
+        command.remove(field);
+    }
+    let policy = storage.policy(config, &read.namespace);
+    let reply = upstream::run_command(stream, command.clone())?;
+    if !policy.should_store(&reply) {
+        guard.complete(reply);
+        return Ok(());
+    }
+    // no client pages through this connection's cursor, it is read to its
+    // end here and stored along with the reply
+    let namespace = read.namespace.clone();
+    let id = cursor_id(&reply);
+    let rest = match warmup::read_cursor(stream, &namespace, id) {
+        Ok(rest) => rest,
+        Err(e) => {
+            guard.complete(reply);
+            return Err(e);
+        }
+    };
+    if storage.store_read(&partition, &command, read, &reply, &policy, generation) {
+        if let Some(rest) = rest {
+            let key = cursor_key(&partition, id);
+            storage.insert(key, &namespace, InnerData::Documents(rest), &policy, generation);
+        }
+    }
+    guard.complete(reply);
+    Ok(())
+}
+
+// refreshes stale entries one at a time over a connection of its own, not
+// started in passthrough mode where only the client's login may read
+pub fn start(config: Arc<Config>, tls_config: Arc<ClientConfig>, addr: Arc<String>, storage: Storage) {
+    let (sender, receiver) = mpsc::channel::<Refresh>();
+    storage.set_refresher(sender);
+    thread::spawn(move || {
+        let mut stream: Option<UpstreamStream> = None;
+        for request in receiver {
+            let namespace = request.read.namespace.clone();
+            if stream.is_none() {
+                match upstream::connect(&addr, tls_config.clone(), &config.upstream) {
+                    Ok(connected) => stream = Some(connected),
+                    Err(e) => {
+                        error!(namespace, "cache refresh failed: {}", e);
+                        continue;
+                    }
+                }
+            }
+            let connection = stream.as_mut().unwrap();
+            // policies reloaded since start apply to the next refresh
+            match refresh(request, connection, &storage, &config::current()) {
+                Ok(()) => debug!(namespace, "Refreshed a stale entry"),
+                Err(e) => {
+                    error!(namespace, "cache refresh failed: {}", e);
+                    stream = None;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::doc;
+
+    use super::*;
+    use crate::cache::flight::Join;
+    use crate::cache::{reads, Cache, Lookup};
+    use crate::upstream::testing::FakeUpstream;
+
+    fn batch(id: i64, field: &str) -> Document {
+        doc! { "cursor": { "id": id, field: [{ "_id": id }] }, "ok": 1.0 }
+    }
+
+    // a refresh of `command` the way the handler hands it over
+    fn stale(storage: &Storage, command: Document) -> Refresh {
+        let read = reads::cacheable("find", &command, "").unwrap();
+        let guard = match storage.join(&read.key) {
+            Join::Leader(guard) => guard,
+            Join::Follower(_) => panic!("a flight is still going"),
+        };
+        Refresh {
+            guard,
+            partition: String::new(),
+            generation: storage.generation(&read),
+            command,
+            read,
+        }
+    }
+
+    fn next_batch(storage: &Storage, id: i64) -> Option<Option<Document>> {
+        storage.update(&cursor_key("", id), |data| match data {
+            InnerData::Documents(get_more) => get_more.get_document(),
+            InnerData::Document(_) => None,
+        })
+    }
+
+    #[test]
+    fn reads_the_cursor_to_its_end() {
+        let storage: Storage = Arc::new(Cache::new());
+        let command = doc! { "find": "orders", "batchSize": 1, "$db": "shop", "lsid": { "id": 1 } };
+        let key = reads::key("", "shop.orders", &command);
+        let mut upstream = FakeUpstream::new(|command| match command.get_i64("getMore") {
+            Err(_) => batch(7, "firstBatch"),
+            Ok(7) => batch(8, "nextBatch"),
+            Ok(_) => batch(0, "nextBatch"),
+        });
+        let request = stale(&storage, command);
+        let flight = match storage.join(&key) {
+            Join::Follower(flight) => flight,
+            Join::Leader(_) => panic!("the refresh leads the flight"),
+        };
+        refresh(request, &mut upstream, &storage, &Config::default()).unwrap();
+
+        // the client's session isn't reused
+        assert!(!upstream.commands[0].contains_key("lsid"));
+        assert_eq!(upstream.commands[1], doc! { "getMore": 7i64, "collection": "orders", "$db": "shop" });
+        assert_eq!(upstream.commands.len(), 3);
+        assert!(matches!(storage.lookup(&key), Lookup::Fresh(reply) if reply == batch(7, "firstBatch")));
+        assert_eq!(next_batch(&storage, 7), Some(Some(batch(8, "nextBatch"))));
+        assert_eq!(next_batch(&storage, 7), Some(Some(batch(0, "nextBatch"))));
+        assert_eq!(flight.wait(), Some(batch(7, "firstBatch")));
+    }
+
+    #[test]
+    fn a_cursor_that_fails_is_not_stored() {
+        let storage: Storage = Arc::new(Cache::new());
+        let command = doc! { "find": "orders", "$db": "shop" };
+        let mut upstream = FakeUpstream::new(|command| match command.contains_key("getMore") {
+            false => batch(7, "firstBatch"),
+            true => doc! { "ok": 0.0, "errmsg": "cursor id 7 not found", "code": 43 },
+        });
+        let request = stale(&storage, command.clone());
+        let error = refresh(request, &mut upstream, &storage, &Config::default()).unwrap_err();
+        assert_eq!(error.to_string(), "cursor id 7 not found");
+        assert!(storage.is_empty());
+        // the flight is over, the next stale hit refreshes again
+        assert!(matches!(storage.join(&reads::key("", "shop.orders", &command)), Join::Leader(_)));
+    }
+
+    #[test]
+    fn errors_are_not_stored() {
+        let storage: Storage = Arc::new(Cache::new());
+        let mut upstream = FakeUpstream::new(|_| doc! { "ok": 0.0, "errmsg": "not primary" });
+        let request = stale(&storage, doc! { "find": "orders", "$db": "shop" });
+        refresh(request, &mut upstream, &storage, &Config::default()).unwrap();
+        assert_eq!(upstream.commands.len(), 1);
+        assert!(storage.is_empty());
+    }
+}
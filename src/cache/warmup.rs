@@ -0,0 +1,447 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::Instant;
+
+use bson::{doc, Bson, Document};
+use rustls::ClientConfig;
+use tracing::{error, info};
+
+use super::local::{self, LocalCollection};
+use super::policy::is_ok;
+use super::{cursor_id, cursor_key, reads};
+use crate::config::{Config, ConfigError};
+use crate::handler::{GetMore, InnerData, Storage};
+use crate::upstream;
+
+// an entry of the warm-up file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warmup {
+    // a read exactly as clients send it, minus `$db`
+    Query {
+        db: String,
+        command: Document,
+        partition: String,
+    },
+    // every document of the collection, for the `_id` cache and, when the
+    // policy has `local = true`, the local query engine
+    Collection { namespace: String, partition: String },
+}
+
+impl Warmup {
+    fn namespace(&self) -> String {
+        match self {
+            Warmup::Query { db, command, .. } => {
+                let collection = command.values().next().and_then(|value| value.as_str()).unwrap_or("");
+                format!("{}.{}", db, collection)
+            }
+            Warmup::Collection { namespace, .. } => namespace.clone(),
+        }
+    }
+}
+
+// toml has no ObjectId or Long, so values go through extended json:
+// `{ "$oid" = "..." }`, `{ "$numberLong" = "..." }`, dates as toml datetimes
+fn json(value: &toml::Value) -> serde_json::Value {
+    match value {
+        toml::Value::String(value) => serde_json::Value::from(value.clone()),
+        toml::Value::Integer(value) => serde_json::Value::from(*value),
+        toml::Value::Float(value) => serde_json::Value::from(*value),
+        toml::Value::Boolean(value) => serde_json::Value::from(*value),
+        toml::Value::Datetime(value) => serde_json::json!({ "$date": value.to_string() }),
+        toml::Value::Array(values) => serde_json::Value::Array(values.iter().map(json).collect()),
+        toml::Value::Table(table) => {
+            serde_json::Value::Object(table.iter().map(|(key, value)| (key.clone(), json(value))).collect())
+        }
+    }
+}
+
+fn field<'a>(table: &'a toml::Table, prefix: &str, name: &str) -> Result<&'a str, ConfigError> {
+    match table.get(name) {
+        Some(toml::Value::String(value)) if !value.is_empty() => Ok(value),
+        Some(_) => Err(ConfigError::new(&format!("{}.{}", prefix, name), "expected a string".to_string())),
+        None => Err(ConfigError::new(&format!("{}.{}", prefix, name), "is required".to_string())),
+    }
+}
+
+// `user = "db.name"` warms the cache partition of a local user
+fn partition(table: &toml::Table, prefix: &str) -> Result<String, ConfigError> {
+    match table.get("user") {
+        None => Ok(String::new()),
+        Some(_) => field(table, prefix, "user").map(|user| user.to_string()),
+    }
+}
+
+fn tables<'a>(file: &'a toml::Table, name: &str) -> Result<Vec<&'a toml::Table>, ConfigError> {
+    match file.get(name) {
+        None => Ok(vec![]),
+        Some(toml::Value::Array(values)) => values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| {
+                value
+                    .as_table()
+                    .ok_or_else(|| ConfigError::new(&format!("{}[{}]", name, index), "expected a table".to_string()))
+            })
+            .collect(),
+        Some(_) => Err(ConfigError::new(name, format!("expected [[{}]] tables", name))),
+    }
+}
+
+pub fn parse(text: &str) -> Result<Vec<Warmup>, ConfigError> {
+    let file: toml::Table = text
+        .parse()
+        .map_err(|e: toml::de::Error| ConfigError::new("warm-up file", e.message().to_string()))?;
+    if let Some(key) = file.keys().find(|key| *key != "query" && *key != "collection") {
+        return Err(ConfigError::new(key, "unknown warm-up key, expected [[query]] or [[collection]]".to_string()));
+    }
+    let mut entries = vec![];
+    for (index, table) in tables(&file, "query")?.into_iter().enumerate() {
+        let prefix = format!("query[{}]", index);
+        let db = field(table, &prefix, "db")?.to_string();
+        let command = match table.get("command") {
+            Some(command @ toml::Value::Table(_)) => match Bson::try_from(json(command)) {
+                Ok(Bson::Document(command)) => command,
+                Ok(_) => unreachable!(),
+                Err(e) => return Err(ConfigError::new(&format!("{}.command", prefix), e.to_string())),
+            },
+            _ => return Err(ConfigError::new(&format!("{}.command", prefix), "expected a table".to_string())),
+        };
+        let name = command.keys().next().cloned().unwrap_or_default();
+        if reads::cacheable(&name, &command, "").is_none() {
+            return Err(ConfigError::new(
+                &format!("{}.command", prefix),
+                "is not a cacheable find, aggregate, count or distinct".to_string(),
+            ));
+        }
+        entries.push(Warmup::Query {
+            db,
+            command,
+            partition: partition(table, &prefix)?,
+        });
+    }
+    for (index, table) in tables(&file, "collection")?.into_iter().enumerate() {
+        let prefix = format!("collection[{}]", index);
+        let namespace = field(table, &prefix, "namespace")?;
+        if !namespace.contains('.') {
+            return Err(ConfigError::new(
+                &format!("{}.namespace", prefix),
+                "must look like `db.collection`".to_string(),
+            ));
+        }
+        entries.push(Warmup::Collection {
+            namespace: namespace.to_string(),
+            partition: partition(table, &prefix)?,
+        });
+    }
+    Ok(entries)
+}
+
+pub fn load(path: &str) -> Result<Vec<Warmup>, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::new(path, e.to_string()))?;
+    parse(&text).map_err(|e| ConfigError::new(&format!("{}: {}", path, e.key), e.message))
+}
+
+// a write evicted the namespace while it was being read
+const CHANGED: &str = "the namespace was written to while warming, not cached";
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// the batches after the first of cursor `id` of `namespace`, read to its end
+// so clients can page through it from the cache. None when there are none.
+pub fn read_cursor<S: Read + Write>(stream: &mut S, namespace: &str, mut id: i64) -> io::Result<Option<GetMore>> {
+    if id == 0 {
+        return Ok(None);
+    }
+    let (db, collection) = namespace.split_once('.').unwrap_or((namespace, ""));
+    let mut get_more = GetMore::new();
+    while id != 0 {
+        let reply = upstream::run_command(stream, doc! { "getMore": id, "collection": collection, "$db": db })?;
+        if !is_ok(&reply) {
+            let errmsg = reply.get_str("errmsg").unwrap_or("getMore failed");
+            return Err(invalid(errmsg.to_string()));
+        }
+        id = cursor_id(&reply);
+        get_more.add_document(reply);
+    }
+    Ok(Some(get_more))
+}
+
+// runs the read and caches it the way the handler would, later batches
+// included. The cursor is read to its end before anything is stored.
+fn warm_query<S: Read + Write>(
+    db: &str,
+    command: &Document,
+    partition: &str,
+    stream: &mut S,
+    storage: &Storage,
+    config: &Config,
+) -> io::Result<()> {
+    let mut command = command.clone();
+    command.insert("$db", db);
+    let name = command.keys().next().cloned().unwrap_or_default();
+    let read = reads::cacheable(&name, &command, partition).ok_or_else(|| invalid("not cacheable".to_string()))?;
+    let policy = storage.policy(config, &read.namespace);
+    if !policy.enabled {
+        return Err(invalid("caching is disabled for the namespace".to_string()));
+    }
+    let generation = storage.generation(&read);
+    let reply = upstream::run_command(stream, command.clone())?;
+    if !policy.should_store(&reply) {
+        let errmsg = reply.get_str("errmsg").unwrap_or("the reply is not cached by the namespace's policy");
+        return Err(invalid(errmsg.to_string()));
+    }
+    let id = cursor_id(&reply);
+    let namespace = read.namespace.clone();
+    let rest = read_cursor(stream, &namespace, id)?;
+    if !storage.store_read(partition, &command, read, &reply, &policy, generation) {
+        return Err(invalid(CHANGED.to_string()));
+    }
+    if let Some(rest) = rest {
+        let key = cursor_key(partition, id);
+        if !storage.insert(key, &namespace, InnerData::Documents(rest), &policy, generation) {
+            return Err(invalid(CHANGED.to_string()));
+        }
+    }
+    Ok(())
+}
+
+fn warm_collection<S: Read + Write>(
+    namespace: &str,
+    partition: &str,
+    stream: &mut S,
+    storage: &Storage,
+    config: &Config,
+) -> io::Result<()> {
+    let (db, collection) = namespace.split_once('.').unwrap_or((namespace, ""));
+    let policy = storage.policy(config, namespace);
+    if !policy.enabled {
+        return Err(invalid("caching is disabled for the namespace".to_string()));
+    }
+    // the `_id` cache keeps at most max_entries of them by itself
+    let max = match policy.local {
+        true => Some(policy.max_entries.unwrap_or(local::MAX_DOCUMENTS)),
+        false => None,
+    };
+    let generation = storage.namespace_generation(namespace);
+    let documents = local::load(|command| upstream::run_command(stream, command), db, collection, max)
+        .map_err(invalid)?;
+    if !storage.store_documents(partition, namespace, &documents, &policy, generation) {
+        return Err(invalid(CHANGED.to_string()));
+    }
+    if policy.local {
+        let collection = LocalCollection::loaded(documents, &policy);
+        if !storage.insert_collection(partition, namespace, collection, generation) {
+            return Err(invalid(CHANGED.to_string()));
+        }
+    }
+    Ok(())
+}
+
+// runs every entry over a connection of its own, failures are logged and
+// skipped so a bad entry can't keep rengo from starting
+pub fn run(entries: &[Warmup], config: &Config, tls_config: Arc<ClientConfig>, addr: &str, storage: &Storage) {
+    if entries.is_empty() {
+        return;
+    }
+    let started = Instant::now();
+    let mut stream = match upstream::connect(addr, tls_config, &config.upstream) {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("cache warm-up: {}", e);
+            return;
+        }
+    };
+    let mut warmed = 0;
+    for entry in entries {
+        let result = match entry {
+            Warmup::Query { db, command, partition } => warm_query(db, command, partition, &mut stream, storage, config),
+            Warmup::Collection { namespace, partition } => {
+                warm_collection(namespace, partition, &mut stream, storage, config)
+            }
+        };
+        match result {
+            Ok(()) => warmed += 1,
+            Err(e) => error!(namespace = entry.namespace(), "cache warm-up failed: {}", e),
+        }
+    }
+    info!(
+        "Cache warmed with {} of {} entries in {:.1}s",
+        warmed,
+        entries.len(),
+        started.elapsed().as_secs_f64()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::oid::ObjectId;
+
+    use super::*;
+    use crate::cache::policy::PolicyRule;
+    use crate::cache::{Cache, Lookup};
+    use crate::upstream::testing::FakeUpstream;
+
+    fn parse_error(text: &str) -> ConfigError {
+        parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn parses_queries_and_collections() {
+        let entries = parse(
+            r#"
+            [[query]]
+            db = "app"
+            command = { find = "products", filter = { _id = { "$oid" = "65f0a1b2c3d4e5f6a7b8c9d0" }, n = { "$numberLong" = "5" }, at = 2024-01-02T03:04:05Z } }
+
+            [[query]]
+            db = "app"
+            user = "app.alice"
+            command = { aggregate = "orders", pipeline = [{ "$match" = { status = "A" } }], cursor = {} }
+
+            [[collection]]
+            namespace = "app.countries"
+            "#,
+        )
+        .unwrap();
+        let id = ObjectId::parse_str("65f0a1b2c3d4e5f6a7b8c9d0").unwrap();
+        let at = bson::DateTime::parse_rfc3339_str("2024-01-02T03:04:05Z").unwrap();
+        assert_eq!(
+            entries,
+            [
+                Warmup::Query {
+                    db: "app".to_string(),
+                    command: doc! { "find": "products", "filter": { "_id": id, "n": 5i64, "at": at } },
+                    partition: String::new(),
+                },
+                Warmup::Query {
+                    db: "app".to_string(),
+                    command: doc! {
+                        "aggregate": "orders",
+                        "pipeline": [{ "$match": { "status": "A" } }],
+                        "cursor": {},
+                    },
+                    partition: "app.alice".to_string(),
+                },
+                Warmup::Collection {
+                    namespace: "app.countries".to_string(),
+                    partition: String::new(),
+                },
+            ]
+        );
+        assert_eq!(entries[2].namespace(), "app.countries");
+        assert_eq!(entries[1].namespace(), "app.orders");
+    }
+
+    #[test]
+    fn errors_name_the_entry() {
+        assert_eq!(parse_error("[[queries]]").key, "queries");
+        assert_eq!(parse_error("query = 1").key, "query");
+        assert_eq!(parse_error("query = [1]").key, "query[0]");
+        assert_eq!(parse_error("[[query]]\ncommand = { find = \"a\" }").key, "query[0].db");
+        assert_eq!(parse_error("[[query]]\ndb = \"app\"").key, "query[0].command");
+        let uncacheable = "[[query]]\ndb = \"app\"\ncommand = { insert = \"a\" }";
+        assert_eq!(parse_error(uncacheable).key, "query[0].command");
+        let user = "[[query]]\ndb = \"app\"\nuser = 1\ncommand = { find = \"a\" }";
+        assert_eq!(parse_error(user).key, "query[0].user");
+        assert_eq!(
+            parse_error("[[collection]]\nnamespace = \"app\"").key,
+            "collection[0].namespace"
+        );
+        assert_eq!(
+            parse_error("[[collection]]\nnamespace = \"\"").key,
+            "collection[0].namespace"
+        );
+        assert_eq!(parse_error("[[query").key, "warm-up file");
+    }
+
+    #[test]
+    fn caches_every_batch_of_a_query() {
+        let storage: Storage = Arc::new(Cache::new());
+        let batch = |id: i64, field: &str, n: i32| doc! { "cursor": { "id": id, field: [{ "_id": n }] }, "ok": 1.0 };
+        let mut upstream = FakeUpstream::new(|command| match command.get_i64("getMore") {
+            Err(_) => batch(9, "firstBatch", 1),
+            Ok(_) if command.get_str("collection") == Ok("products") => batch(0, "nextBatch", 2),
+            Ok(_) => doc! { "ok": 0.0, "errmsg": "wrong collection" },
+        });
+        let command = doc! { "find": "products", "batchSize": 1 };
+        warm_query(
+            "app",
+            &command,
+            "app.alice",
+            &mut upstream,
+            &storage,
+            &Config::default(),
+        )
+        .unwrap();
+        assert_eq!(upstream.commands[0].get_str("$db").unwrap(), "app");
+        assert_eq!(upstream.commands.len(), 2);
+
+        // a client sending the same find hits the first batch, its getMore the rest
+        let sent = doc! { "find": "products", "batchSize": 1, "$db": "app" };
+        let key = reads::key("app.alice", "app.products", &sent);
+        assert!(matches!(storage.lookup(&key), Lookup::Fresh(reply) if reply == batch(9, "firstBatch", 1)));
+        let next = storage.update(&cursor_key("app.alice", 9), |data| match data {
+            InnerData::Documents(get_more) => get_more.get_document(),
+            InnerData::Document(_) => None,
+        });
+        assert_eq!(next, Some(Some(batch(0, "nextBatch", 2))));
+        assert!(storage.document("app.alice", "app.products", &Bson::Int32(2)).is_none());
+        assert!(storage.document("app.alice", "app.products", &Bson::Int32(1)).is_some());
+    }
+
+    #[test]
+    fn skips_what_the_policy_keeps_out() {
+        let storage: Storage = Arc::new(Cache::new());
+        let mut upstream = FakeUpstream::new(|_| doc! { "ok": 0.0, "errmsg": "not authorized" });
+        let command = doc! { "find": "products" };
+        let error = warm_query("app", &command, "", &mut upstream, &storage, &Config::default()).unwrap_err();
+        assert_eq!(error.to_string(), "not authorized");
+
+        storage.set_policy(PolicyRule {
+            namespace: "app.*".to_string(),
+            enabled: Some(false),
+            ..PolicyRule::default()
+        });
+        let error = warm_query("app", &command, "", &mut upstream, &storage, &Config::default()).unwrap_err();
+        assert_eq!(error.to_string(), "caching is disabled for the namespace");
+        let error = warm_collection("app.products", "", &mut upstream, &storage, &Config::default()).unwrap_err();
+        assert_eq!(error.to_string(), "caching is disabled for the namespace");
+        assert_eq!(upstream.commands.len(), 1);
+        assert!(storage.is_empty());
+    }
+
+    #[test]
+    fn loads_collections() {
+        let storage: Storage = Arc::new(Cache::new());
+        storage.set_policy(PolicyRule {
+            namespace: "app.countries".to_string(),
+            local: Some(true),
+            ..PolicyRule::default()
+        });
+        let documents = vec![doc! { "_id": "fr" }, doc! { "_id": "nl" }];
+        let reply = doc! { "cursor": { "id": 0i64, "firstBatch": documents.clone() }, "ok": 1.0 };
+        let mut upstream = FakeUpstream::new(|_| reply.clone());
+        for namespace in ["app.countries", "app.cities"] {
+            warm_collection(namespace, "", &mut upstream, &storage, &Config::default()).unwrap();
+        }
+        assert_eq!(
+            upstream.commands[0],
+            doc! { "find": "countries", "filter": {}, "$db": "app" }
+        );
+        assert!(storage
+            .document("", "app.countries", &Bson::String("nl".to_string()))
+            .is_some());
+        assert!(storage
+            .document("", "app.cities", &Bson::String("nl".to_string()))
+            .is_some());
+        // only the local policy keeps the whole collection for queries
+        assert!(matches!(
+            storage.collection("", "app.countries"),
+            Some(LocalCollection::Loaded { documents: loaded, .. }) if *loaded == documents
+        ));
+        assert!(storage.collection("", "app.cities").is_none());
+    }
+}
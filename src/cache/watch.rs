@@ -0,0 +1,452 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use bson::{doc, Bson, Document};
+use rustls::ClientConfig;
+use tracing::{debug, error, info, warn};
+
+use super::documents::Change;
+use crate::config::{self, Config};
+use crate::handler::Storage;
+use crate::upstream;
+
+// the longest we wait before reconnecting a failed change stream
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// server codes meaning the stream can not be resumed from our token
+const CHANGE_STREAM_FATAL_ERROR: i32 = 280;
+const CHANGE_STREAM_HISTORY_LOST: i32 = 286;
+
+// the last resume token of every watched database, kept in memory and, when a
+// file is configured, on disk as a single bson document keyed by database
+pub struct ResumeTokens {
+    path: Option<String>,
+    tokens: Mutex<Document>,
+}
+
+impl ResumeTokens {
+    // a missing or unreadable file starts every stream from now
+    pub fn load(path: Option<String>) -> ResumeTokens {
+        let tokens = match &path {
+            Some(path) => match File::open(path) {
+                Ok(mut file) => Document::from_reader(&mut file).unwrap_or_else(|e| {
+                    warn!("ignoring resume tokens in {}: {}", path, e);
+                    Document::new()
+                }),
+                Err(_) => Document::new(),
+            },
+            None => Document::new(),
+        };
+        ResumeTokens {
+            path,
+            tokens: Mutex::new(tokens),
+        }
+    }
+    pub fn get(&self, db: &str) -> Option<Bson> {
+        self.tokens.lock().unwrap().get(db).cloned()
+    }
+    pub fn set(&self, db: &str, token: Option<Bson>) {
+        let mut tokens = self.tokens.lock().unwrap();
+        match token {
+            Some(token) => {
+                if tokens.get(db) == Some(&token) {
+                    return;
+                }
+                tokens.insert(db, token);
+            }
+            None => {
+                if tokens.remove(db).is_none() {
+                    return;
+                }
+            }
+        }
+        if let Err(e) = self.save(&tokens) {
+            error!("could not save resume tokens: {}", e);
+        }
+    }
+    // written next to the target and renamed so a crash never leaves half a file
+    fn save(&self, tokens: &Document) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let tmp = format!("{}.tmp", path);
+        let mut bytes = vec![];
+        tokens
+            .to_writer(&mut bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+// what a change event makes stale, Err when the stream has to be reopened
+fn apply(event: &Document, storage: &Storage, config: &Config) -> Result<(), ()> {
+    let operation = event.get_str("operationType").unwrap_or("");
+    let ns = event.get_document("ns").ok();
+    let db = ns.and_then(|ns| ns.get_str("db").ok());
+    let namespace = ns.and_then(|ns| {
+        let collection = ns.get_str("coll").ok()?;
+        Some(format!("{}.{}", ns.get_str("db").ok()?, collection))
+    });
+    let evicted = match (operation, namespace, db) {
+        ("insert" | "update" | "replace" | "delete", Some(namespace), _)
+            if storage.policy(config, &namespace).invalidate_on_write =>
+        {
+            // only the changed document is dropped from the document cache
+            let changes = match event.get_document("documentKey").ok().and_then(|key| key.get("_id")) {
+                Some(id) => vec![Change::Remove(id.clone())],
+                None => vec![],
+            };
+            storage.apply_write("", &namespace, changes, &storage.policy(config, &namespace))
+        }
+        ("drop", Some(namespace), _) => storage.drop_namespace(&namespace),
+        ("rename", Some(namespace), _) => {
+            let to = event
+                .get_document("to")
+                .ok()
+                .and_then(|to| Some(format!("{}.{}", to.get_str("db").ok()?, to.get_str("coll").ok()?)));
+            storage.drop_namespace(&namespace) + to.map_or(0, |to| storage.drop_namespace(&to))
+        }
+        ("dropDatabase", _, Some(db)) => storage.invalidate_database(db),
+        // the database or collection watched is gone and the server closed
+        // the stream
+        ("invalidate", _, _) => return Err(()),
+        _ => 0,
+    };
+    if evicted > 0 {
+        debug!(operation, "Change stream evicted {} entries", evicted);
+    }
+    Ok(())
+}
+
+fn error_code(reply: &Document) -> Option<i32> {
+    match reply.get("code") {
+        Some(Bson::Int32(code)) => Some(*code),
+        Some(Bson::Int64(code)) => Some(*code as i32),
+        Some(Bson::Double(code)) => Some(*code as i32),
+        _ => None,
+    }
+}
+
+fn failed(reply: &Document) -> io::Error {
+    let errmsg = reply.get_str("errmsg").unwrap_or("change stream failed");
+    io::Error::other(errmsg.to_string())
+}
+
+// every entry the watched database could have cached, used when events may
+// have been missed
+fn flush(db: &str, storage: &Storage) {
+    if db == "*" {
+        storage.clear();
+    } else {
+        storage.invalidate_database(db);
+    }
+}
+
+// one change stream over its own upstream connection, returns once the
+// stream has to be reopened and errors when the connection failed
+fn watch<S: Read + Write>(
+    db: &str,
+    stream: &mut S,
+    storage: &Storage,
+    tokens: &ResumeTokens,
+    reopened: bool,
+) -> io::Result<()> {
+    let (target, mut change_stream) = if db == "*" {
+        ("admin", doc! { "allChangesForCluster": true })
+    } else {
+        (db, doc! {})
+    };
+    let resume = tokens.get(db);
+    match &resume {
+        Some(token) => {
+            change_stream.insert("resumeAfter", token.clone());
+        }
+        // nothing tells us what changed while we were not watching, the
+        // first stream of the process was settled by start()
+        None if reopened => flush(db, storage),
+        None => {}
+    }
+    let reply = upstream::run_command(
+        stream,
+        doc! {
+            "aggregate": 1,
+            "pipeline": [{ "$changeStream": change_stream }],
+            "cursor": {},
+            "$db": target,
+        },
+    )?;
+    if reply.get_document("cursor").is_err() {
+        if resume.is_some()
+            && matches!(
+                error_code(&reply),
+                Some(CHANGE_STREAM_HISTORY_LOST) | Some(CHANGE_STREAM_FATAL_ERROR)
+            )
+        {
+            warn!(db, "change stream can not resume, starting over");
+            tokens.set(db, None);
+            return Ok(());
+        }
+        return Err(failed(&reply));
+    }
+    info!(db, "Watching change stream");
+    let mut reply = reply;
+    loop {
+        let cursor = match reply.get_document("cursor") {
+            Ok(cursor) => cursor,
+            Err(_) => return Err(failed(&reply)),
+        };
+        let batch = cursor
+            .get_array("firstBatch")
+            .or_else(|_| cursor.get_array("nextBatch"))
+            .map_err(|_| failed(&reply))?;
+        // the token is saved once per batch, an event seen twice after a
+        // restart only evicts again
+        let mut last = None;
+        for event in batch.iter().filter_map(|event| event.as_document()) {
+            if apply(event, storage, &config::current()).is_err() {
+                tokens.set(db, None);
+                return Ok(());
+            }
+            last = event.get("_id");
+        }
+        if let Some(token) = cursor.get("postBatchResumeToken").or(last) {
+            tokens.set(db, Some(token.clone()));
+        }
+        let id = cursor.get_i64("id").unwrap_or(0);
+        if id == 0 {
+            return Ok(());
+        }
+        reply = upstream::run_command(
+            stream,
+            doc! {
+                "getMore": id,
+                "collection": "$cmd.aggregate",
+                "$db": target,
+            },
+        )?;
+    }
+}
+
+// starts a thread per watched database, each reconnects with backoff and
+// resumes from the last token it saw
+pub fn start(config: Arc<Config>, tls_config: Arc<ClientConfig>, addr: Arc<String>, storage: Storage, restored: bool) {
+    if config.watch_databases.is_empty() {
+        return;
+    }
+    let tokens = Arc::new(ResumeTokens::load(config.resume_token_file.clone()));
+    for db in config.watch_databases.clone() {
+        // a restored snapshot may hold writes made while rengo was down, only
+        // a resume token can tell which
+        if restored && tokens.get(&db).is_none() {
+            flush(&db, &storage);
+        }
+        let config = config.clone();
+        let tls_config = tls_config.clone();
+        let addr = addr.clone();
+        let storage = storage.clone();
+        let tokens = tokens.clone();
+        thread::spawn(move || {
+            let mut backoff = Duration::from_secs(1);
+            let mut reopened = false;
+            loop {
+                let result = upstream::connect(&addr, tls_config.clone(), &config.upstream)
+                    .and_then(|mut stream| watch(&db, &mut stream, &storage, &tokens, reopened));
+                reopened = true;
+                match result {
+                    Ok(()) => backoff = Duration::from_secs(1),
+                    Err(e) => {
+                        error!(db = db.as_str(), "change stream failed: {}", e);
+                        thread::sleep(backoff);
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::cache::policy::{CachePolicy, PolicyRule};
+    use crate::cache::reads::CacheableRead;
+    use crate::cache::Cache;
+    use crate::upstream::testing::FakeUpstream;
+
+    fn path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("rengo-watch-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    // a cached find of `namespace` that also put `{_id: 1}` in the document cache
+    fn cached(storage: &Storage, namespace: &str) {
+        let (db, collection) = namespace.split_once('.').unwrap();
+        let read = CacheableRead {
+            namespace: namespace.to_string(),
+            dependencies: vec![],
+            key: namespace.to_string(),
+        };
+        let generation = storage.generation(&read);
+        let find = doc! { "find": collection, "$db": db };
+        let reply = doc! { "cursor": { "id": 0i64, "firstBatch": [{ "_id": 1 }] }, "ok": 1.0 };
+        storage.store_read("", &find, read, &reply, &CachePolicy::default(), generation);
+    }
+
+    fn is_cached(storage: &Storage, namespace: &str) -> bool {
+        storage.document("", namespace, &Bson::Int32(1)).is_some()
+    }
+
+    fn event(operation: &str, namespace: &str) -> Document {
+        let (db, collection) = namespace.split_once('.').unwrap();
+        doc! {
+            "_id": { "_data": "token" },
+            "operationType": operation,
+            "ns": { "db": db, "coll": collection },
+            "documentKey": { "_id": 1 },
+        }
+    }
+
+    #[test]
+    fn events_evict_what_they_change() {
+        let storage: Storage = Arc::new(Cache::new());
+        let config = Config::default();
+        for operation in ["insert", "update", "replace", "delete", "drop"] {
+            cached(&storage, "shop.orders");
+            cached(&storage, "shop.carts");
+            apply(&event(operation, "shop.orders"), &storage, &config).unwrap();
+            assert!(!is_cached(&storage, "shop.orders"), "{}", operation);
+            assert!(is_cached(&storage, "shop.carts"));
+            assert_eq!(storage.len(), 1, "{}", operation);
+            storage.clear();
+        }
+
+        cached(&storage, "shop.orders");
+        cached(&storage, "shop.archive");
+        cached(&storage, "shop.carts");
+        let mut rename = event("rename", "shop.orders");
+        rename.insert("to", doc! { "db": "shop", "coll": "archive" });
+        apply(&rename, &storage, &config).unwrap();
+        assert_eq!(storage.len(), 1);
+        assert!(is_cached(&storage, "shop.carts"));
+
+        cached(&storage, "users.sessions");
+        let drop_database = doc! { "operationType": "dropDatabase", "ns": { "db": "shop" } };
+        apply(&drop_database, &storage, &config).unwrap();
+        assert!(!is_cached(&storage, "shop.carts"));
+        assert!(is_cached(&storage, "users.sessions"));
+    }
+
+    #[test]
+    fn writes_keep_entries_without_invalidate_on_write() {
+        let storage: Storage = Arc::new(Cache::new());
+        storage.set_policy(PolicyRule {
+            namespace: "reference.*".to_string(),
+            invalidate_on_write: Some(false),
+            ..PolicyRule::default()
+        });
+        cached(&storage, "reference.countries");
+        apply(&event("update", "reference.countries"), &storage, &Config::default()).unwrap();
+        assert!(is_cached(&storage, "reference.countries"));
+        assert_eq!(storage.len(), 1);
+        // a drop is always applied
+        apply(&event("drop", "reference.countries"), &storage, &Config::default()).unwrap();
+        assert!(storage.is_empty());
+    }
+
+    #[test]
+    fn invalidate_reopens_the_stream() {
+        let storage: Storage = Arc::new(Cache::new());
+        assert!(apply(&event("invalidate", "shop.orders"), &storage, &Config::default()).is_err());
+    }
+
+    #[test]
+    fn resume_tokens_survive_a_restart() {
+        let file = path("tokens");
+        let tokens = ResumeTokens::load(Some(file.clone()));
+        tokens.set("shop", Some(Bson::String("a".to_string())));
+        tokens.set("users", Some(Bson::String("b".to_string())));
+        tokens.set("users", None);
+        let loaded = ResumeTokens::load(Some(file.clone()));
+        assert_eq!(loaded.get("shop"), Some(Bson::String("a".to_string())));
+        assert_eq!(loaded.get("users"), None);
+
+        // a corrupt file starts over instead of failing
+        fs::write(&file, b"not bson").unwrap();
+        assert_eq!(ResumeTokens::load(Some(file)).get("shop"), None);
+        assert_eq!(ResumeTokens::load(None).get("shop"), None);
+    }
+
+    #[test]
+    fn follows_the_stream_and_saves_the_token() {
+        let storage: Storage = Arc::new(Cache::new());
+        let tokens = ResumeTokens::load(None);
+        cached(&storage, "shop.orders");
+        cached(&storage, "shop.carts");
+        let mut upstream = FakeUpstream::new(|command| match command.contains_key("getMore") {
+            false => doc! {
+                "cursor": {
+                    "id": 7i64,
+                    "firstBatch": [event("insert", "shop.orders")],
+                    "postBatchResumeToken": { "_data": "first" },
+                },
+                "ok": 1.0,
+            },
+            true => doc! {
+                "cursor": { "id": 0i64, "nextBatch": [event("delete", "shop.carts")] },
+                "ok": 1.0,
+            },
+        });
+        watch("shop", &mut upstream, &storage, &tokens, false).unwrap();
+
+        assert_eq!(upstream.commands[0].get_str("$db").unwrap(), "shop");
+        assert_eq!(
+            upstream.commands[0].get_array("pipeline").unwrap()[0],
+            Bson::Document(doc! { "$changeStream": {} })
+        );
+        assert_eq!(upstream.commands[1].get_i64("getMore").unwrap(), 7);
+        assert!(storage.is_empty());
+        // without a postBatchResumeToken the last event's id is kept
+        assert_eq!(tokens.get("shop"), Some(Bson::Document(doc! { "_data": "token" })));
+    }
+
+    #[test]
+    fn resumes_after_the_saved_token() {
+        let storage: Storage = Arc::new(Cache::new());
+        let tokens = ResumeTokens::load(None);
+        tokens.set("*", Some(Bson::String("saved".to_string())));
+        let mut upstream = FakeUpstream::new(|_| doc! { "cursor": { "id": 0i64, "firstBatch": [] }, "ok": 1.0 });
+        watch("*", &mut upstream, &storage, &tokens, true).unwrap();
+        let command = &upstream.commands[0];
+        assert_eq!(command.get_str("$db").unwrap(), "admin");
+        assert_eq!(
+            command.get_array("pipeline").unwrap()[0],
+            Bson::Document(doc! { "$changeStream": { "allChangesForCluster": true, "resumeAfter": "saved" } })
+        );
+    }
+
+    #[test]
+    fn starts_over_when_the_history_is_lost() {
+        let storage: Storage = Arc::new(Cache::new());
+        let tokens = ResumeTokens::load(None);
+        tokens.set("shop", Some(Bson::String("old".to_string())));
+        let mut upstream = FakeUpstream::new(|_| doc! { "ok": 0.0, "code": 286, "errmsg": "history lost" });
+        watch("shop", &mut upstream, &storage, &tokens, true).unwrap();
+        assert_eq!(tokens.get("shop"), None);
+
+        // reopened without a token, whatever the database cached may be stale
+        cached(&storage, "shop.orders");
+        cached(&storage, "users.sessions");
+        let error = watch("shop", &mut upstream, &storage, &tokens, true).unwrap_err();
+        assert_eq!(error.to_string(), "history lost");
+        assert!(!is_cached(&storage, "shop.orders"));
+        assert!(is_cached(&storage, "users.sessions"));
+    }
+}
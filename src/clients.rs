@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Instant, SystemTime};
+
+use crate::auth::Session;
+
+// a client connection as the admin api lists it
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub id: u64,
+    pub peer: Option<SocketAddr>,
+    pub connected: SystemTime,
+    // the local end of its upstream connection, None while it waits for a
+    // worker
+    pub upstream: Option<SocketAddr>,
+    // `db.user`, empty until the client authenticates
+    pub user: String,
+    pub requests: u64,
+    pub busy: bool,
+    pub last_request: Option<Instant>,
+}
+
+struct State {
+    client: Client,
+    session: Option<Arc<Mutex<Session>>>,
+    // a handle on the client's socket, shut down to close it while idle
+    socket: Option<TcpStream>,
+    closing: bool,
+}
+
+type Registry = Mutex<BTreeMap<u64, Arc<Mutex<State>>>>;
+
+fn registry() -> &'static Registry {
+    static CLIENTS: OnceLock<Registry> = OnceLock::new();
+    CLIENTS.get_or_init(Registry::default)
+}
+
+// listed from accept until the returned registration is dropped
+pub fn register(id: u64, peer: Option<SocketAddr>, socket: Option<TcpStream>) -> Registration {
+    let state = Arc::new(Mutex::new(State {
+        client: Client {
+            id,
+            peer,
+            connected: SystemTime::now(),
+            upstream: None,
+            user: String::new(),
+            requests: 0,
+            busy: false,
+            last_request: None,
+        },
+        session: None,
+        socket,
+        closing: false,
+    }));
+    registry().lock().unwrap().insert(id, state.clone());
+    Registration(state)
+}
+
+pub struct Registration(Arc<Mutex<State>>);
+
+impl Registration {
+    // a worker picked the client up and connected it upstream
+    pub fn serving(&self, upstream: Option<SocketAddr>, session: Arc<Mutex<Session>>) {
+        let mut state = self.0.lock().unwrap();
+        state.client.upstream = upstream;
+        state.session = Some(session);
+    }
+    // a message arrived, false when the connection is being closed and it
+    // should not be handled
+    pub fn request(&self) -> bool {
+        let mut state = self.0.lock().unwrap();
+        if state.closing {
+            return false;
+        }
+        state.client.requests += 1;
+        state.client.busy = true;
+        state.client.last_request = Some(Instant::now());
+        true
+    }
+    pub fn replied(&self) {
+        self.0.lock().unwrap().client.busy = false;
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let id = self.0.lock().unwrap().client.id;
+        registry().lock().unwrap().remove(&id);
+    }
+}
+
+// every client connection, by id
+pub fn list() -> Vec<Client> {
+    let states: Vec<Arc<Mutex<State>>> = registry().lock().unwrap().values().cloned().collect();
+    states
+        .iter()
+        .map(|state| {
+            let state = state.lock().unwrap();
+            let mut client = state.client.clone();
+            // a session held across an upstream round trip is skipped
+            if let Some(Ok(session)) = state.session.as_ref().map(|session| session.try_lock()) {
+                client.user = session.partition();
+            }
+            client
+        })
+        .collect()
+}
+
+pub fn count() -> usize {
+    registry().lock().unwrap().len()
+}
+
+// shuts down the sockets of clients not in the middle of a command, their
+// workers see the connection end. Busy clients are left to finish.
+pub fn close_idle() -> usize {
+    let states: Vec<Arc<Mutex<State>>> = registry().lock().unwrap().values().cloned().collect();
+    let mut closed = 0;
+    for state in states {
+        let mut state = state.lock().unwrap();
+        if state.client.busy || state.closing {
+            continue;
+        }
+        state.closing = true;
+        if let Some(socket) = &state.socket {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        closed += 1;
+    }
+    closed
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::sync::{Mutex, MutexGuard};
+
+    // count and close_idle see every client, tests that register clients
+    // take turns
+    pub fn exclusive() -> MutexGuard<'static, ()> {
+        static EXCLUSIVE: Mutex<()> = Mutex::new(());
+        EXCLUSIVE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::exclusive;
+    use super::*;
+    use crate::auth::Principal;
+
+    fn listed(id: u64) -> Option<Client> {
+        list().into_iter().find(|client| client.id == id)
+    }
+
+    #[test]
+    fn lists_clients_while_registered() {
+        let _exclusive = exclusive();
+        let peer: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        let registration = register(8001, Some(peer), None);
+        let client = listed(8001).unwrap();
+        assert_eq!(client.peer, Some(peer));
+        assert_eq!(client.upstream, None);
+        assert_eq!((client.requests, client.busy, client.user.as_str()), (0, false, ""));
+
+        let session = Arc::new(Mutex::new(Session::new()));
+        registration.serving(Some("10.0.0.9:40000".parse().unwrap()), session.clone());
+        session.lock().unwrap().principal = Some(Principal {
+            user: "app".to_string(),
+            db: "shop".to_string(),
+        });
+        assert!(registration.request());
+        let client = listed(8001).unwrap();
+        assert_eq!(client.upstream, Some("10.0.0.9:40000".parse().unwrap()));
+        assert_eq!(client.user, "shop.app");
+        assert!(client.busy);
+        assert!(client.last_request.is_some());
+        // a session held across a round trip isn't waited for
+        let held = session.lock().unwrap();
+        assert_eq!(listed(8001).unwrap().user, "");
+        drop(held);
+
+        registration.replied();
+        assert!(!listed(8001).unwrap().busy);
+        drop(registration);
+        assert!(listed(8001).is_none());
+    }
+
+    #[test]
+    fn closes_only_idle_clients() {
+        let _exclusive = exclusive();
+        let idle = register(8002, None, None);
+        let busy = register(8003, None, None);
+        assert!(busy.request());
+        assert_eq!(close_idle(), 1);
+        assert!(!idle.request());
+        busy.replied();
+        assert_eq!(close_idle(), 1);
+        assert_eq!(close_idle(), 0);
+        assert_eq!(count(), 2);
+    }
+}
@@ -4,6 +4,9 @@ use crate::Wire::{MAX_DOCUMENT_LEN, MAX_MSG_LEN};
 use bson::{doc, Bson, Document};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+// mongos reports the same session timeout as the config servers default
+const LOGICAL_SESSION_TIMEOUT_MINUTES: i32 = 30;
+
 pub struct IsMaster {}
 
 impl Handler for IsMaster {
@@ -13,23 +16,112 @@ impl Handler for IsMaster {
 
     fn handle(
         &self,
-        _request: &Request,
-        _msg: &Vec<Document>,
+        request: &Request,
+        msg: &Vec<Document>,
     ) -> Result<Document, CommandExecutionError> {
         let local_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_millis();
-        Ok(doc! {
-          "ismaster": Bson::Boolean(true),
-          "maxBsonObjectSize": MAX_DOCUMENT_LEN,
-          "maxMessageSizeBytes": MAX_MSG_LEN,
-          "maxWriteBatchSize": 100000,
-          "localTime": Bson::DateTime(bson::DateTime::from_millis(local_time.try_into().unwrap())),
-          "minWireVersion": 0,
-          "maxWireVersion": 13,
-          "readOnly": Bson::Boolean(false),
-          "ok": Bson::Double(1.into())
+        let local_time = Bson::DateTime(bson::DateTime::from_millis(local_time.try_into().unwrap()));
+        if !request.get_config().is_router() {
+            return Ok(doc! {
+              "ismaster": Bson::Boolean(true),
+              "maxBsonObjectSize": MAX_DOCUMENT_LEN,
+              "maxMessageSizeBytes": MAX_MSG_LEN,
+              "maxWriteBatchSize": 100000,
+              "localTime": local_time,
+              "minWireVersion": 0,
+              "maxWireVersion": 13,
+              "readOnly": Bson::Boolean(false),
+              "ok": Bson::Double(1.into())
+            });
+        }
+        // drivers treat `isdbgrid` as a sharded router and route retryable
+        // writes / sessions through us. A driver given replicaSet= in the uri
+        // removes a mongos from its topology, so clients must leave it out
+        let command = msg
+            .first()
+            .and_then(|doc| doc.keys().next().cloned())
+            .unwrap_or_default();
+        let mut reply = doc! {};
+        if command == "hello" {
+            reply.insert("isWritablePrimary", Bson::Boolean(true));
+        } else {
+            reply.insert("ismaster", Bson::Boolean(true));
+        }
+        if msg.first().is_some_and(|doc| doc.get_bool("helloOk").unwrap_or(false)) {
+            reply.insert("helloOk", Bson::Boolean(true));
+        }
+        reply.insert("msg", "isdbgrid");
+        reply.insert("maxBsonObjectSize", MAX_DOCUMENT_LEN);
+        reply.insert("maxMessageSizeBytes", MAX_MSG_LEN);
+        reply.insert("maxWriteBatchSize", 100000);
+        reply.insert("localTime", local_time);
+        reply.insert("logicalSessionTimeoutMinutes", LOGICAL_SESSION_TIMEOUT_MINUTES);
+        reply.insert("minWireVersion", 0);
+        reply.insert("maxWireVersion", 13);
+        reply.insert("readOnly", Bson::Boolean(false));
+        reply.insert("ok", Bson::Double(1.into()));
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::auth::{Session, UserStore};
+    use crate::cache::Cache;
+    use crate::config::{Config, Topology};
+    use crate::handler::testing::with_request;
+
+    fn handshake(topology: Topology, doc: Document) -> Document {
+        let config = Config {
+            topology,
+            ..Config::default()
+        };
+        let storage = Arc::new(Cache::new());
+        let session = Mutex::new(Session::new());
+        with_request(&config, &UserStore::default(), &session, &storage, &doc, |request| {
+            IsMaster::new().handle(request, &vec![doc.clone()]).unwrap()
         })
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn standalone_answers_as_a_primary() {
+        let reply = handshake(Topology::Standalone, doc! { "isMaster": 1 });
+        assert_eq!(reply.get_bool("ismaster"), Ok(true));
+        assert!(reply.get("msg").is_none());
+        assert!(reply.get("logicalSessionTimeoutMinutes").is_none());
+    }
+
+    #[test]
+    fn router_answers_as_isdbgrid() {
+        let reply = handshake(Topology::Router, doc! { "isMaster": 1 });
+        assert_eq!(reply.get_str("msg"), Ok("isdbgrid"));
+        assert_eq!(reply.get_bool("ismaster"), Ok(true));
+        assert_eq!(reply.get_i32("logicalSessionTimeoutMinutes"), Ok(LOGICAL_SESSION_TIMEOUT_MINUTES));
+        assert!(reply.get("setName").is_none());
+        assert!(reply.get("hosts").is_none());
+        assert!(reply.get("helloOk").is_none());
+    }
+
+    #[test]
+    fn router_answers_hello_with_writable_primary() {
+        let reply = handshake(Topology::Router, doc! { "hello": 1, "helloOk": true });
+        assert_eq!(reply.get_bool("isWritablePrimary"), Ok(true));
+        assert!(reply.get("ismaster").is_none());
+        assert_eq!(reply.get_bool("helloOk"), Ok(true));
+        assert_eq!(reply.get_f64("ok"), Ok(1.0));
+    }
+
+    #[test]
+    fn topology_names() {
+        assert_eq!(Topology::parse("mongos"), Some(Topology::Router));
+        assert_eq!(Topology::parse("isdbgrid"), Some(Topology::Router));
+        assert_eq!(Topology::parse("standalone"), Some(Topology::Standalone));
+        assert_eq!(Topology::parse("replset"), None);
+    }
+}
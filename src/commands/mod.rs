@@ -2,6 +2,8 @@ use std::{collections::hash_map::DefaultHasher, hash::{Hash, Hasher}};
 use crate::handler::{Request, CommandExecutionError};
 use bson::Document;
 pub mod is_master;
+pub mod rengo;
+pub mod sasl;
 pub trait Handler {
     fn new() -> Self;
     fn handle(&self,request: &Request,msg: &Vec<Document>,) -> Result<Document, CommandExecutionError>;
@@ -13,4 +15,11 @@ pub fn hash(filter: String ) -> String {
     let result = hasher.finish();
     let hash = format!("{:x}", result);
     hash
+}
+// a key built from several parts, each hashed with its length so `ab` + `c`
+// and `a` + `bc` don't collide
+pub fn hash_parts(parts: &[&str]) -> String {
+    let mut hasher = DefaultHasher::new();
+    parts.hash(&mut hasher);
+    format!("{:x}", hasher.finish())
 }
\ No newline at end of file
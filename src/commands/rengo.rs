@@ -0,0 +1,201 @@
+use crate::admin;
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use crate::reload;
+use bson::{doc, Bson, Document};
+use serde_json::Value;
+use tracing::info;
+
+// proxy commands, answered by rengo when sent to the admin database
+const COMMANDS: [&str; 4] = ["rengoCacheStats", "rengoFlush", "rengoConfig", "rengoReload"];
+
+pub fn is_rengo_command(command: &str, doc: &Document) -> bool {
+    COMMANDS.contains(&command) && doc.get_str("$db") == Ok("admin")
+}
+
+fn not_authorized(command: &str) -> Document {
+    doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": Bson::String(format!("not authorized on admin to execute command {}, it requires rengo's admin role", command)),
+        "code": Bson::Int32(13),
+        "codeName": "Unauthorized",
+    }
+}
+
+fn bad_value(message: &str) -> Document {
+    doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": Bson::String(message.to_string()),
+        "code": Bson::Int32(2),
+        "codeName": "BadValue",
+    }
+}
+
+// the admin api's json as a reply document
+fn reply(value: Value) -> Result<Document, CommandExecutionError> {
+    let mut reply = match Bson::try_from(value) {
+        Ok(Bson::Document(reply)) => reply,
+        _ => return Err(CommandExecutionError::new("could not convert the reply".to_string())),
+    };
+    reply.insert("ok", Bson::Double(1.0));
+    Ok(reply)
+}
+
+pub struct RengoCommand {}
+
+impl Handler for RengoCommand {
+    fn new() -> Self {
+        RengoCommand {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        msg: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let doc = &msg[0];
+        let command = doc.keys().next().map_or("", |command| command.as_str());
+        // users listed in `admin.users` hold the admin role
+        let user = request.get_session().lock().unwrap().partition();
+        if user.is_empty() || !request.get_config().admin_users.contains(&user) {
+            return Ok(not_authorized(command));
+        }
+        let storage = request.get_storage();
+        match command {
+            "rengoCacheStats" => reply(admin::cache_stats(storage)),
+            // a namespace, or `1` / `"*"` for the whole cache
+            "rengoFlush" => {
+                let namespace = match doc.get(command) {
+                    Some(Bson::String(all)) if all == "*" => None,
+                    Some(Bson::String(namespace)) if namespace.contains('.') => Some(namespace.as_str()),
+                    Some(Bson::Int32(1) | Bson::Int64(1) | Bson::Boolean(true)) => None,
+                    Some(Bson::Double(one)) if *one == 1.0 => None,
+                    _ => return Ok(bad_value("rengoFlush takes a \"db.collection\" namespace, or 1 for the whole cache")),
+                };
+                info!(%user, "{} through the admin command", command);
+                let flushed = admin::flush(storage, namespace);
+                Ok(doc! { "flushed": flushed as i64, "ok": Bson::Double(1.0) })
+            }
+            "rengoConfig" => reply(serde_json::json!({ "config": admin::settings(request.get_config(), storage) })),
+            // the new settings apply from the next message, this one ran
+            // with the old ones
+            "rengoReload" => {
+                info!(%user, "{} through the admin command", command);
+                match reload::reload() {
+                    Ok(report) => reply(admin::reloaded(&report)),
+                    Err(e) => Ok(bad_value(&e.to_string())),
+                }
+            }
+            _ => Ok(bad_value(&format!("unknown rengo command {}", command))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::auth::{Principal, Session, UserStore};
+    use crate::cache::policy::CachePolicy;
+    use crate::cache::Cache;
+    use crate::config::Config;
+    use crate::handler::testing::with_request;
+    use crate::handler::{InnerData, Storage};
+
+    fn session(db: &str, user: &str) -> Mutex<Session> {
+        let mut session = Session::new();
+        session.principal = Some(Principal {
+            user: user.to_string(),
+            db: db.to_string(),
+        });
+        Mutex::new(session)
+    }
+
+    fn config() -> Config {
+        Config {
+            admin_users: vec!["admin.ops".to_string()],
+            ..Config::default()
+        }
+    }
+
+    fn storage() -> Storage {
+        let storage = Arc::new(Cache::new());
+        for (key, namespace) in [("a", "shop.orders"), ("b", "shop.carts")] {
+            let generation = storage.namespace_generation(namespace);
+            let data = InnerData::Document(doc! { "ok": 1.0 });
+            storage.insert(key.to_string(), namespace, data, &CachePolicy::default(), generation);
+        }
+        storage
+    }
+
+    fn run(config: &Config, session: &Mutex<Session>, storage: &Storage, doc: Document) -> Document {
+        with_request(config, &UserStore::default(), session, storage, &doc, |request| {
+            RengoCommand::new().handle(request, &vec![doc.clone()]).unwrap()
+        })
+    }
+
+    #[test]
+    fn only_on_the_admin_database() {
+        assert!(is_rengo_command("rengoFlush", &doc! { "rengoFlush": 1, "$db": "admin" }));
+        assert!(!is_rengo_command("rengoFlush", &doc! { "rengoFlush": 1, "$db": "shop" }));
+        assert!(!is_rengo_command("rengoFlush", &doc! { "rengoFlush": 1 }));
+        assert!(!is_rengo_command("ping", &doc! { "ping": 1, "$db": "admin" }));
+    }
+
+    #[test]
+    fn requires_the_admin_role() {
+        let storage = storage();
+        for session in [Mutex::new(Session::new()), session("shop", "app"), session("shop", "ops")] {
+            let reply = run(&config(), &session, &storage, doc! { "rengoFlush": 1, "$db": "admin" });
+            assert_eq!(reply.get_f64("ok"), Ok(0.0));
+            assert_eq!(reply.get_i32("code"), Ok(13));
+            assert_eq!(reply.get_str("codeName"), Ok("Unauthorized"));
+        }
+        assert_eq!(storage.len(), 2);
+    }
+
+    #[test]
+    fn reports_cache_stats() {
+        let reply = run(&config(), &session("admin", "ops"), &storage(), doc! { "rengoCacheStats": 1, "$db": "admin" });
+        assert_eq!(reply.get_f64("ok"), Ok(1.0));
+        assert_eq!(reply.get("entries"), Some(&Bson::Int32(2)));
+        let namespaces = reply.get_array("namespaces").unwrap();
+        assert_eq!(namespaces.len(), 2);
+        assert!(reply.get_document("memory").is_ok());
+    }
+
+    #[test]
+    fn flushes_a_namespace_or_everything() {
+        let admin = session("admin", "ops");
+        let storage = storage();
+        let reply = run(&config(), &admin, &storage, doc! { "rengoFlush": "shop.orders", "$db": "admin" });
+        assert_eq!(reply, doc! { "flushed": 1i64, "ok": 1.0 });
+        assert_eq!(storage.len(), 1);
+        for all in [Bson::Int32(1), Bson::Double(1.0), Bson::Boolean(true), Bson::String("*".to_string())] {
+            let storage = self::storage();
+            let reply = run(&config(), &admin, &storage, doc! { "rengoFlush": all.clone(), "$db": "admin" });
+            assert_eq!(reply, doc! { "flushed": 2i64, "ok": 1.0 }, "{}", all);
+            assert!(storage.is_empty());
+        }
+        for invalid in [Bson::String("orders".to_string()), Bson::Int32(0), Bson::Null] {
+            let reply = run(&config(), &admin, &storage, doc! { "rengoFlush": invalid.clone(), "$db": "admin" });
+            assert_eq!(reply.get_i32("code"), Ok(2), "{}", invalid);
+            assert_eq!(reply.get_str("codeName"), Ok("BadValue"));
+        }
+        assert_eq!(storage.len(), 1);
+    }
+
+    #[test]
+    fn shows_the_settings_without_credentials() {
+        let mut config = config();
+        config.upstream.username = Some("proxy".to_string());
+        config.upstream.password = Some("hunter2".to_string());
+        let reply = run(&config, &session("admin", "ops"), &storage(), doc! { "rengoConfig": 1, "$db": "admin" });
+        assert_eq!(reply.get_f64("ok"), Ok(1.0));
+        let settings = reply.get_document("config").unwrap();
+        assert_eq!(settings.get_str("upstream.user"), Ok("proxy"));
+        assert_eq!(settings.get_array("admin.users").unwrap(), &vec![Bson::String("admin.ops".to_string())]);
+        assert!(!reply.to_string().contains("hunter2"));
+    }
+}
@@ -0,0 +1,235 @@
+use crate::auth::scram::{Mechanism, ScramServer};
+use crate::auth::{authentication_failed, Principal};
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use bson::{doc, spec::BinarySubtype, Binary, Bson, Document};
+
+// rengo only ever runs one conversation per connection at a time
+const CONVERSATION_ID: i32 = 1;
+
+fn payload(doc: &Document) -> Vec<u8> {
+    match doc.get("payload") {
+        Some(Bson::Binary(binary)) => binary.bytes.clone(),
+        Some(Bson::String(payload)) => payload.clone().into_bytes(),
+        _ => vec![],
+    }
+}
+
+fn reply(done: bool, payload: Vec<u8>) -> Document {
+    doc! {
+        "conversationId": CONVERSATION_ID,
+        "done": done,
+        "payload": Bson::Binary(Binary { subtype: BinarySubtype::Generic, bytes: payload }),
+        "ok": Bson::Double(1.0),
+    }
+}
+
+pub struct SaslStart {}
+
+impl Handler for SaslStart {
+    fn new() -> Self {
+        SaslStart {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        msg: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let doc = &msg[0];
+        let mechanism = doc.get_str("mechanism").unwrap_or("");
+        let mechanism = match Mechanism::parse(mechanism) {
+            Some(mechanism) => mechanism,
+            None => {
+                return Ok(doc! {
+                    "ok": Bson::Double(0.0),
+                    "errmsg": Bson::String(format!("Received authentication for mechanism {} which is not enabled", mechanism)),
+                    "code": Bson::Int32(334),
+                    "codeName": "MechanismUnavailable",
+                })
+            }
+        };
+        let db = doc.get_str("$db").unwrap_or("admin").to_string();
+        let mut conversation = match ScramServer::start(mechanism, &payload(doc)) {
+            Ok(conversation) => conversation,
+            Err(e) => return Ok(authentication_failed(&e.message)),
+        };
+        let credential = request
+            .get_users()
+            .get(&db, &conversation.username)
+            .and_then(|user| user.credential(mechanism))
+            .cloned();
+        let server_first = conversation.server_first(credential);
+        let mut session = request.get_session().lock().unwrap();
+        session.principal = None;
+        session.conversation = Some(conversation);
+        session.conversation_db = db;
+        session.skip_empty_exchange = doc
+            .get_document("options")
+            .and_then(|options| options.get_bool("skipEmptyExchange"))
+            .unwrap_or(false);
+        Ok(reply(false, server_first))
+    }
+}
+
+pub struct SaslContinue {}
+
+impl Handler for SaslContinue {
+    fn new() -> Self {
+        SaslContinue {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        msg: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let mut session = request.get_session().lock().unwrap();
+        let mut conversation = match session.conversation.take() {
+            Some(conversation) => conversation,
+            None => return Ok(authentication_failed("No SASL session state found")),
+        };
+        if conversation.done {
+            // the empty exchange of clients that do not send skipEmptyExchange
+            session.principal = Some(Principal {
+                user: conversation.username.clone(),
+                db: session.conversation_db.clone(),
+            });
+            return Ok(reply(true, vec![]));
+        }
+        let server_final = match conversation.server_final(&payload(&msg[0])) {
+            Ok(server_final) => server_final,
+            Err(e) => return Ok(authentication_failed(&e.message)),
+        };
+        let skip_empty_exchange = session.skip_empty_exchange;
+        if skip_empty_exchange {
+            session.principal = Some(Principal {
+                user: conversation.username.clone(),
+                db: session.conversation_db.clone(),
+            });
+        } else {
+            session.conversation = Some(conversation);
+        }
+        Ok(reply(skip_empty_exchange, server_final))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::auth::scram::ScramClient;
+    use crate::auth::{Session, UserStore};
+    use crate::cache::Cache;
+    use crate::config::Config;
+    use crate::handler::testing::with_request;
+
+    fn users() -> UserStore {
+        let path = std::env::temp_dir().join(format!("rengo-sasl-users-{}.json", std::process::id()));
+        std::fs::write(&path, r#"[{"user": "app", "db": "shop", "password": "secret"}]"#).unwrap();
+        let users = UserStore::load(path.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(path);
+        users
+    }
+
+    fn run<H: Handler>(users: &UserStore, session: &Mutex<Session>, doc: Document) -> Document {
+        let storage = Arc::new(Cache::new());
+        with_request(&Config::default(), users, session, &storage, &doc, |request| {
+            H::new().handle(request, &vec![doc.clone()]).unwrap()
+        })
+    }
+
+    fn binary(bytes: Vec<u8>) -> Bson {
+        Bson::Binary(Binary { subtype: BinarySubtype::Generic, bytes })
+    }
+
+    // runs a conversation for `shop.app`, with or without skipEmptyExchange
+    fn authenticate(password: &str, skip_empty_exchange: bool) -> (Session, Vec<Document>) {
+        let users = users();
+        let session = Mutex::new(Session::new());
+        let mut client = ScramClient::new(Mechanism::ScramSha256, "app", password);
+        let mut replies = vec![];
+        let start = run::<SaslStart>(
+            &users,
+            &session,
+            doc! {
+                "saslStart": 1,
+                "mechanism": "SCRAM-SHA-256",
+                "payload": binary(client.client_first()),
+                "options": { "skipEmptyExchange": skip_empty_exchange },
+                "$db": "shop",
+            },
+        );
+        replies.push(start.clone());
+        let client_final = client.client_final(&payload(&start)).unwrap();
+        let next = run::<SaslContinue>(
+            &users,
+            &session,
+            doc! { "saslContinue": 1, "conversationId": 1, "payload": binary(client_final), "$db": "shop" },
+        );
+        replies.push(next.clone());
+        if next.get_f64("ok") == Ok(1.0) && !next.get_bool("done").unwrap() {
+            client.verify_server_final(&payload(&next)).unwrap();
+            replies.push(run::<SaslContinue>(
+                &users,
+                &session,
+                doc! { "saslContinue": 1, "conversationId": 1, "payload": binary(vec![]), "$db": "shop" },
+            ));
+        }
+        (session.into_inner().unwrap(), replies)
+    }
+
+    #[test]
+    fn authenticates_with_the_empty_exchange() {
+        let (session, replies) = authenticate("secret", false);
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[2].get_bool("done"), Ok(true));
+        assert_eq!(session.partition(), "shop.app");
+    }
+
+    #[test]
+    fn authenticates_skipping_the_empty_exchange() {
+        let (session, replies) = authenticate("secret", true);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[1].get_bool("done"), Ok(true));
+        assert!(session.is_authenticated());
+    }
+
+    #[test]
+    fn wrong_password_is_authentication_failed() {
+        let (session, replies) = authenticate("guess", false);
+        assert_eq!(replies[1].get_i32("code"), Ok(18));
+        assert!(!session.is_authenticated());
+    }
+
+    #[test]
+    fn unknown_mechanism_is_unavailable() {
+        let reply = run::<SaslStart>(
+            &users(),
+            &Mutex::new(Session::new()),
+            doc! { "saslStart": 1, "mechanism": "PLAIN", "payload": binary(vec![]), "$db": "shop" },
+        );
+        assert_eq!(reply.get_str("codeName"), Ok("MechanismUnavailable"));
+    }
+
+    #[test]
+    fn continue_without_start_fails() {
+        let reply = run::<SaslContinue>(
+            &users(),
+            &Mutex::new(Session::new()),
+            doc! { "saslContinue": 1, "conversationId": 1, "payload": binary(vec![]), "$db": "shop" },
+        );
+        assert_eq!(reply.get_i32("code"), Ok(18));
+    }
+
+    #[test]
+    fn multibyte_attribute_keys_fail_instead_of_panicking() {
+        let reply = run::<SaslStart>(
+            &users(),
+            &Mutex::new(Session::new()),
+            doc! { "saslStart": 1, "mechanism": "SCRAM-SHA-256", "payload": "n,,€=x", "$db": "shop" },
+        );
+        assert_eq!(reply.get_i32("code"), Ok(18));
+    }
+}
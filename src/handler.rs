@@ -1,18 +1,40 @@
 use bson::{doc, Bson, Document};
 use byteorder::{ByteOrder, LittleEndian};
-use std::collections::HashMap;
+use std::cell::Cell;
 use std::io::{Cursor, Read, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, Span};
+use crate::auth::{self, passthrough, Session, UserStore};
+use crate::cache::local::{self, LocalCollection};
+use crate::cache::policy::CachePolicy;
+use crate::cache::query::Query;
+use crate::cache::flight::Join;
+use crate::cache::reads::CacheableRead;
+use crate::cache::refresh::Refresh;
+use crate::cache::{self, documents, Cache, Invalidation, Lookup};
 use crate::commands::is_master::IsMaster;
-use crate::commands::{hash, Handler};
+use crate::commands::rengo::{is_rengo_command, RengoCommand};
+use crate::commands::sasl::{SaslContinue, SaslStart};
+use crate::commands::Handler;
+use crate::config::Config;
+use crate::health;
+use crate::metrics::metrics;
+use crate::slowlog::{self, Record};
+use crate::telemetry;
 use crate::Wire::{OpCode, HEADER_SIZE, OP_MSG};
-pub type Storage = std::sync::Arc<Mutex<HashMap<String, InnerData>>>;
+pub type Storage = std::sync::Arc<Cache>;
 pub struct Request<'a, 'b> {
     pub client: Arc<Mutex<rustls::Stream<'b, rustls::ClientConnection, TcpStream>>>,
     pub peer_addr: std::net::SocketAddr,
     pub op_code: &'a OpCode,
     pub storage: &'a Storage,
+    pub config: &'a Config,
+    pub users: &'a UserStore,
+    pub session: &'a Mutex<Session>,
+    // how the cache answered, for the slow query log
+    pub cache: Cell<Option<&'static str>>,
 }
 
 
@@ -20,6 +42,8 @@ pub struct GetMore {
     can_read: bool,
     position: usize,
     documents: Vec<Document>,
+    // bson bytes of the batches
+    size: usize,
 }
 
 impl GetMore {
@@ -28,18 +52,32 @@ impl GetMore {
             can_read: false,
             position: 0,
             documents: vec![],
+            size: 0,
         }
     }
     pub fn add_document(&mut self, doc: Document) {
         // check the cursor id if it is 0 then we can read
         let cursor_id = doc.get_document("cursor").unwrap().get_i64("id").unwrap();
         if !self.can_read {
+            self.size += cache::document_size(&doc);
             self.documents.push(doc);
         }
         if cursor_id == 0 {
             self.can_read = true;
         }
     }
+    // the batches of a cursor that was read to the end, for snapshots
+    pub fn batches(&self) -> Option<&[Document]> {
+        self.can_read.then_some(self.documents.as_slice())
+    }
+    pub fn from_batches(documents: Vec<Document>) -> Self {
+        GetMore {
+            can_read: true,
+            position: 0,
+            size: documents.iter().map(cache::document_size).sum(),
+            documents,
+        }
+    }
     pub fn get_document(&mut self) -> Option<Document> {
         if self.can_read {
             if self.position < self.documents.len() {
@@ -57,11 +95,20 @@ impl GetMore {
 
 fn get_document_server( request: &Request , docs: &Vec<Document>) -> Document {
     let response_to_server = request.client.clone();
-    let res = Response::new(0, request.op_code, docs.to_owned());
+    let mut upstream = telemetry::upstream(&docs[0]);
+    let mut forwarded = docs.to_owned();
+    if let Some(upstream) = &upstream {
+        upstream.propagate(&mut forwarded[0]);
+    }
+    let res = Response::new(0, request.op_code, forwarded);
     let res: Vec<u8> = request.op_code.reply(res).unwrap();
     let mut lock = response_to_server.lock().unwrap();
+    let started = Instant::now();
     lock.write_all(&res).unwrap();
     lock.flush().unwrap();
+    if let Some(upstream) = &mut upstream {
+        upstream.sent();
+    }
     // read the response from the server
     let mut size_buffer: Vec<u8> = vec![0; 4];
     lock.read_exact(&mut size_buffer).unwrap();
@@ -73,21 +120,64 @@ fn get_document_server( request: &Request , docs: &Vec<Document>) -> Document {
     let mut cursor = Cursor::new(buffer);
     cursor.set_position((HEADER_SIZE + 5 as u32).into());
     let document = Document::from_reader(cursor).unwrap();
+    drop(lock);
+    let elapsed = started.elapsed();
+    if let Some(upstream) = upstream {
+        upstream.replied();
+    }
+    metrics().upstream_latency.observe(elapsed);
+    slow_log(request, &docs[0], elapsed);
     document
 }
 
+// slow round trips, and every write in audit mode, go to the slow query log
+fn slow_log(request: &Request, doc: &Document, duration: Duration) {
+    let command = doc.keys().next().map_or("", |command| command.as_str());
+    let event = match slowlog::event(command, duration) {
+        Some(event) => event,
+        None => return,
+    };
+    // the passthrough auth relay holds the session across its round trip
+    let user = match request.get_session().try_lock() {
+        Ok(session) => session.partition(),
+        Err(_) => String::new(),
+    };
+    slowlog::record(&Record {
+        event,
+        command,
+        doc,
+        duration,
+        cache: request.cache.get(),
+        client: request.peer_addr(),
+        user: &user,
+    });
+}
+
 pub enum InnerData {
     // the data could be document or queue of documents
     Document(Document),
     Documents(GetMore),
 }
 
+impl InnerData {
+    // approximate bytes held in memory
+    pub fn size(&self) -> usize {
+        match self {
+            InnerData::Document(doc) => cache::document_size(doc),
+            InnerData::Documents(get_more) => get_more.size,
+        }
+    }
+}
+
 impl<'a, 'b> Request<'a, 'b> {
     pub fn new(
         client: Arc<Mutex<rustls::Stream<'b, rustls::ClientConnection, TcpStream>>>,
         peer_addr: std::net::SocketAddr,
         op_code: &'a OpCode,
         storage: &'a Storage,
+        config: &'a Config,
+        users: &'a UserStore,
+        session: &'a Mutex<Session>,
     ) -> Request<'a, 'b> {
         return Request {
             client,
@@ -95,6 +185,10 @@ impl<'a, 'b> Request<'a, 'b> {
             op_code: op_code,
             // redis_client: redis_client ,
             storage: storage,
+            config,
+            users,
+            session,
+            cache: Cell::new(None),
         };
     }
     pub fn peer_addr(&self) -> std::net::SocketAddr {
@@ -106,6 +200,15 @@ impl<'a, 'b> Request<'a, 'b> {
     pub fn get_storage(&self) -> &'a Storage {
         return self.storage;
     }
+    pub fn get_config(&self) -> &'a Config {
+        self.config
+    }
+    pub fn get_users(&self) -> &'a UserStore {
+        self.users
+    }
+    pub fn get_session(&self) -> &'a Mutex<Session> {
+        self.session
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -144,25 +247,13 @@ impl<'a> Response<'a> {
         &self.docs[0]
     }
 }
-pub fn handle(
-    id: u32,
-    peer_addr: SocketAddr,
-    op_code: &OpCode,
-    mongo_client: Arc<Mutex<rustls::Stream<rustls::ClientConnection, TcpStream>>>,
-    storage: &Storage,
-) -> Result<Vec<u8>, CommandExecutionError> {
-    // let opcode = op_code.clone();
-    let request = Request {
-        client: mongo_client,
-        op_code: op_code,
-        storage,
-        peer_addr: peer_addr,
-    };
-    match route(&request) {
+pub fn handle(id: u32, request: &Request) -> Result<Vec<u8>, CommandExecutionError> {
+    let op_code = request.get_op_code();
+    match route(request) {
         Ok(doc) => {
             let response = Response {
                 id,
-                op_code: &op_code,
+                op_code,
                 docs: vec![doc],
             };
             Ok(op_code.reply(response).unwrap())
@@ -184,9 +275,10 @@ fn run_op_query(
 ) -> Result<Document, CommandExecutionError> {
     let empty = "".to_string();
     let command = docs[0].keys().next().unwrap_or(&empty);
-    if command == "" || command == "isMaster" || command == "ismaster" {
-        let is_master = IsMaster::new().handle(request, docs);
-        return is_master;
+    if command == "" || command == "isMaster" || command == "ismaster" || command == "hello" {
+        let mut is_master = IsMaster::new().handle(request, docs)?;
+        advertise_mechanisms(request, &docs[0], &mut is_master);
+        return Ok(is_master);
     } else {
         Ok(doc! {
             "ok": Bson::Double(0.0),
@@ -196,57 +288,172 @@ fn run_op_query(
         })
     }
 }
+fn is_handshake(command: &str) -> bool {
+    command == "hello" || command == "isMaster" || command == "ismaster"
+}
+fn is_auth_command(command: &str) -> bool {
+    command == "saslStart" || command == "saslContinue" || command == "authenticate" || command == "logout"
+}
+// answers `saslSupportedMechs: "db.user"` from the local user store
+fn advertise_mechanisms(request: &Request, doc: &Document, reply: &mut Document) {
+    let users = request.get_users();
+    if !users.is_enabled() {
+        return;
+    }
+    if let Ok(user) = doc.get_str("saslSupportedMechs") {
+        if let Some(mechanisms) = users.mechanisms(user) {
+            reply.insert("saslSupportedMechs", mechanisms);
+        }
+    }
+}
+fn handshake(request: &Request<'_, '_>, docs: &Vec<Document>) -> Result<Document, CommandExecutionError> {
+    let mut reply = if request.get_config().is_router() {
+        // the upstream would advertise its replica set, answer as a router instead
+        IsMaster::new().handle(request, docs)?
+    } else if request.get_users().is_enabled() || request.get_config().is_passthrough() {
+        // terminating: the upstream would run speculative auth against its own
+        // users, passthrough: drivers fall back to saslStart which we can observe
+        let mut docs = docs.to_owned();
+        docs[0].remove("speculativeAuthenticate");
+        docs[0].remove("saslSupportedMechs");
+        get_document_server(request, &docs)
+    } else {
+        get_document_server(request, docs)
+    };
+    advertise_mechanisms(request, &docs[0], &mut reply);
+    Ok(reply)
+}
 fn run(request: &Request<'_, '_>, docs: &Vec<Document>) -> Result<Document, CommandExecutionError> {
     let command = docs[0].keys().next().unwrap();
+    metrics().commands.with(&[command]).inc();
+    let span = Span::current();
+    span.record("command", command.as_str());
+    span.record("db", docs[0].get_str("$db").unwrap_or("admin"));
+    if command == "logout" && !request.get_config().is_passthrough() {
+        // the upstream connection is logged in as rengo's service user, so
+        // only the client's own login is dropped
+        request.get_session().lock().unwrap().principal = None;
+        return Ok(doc! { "ok": 1.0 });
+    }
+    if request.get_users().is_enabled() {
+        if command == "saslStart" {
+            return SaslStart::new().handle(request, docs);
+        }
+        if command == "saslContinue" {
+            return SaslContinue::new().handle(request, docs);
+        }
+        let authenticated = request.get_session().lock().unwrap().is_authenticated();
+        if !authenticated && auth::requires_auth(command) {
+            return Ok(auth::unauthorized(command));
+        }
+    }
+    if is_handshake(command) {
+        return handshake(request, docs);
+    }
+    if command == "ping" {
+        return Ok(health::ping(request.get_config()));
+    }
+    if is_rengo_command(command, &docs[0]) {
+        return RengoCommand::new().handle(request, docs);
+    }
+    telemetry::command(command, &docs[0], request.peer_addr());
+    if request.get_config().is_passthrough() && is_auth_command(command) {
+        // the upstream connection is pinned to this client, so relaying the
+        // conversation logs the upstream in as the client's own user
+        let mut session = request.get_session().lock().unwrap();
+        let connection = request.peer_addr().to_string();
+        passthrough::observe_request(&mut session, command, &docs[0], &connection);
+        let document = get_document_server(request, docs);
+        passthrough::observe_reply(&mut session, command, &document);
+        return Ok(document);
+    }
+    let partition = request.get_session().lock().unwrap().partition();
+    if !request.get_config().cache_enabled() {
+        return Ok(get_document_server(request, docs));
+    }
+    telemetry::cache_lookup();
+    let db = docs[0].get_str("$db").unwrap_or("admin").to_string();
     if command == "find" {
-        let doc: &Document = &docs[0];
-        let filter = if doc.contains_key("filter") {
-            doc.get_document("filter").unwrap().clone()
-        } else {
-            doc! {}
-        };
-        let collection_name = doc.get_str("find").unwrap();
-        let storage = request.get_storage();
-        let hash = hash(format!("{}{}", collection_name, filter));
-        let mut st = storage.lock().unwrap();
-        let data = st.get(&hash);
-        if data.is_none() {
-            let document = get_document_server(request, docs);
-            st.insert(hash, InnerData::Document(document.clone()));
-            return Ok(document);
-        } else {
-            if let InnerData::Document(data) = data.unwrap() {
-                return Ok(data.clone());
-            } else {
-                panic!("data is not a document");
+        let namespace = format!("{}.{}", db, docs[0].get_str("find").unwrap_or(""));
+        let policy = request.get_storage().policy(request.get_config(), &namespace);
+        if policy.enabled && policy.local {
+            if let Some(reply) = local_find(request, &docs[0], &namespace, &partition, &policy) {
+                return Ok(reply);
+            }
+        }
+    }
+    if let Some((namespace, id)) = documents::point_lookup(command, &docs[0]) {
+        if request.get_storage().policy(request.get_config(), &namespace).enabled {
+            if let Some(document) = request.get_storage().document(&partition, &namespace, &id) {
+                cache_outcome(request, &namespace, CacheOutcome::Hit);
+                return Ok(documents::reply(&namespace, document));
             }
         }
+    }
+    if let Some(read) = cache::reads::cacheable(command, &docs[0], &partition) {
+        let policy = request.get_storage().policy(request.get_config(), &read.namespace);
+        if !policy.enabled {
+            cache_outcome(request, &read.namespace, CacheOutcome::Bypass);
+            return Ok(get_document_server(request, docs));
+        }
+        Ok(cached_read(request, docs, &partition, read, &policy))
     } else if command == "getMore" {
         let doc = &docs[0];
         let cursor_id = doc.get_i64("getMore").unwrap();
-        let storage = request.get_storage().clone();
-        let hashh = hash(format!("{}", cursor_id));
-        let mut st = storage.lock().unwrap();
-        let data = st.get_mut(&hashh);
-        if data.is_some() {
-            if let InnerData::Documents(ref mut get_more) = data.unwrap() {
-                if let Some(doc) = get_more.get_document() {
-                    return Ok(doc);
-                } else {
-                    let document = get_document_server(request, docs);
-                    get_more.add_document(document.clone());
-                    return Ok(document);
-                }
-            } else {
-                panic!("data is not a document");
+        let namespace = format!("{}.{}", db, doc.get_str("collection").unwrap_or(""));
+        let policy = request.get_storage().policy(request.get_config(), &namespace);
+        if !policy.enabled {
+            cache_outcome(request, &namespace, CacheOutcome::Bypass);
+            return Ok(get_document_server(request, docs));
+        }
+        let storage = request.get_storage();
+        let hashh = cache::cursor_key(&partition, cursor_id);
+        let cached = storage.update(&hashh, |data| match data {
+            InnerData::Documents(get_more) => get_more.get_document(),
+            _ => panic!("data is not a document"),
+        });
+        if let Some(Some(doc)) = cached {
+            cache_outcome(request, &namespace, CacheOutcome::Hit);
+            return Ok(doc);
+        }
+        cache_outcome(request, &namespace, CacheOutcome::Miss);
+        let generation = storage.namespace_generation(&namespace);
+        let document = get_document_server(request, docs);
+        let added = storage.update(&hashh, |data| {
+            if let InnerData::Documents(get_more) = data {
+                get_more.add_document(document.clone());
             }
-        } else {
+        });
+        if added.is_none() && policy.should_store(&document) {
             let mut get_more = GetMore::new();
-            let document = get_document_server(request, docs);
             get_more.add_document(document.clone());
-            st.insert(hashh, InnerData::Documents(get_more));
-            return Ok(document);
+            storage.insert(hashh, &namespace, InnerData::Documents(get_more), &policy, generation);
+        }
+        Ok(document)
+    } else if let Some(invalidation) = cache::invalidation(command, &docs[0]) {
+        // evict after the write so a concurrent find can't re-cache the old data
+        let document = get_document_server(request, docs);
+        let storage = request.get_storage();
+        match invalidation {
+            Invalidation::Write(namespace) => {
+                let policy = request.get_storage().policy(request.get_config(), &namespace);
+                if policy.invalidate_on_write {
+                    let changes = documents::changes(command, &docs[0], &document);
+                    storage.apply_write(&partition, &namespace, changes, &policy);
+                }
+            }
+            Invalidation::Drop(namespace) => {
+                storage.drop_namespace(&namespace);
+            }
+            Invalidation::DropDatabase(db) => {
+                storage.invalidate_database(&db);
+            }
+            Invalidation::Rename(from, to) => {
+                storage.drop_namespace(&from);
+                storage.drop_namespace(&to);
+            }
         }
+        Ok(document)
     } else {
         let document = get_document_server(request, docs);
         let doc = document.clone();
@@ -254,6 +461,193 @@ fn run(request: &Request<'_, '_>, docs: &Vec<Document>) -> Result<Document, Comm
     }
 }
 
+// how a cacheable read was answered
+#[derive(Debug, Clone, Copy)]
+enum CacheOutcome {
+    Hit,
+    // past its ttl, within stale_while_revalidate
+    Stale,
+    // the reply another client was fetching for the same read
+    Shared,
+    // answered by the local query engine
+    Local,
+    Miss,
+    // caching is off for the namespace
+    Bypass,
+}
+
+impl CacheOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            CacheOutcome::Hit => "hit",
+            CacheOutcome::Stale => "stale",
+            CacheOutcome::Shared => "shared",
+            CacheOutcome::Local => "local",
+            CacheOutcome::Miss => "miss",
+            CacheOutcome::Bypass => "bypass",
+        }
+    }
+}
+
+// recorded on the request's span and counted per namespace, anything that
+// spared the upstream a round trip is a hit
+fn cache_outcome(request: &Request, namespace: &str, outcome: CacheOutcome) {
+    Span::current().record("cache", outcome.as_str());
+    request.cache.set(Some(outcome.as_str()));
+    telemetry::cache_outcome(outcome.as_str());
+    match outcome {
+        CacheOutcome::Miss => metrics().cache_misses.with(&[namespace]).inc(),
+        CacheOutcome::Bypass => {}
+        _ => metrics().cache_hits.with(&[namespace]).inc(),
+    }
+}
+
+// a cacheable read, concurrent misses of the same key share one upstream
+// request and a stale entry is served while a single refresh runs
+fn cached_read(
+    request: &Request,
+    docs: &Vec<Document>,
+    partition: &str,
+    read: CacheableRead,
+    policy: &CachePolicy,
+) -> Document {
+    let storage = request.get_storage();
+    let namespace = read.namespace.clone();
+    let stale = match storage.lookup(&read.key) {
+        Lookup::Fresh(reply) => {
+            cache_outcome(request, &namespace, CacheOutcome::Hit);
+            return reply;
+        }
+        Lookup::Stale(reply) => Some(reply),
+        Lookup::Miss => None,
+    };
+    let generation = storage.generation(&read);
+    match (storage.join(&read.key), stale) {
+        (Join::Follower(_), Some(reply)) => {
+            cache_outcome(request, &namespace, CacheOutcome::Stale);
+            reply
+        }
+        (Join::Follower(flight), None) => match flight.wait() {
+            // an open cursor lives on the leader's connection, this client
+            // could not continue it
+            Some(reply) if cache::cursor_id(&reply) == 0 => {
+                cache_outcome(request, &namespace, CacheOutcome::Shared);
+                reply
+            }
+            _ => {
+                cache_outcome(request, &namespace, CacheOutcome::Miss);
+                get_document_server(request, docs)
+            }
+        },
+        (Join::Leader(guard), Some(reply)) => {
+            let refresh = Refresh {
+                guard,
+                partition: partition.to_string(),
+                command: docs[0].clone(),
+                read,
+                generation,
+            };
+            let refused = match storage.refresher() {
+                Some(refresher) => refresher.send(refresh).map_err(|e| e.0),
+                None => Err(refresh),
+            };
+            match refused {
+                Ok(()) => {
+                    cache_outcome(request, &namespace, CacheOutcome::Stale);
+                    reply
+                }
+                // no refresh thread, this client refreshes it for the others
+                Err(refresh) => {
+                    cache_outcome(request, &namespace, CacheOutcome::Miss);
+                    fetch(request, docs, refresh, policy)
+                }
+            }
+        }
+        (Join::Leader(guard), None) => {
+            let refresh = Refresh {
+                guard,
+                partition: partition.to_string(),
+                command: docs[0].clone(),
+                read,
+                generation,
+            };
+            cache_outcome(request, &namespace, CacheOutcome::Miss);
+            fetch(request, docs, refresh, policy)
+        }
+    }
+}
+
+// runs the read over the client's connection and stores the reply unless the
+// namespace was invalidated meanwhile
+fn fetch(request: &Request, docs: &Vec<Document>, refresh: Refresh, policy: &CachePolicy) -> Document {
+    let document = get_document_server(request, docs);
+    if policy.should_store(&document) {
+        let storage = request.get_storage();
+        storage.store_read(&refresh.partition, &docs[0], refresh.read, &document, policy, refresh.generation);
+    }
+    refresh.guard.complete(document.clone());
+    document
+}
+
+// reads a whole collection through the client's upstream connection
+fn load_collection(request: &Request, doc: &Document, policy: &CachePolicy) -> LocalCollection {
+    let db = doc.get_str("$db").unwrap_or("admin");
+    let collection = doc.get_str("find").unwrap_or("");
+    let max = policy.max_entries.unwrap_or(local::MAX_DOCUMENTS);
+    let run = |command: Document| Ok(get_document_server(request, &vec![command]));
+    match local::load(run, db, collection, Some(max)) {
+        Ok(documents) => LocalCollection::loaded(documents, policy),
+        Err(e) => {
+            debug!("Local collection {}.{} not loaded: {}", db, collection, e);
+            LocalCollection::unavailable()
+        }
+    }
+}
+
+// answers a find from the collection held in memory, loading it on first
+// use, None sends the query upstream
+fn local_find(
+    request: &Request,
+    doc: &Document,
+    namespace: &str,
+    partition: &str,
+    policy: &CachePolicy,
+) -> Option<Document> {
+    let query = match Query::parse(doc) {
+        Ok(query) => query,
+        Err(unsupported) => {
+            debug!(namespace, "Local query not supported: {}", unsupported.0);
+            return None;
+        }
+    };
+    let storage = request.get_storage();
+    let mut loaded = false;
+    if storage.collection(partition, namespace).is_none() {
+        // one client loads it, the others wait for it instead of loading too
+        let generation = storage.namespace_generation(namespace);
+        match storage.join(&format!("local {} {}", partition, namespace)) {
+            Join::Leader(guard) => {
+                loaded = true;
+                let collection = load_collection(request, doc, policy);
+                storage.insert_collection(partition, namespace, collection, generation);
+                guard.complete(Document::new());
+            }
+            Join::Follower(flight) => {
+                flight.wait();
+            }
+        }
+    }
+    let reply = match storage.collection(partition, namespace)? {
+        LocalCollection::Loaded { documents, .. } => local::reply(namespace, query.run(&documents))?,
+        LocalCollection::Unavailable { .. } => return None,
+    };
+    match loaded {
+        true => cache_outcome(request, namespace, CacheOutcome::Miss),
+        false => cache_outcome(request, namespace, CacheOutcome::Local),
+    }
+    Some(reply)
+}
+
 fn handle_op_msg(
     mut request: &Request<'_, '_>,
     msg: OP_MSG,
@@ -267,12 +661,13 @@ fn handle_op_msg(
     if section.kind == 0 {
         let mut documents = section.documents.clone();
         if msg.sections.len() > 1 {
+            // document sequences (documents, updates, deletes) become arrays
+            // of the command document so the upstream gets the whole batch
             for section in msg.sections[1..].iter() {
                 if let Some(identifier) = section.identifier.clone() {
-                    if identifier == "documents\0" {
-                        let new_doc = section.documents[0].clone();
-                        documents[0].insert("documents", Bson::Array(vec![new_doc.into()]));
-                    }
+                    let identifier = identifier.trim_end_matches('\0').to_string();
+                    let batch = section.documents.iter().cloned().map(Bson::Document).collect();
+                    documents[0].insert(identifier, Bson::Array(batch));
                 }
             }
         }
@@ -311,4 +706,222 @@ fn handle_op_msg(
         )
         .to_string(),
     ))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub mod testing {
+    use std::io;
+    use std::net::{Shutdown, TcpListener};
+    use std::thread;
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::{PrivateKeyDer, ServerName};
+
+    use super::*;
+    use crate::tls;
+    use crate::upstream::testing::FakeUpstream;
+    use crate::upstream::ConnectionString;
+    use crate::Wire::Op_msg::OP_MSG as OpMsg;
+    use crate::Wire::MsgHeader;
+
+    fn op_code(doc: &Document) -> OpCode {
+        let header = MsgHeader {
+            msg_length: 0,
+            request_id: 1,
+            response_to: 0,
+            op_code: OP_MSG,
+        };
+        OpCode::OpMsg(OpMsg::new_with_body_kind(header, 0, None, doc))
+    }
+
+    // runs `f` with a request for `doc`, its upstream is a local socket that
+    // never answers, for commands rengo handles itself
+    pub fn with_request<R>(
+        config: &Config,
+        users: &UserStore,
+        session: &Mutex<Session>,
+        storage: &Storage,
+        doc: &Document,
+        f: impl FnOnce(&Request) -> R,
+    ) -> R {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let peer_addr = socket.local_addr().unwrap();
+        let tls_config = tls::client_config(&ConnectionString::default()).unwrap();
+        let mut client = rustls::ClientConnection::new(tls_config, ServerName::try_from("localhost").unwrap()).unwrap();
+        let upstream = Arc::new(Mutex::new(rustls::Stream::new(&mut client, &mut socket)));
+        let op_code = op_code(doc);
+        let request = Request::new(upstream, peer_addr, &op_code, storage, config, users, session);
+        f(&request)
+    }
+
+    // a whole wire message, its length included
+    fn message<S: Read>(stream: &mut S) -> io::Result<Vec<u8>> {
+        let mut length = [0; 4];
+        stream.read_exact(&mut length)?;
+        let mut message = vec![0; LittleEndian::read_i32(&length).max(4) as usize];
+        message[..4].copy_from_slice(&length);
+        stream.read_exact(&mut message[4..])?;
+        Ok(message)
+    }
+
+    // a tls listener on localhost and a client config that trusts it
+    fn tls_pair() -> (Arc<rustls::ServerConfig>, Arc<rustls::ClientConfig>) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        let server = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone()], PrivateKeyDer::Pkcs8(key.serialize_der().into()))
+            .unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let client = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (Arc::new(server), Arc::new(client))
+    }
+
+    // like with_request with an upstream that answers every command with
+    // `answer`, also returns the commands it was sent
+    pub fn with_upstream<R, F>(
+        config: &Config,
+        storage: &Storage,
+        doc: &Document,
+        answer: F,
+        f: impl FnOnce(&Request) -> R,
+    ) -> (R, Vec<Document>)
+    where
+        F: FnMut(&Document) -> Document + Send + 'static,
+    {
+        let (server, client_config) = tls_pair();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let upstream = thread::spawn(move || {
+            let mut fake = FakeUpstream::new(answer);
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = rustls::StreamOwned::new(rustls::ServerConnection::new(server).unwrap(), socket);
+            // until the client hangs up
+            while let Ok(command) = message(&mut stream) {
+                fake.write_all(&command).unwrap();
+                fake.flush().unwrap();
+                let reply = message(&mut fake).unwrap();
+                stream.write_all(&reply).unwrap();
+                stream.flush().unwrap();
+            }
+            fake.commands
+        });
+        let mut socket = TcpStream::connect(addr).unwrap();
+        let peer_addr = socket.local_addr().unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut client = rustls::ClientConnection::new(client_config, server_name).unwrap();
+        let users = UserStore::default();
+        let session = Mutex::new(Session::new());
+        let op_code = op_code(doc);
+        let result = {
+            let upstream = Arc::new(Mutex::new(rustls::Stream::new(&mut client, &mut socket)));
+            let request = Request::new(upstream, peer_addr, &op_code, storage, config, &users, &session);
+            f(&request)
+        };
+        socket.shutdown(Shutdown::Both).unwrap();
+        (result, upstream.join().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::testing::{with_request, with_upstream};
+    use super::*;
+    use crate::auth::Principal;
+    use crate::cache::reads;
+
+    fn find() -> Document {
+        doc! { "find": "orders", "filter": {}, "$db": "shop" }
+    }
+
+    fn cursor(id: i64) -> Document {
+        doc! { "cursor": { "id": id, "ns": "shop.orders", "firstBatch": [{ "_id": id }] }, "ok": 1.0 }
+    }
+
+    #[test]
+    fn caches_a_find_and_answers_it_again() {
+        let storage: Storage = Arc::new(Cache::new());
+        let config = Config::default();
+        let (reply, commands) = with_upstream(&config, &storage, &find(), |_| cursor(0), |request| {
+            route(request).unwrap()
+        });
+        assert_eq!(reply, cursor(0));
+        assert_eq!(commands.len(), 1);
+        // the upstream of this request never answers
+        let users = UserStore::default();
+        let session = Mutex::new(Session::new());
+        let cached = with_request(&config, &users, &session, &storage, &find(), |request| route(request).unwrap());
+        assert_eq!(cached, cursor(0));
+    }
+
+    #[test]
+    fn followers_only_share_replies_without_an_open_cursor() {
+        let storage: Storage = Arc::new(Cache::new());
+        let key = reads::cacheable("find", &find(), "").unwrap().key;
+        for (leader_reply, shared) in [(cursor(0), true), (cursor(7), false)] {
+            let guard = match storage.join(&key) {
+                Join::Leader(guard) => guard,
+                Join::Follower(_) => panic!("a flight is still going"),
+            };
+            let expected = leader_reply.clone();
+            let leader = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                guard.complete(leader_reply);
+            });
+            let answer = |_: &Document| doc! { "cursor": { "id": 0i64, "firstBatch": [] }, "ok": 1.0 };
+            let (reply, commands) = with_upstream(&Config::default(), &storage, &find(), answer, |request| {
+                route(request).unwrap()
+            });
+            leader.join().unwrap();
+            match shared {
+                true => {
+                    assert_eq!(reply, expected);
+                    assert!(commands.is_empty());
+                }
+                // the leader's cursor can't be continued from this connection
+                false => {
+                    assert_eq!(reply, answer(&find()));
+                    assert_eq!(commands.len(), 1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn answers_logout_locally_when_terminating() {
+        let storage: Storage = Arc::new(Cache::new());
+        let config = Config::default();
+        let users = UserStore::default();
+        let session = Mutex::new(Session::new());
+        session.lock().unwrap().principal = Some(Principal {
+            user: "app".to_string(),
+            db: "shop".to_string(),
+        });
+        let logout = doc! { "logout": 1, "$db": "shop" };
+        // the upstream of this request never answers
+        let reply = with_request(&config, &users, &session, &storage, &logout, |request| route(request).unwrap());
+        assert_eq!(reply, doc! { "ok": 1.0 });
+        assert!(!session.lock().unwrap().is_authenticated());
+    }
+
+    #[test]
+    fn answers_ping_without_a_round_trip() {
+        let storage: Storage = Arc::new(Cache::new());
+        let ping = doc! { "ping": 1, "$db": "admin" };
+        let (reply, commands) = with_upstream(&Config::default(), &storage, &ping, |_| doc! { "ok": 1.0 }, |request| {
+            route(request).unwrap()
+        });
+        assert_eq!(reply, doc! { "ok": 1.0 });
+        assert!(commands.is_empty());
+    }
+}
@@ -0,0 +1,275 @@
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use bson::{doc, Bson, Document};
+use rustls::ClientConfig;
+use serde_json::json;
+use tracing::{error, info};
+
+use crate::config::Config;
+use crate::http::{self, HttpRequest, HttpResponse};
+use crate::upstream::{self, UpstreamStream};
+
+// set by the topology monitor, false until its first check succeeds
+static UPSTREAM_UP: AtomicBool = AtomicBool::new(false);
+static WARMED_UP: AtomicBool = AtomicBool::new(false);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+// why the last check failed, for /readyz
+static LAST_ERROR: Mutex<Option<String>> = Mutex::new(None);
+// the primary's maxWireVersion, 0 until a check succeeds
+static WIRE_VERSION: AtomicI32 = AtomicI32::new(0);
+
+pub fn upstream_up() -> bool {
+    UPSTREAM_UP.load(Ordering::SeqCst)
+}
+
+pub fn upstream_wire_version() -> i32 {
+    WIRE_VERSION.load(Ordering::SeqCst)
+}
+
+// the cache is restored and warm, clients can be let in
+pub fn warmed_up() {
+    WARMED_UP.store(true, Ordering::SeqCst);
+}
+
+// shutting down, readiness fails so no new clients are routed here
+pub fn set_draining() {
+    DRAINING.store(true, Ordering::SeqCst);
+}
+
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+// the reasons rengo is not ready, empty when it is
+pub fn not_ready() -> Vec<String> {
+    let mut reasons = vec![];
+    if !upstream_up() {
+        let error = LAST_ERROR.lock().unwrap().clone();
+        reasons.push(match error {
+            Some(error) => format!("upstream primary unreachable: {}", error),
+            None => "upstream primary not checked yet".to_string(),
+        });
+    }
+    if !WARMED_UP.load(Ordering::SeqCst) {
+        reasons.push("cache warm-up in progress".to_string());
+    }
+    if is_draining() {
+        reasons.push("draining".to_string());
+    }
+    reasons
+}
+
+// logged when the state or the error changes, not on every check
+fn mark(up: bool, error: Option<String>) {
+    let was_up = UPSTREAM_UP.swap(up, Ordering::SeqCst);
+    let mut last = LAST_ERROR.lock().unwrap();
+    match &error {
+        None if !was_up => info!("Upstream primary is reachable"),
+        Some(e) if was_up || last.as_ref() != Some(e) => error!("upstream primary unreachable: {}", e),
+        _ => {}
+    }
+    *last = error;
+}
+
+// the primary answers `hello` and still calls itself writable, servers
+// before 4.4 only know `isMaster`
+fn check<S: Read + Write>(stream: &mut S) -> Result<(), String> {
+    let mut reply = upstream::run_command(stream, doc! { "hello": 1, "$db": "admin" }).map_err(|e| e.to_string())?;
+    if reply.get_i32("code") == Ok(59) {
+        reply = upstream::run_command(stream, doc! { "isMaster": 1, "$db": "admin" }).map_err(|e| e.to_string())?;
+    }
+    let reply = upstream::check(reply).map_err(|e| e.to_string())?;
+    if let Ok(version) = reply.get_i32("maxWireVersion") {
+        WIRE_VERSION.store(version, Ordering::SeqCst);
+    }
+    let writable = reply.get_bool("isWritablePrimary").or_else(|_| reply.get_bool("ismaster"));
+    match writable {
+        Ok(true) => Ok(()),
+        _ => Err("no longer the primary".to_string()),
+    }
+}
+
+// checks the primary every `health.interval` over a connection of its own
+pub fn monitor(config: Arc<Config>, tls_config: Arc<ClientConfig>, addr: Arc<String>) {
+    thread::spawn(move || {
+        let mut stream: Option<UpstreamStream> = None;
+        loop {
+            if stream.is_none() {
+                match upstream::connect(&addr, tls_config.clone(), &config.upstream) {
+                    Ok(connected) => stream = Some(connected),
+                    Err(e) => mark(false, Some(e.to_string())),
+                }
+            }
+            if let Some(connection) = stream.as_mut() {
+                // a primary that stops answering counts as down
+                let _ = connection.sock.set_read_timeout(Some(config.health_interval));
+                let _ = connection.sock.set_write_timeout(Some(config.health_interval));
+                match check(connection) {
+                    Ok(()) => mark(true, None),
+                    Err(e) => {
+                        mark(false, Some(e));
+                        stream = None;
+                    }
+                }
+            }
+            thread::sleep(config.health_interval);
+        }
+    });
+}
+
+// a `ping` answered without a round trip, with health.ping_upstream it
+// fails while the monitor can't reach the primary
+pub fn ping(config: &Config) -> Document {
+    if config.health_ping_upstream && !upstream_up() {
+        return doc! {
+            "ok": Bson::Double(0.0),
+            "errmsg": "upstream primary unreachable",
+            "code": Bson::Int32(6),
+            "codeName": "HostUnreachable",
+        };
+    }
+    doc! { "ok": Bson::Double(1.0) }
+}
+
+fn probe(request: &HttpRequest, started: Instant) -> HttpResponse {
+    match (request.method.as_str(), request.path.as_str()) {
+        // the process is up and serving http
+        ("GET", "/healthz") => HttpResponse::new(
+            200,
+            "application/json",
+            format!("{}\n", json!({ "status": "ok", "uptime": started.elapsed().as_secs() })),
+        ),
+        ("GET", "/readyz") => {
+            let reasons = not_ready();
+            let status = if reasons.is_empty() { 200 } else { 503 };
+            let body = json!({ "ready": reasons.is_empty(), "reasons": reasons });
+            HttpResponse::new(status, "application/json", format!("{}\n", body))
+        }
+        _ => HttpResponse::not_found(),
+    }
+}
+
+// serves `/healthz` and `/readyz` on `health.address`
+pub fn start(config: &Config) {
+    let addr = match &config.health_addr {
+        Some(addr) => addr.clone(),
+        None => return,
+    };
+    let started = Instant::now();
+    match http::serve(&addr, move |request: &HttpRequest| probe(request, started)) {
+        Ok(()) => info!("Health probes on http://{}/healthz and /readyz", addr),
+        Err(e) => error!("health listener on {}: {}", addr, e),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::http::testing::free_addr;
+    use crate::upstream::testing::FakeUpstream;
+
+    fn get(path: &str) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            query: Default::default(),
+            headers: Default::default(),
+            body: vec![],
+        }
+    }
+
+    fn json(response: &HttpResponse) -> Value {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[test]
+    fn checks_that_the_primary_is_writable() {
+        let mut primary = FakeUpstream::new(|_| doc! { "isWritablePrimary": true, "maxWireVersion": 17, "ok": 1.0 });
+        assert_eq!(check(&mut primary), Ok(()));
+        assert_eq!(primary.commands, [doc! { "hello": 1, "$db": "admin" }]);
+        assert_eq!(upstream_wire_version(), 17);
+        // 3.6 doesn't know hello
+        let mut legacy = FakeUpstream::new(|command| match command.contains_key("hello") {
+            true => doc! { "ok": 0.0, "errmsg": "no such command: 'hello'", "code": 59, "codeName": "CommandNotFound" },
+            false => doc! { "ismaster": true, "maxWireVersion": 6, "ok": 1.0 },
+        });
+        assert_eq!(check(&mut legacy), Ok(()));
+        assert_eq!(legacy.commands[1], doc! { "isMaster": 1, "$db": "admin" });
+        assert_eq!(upstream_wire_version(), 6);
+        let mut secondary = FakeUpstream::new(|_| doc! { "isWritablePrimary": false, "secondary": true, "ok": 1.0 });
+        assert_eq!(check(&mut secondary), Err("no longer the primary".to_string()));
+        let mut failing = FakeUpstream::new(|_| doc! { "ok": 0.0, "errmsg": "not master", "code": 10107 });
+        assert!(check(&mut failing).unwrap_err().contains("not master"));
+    }
+
+    #[test]
+    fn readiness_follows_the_monitor_warm_up_and_draining() {
+        let unreachable = "upstream primary unreachable: connection refused".to_string();
+        mark(false, Some("connection refused".to_string()));
+        assert!(!upstream_up());
+        assert!(not_ready().contains(&unreachable));
+        let mut config = Config::default();
+        assert_eq!(ping(&config), doc! { "ok": 1.0 });
+        config.health_ping_upstream = true;
+        let failed = ping(&config);
+        assert_eq!(failed.get_i32("code"), Ok(6));
+        assert_eq!(failed.get_str("codeName"), Ok("HostUnreachable"));
+        let response = probe(&get("/readyz"), Instant::now());
+        assert_eq!(response.status, 503);
+        let body = json(&response);
+        assert_eq!(body["ready"], false);
+        assert!(body["reasons"].as_array().unwrap().contains(&Value::from(unreachable.clone())));
+
+        mark(true, None);
+        warmed_up();
+        assert!(upstream_up());
+        assert_eq!(ping(&config), doc! { "ok": 1.0 });
+        let reasons = not_ready();
+        assert!(!reasons.contains(&unreachable));
+        assert!(!reasons.contains(&"cache warm-up in progress".to_string()));
+        // draining is set for good once a shutdown starts
+        set_draining();
+        assert!(is_draining());
+        let response = probe(&get("/readyz"), Instant::now());
+        assert_eq!(response.status, 503);
+        assert_eq!(json(&response)["reasons"], serde_json::json!(["draining"]));
+    }
+
+    #[test]
+    fn liveness_only_needs_the_process() {
+        let started = Instant::now() - Duration::from_secs(3);
+        let response = probe(&get("/healthz"), started);
+        assert_eq!(response.status, 200);
+        let body = json(&response);
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["uptime"], 3);
+        assert_eq!(probe(&get("/metrics"), started).status, 404);
+    }
+
+    #[test]
+    fn serves_the_probes() {
+        let addr = free_addr();
+        let config = Config {
+            health_addr: Some(addr.clone()),
+            ..Config::default()
+        };
+        start(&config);
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream.write_all(b"GET /healthz HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\"status\":\"ok\""));
+    }
+}
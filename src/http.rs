@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// enough for the small endpoints rengo serves, bigger requests are refused
+const MAX_BODY: usize = 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        HttpResponse {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        HttpResponse::new(status, "text/plain; charset=utf-8", body.into())
+    }
+    pub fn not_found() -> Self {
+        HttpResponse::text(404, "not found\n")
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+fn hex(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' if index + 2 < bytes.len() => match (hex(bytes[index + 1]), hex(bytes[index + 2])) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high << 4 | low);
+                    index += 2;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) => (percent_decode(name), percent_decode(value)),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+fn read_request(stream: &TcpStream) -> Result<HttpRequest, HttpResponse> {
+    let bad = |message: &str| HttpResponse::text(400, format!("{}\n", message));
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|_| bad("unreadable request"))?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(bad("malformed request line")),
+    };
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(|_| bad("unreadable headers"))? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    let length: usize = headers
+        .get("content-length")
+        .map_or(Ok(0), |length| length.parse())
+        .map_err(|_| bad("invalid content-length"))?;
+    if length > MAX_BODY {
+        return Err(HttpResponse::text(413, "request body too large\n"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|_| bad("truncated body"))?;
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    Ok(HttpRequest {
+        method,
+        path: percent_decode(path),
+        query: parse_query(query),
+        headers,
+        body,
+    })
+}
+
+fn write_response(mut stream: &TcpStream, response: &HttpResponse) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+// `http://host:port/path` split into the address to connect to and the path
+fn split_url(url: &str) -> io::Result<(String, String)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is not an http:// url", url));
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (host, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    let host = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:80", host),
+    };
+    Ok((host, path.to_string()))
+}
+
+// sends a POST and returns the response status, plain http only
+pub fn post(url: &str, content_type: &str, body: &[u8]) -> io::Result<u16> {
+    let (host, path) = split_url(url)?;
+    let mut stream = TcpStream::connect(&host)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("malformed status line '{}'", line.trim_end())))
+}
+
+// serves one request per connection, each on its own thread
+pub fn serve<F>(addr: &str, handler: F) -> io::Result<()>
+where
+    F: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let handler = handler.clone();
+            thread::spawn(move || {
+                let _ = stream.set_read_timeout(Some(TIMEOUT));
+                let _ = stream.set_write_timeout(Some(TIMEOUT));
+                let response = match read_request(&stream) {
+                    Ok(request) => handler(&request),
+                    Err(response) => response,
+                };
+                let _ = write_response(&stream, &response);
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::net::TcpListener;
+
+    // a local address nothing listens on, for servers that bind their own
+    pub fn free_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::testing::free_addr;
+    use super::*;
+
+    // the raw response to `request`, sent as is
+    fn exchange(addr: &str, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn decodes_paths_and_queries() {
+        assert_eq!(percent_decode("a%2Fb+c%41"), "a/b cA");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        let query = parse_query("namespace=shop.orders&all&&ttl=%35");
+        assert_eq!(query.len(), 3);
+        assert_eq!(query["namespace"], "shop.orders");
+        assert_eq!(query["all"], "");
+        assert_eq!(query["ttl"], "5");
+    }
+
+    #[test]
+    fn splits_urls() {
+        let split = |url| split_url(url).unwrap();
+        assert_eq!(split("http://collector:4318/v1/traces"), ("collector:4318".into(), "/v1/traces".into()));
+        assert_eq!(split("http://collector"), ("collector:80".into(), "/".into()));
+        assert!(split_url("https://collector/v1/traces").is_err());
+        assert!(split_url("http:///v1/traces").is_err());
+    }
+
+    #[test]
+    fn serves_requests() {
+        let addr = free_addr();
+        let (seen, requests) = mpsc::channel();
+        serve(&addr, move |request: &HttpRequest| {
+            seen.send((
+                request.method.clone(),
+                request.path.clone(),
+                request.query.clone(),
+                request.headers["content-type"].clone(),
+                request.body.clone(),
+            ))
+            .unwrap();
+            HttpResponse::text(204, "")
+        })
+        .unwrap();
+        let status = post(&format!("http://{}/a%20path?flush=1", addr), "application/json", b"{}").unwrap();
+        assert_eq!(status, 204);
+        let (method, path, query, content_type, body) = requests.recv().unwrap();
+        assert_eq!(method, "POST");
+        assert_eq!(path, "/a path");
+        assert_eq!(query["flush"], "1");
+        assert_eq!(content_type, "application/json");
+        assert_eq!(body, b"{}");
+    }
+
+    #[test]
+    fn writes_the_status_and_length() {
+        let addr = free_addr();
+        serve(&addr, |request: &HttpRequest| match request.path.as_str() {
+            "/" => HttpResponse::text(200, "ok\n"),
+            _ => HttpResponse::not_found(),
+        })
+        .unwrap();
+        let response = exchange(&addr, b"GET / HTTP/1.1\r\nHost: rengo\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Length: 3\r\n"));
+        assert!(response.ends_with("\r\n\r\nok\n"));
+        let response = exchange(&addr, b"GET /missing HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn refuses_malformed_requests() {
+        let addr = free_addr();
+        serve(&addr, |_: &HttpRequest| HttpResponse::text(200, "ok\n")).unwrap();
+        let response = exchange(&addr, b"GARBAGE\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        let response = exchange(&addr, b"POST / HTTP/1.1\r\nContent-Length: nine\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+        let request = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+        let response = exchange(&addr, request.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn reports_what_a_post_could_not_do() {
+        assert!(post(&format!("http://{}/", free_addr()), "text/plain", b"").is_err());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.write_all(b"nonsense\r\n");
+        });
+        let error = post(&format!("http://{}/", addr), "text/plain", b"").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,21 @@
+pub mod Wire;
+pub mod admin;
+pub mod auth;
+pub mod cache;
+pub mod clients;
+pub mod commands;
+pub mod config;
+pub mod handler;
+pub mod health;
+pub mod http;
+pub mod limits;
+pub mod logging;
+pub mod metrics;
+pub mod reload;
+pub mod shutdown;
+pub mod slowlog;
+pub mod telemetry;
+pub mod tls;
+pub mod upgrade;
+pub mod upstream;
+pub mod utils;
@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+
+// a client's commands per second, as a token bucket holding one second's
+// worth so short bursts go through at once. Commands over the limit are
+// delayed rather than refused, drivers have no error to retry on.
+pub struct RateLimit {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit::new(Instant::now())
+    }
+}
+
+impl RateLimit {
+    pub fn new(now: Instant) -> RateLimit {
+        RateLimit {
+            tokens: f64::INFINITY,
+            last: now,
+        }
+    }
+    // how long the command arriving at `now` waits under `per_second`, which
+    // is read for every command so a reload applies from the next one. 0
+    // disables the limit.
+    pub fn delay(&mut self, per_second: u32, now: Instant) -> Duration {
+        if per_second == 0 {
+            self.tokens = f64::INFINITY;
+            return Duration::ZERO;
+        }
+        let rate = f64::from(per_second);
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - 1.0;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        // the tokens the wait earns are spent by this command
+        let wait = Duration::from_secs_f64(-self.tokens / rate);
+        self.tokens = 0.0;
+        self.last = now + wait;
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_up_to_a_second_then_paces() {
+        let start = Instant::now();
+        let mut limit = RateLimit::new(start);
+        for _ in 0..10 {
+            assert_eq!(limit.delay(10, start), Duration::ZERO);
+        }
+        assert_eq!(limit.delay(10, start), Duration::from_millis(100));
+        assert_eq!(limit.delay(10, start + Duration::from_millis(100)), Duration::from_millis(100));
+        // idle time refills the bucket, up to one second's worth
+        let later = start + Duration::from_secs(5);
+        for _ in 0..10 {
+            assert_eq!(limit.delay(10, later), Duration::ZERO);
+        }
+        assert!(limit.delay(10, later) > Duration::ZERO);
+    }
+
+    #[test]
+    fn a_changed_limit_applies_from_the_next_command() {
+        let start = Instant::now();
+        let mut limit = RateLimit::new(start);
+        assert_eq!(limit.delay(1, start), Duration::ZERO);
+        assert_eq!(limit.delay(1, start), Duration::from_secs(1));
+        assert_eq!(limit.delay(0, start), Duration::ZERO);
+        assert_eq!(limit.delay(2, start), Duration::ZERO);
+        assert_eq!(limit.delay(2, start), Duration::ZERO);
+        assert_eq!(limit.delay(2, start), Duration::from_millis(500));
+    }
+}
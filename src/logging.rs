@@ -0,0 +1,179 @@
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use tracing::level_filters::LevelFilter;
+use tracing::Dispatch;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::{fmt, reload, Registry};
+
+use crate::config::{Config, LogFormat, LogLevel};
+
+// tells the log lines of concurrent client connections apart
+static CONNECTION_IDS: AtomicU64 = AtomicU64::new(1);
+
+// changes the level of the installed subscriber on a config reload
+static LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+pub fn next_connection_id() -> u64 {
+    CONNECTION_IDS.fetch_add(1, Ordering::Relaxed)
+}
+
+fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Error => LevelFilter::ERROR,
+        LogLevel::Warn => LevelFilter::WARN,
+        LogLevel::Info => LevelFilter::INFO,
+        LogLevel::Debug => LevelFilter::DEBUG,
+        LogLevel::Trace => LevelFilter::TRACE,
+    }
+}
+
+// the subscriber every log line goes through, writing to `writer`. Lines
+// carry the fields of the spans they were logged in: the connection's peer
+// and id, and the request's id, database, command and cache outcome.
+fn dispatch<W>(config: &Config, writer: W, ansi: bool) -> (Dispatch, reload::Handle<LevelFilter, Registry>)
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let (level, handle) = reload::Layer::new(level_filter(config.log_level));
+    let subscriber = tracing_subscriber::registry().with(level);
+    let layer = fmt::layer().with_target(false).with_ansi(ansi).with_writer(writer);
+    let dispatch = match config.log_format {
+        LogFormat::Text => Dispatch::new(subscriber.with(layer)),
+        LogFormat::Json => Dispatch::new(subscriber.with(layer.json().with_current_span(false).with_span_list(true))),
+    };
+    (dispatch, handle)
+}
+
+// installs the subscriber on stdout, with colors only for a terminal, not for
+// log files and collectors
+pub fn init(config: &Config) {
+    let (dispatch, handle) = dispatch(config, io::stdout, io::stdout().is_terminal());
+    match dispatch.try_init() {
+        Ok(()) => {
+            let _ = LEVEL.set(handle);
+        }
+        Err(e) => eprintln!("Error: logging: {}", e),
+    }
+}
+
+pub fn set_level(level: LogLevel) {
+    if let Some(handle) = LEVEL.get() {
+        let _ = handle.reload(level_filter(level));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::Value;
+    use tracing::{field, info, info_span, warn};
+
+    use super::*;
+
+    // what the subscriber wrote, shared with the test
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Capture {
+        fn lines(&self) -> Vec<String> {
+            let written = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            written.lines().map(String::from).collect()
+        }
+    }
+
+    // logs a line in a request span inside a connection span, as clients do
+    fn log(config: &Config) -> Vec<String> {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let (dispatch, _) = dispatch(config, move || writer.clone(), false);
+        tracing::dispatcher::with_default(&dispatch, || {
+            let connection = info_span!("connection", id = 3u64, peer = "10.0.0.1:50000");
+            let _connection = connection.enter();
+            let request = info_span!("request", id = 42u32, db = field::Empty, command = field::Empty);
+            let _request = request.enter();
+            request.record("db", "shop");
+            request.record("command", "find");
+            info!("answered from the cache");
+        });
+        capture.lines()
+    }
+
+    #[test]
+    fn text_lines_carry_span_fields() {
+        let lines = log(&Config::default());
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        let line = &lines[0];
+        assert!(line.contains(" INFO "), "{}", line);
+        assert!(line.contains("connection{id=3 peer=\"10.0.0.1:50000\"}"), "{}", line);
+        assert!(line.contains("request{id=42 db=\"shop\" command=\"find\"}"), "{}", line);
+        assert!(line.ends_with("answered from the cache"), "{}", line);
+        assert!(!line.contains('\x1b'), "{}", line);
+    }
+
+    #[test]
+    fn json_lines_carry_span_fields() {
+        let config = Config {
+            log_format: LogFormat::Json,
+            ..Config::default()
+        };
+        let lines = log(&config);
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        let line: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "answered from the cache");
+        assert!(line.get("target").is_none());
+        let spans = line["spans"].as_array().unwrap();
+        assert_eq!(spans[0]["name"], "connection");
+        assert_eq!(spans[0]["id"], 3);
+        assert_eq!(spans[0]["peer"], "10.0.0.1:50000");
+        assert_eq!(spans[1]["name"], "request");
+        assert_eq!(spans[1]["id"], 42);
+        assert_eq!(spans[1]["db"], "shop");
+        assert_eq!(spans[1]["command"], "find");
+    }
+
+    #[test]
+    fn the_level_can_be_changed() {
+        let config = Config {
+            log_level: LogLevel::Warn,
+            ..Config::default()
+        };
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let (dispatch, handle) = dispatch(&config, move || writer.clone(), false);
+        tracing::dispatcher::with_default(&dispatch, || {
+            info!("dropped");
+            warn!("kept");
+            handle.reload(level_filter(LogLevel::Debug)).unwrap();
+            tracing::debug!("kept after the reload");
+            tracing::trace!("dropped after the reload");
+        });
+        let lines = capture.lines();
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines[0].ends_with("kept"));
+        assert!(lines[1].ends_with("kept after the reload"));
+    }
+
+    #[test]
+    fn connection_ids_are_unique() {
+        let first = next_connection_id();
+        let second = next_connection_id();
+        assert!(second > first);
+    }
+}
@@ -1,12 +1,12 @@
-use bson::{doc, Bson, Document};
+use bson::{doc, Bson};
 use byteorder::{ByteOrder, LittleEndian};
-use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+use clap::Parser;
+use rustls::{pki_types::ServerName, ClientConfig};
 use std::{
-    collections::HashMap,
-    env,
-    io::{Cursor, Read, Write},
-    net::{TcpListener, TcpStream},
+    io::{Read, Write},
+    net::TcpStream,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use trust_dns_resolver::{
     config::{ResolverConfig, ResolverOpts},
@@ -14,174 +14,363 @@ use trust_dns_resolver::{
 };
 // use tokio::io::{AsyncWriteExt, AsyncReadExt};
 use threadpool::ThreadPool;
+use tracing::{debug, error, field, info, info_span};
 
-use crate::Wire::{MsgHeader, Op_msg::Section, HEADER_SIZE, OP_MSG};
-pub mod Wire;
-pub mod commands;
-pub mod handler;
-
-
-pub mod utils;
+use rengo::auth::{Session, UserStore};
+use rengo::cache::{self, Cache};
+use rengo::config::{self, Cli, Config};
+use rengo::limits::RateLimit;
+use rengo::tls::{self, ClientStream};
+use rengo::metrics::{self, metrics};
+use rengo::upstream::{self, ConnectionString};
+use rengo::{admin, clients, handler, health, logging, reload, shutdown, slowlog, telemetry, upgrade, Wire};
 
 fn main() {
-    start_main("127.0.0.1".to_string(), 27017);
+    let cli = Cli::parse();
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+    };
+    start_main(cli, config);
 }
 
-fn find_primary(mongouri: &str) -> String {
-    let resolver = Resolver::new(ResolverConfig::default(), ResolverOpts::default()).unwrap();
-    let uri = mongouri.split("://").collect::<Vec<&str>>()[1]
-        .split("@")
-        .collect::<Vec<&str>>()[1]
-        .split("/")
-        .collect::<Vec<&str>>()[0];
-    let response = resolver
-        .srv_lookup(format!("_mongodb._tcp.{}", uri))
-        .unwrap();
-    
-    let ip: &trust_dns_resolver::proto::rr::rdata::SRV = response.iter().next().unwrap();
-    let main_ip = ip.target().to_string();
-    let dns_name = ServerName::try_from(main_ip.clone()).unwrap();
+// the host:port pairs to look for the primary on, from the srv records of a
+// mongodb+srv:// uri or the hosts of a mongodb:// one
+fn seeds(upstream: &ConnectionString) -> Result<Vec<String>, String> {
+    if !upstream.srv {
+        return Ok(upstream.seeds());
+    }
+    let resolver = Resolver::new(ResolverConfig::default(), ResolverOpts::default()).map_err(|e| e.to_string())?;
+    let name = format!("_mongodb._tcp.{}", upstream.hosts);
+    let response = resolver.srv_lookup(name.as_str()).map_err(|e| format!("{}: {}", name, e))?;
     // remove the last dot and add the port
-    let main_ip = format!(
-        "{}:{}",
-        main_ip[0..main_ip.len() - 1].to_string(),
-        ip.port()
-    );
-    // now find the primary shard
-    let mut root_cert_store = RootCertStore::empty();
-    root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-    let config = ClientConfig::builder()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth();
-    let arc = std::sync::Arc::new(config);
-    let mut client: rustls::ClientConnection =
-        rustls::ClientConnection::new(arc.clone(), dns_name).unwrap();
-    let mut server: TcpStream = TcpStream::connect(main_ip).unwrap();
-    let mut mongo_client: rustls::Stream<'_, rustls::ClientConnection, TcpStream> =
-        rustls::Stream::new(&mut client, &mut server);
+    Ok(response
+        .iter()
+        .map(|srv| format!("{}:{}", srv.target().to_string().trim_end_matches('.'), srv.port()))
+        .collect())
+}
+
+// asks one seed which member is the primary
+fn ask_primary(seed: &str, tls_config: Arc<ClientConfig>) -> Result<String, String> {
+    let host = seed.rsplit_once(':').map_or(seed, |(host, _)| host).to_string();
+    let dns_name = ServerName::try_from(host).map_err(|e| e.to_string())?;
+    let mut client = rustls::ClientConnection::new(tls_config, dns_name).map_err(|e| e.to_string())?;
+    let mut server = TcpStream::connect(seed).map_err(|e| e.to_string())?;
+    let mut mongo_client = rustls::Stream::new(&mut client, &mut server);
     // send a ismaster command
-    let mut op_msg = OP_MSG {
-        header: MsgHeader {
-            msg_length: 0,
-            request_id: 0,
-            response_to: 0,
-            op_code: 2013,
+    let document = upstream::run_command(
+        &mut mongo_client,
+        doc! {
+            "ismaster": Bson::Int32(1),
+            "$db": Bson::String("admin".to_string()),
         },
-        flags: 0,
-        sections: vec![Section {
-            kind: 0,
-            identifier: None,
-            documents: vec![doc! {
-                "ismaster": Bson::Int32(1),
-                "$db": Bson::String("admin".to_string()),
-            }],
-        }],
-        checksum: None,
-    };
-    op_msg.header.msg_length = op_msg.to_vec().len() as u32;
-    let t = op_msg.to_vec();
-    mongo_client.write_all(&t).unwrap();
-    let mut size_buffer: Vec<u8> = vec![0; 4];
-    // wait for the server to send a response
-    mongo_client.read_exact(&mut size_buffer).unwrap();
-    let mut size = LittleEndian::read_i32(&size_buffer);
-    size = size - size_buffer.len() as i32;
-    let mut buffer = vec![0; size as usize];
-    mongo_client.read_exact(&mut buffer).unwrap();
-    let buffer = [size_buffer, buffer].concat();
-    let mut cursor = Cursor::new(buffer);
-    cursor.set_position((HEADER_SIZE + 5 as u32).into());
-    let document = Document::from_reader(cursor).unwrap();
-    let primary = document
-        .get("primary")
-        .unwrap()
-        .as_str()
-        .unwrap()
-        .to_owned();
-    primary
+    )
+    .map_err(|e| e.to_string())?;
+    if let Ok(primary) = document.get_str("primary") {
+        return Ok(primary.to_owned());
+    }
+    // a standalone server doesn't name a primary, it is one
+    if document.get_bool("ismaster").unwrap_or(false) {
+        return Ok(seed.to_string());
+    }
+    Err("no primary".to_string())
+}
+
+fn find_primary(upstream: &ConnectionString, tls_config: Arc<ClientConfig>) -> Result<String, String> {
+    let mut errors = vec![];
+    for seed in seeds(upstream)? {
+        match ask_primary(&seed, tls_config.clone()) {
+            Ok(primary) => return Ok(primary),
+            Err(e) => errors.push(format!("{}: {}", seed, e)),
+        }
+    }
+    Err(format!("no primary found ({})", errors.join(", ")))
 }
 
-pub fn start_main(listen_addr: String, port: u16) {
-    println!("Starting server...");
-    let listner = TcpListener::bind(format!("{}:{}", listen_addr, port)).unwrap();
-    let pool = ThreadPool::new(4);
-    let storage = Arc::new(Mutex::new(HashMap::new()));
-    let mongouri = env::var("MONGO_URI").ok();
-    if mongouri.is_none() {
-        panic!("Mongo uri not found");
+pub fn start_main(cli: Cli, config: Config) {
+    logging::init(&config);
+    slowlog::init(&config);
+    info!("Starting server...");
+    shutdown::install();
+    // alive from here on, ready once the upstream and the cache are
+    health::start(&config);
+    let port = config.port;
+    // a ready file left by the previous process must not count
+    if let Some(ready_file) = &config.ready_file {
+        let _ = std::fs::remove_file(ready_file);
     }
-    let addr = find_primary(mongouri.unwrap().as_str());
+    let pool = ThreadPool::new(config.workers);
+    let storage = Arc::new(Cache::new());
+    let config = Arc::new(config);
+    // what requests read, swapped on a reload
+    config::replace(config.clone());
+    reload::install(cli, pool.clone());
+    let users = match &config.users_file {
+        Some(path) => match UserStore::load(path) {
+            Ok(users) => users,
+            Err(e) => {
+                error!("auth.users_file: {}", e);
+                std::process::exit(2);
+            }
+        },
+        None => UserStore::default(),
+    };
+    let users = Arc::new(users);
+    let tls_config = match tls::client_config(&config.upstream) {
+        Ok(tls_config) => tls_config,
+        Err(e) => {
+            error!("upstream tls: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let server_tls = match tls::server_config(&config) {
+        Ok(server_tls) => server_tls,
+        Err(e) => {
+            error!("listener tls: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let warmup = match &config.warmup_file {
+        Some(path) if config.cache_enabled() => match cache::warmup::load(path) {
+            Ok(warmup) => warmup,
+            Err(e) => {
+                error!("cache.warmup.file: {}", e);
+                std::process::exit(2);
+            }
+        },
+        _ => vec![],
+    };
+    // a rolling restart picks up where the previous process left off
+    let mut restored = false;
+    if let (Some(path), true) = (&config.persist_file, config.cache_enabled()) {
+        match cache::persist::restore(path, &storage, &config) {
+            Ok(count) => {
+                restored = count > 0;
+                info!("Restored {} cache entries from {}", count, path);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => error!("not restoring the cache from {}: {}", path, e),
+        }
+        cache::persist::start(&config, storage.clone());
+        let path = path.clone();
+        let storage = storage.clone();
+        shutdown::on_shutdown(move || match cache::persist::save(&path, &storage) {
+            Ok(count) => info!("Saved {} cache entries to {}", count, path),
+            Err(e) => error!("could not save the cache to {}: {}", path, e),
+        });
+    }
+    let addr = match find_primary(&config.upstream, tls_config.clone()) {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("upstream.uri: {}", e);
+            std::process::exit(2);
+        }
+    };
     let addr = Arc::new(addr);
-    println!("Server started on port {}", port);
-    for stream in listner.incoming() {
-        let stream = stream.unwrap();
-        stream.set_nodelay(true).unwrap();
-        println!("New connection: {}", stream.peer_addr().unwrap());
-        // get mongodb uri from env
-        let mut root_cert_store = RootCertStore::empty();
-        root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-        let config = ClientConfig::builder()
-            .with_root_certificates(root_cert_store)
-            .with_no_client_auth();
-        let arc = std::sync::Arc::new(config);
+    health::monitor(config.clone(), tls_config.clone(), addr.clone());
+    metrics::start(config.clone(), storage.clone());
+    telemetry::start(&config);
+    // spans of the commands drained on shutdown go out before exiting
+    shutdown::on_shutdown(|| telemetry::flush(Duration::from_secs(5)));
+    admin::start(config.clone(), storage.clone(), addr.clone());
+    if config.cache_enabled() {
+        // watching first so writes made while warming still evict
+        cache::watch::start(config.clone(), tls_config.clone(), addr.clone(), storage.clone(), restored);
+        if !config.is_passthrough() {
+            cache::refresh::start(config.clone(), tls_config.clone(), addr.clone(), storage.clone());
+        }
+        cache::warmup::run(&warmup, &config, tls_config.clone(), &addr, &storage);
+    }
+    health::warmed_up();
+    // clients only get in once the cache is warm
+    let listner = match upgrade::listener(&config) {
+        Ok(listner) => listner,
+        Err(e) => {
+            error!("could not listen on {}:{}: {}", config.listen_addr, config.port, e);
+            std::process::exit(1);
+        }
+    };
+    upgrade::serve(&config, &listner);
+    if let Some(ready_file) = &config.ready_file {
+        if let Err(e) = std::fs::write(ready_file, format!("{}\n", std::process::id())) {
+            error!("could not write {}: {}", ready_file, e);
+        }
+    }
+    info!("Server started on port {}", port);
+    while let Some(stream) = shutdown::accept(&listner) {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("could not accept a connection: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_nodelay(true) {
+            error!("could not set up the connection: {}", e);
+            continue;
+        }
+        metrics().client_connections_total.inc();
+        // counted from accept, so clients queued for a worker show up too
+        let connected = metrics().client_connections.track();
+        // every line logged for the connection carries its id and peer
+        let id = logging::next_connection_id();
+        let peer = stream.peer_addr().ok();
+        let span = match peer {
+            Some(peer) => info_span!("connection", id, %peer),
+            None => info_span!("connection", id),
+        };
+        let registration = clients::register(id, peer, stream.try_clone().ok());
+        let arc = tls_config.clone();
         let a = addr.clone().split(":").collect::<Vec<&str>>()[0].to_string();
-        let dns_name = ServerName::try_from(a).unwrap();
-        let storage: Arc<Mutex<HashMap<String, crate::handler::InnerData>>> = storage.clone();
+        let storage: handler::Storage = storage.clone();
         let addr = addr.clone();
+        let config = config.clone();
+        let users = users.clone();
+        let server_tls = server_tls.clone();
         pool.execute(move || {
-            let client: rustls::ClientConnection =
-                rustls::ClientConnection::new(arc.clone(), dns_name).unwrap();
-            let server: TcpStream = TcpStream::connect(addr.to_string()).unwrap();
+            let _connected = connected;
+            let _span = span.enter();
+            // queued for a worker while the proxy began shutting down
+            if shutdown::requested() {
+                return;
+            }
+            let stream = match ClientStream::accept(stream, server_tls.as_ref()) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("client handshake failed: {}", e);
+                    return;
+                }
+            };
+            let dns_name = match ServerName::try_from(a) {
+                Ok(dns_name) => dns_name,
+                Err(e) => {
+                    error!("upstream address {}: {}", addr, e);
+                    return;
+                }
+            };
+            let client = match rustls::ClientConnection::new(arc.clone(), dns_name) {
+                Ok(client) => client,
+                Err(e) => {
+                    error!("upstream tls: {}", e);
+                    return;
+                }
+            };
+            let server = match TcpStream::connect(addr.to_string()) {
+                Ok(server) => server,
+                Err(e) => {
+                    error!("could not connect to the upstream {}: {}", addr, e);
+                    return;
+                }
+            };
+            let _upstream = metrics().upstream_connections.track();
             // let mut tcp_out_stream: rustls::Stream<'static, rustls::ClientConnection, TcpStream> = rustls::Stream::new(&mut client, &mut server);
-            handle_connection(stream, client, server, &storage);
+            handle_connection(stream, client, server, &storage, &config, &users, &registration);
         });
     }
-    println!("Shutting down server");
+    info!("Shutting down server");
+    // new clients are refused from here on
+    drop(listner);
+    shutdown::drain(config::current().shutdown_timeout);
+    shutdown::cleanup();
+    info!("Shut down");
 }
 
 fn handle_connection(
-    mut stream: TcpStream,
+    mut stream: ClientStream,
     mut client: rustls::ClientConnection,
     mut server: TcpStream,
-    storage: &crate::handler::Storage,
+    storage: &handler::Storage,
+    config: &Config,
+    users: &UserStore,
+    registration: &clients::Registration,
 ) {
     // need to possibly use request id here
-    let addr = stream.peer_addr().unwrap();
-    println!("Client connected: {}", addr);
-    let mongo_client: rustls::Stream<'_, rustls::ClientConnection, TcpStream> =
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("client went away: {}", e);
+            return;
+        }
+    };
+    info!("Client connected");
+    let upstream_addr = server.local_addr().ok();
+    let mut mongo_client: rustls::Stream<'_, rustls::ClientConnection, TcpStream> =
         rustls::Stream::new(&mut client, &mut server);
+    // in passthrough mode the client logs this connection in itself
+    let authenticated = if config.is_passthrough() {
+        Ok(())
+    } else {
+        upstream::authenticate(&mut mongo_client, &config.upstream)
+    };
+    if let Err(e) = authenticated {
+        error!("upstream authentication failed: {}", e);
+        return;
+    }
+    let session = Arc::new(Mutex::new(Session::new()));
+    registration.serving(upstream_addr, session.clone());
     // throw error if tls handshake fails
     let mongo_client: Arc<Mutex<rustls::Stream<'_, rustls::ClientConnection, TcpStream>>> =
         Arc::new(Mutex::new(mongo_client));
+    let mut rate_limit = RateLimit::default();
     loop {
         let mut size_buffer = [0; 4];
-        stream.peek(&mut size_buffer).unwrap();
+        // a tls stream can't be peeked, read the length and then the rest
+        if stream.read_exact(&mut size_buffer).is_err() {
+            info!("Client disconnected");
+            break;
+        }
+        // a client closed for draining doesn't get this message handled
+        if !registration.request() {
+            break;
+        }
         let size = LittleEndian::read_i32(&size_buffer);
-        if size == 0 {
-            stream.flush().unwrap();
-            println!("Client disconnected: {}", addr);
+        if size < 4 {
+            let _ = stream.flush();
+            info!("Client disconnected");
             break;
         }
         let mut buffer = vec![0; size as usize];
-        match stream.read_exact(&mut buffer) {
+        buffer[..4].copy_from_slice(&size_buffer);
+        match stream.read_exact(&mut buffer[4..]) {
             Ok(_read) => {
+                // the fields the handler learns are recorded as it goes
+                let request_id = buffer.get(4..8).map_or(0, LittleEndian::read_u32);
+                let span = info_span!(
+                    "request",
+                    id = request_id,
+                    db = field::Empty,
+                    command = field::Empty,
+                    cache = field::Empty
+                );
+                let _span = span.enter();
+                let started = std::time::Instant::now();
                 let op_code = Wire::parse(&buffer);
                 if op_code.is_err() {
-                    println!("Error: {:?}", op_code);
-                    stream.write(&[0x00, 0x00, 0x00, 0x00]).unwrap();
-                    stream.write(&[0x00, 0x00, 0x00, 0x00]).unwrap();
-                    stream.write(&[0x00, 0x00, 0x00, 0x00]).unwrap();
-                    stream.write(&[0x00, 0x00, 0x00, 0x00]).unwrap();
+                    metrics().wire_parse_errors.inc();
+                    error!("could not parse the message: {:?}", op_code);
+                    // the connection is closed either way
+                    let _ = stream.write_all(&[0x00; 16]);
                     return;
                 }
                 let op_code = op_code.unwrap();
+                // settings reloaded since the client connected apply from its next message
+                let current = config::current();
+                let delay = rate_limit.delay(current.rate_limit, std::time::Instant::now());
+                if !delay.is_zero() {
+                    std::thread::sleep(delay);
+                }
+                telemetry::begin(request_id);
                 let mongo_client = Arc::clone(&mongo_client);
                 let storage = storage.clone();
-                let mut response = match handler::handle(0, addr, &op_code, mongo_client, &storage)
+                let request = handler::Request::new(mongo_client, addr, &op_code, &storage, &current, users, &session);
+                let response = match handler::handle(0, &request)
                 {
                     Ok(reply) => reply,
                     Err(e) => {
-                        println!("Error: {}", e);
+                        error!("{}", e);
                         let err = doc! {
                             "ok": Bson::Double(0.0),
                             "errmsg": Bson::String(format!("{}", e)),
@@ -192,11 +381,20 @@ fn handle_connection(
                         op_code.reply(request).unwrap()
                     }
                 };
-                response.flush().unwrap();
-                stream.write_all(&response).unwrap();
+                if let Err(e) = stream.write_all(&response) {
+                    error!("could not write the reply: {}", e);
+                    return;
+                }
+                telemetry::end();
+                registration.replied();
+                debug!(elapsed_ms = started.elapsed().as_secs_f64() * 1000.0, "Handled request");
+                if shutdown::requested() {
+                    info!("Closing the connection to shut down");
+                    break;
+                }
             }
             Err(e) => {
-                println!("Error: {}", e);
+                error!("could not read the message: {}", e);
                 return;
             }
         }
@@ -0,0 +1,355 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use tracing::{error, info};
+
+use crate::config::{self, Config};
+use crate::handler::Storage;
+use crate::health;
+use crate::http::{self, HttpRequest, HttpResponse};
+
+// upper bounds of the upstream latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+// label values come from clients, past this many series new ones are counted
+// under "other"
+const MAX_SERIES: usize = 1000;
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+    pub fn add(&self, count: u64) {
+        self.0.fetch_add(count, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+    // incremented until the returned guard is dropped
+    pub fn track(&'static self) -> GaugeGuard {
+        self.inc();
+        GaugeGuard(self)
+    }
+}
+
+pub struct GaugeGuard(&'static Gauge);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    // microseconds, rendered as seconds
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// counters by label value, e.g. by command name or namespace
+#[derive(Default)]
+pub struct Family {
+    values: Mutex<BTreeMap<Vec<String>, Arc<Counter>>>,
+}
+
+impl Family {
+    pub fn with(&self, labels: &[&str]) -> Arc<Counter> {
+        let mut key: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+        let mut values = self.values.lock().unwrap();
+        if values.len() >= MAX_SERIES && !values.contains_key(&key) {
+            key = labels.iter().map(|_| "other".to_string()).collect();
+        }
+        values.entry(key).or_default().clone()
+    }
+    fn snapshot(&self) -> Vec<(Vec<String>, u64)> {
+        let values = self.values.lock().unwrap();
+        values.iter().map(|(labels, counter)| (labels.clone(), counter.get())).collect()
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub client_connections: Gauge,
+    pub client_connections_total: Counter,
+    // upstream connections held by the workers serving clients
+    pub upstream_connections: Gauge,
+    pub upstream_latency: Histogram,
+    // by command name
+    pub commands: Family,
+    // by namespace
+    pub cache_hits: Family,
+    pub cache_misses: Family,
+    // by namespace and reason
+    pub cache_evictions: Family,
+    pub wire_parse_errors: Counter,
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::default)
+}
+
+// label values are escaped as the exposition format requires
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn labels(names: &[&str], values: &[String]) -> String {
+    let pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn family(out: &mut String, name: &str, help: &str, names: &[&str], family: &Family) {
+    header(out, name, "counter", help);
+    for (values, count) in family.snapshot() {
+        let _ = writeln!(out, "{}{} {}", name, labels(names, &values), count);
+    }
+}
+
+// the prometheus text exposition format
+pub fn render(config: &Config, storage: &Storage) -> String {
+    let metrics = metrics();
+    let mut out = String::new();
+    header(&mut out, "rengo_client_connections", "gauge", "Client connections currently open.");
+    let _ = writeln!(out, "rengo_client_connections {}", metrics.client_connections.get());
+    header(&mut out, "rengo_client_connections_total", "counter", "Client connections accepted.");
+    let _ = writeln!(out, "rengo_client_connections_total {}", metrics.client_connections_total.get());
+    // each busy worker holds the upstream connection of the client it serves
+    header(&mut out, "rengo_workers", "gauge", "Workers serving clients (listen.workers).");
+    let _ = writeln!(out, "rengo_workers {}", config.workers);
+    header(&mut out, "rengo_workers_busy", "gauge", "Workers serving a client, each with its own upstream connection.");
+    let _ = writeln!(out, "rengo_workers_busy {}", metrics.upstream_connections.get());
+    header(&mut out, "rengo_upstream_up", "gauge", "Whether the topology monitor reaches the upstream primary.");
+    let _ = writeln!(out, "rengo_upstream_up {}", health::upstream_up() as u8);
+    header(&mut out, "rengo_upstream_latency_seconds", "histogram", "Round trip of commands sent upstream.");
+    let histogram = &metrics.upstream_latency;
+    for (bound, bucket) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+        let _ = writeln!(
+            out,
+            "rengo_upstream_latency_seconds_bucket{{le=\"{}\"}} {}",
+            bound,
+            bucket.load(Ordering::Relaxed)
+        );
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    let _ = writeln!(out, "rengo_upstream_latency_seconds_bucket{{le=\"+Inf\"}} {}", count);
+    let sum = histogram.sum.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    let _ = writeln!(out, "rengo_upstream_latency_seconds_sum {}", sum);
+    let _ = writeln!(out, "rengo_upstream_latency_seconds_count {}", count);
+    family(&mut out, "rengo_commands_total", "Commands received by name.", &["command"], &metrics.commands);
+    family(&mut out, "rengo_cache_hits_total", "Reads answered from the cache.", &["namespace"], &metrics.cache_hits);
+    family(&mut out, "rengo_cache_misses_total", "Cacheable reads sent upstream.", &["namespace"], &metrics.cache_misses);
+    family(
+        &mut out,
+        "rengo_cache_evictions_total",
+        "Cached replies dropped, by reason.",
+        &["namespace", "reason"],
+        &metrics.cache_evictions,
+    );
+    header(&mut out, "rengo_cache_entries", "gauge", "Cached replies.");
+    let _ = writeln!(out, "rengo_cache_entries {}", storage.len());
+    header(&mut out, "rengo_cache_memory_bytes", "gauge", "Approximate bson size of the cache contents.");
+    let (replies, documents, collections) = storage.memory();
+    let _ = writeln!(out, "rengo_cache_memory_bytes{{store=\"replies\"}} {}", replies);
+    let _ = writeln!(out, "rengo_cache_memory_bytes{{store=\"documents\"}} {}", documents);
+    let _ = writeln!(out, "rengo_cache_memory_bytes{{store=\"collections\"}} {}", collections);
+    header(&mut out, "rengo_wire_parse_errors_total", "counter", "Client messages that could not be parsed.");
+    let _ = writeln!(out, "rengo_wire_parse_errors_total {}", metrics.wire_parse_errors.get());
+    out
+}
+
+// serves `/metrics` on `metrics.address`
+pub fn start(config: Arc<Config>, storage: Storage) {
+    let addr = match &config.metrics_addr {
+        Some(addr) => addr.clone(),
+        None => return,
+    };
+    let handler = move |request: &HttpRequest| match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            HttpResponse::new(200, "text/plain; version=0.0.4", render(&config::current(), &storage))
+        }
+        _ => HttpResponse::not_found(),
+    };
+    match http::serve(&addr, handler) {
+        Ok(()) => info!("Metrics on http://{}/metrics", addr),
+        Err(e) => error!("metrics listener on {}: {}", addr, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use bson::doc;
+
+    use super::*;
+    use crate::cache::policy::CachePolicy;
+    use crate::cache::Cache;
+    use crate::handler::InnerData;
+    use crate::http::testing::free_addr;
+
+    #[test]
+    fn counts_and_tracks() {
+        static GAUGE: Gauge = Gauge(AtomicI64::new(0));
+        let counter = Counter::default();
+        counter.inc();
+        counter.add(4);
+        assert_eq!(counter.get(), 5);
+        let guard = GAUGE.track();
+        let other = GAUGE.track();
+        assert_eq!(GAUGE.get(), 2);
+        drop(guard);
+        drop(other);
+        assert_eq!(GAUGE.get(), 0);
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(300));
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(5));
+        let buckets: Vec<u64> = histogram.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        assert_eq!(buckets, [1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2]);
+        assert_eq!(histogram.count.load(Ordering::Relaxed), 3);
+        assert_eq!(histogram.sum.load(Ordering::Relaxed), 5_020_300);
+    }
+
+    #[test]
+    fn families_cap_their_series() {
+        let family = Family::default();
+        family.with(&["find"]).inc();
+        family.with(&["find"]).inc();
+        for n in 1..MAX_SERIES {
+            family.with(&[&format!("command{}", n)]).inc();
+        }
+        family.with(&["one too many"]).inc();
+        family.with(&["another"]).add(2);
+        family.with(&["find"]).inc();
+        let snapshot = family.snapshot();
+        assert_eq!(snapshot.len(), MAX_SERIES + 1);
+        let count = |label: &str| snapshot.iter().find(|(labels, _)| labels[0] == label).map(|(_, count)| *count);
+        assert_eq!(count("find"), Some(3));
+        assert_eq!(count("other"), Some(3));
+        assert_eq!(count("one too many"), None);
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        let values = vec!["a\"b".to_string(), "c\\d\ne".to_string()];
+        assert_eq!(labels(&["x", "y"], &values), r#"{x="a\"b",y="c\\d\ne"}"#);
+    }
+
+    #[test]
+    fn renders_the_exposition_format() {
+        let config = Config {
+            workers: 7,
+            ..Config::default()
+        };
+        let storage = Arc::new(Cache::new());
+        let data = InnerData::Document(doc! { "ok": 1.0 });
+        let generation = storage.namespace_generation("shop.orders");
+        storage.insert("key".to_string(), "shop.orders", data, &CachePolicy::default(), generation);
+        metrics().cache_hits.with(&["metrics.\"render\""]).inc();
+        let out = render(&config, &storage);
+        assert!(out.contains("# TYPE rengo_client_connections gauge\n"));
+        assert!(out.contains("# TYPE rengo_upstream_latency_seconds histogram\n"));
+        assert!(out.contains("\nrengo_workers 7\n"));
+        assert!(out.contains("# TYPE rengo_workers_busy gauge\n"));
+        assert!(out.contains("\nrengo_cache_entries 1\n"));
+        assert!(out.contains("rengo_cache_hits_total{namespace=\"metrics.\\\"render\\\"\"} 1\n"));
+        assert!(out.contains("rengo_upstream_latency_seconds_bucket{le=\"+Inf\"} "));
+        // every sample follows the HELP and TYPE of its metric
+        let mut typed = vec![];
+        for line in out.lines() {
+            match line.strip_prefix("# TYPE ") {
+                Some(rest) => typed.push(rest.split(' ').next().unwrap().to_string()),
+                None if line.starts_with('#') => {}
+                None => {
+                    let name = line.split(['{', ' ']).next().unwrap();
+                    let metric = typed.last().unwrap();
+                    assert!(name.starts_with(metric.as_str()), "{} under {}", name, metric);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn serves_metrics() {
+        let addr = free_addr();
+        let config = Config {
+            metrics_addr: Some(addr.clone()),
+            ..Config::default()
+        };
+        start(Arc::new(config), Arc::new(Cache::new()));
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(&addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("# TYPE rengo_cache_entries gauge\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404 "));
+    }
+}
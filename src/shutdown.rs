@@ -0,0 +1,202 @@
+use std::io::{self, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::{clients, health};
+
+// how often the signal flag is checked
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+type Cleanup = Box<dyn FnOnce() + Send>;
+
+static CLEANUPS: Mutex<Vec<Cleanup>> = Mutex::new(Vec::new());
+
+// only async-signal-safe work here, the flag is picked up by the accept
+// loop. A second signal exits without draining.
+extern "C" fn on_signal(_signal: libc::c_int) {
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(1) };
+    }
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+// starts the same graceful shutdown as a signal
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// SIGTERM and SIGINT start a graceful shutdown
+pub fn install() {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+}
+
+// runs `cleanup` once the clients are drained, in the order registered
+pub fn on_shutdown<F>(cleanup: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    CLEANUPS.lock().unwrap().push(Box::new(cleanup));
+}
+
+// the next client, None once a shutdown was requested. The listener is
+// polled so the signal flag is seen without a connection arriving.
+pub fn accept(listener: &TcpListener) -> Option<io::Result<TcpStream>> {
+    accept_until(listener, &REQUESTED)
+}
+
+fn accept_until(listener: &TcpListener, stop: &AtomicBool) -> Option<io::Result<TcpStream>> {
+    let mut fd = libc::pollfd {
+        fd: listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    while !stop.load(Ordering::SeqCst) {
+        // interrupted by the signal itself, or timed out, either way the
+        // flag is checked again
+        let ready = unsafe { libc::poll(&mut fd, 1, POLL_INTERVAL.as_millis() as libc::c_int) };
+        if ready > 0 {
+            match listener.accept() {
+                // taken by the process the listener was handed over to
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                accepted => return Some(accepted.map(|(stream, _)| stream)),
+            }
+        }
+    }
+    None
+}
+
+// waits up to `timeout` for clients to finish the command they are running,
+// idle connections are closed right away
+pub fn drain(timeout: Duration) {
+    health::set_draining();
+    let deadline = Instant::now() + timeout;
+    let open = clients::count();
+    if open > 0 {
+        info!("Draining {} client connections", open);
+    }
+    loop {
+        // clients that were busy are closed by their worker once they reply
+        clients::close_idle();
+        let open = clients::count();
+        if open == 0 {
+            break;
+        }
+        if Instant::now() >= deadline {
+            warn!("{} client connections still open after {:?}, closing them", open, timeout);
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// the cleanups registered with on_shutdown
+pub fn cleanup() {
+    let cleanups: Vec<Cleanup> = CLEANUPS.lock().unwrap().drain(..).collect();
+    for cleanup in cleanups {
+        cleanup();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::clients::testing::exclusive;
+
+    // both ends of a client connection, rengo's first
+    fn connection(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        (accepted, client)
+    }
+
+    fn closed(mut client: &TcpStream) -> bool {
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        matches!(client.read(&mut [0; 1]), Ok(0))
+    }
+
+    #[test]
+    fn accepts_until_stopped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert!(matches!(accept_until(&listener, &stop), Some(Ok(_))));
+        let stopping = stop.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            stopping.store(true, Ordering::SeqCst);
+        });
+        let started = Instant::now();
+        assert!(accept_until(&listener, &stop).is_none());
+        assert!(started.elapsed() < POLL_INTERVAL * 5);
+    }
+
+    #[test]
+    fn drains_idle_clients_and_waits_for_busy_ones() {
+        let _exclusive = exclusive();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (idle, idle_client) = connection(&listener);
+        let (busy, busy_client) = connection(&listener);
+        let idle = clients::register(9001, idle.peer_addr().ok(), Some(idle));
+        let busy = clients::register(9002, busy.peer_addr().ok(), Some(busy));
+        assert!(busy.request());
+        let (done, drained) = mpsc::channel();
+        thread::spawn(move || {
+            drain(Duration::from_secs(10));
+            done.send(()).unwrap();
+        });
+        assert!(closed(&idle_client));
+        // its worker sees the connection end
+        drop(idle);
+        assert!(drained.recv_timeout(Duration::from_millis(200)).is_err());
+        assert!(health::is_draining());
+        busy.replied();
+        assert!(closed(&busy_client));
+        assert!(!busy.request());
+        drop(busy);
+        drained.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(clients::count(), 0);
+    }
+
+    #[test]
+    fn stops_draining_at_the_timeout() {
+        let _exclusive = exclusive();
+        let busy = clients::register(9003, None, None);
+        assert!(busy.request());
+        let started = Instant::now();
+        drain(Duration::from_millis(100));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(clients::count(), 1);
+    }
+
+    #[test]
+    fn cleans_up_in_order() {
+        let ran = Arc::new(Mutex::new(vec![]));
+        for step in ["snapshot", "spans"] {
+            let ran = ran.clone();
+            on_shutdown(move || ran.lock().unwrap().push(step));
+        }
+        cleanup();
+        cleanup();
+        assert_eq!(*ran.lock().unwrap(), ["snapshot", "spans"]);
+    }
+}
@@ -0,0 +1,408 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use bson::{Bson, Document};
+use serde_json::json;
+use tracing::{error, info};
+
+use crate::config::Config;
+
+// commands that change data or schema, logged in audit mode
+const WRITES: [&str; 13] = [
+    "insert",
+    "update",
+    "delete",
+    "findAndModify",
+    "findandmodify",
+    "bulkWrite",
+    "create",
+    "createIndexes",
+    "drop",
+    "dropIndexes",
+    "dropDatabase",
+    "renameCollection",
+    "collMod",
+];
+
+// a file that is renamed to `path.1` once it would grow past max_size, the
+// older ones shift up to `path.{max_files}` and the oldest is dropped
+struct RotatingFile {
+    path: String,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+fn append(path: &str) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_size: u64, max_files: usize) -> io::Result<RotatingFile> {
+        let file = append(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_string(),
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+            self.size = 0;
+            return Ok(());
+        }
+        for n in (1..self.max_files).rev() {
+            let older = format!("{}.{}", self.path, n);
+            if Path::new(&older).exists() {
+                fs::rename(&older, format!("{}.{}", self.path, n + 1))?;
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path))?;
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+struct SlowLog {
+    threshold: Duration,
+    audit: bool,
+    file: Mutex<RotatingFile>,
+}
+
+static LOG: OnceLock<SlowLog> = OnceLock::new();
+
+// opens `slow_log.file`, rengo runs without the log when it can't be opened
+pub fn init(config: &Config) {
+    let path = match &config.slow_log_file {
+        Some(path) => path,
+        None => return,
+    };
+    match RotatingFile::open(path, config.slow_log_max_size, config.slow_log_max_files) {
+        Ok(file) => {
+            let _ = LOG.set(SlowLog {
+                threshold: config.slow_log_threshold,
+                audit: config.audit_writes,
+                file: Mutex::new(file),
+            });
+            info!("Logging upstream round trips over {:?} to {}", config.slow_log_threshold, path);
+        }
+        Err(e) => error!("slow query log {}: {}", path, e),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Slow,
+    // any write in audit mode, slow writes are logged as Slow
+    Write,
+}
+
+impl Event {
+    fn as_str(self) -> &'static str {
+        match self {
+            Event::Slow => "slow",
+            Event::Write => "write",
+        }
+    }
+}
+
+// whether a round trip of `duration` is logged, checked before the rest of
+// the record is gathered
+pub fn event(command: &str, duration: Duration) -> Option<Event> {
+    LOG.get()?.event(command, duration)
+}
+
+// values are replaced by "?" so the log shows which fields a query uses and
+// how, without the data it was run with
+pub fn shape(value: &Bson) -> Bson {
+    match value {
+        Bson::Document(document) => Bson::Document(
+            document
+                .iter()
+                .map(|(key, value)| (key.clone(), shape(value)))
+                .collect(),
+        ),
+        // `$and: [...]` and pipelines keep their structure, `$in: [...]` does not
+        Bson::Array(values) if !values.is_empty() && values.iter().all(|value| matches!(value, Bson::Document(_))) => {
+            Bson::Array(values.iter().map(shape).collect())
+        }
+        _ => Bson::String("?".to_string()),
+    }
+}
+
+// the filter of a read or write, or an aggregate's pipeline, as a shape
+fn filter_shape(command: &str, doc: &Document) -> Option<Bson> {
+    let statements = |field: &str| -> Option<Bson> {
+        let statements = doc.get_array(field).ok()?;
+        let filters = statements
+            .iter()
+            .filter_map(|statement| statement.as_document()?.get("q"))
+            .map(shape)
+            .collect();
+        Some(Bson::Array(filters))
+    };
+    match command {
+        "find" | "count" | "distinct" => doc.get("filter").or_else(|| doc.get("query")).map(shape),
+        "findAndModify" | "findandmodify" => doc.get("query").map(shape),
+        "aggregate" => doc.get("pipeline").map(shape),
+        "update" => statements("updates"),
+        "delete" => statements("deletes"),
+        _ => None,
+    }
+}
+
+fn namespace(command: &str, doc: &Document) -> String {
+    let db = doc.get_str("$db").unwrap_or("admin");
+    match doc.get_str(command) {
+        Ok(collection) => format!("{}.{}", db, collection),
+        Err(_) => db.to_string(),
+    }
+}
+
+// what is known about a command once its upstream round trip is done
+pub struct Record<'a> {
+    pub event: Event,
+    pub command: &'a str,
+    pub doc: &'a Document,
+    pub duration: Duration,
+    // how the cache answered, None for commands it doesn't handle
+    pub cache: Option<&'static str>,
+    pub client: SocketAddr,
+    // `db.user` of an authenticated client
+    pub user: &'a str,
+}
+
+impl SlowLog {
+    fn event(&self, command: &str, duration: Duration) -> Option<Event> {
+        if duration >= self.threshold {
+            return Some(Event::Slow);
+        }
+        (self.audit && WRITES.contains(&command)).then_some(Event::Write)
+    }
+    fn record(&self, record: &Record) {
+        let filter = filter_shape(record.command, record.doc).map(|filter| filter.into_relaxed_extjson());
+        let line = json!({
+            "time": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "event": record.event.as_str(),
+            "namespace": namespace(record.command, record.doc),
+            "command": record.command,
+            "filter": filter,
+            "duration_ms": record.duration.as_secs_f64() * 1000.0,
+            "cache": record.cache,
+            "client": record.client.to_string(),
+            "user": Some(record.user).filter(|user| !user.is_empty()),
+        });
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_line(&line.to_string()) {
+            error!("slow query log {}: {}", file.path, e);
+        }
+    }
+}
+
+pub fn record(record: &Record) {
+    if let Some(log) = LOG.get() {
+        log.record(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use bson::doc;
+    use serde_json::Value;
+
+    use super::*;
+
+    fn path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("rengo-slowlog-{}-{}", std::process::id(), name));
+        let path = path.to_str().unwrap().to_string();
+        for n in 0..5 {
+            let _ = fs::remove_file(format!("{}.{}", path, n));
+        }
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn read(path: &str) -> String {
+        fs::read_to_string(path).unwrap_or_default()
+    }
+
+    fn slow_log(path: &str, audit: bool) -> SlowLog {
+        SlowLog {
+            threshold: Duration::from_millis(100),
+            audit,
+            file: Mutex::new(RotatingFile::open(path, 1024 * 1024, 2).unwrap()),
+        }
+    }
+
+    #[test]
+    fn rotates_past_max_size() {
+        let path = path("rotate");
+        // each line is 4 bytes with its newline, two fit
+        let mut file = RotatingFile::open(&path, 8, 2).unwrap();
+        for line in ["aaa", "bbb", "ccc", "ddd", "eee"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(read(&path), "eee\n");
+        assert_eq!(read(&format!("{}.1", path)), "ccc\nddd\n");
+        assert_eq!(read(&format!("{}.2", path)), "aaa\nbbb\n");
+        for line in ["fff", "ggg"] {
+            file.write_line(line).unwrap();
+        }
+        // the oldest file is dropped
+        assert_eq!(read(&path), "ggg\n");
+        assert_eq!(read(&format!("{}.1", path)), "eee\nfff\n");
+        assert_eq!(read(&format!("{}.2", path)), "ccc\nddd\n");
+        assert!(!Path::new(&format!("{}.3", path)).exists());
+    }
+
+    #[test]
+    fn reopening_continues_the_size() {
+        let path = path("reopen");
+        RotatingFile::open(&path, 8, 1).unwrap().write_line("aaa").unwrap();
+        let mut file = RotatingFile::open(&path, 8, 1).unwrap();
+        file.write_line("bbb").unwrap();
+        file.write_line("ccc").unwrap();
+        assert_eq!(read(&path), "ccc\n");
+        assert_eq!(read(&format!("{}.1", path)), "aaa\nbbb\n");
+    }
+
+    #[test]
+    fn lines_longer_than_max_size_are_still_written() {
+        let path = path("long");
+        let mut file = RotatingFile::open(&path, 4, 1).unwrap();
+        file.write_line("a long line").unwrap();
+        file.write_line("another").unwrap();
+        assert_eq!(read(&path), "another\n");
+        assert_eq!(read(&format!("{}.1", path)), "a long line\n");
+    }
+
+    #[test]
+    fn without_files_to_keep_the_log_is_truncated() {
+        let path = path("truncate");
+        let mut file = RotatingFile::open(&path, 8, 0).unwrap();
+        for line in ["aaa", "bbb", "ccc"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(read(&path), "ccc\n");
+        assert!(!Path::new(&format!("{}.1", path)).exists());
+    }
+
+    #[test]
+    fn shapes_hide_values() {
+        let filter = doc! {
+            "status": "paid",
+            "total": { "$gt": 100 },
+            "tags": { "$in": ["a", "b"] },
+            "$or": [{ "user": 1 }, { "guest": true }],
+        };
+        let expected = doc! {
+            "status": "?",
+            "total": { "$gt": "?" },
+            "tags": { "$in": "?" },
+            "$or": [{ "user": "?" }, { "guest": "?" }],
+        };
+        assert_eq!(shape(&Bson::Document(filter)), Bson::Document(expected));
+        assert_eq!(shape(&Bson::Array(vec![])), Bson::String("?".into()));
+    }
+
+    #[test]
+    fn finds_the_filter_of_each_command() {
+        let find = doc! { "find": "orders", "filter": { "status": "paid" }, "$db": "shop" };
+        assert_eq!(filter_shape("find", &find), Some(Bson::Document(doc! { "status": "?" })));
+        let count = doc! { "count": "orders", "query": { "status": "paid" } };
+        assert_eq!(filter_shape("count", &count), Some(Bson::Document(doc! { "status": "?" })));
+        let aggregate = doc! { "aggregate": "orders", "pipeline": [{ "$match": { "user": 7 } }] };
+        let pipeline = Bson::Array(vec![Bson::Document(doc! { "$match": { "user": "?" } })]);
+        assert_eq!(filter_shape("aggregate", &aggregate), Some(pipeline));
+        let update = doc! { "update": "orders", "updates": [{ "q": { "_id": 1 }, "u": { "$set": { "paid": true } } }] };
+        let filters = Bson::Array(vec![Bson::Document(doc! { "_id": "?" })]);
+        assert_eq!(filter_shape("update", &update), Some(filters.clone()));
+        let delete = doc! { "delete": "orders", "deletes": [{ "q": { "_id": 1 }, "limit": 1 }] };
+        assert_eq!(filter_shape("delete", &delete), Some(filters));
+        assert_eq!(filter_shape("insert", &doc! { "insert": "orders" }), None);
+    }
+
+    #[test]
+    fn names_the_namespace() {
+        assert_eq!(namespace("find", &doc! { "find": "orders", "$db": "shop" }), "shop.orders");
+        assert_eq!(namespace("dropDatabase", &doc! { "dropDatabase": 1, "$db": "shop" }), "shop");
+        assert_eq!(namespace("ping", &doc! { "ping": 1 }), "admin");
+    }
+
+    #[test]
+    fn logs_slow_round_trips_and_audited_writes() {
+        let log = slow_log(&path("events"), false);
+        assert_eq!(log.event("find", Duration::from_millis(100)), Some(Event::Slow));
+        assert_eq!(log.event("find", Duration::from_millis(99)), None);
+        assert_eq!(log.event("insert", Duration::from_millis(1)), None);
+        let log = slow_log(&path("audit"), true);
+        assert_eq!(log.event("insert", Duration::from_millis(1)), Some(Event::Write));
+        assert_eq!(log.event("insert", Duration::from_secs(1)), Some(Event::Slow));
+        assert_eq!(log.event("find", Duration::from_millis(1)), None);
+    }
+
+    #[test]
+    fn records_are_json_lines() {
+        let path = path("record");
+        let log = slow_log(&path, true);
+        let doc = doc! {
+            "update": "orders",
+            "updates": [{ "q": { "_id": 1 }, "u": { "$set": { "paid": true } } }],
+            "$db": "shop",
+        };
+        log.record(&Record {
+            event: Event::Write,
+            command: "update",
+            doc: &doc,
+            duration: Duration::from_micros(2500),
+            cache: None,
+            client: "10.0.0.1:50000".parse().unwrap(),
+            user: "shop.app",
+        });
+        let doc = doc! { "find": "orders", "filter": { "_id": 1 }, "$db": "shop" };
+        log.record(&Record {
+            event: Event::Slow,
+            command: "find",
+            doc: &doc,
+            duration: Duration::from_millis(150),
+            cache: Some("miss"),
+            client: "10.0.0.2:50000".parse().unwrap(),
+            user: "",
+        });
+        let lines: Vec<Value> = read(&path).lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "write");
+        assert_eq!(lines[0]["namespace"], "shop.orders");
+        assert_eq!(lines[0]["command"], "update");
+        assert_eq!(lines[0]["filter"], serde_json::json!([{ "_id": "?" }]));
+        assert_eq!(lines[0]["duration_ms"], 2.5);
+        assert_eq!(lines[0]["cache"], Value::Null);
+        assert_eq!(lines[0]["client"], "10.0.0.1:50000");
+        assert_eq!(lines[0]["user"], "shop.app");
+        assert!(lines[0]["time"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(lines[1]["event"], "slow");
+        assert_eq!(lines[1]["cache"], "miss");
+        assert_eq!(lines[1]["user"], Value::Null);
+    }
+}
@@ -0,0 +1,639 @@
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bson::{Bson, Document};
+use serde_json::{json, Value};
+use tracing::{error, info};
+
+use crate::config::Config;
+use crate::health;
+use crate::http;
+
+const BATCH_SIZE: usize = 512;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+// spans finished while the collector is slow or down are dropped past this
+const MAX_QUEUED: usize = 8192;
+
+// commands whose `comment` mongod records in the profiler and slow query log
+const COMMENTED: [&str; 10] = [
+    "find",
+    "aggregate",
+    "count",
+    "distinct",
+    "getMore",
+    "findAndModify",
+    "findandmodify",
+    "insert",
+    "update",
+    "delete",
+];
+
+// writes accept a `comment` from 4.4 on, older servers reject it
+const WRITES: [&str; 5] = ["findAndModify", "findandmodify", "insert", "update", "delete"];
+const COMMENTED_WRITES_WIRE_VERSION: i32 = 9;
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent: Option<[u8; 8]>,
+    name: String,
+    kind: Kind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+}
+
+enum Message {
+    Span(SpanData),
+    // export what is queued now and report back, sent on shutdown
+    Flush(Sender<()>),
+}
+
+struct Exporter {
+    spans: SyncSender<Message>,
+    sample_ratio: f64,
+    propagate: bool,
+}
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2 || !value.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+// a W3C trace context, as sent by the client or passed on to mongod
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    // `00-{trace id}-{span id}-{flags}`
+    pub fn parse(traceparent: &str) -> Option<TraceContext> {
+        let mut parts = traceparent.trim().split('-');
+        let (version, trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version != "00" || parts.next().is_some() {
+            return None;
+        }
+        let context = TraceContext {
+            trace_id: unhex(trace_id)?,
+            span_id: unhex(span_id)?,
+            sampled: unhex::<1>(flags)?[0] & 1 == 1,
+        };
+        // all zero ids are invalid
+        let valid = context.trace_id != [0; 16] && context.span_id != [0; 8];
+        valid.then_some(context)
+    }
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", hex(&self.trace_id), hex(&self.span_id), self.sampled as u8)
+    }
+}
+
+// the client's trace context, from a `comment` that is a traceparent or a
+// document with a `traceparent` field
+pub fn client_context(doc: &Document) -> Option<TraceContext> {
+    match doc.get("comment")? {
+        Bson::String(comment) => TraceContext::parse(comment),
+        Bson::Document(comment) => TraceContext::parse(comment.get_str("traceparent").ok()?),
+        _ => None,
+    }
+}
+
+// whether mongod at `wire_version` accepts a `comment` on `command`, writes
+// aren't commented until the topology monitor has seen a 4.4 primary
+fn commented(command: &str, wire_version: i32) -> bool {
+    COMMENTED.contains(&command) && (!WRITES.contains(&command) || wire_version >= COMMENTED_WRITES_WIRE_VERSION)
+}
+
+// puts `context` in the command's comment for mongod, a comment of the
+// client's own that carries no trace context is left alone
+fn propagate(doc: &mut Document, context: &TraceContext) {
+    let traceparent = context.traceparent();
+    match doc.get_mut("comment") {
+        None => {
+            doc.insert("comment", bson::doc! { "traceparent": traceparent });
+        }
+        Some(Bson::Document(comment)) => {
+            comment.insert("traceparent", traceparent);
+        }
+        Some(Bson::String(comment)) if TraceContext::parse(comment).is_some() => *comment = traceparent,
+        Some(_) => {}
+    }
+}
+
+fn span_json(span: &SpanData) -> Value {
+    let nanos = |time: SystemTime| {
+        let nanos = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        nanos.to_string()
+    };
+    let attributes: Vec<Value> = span
+        .attributes
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+        .collect();
+    json!({
+        "traceId": hex(&span.trace_id),
+        "spanId": hex(&span.span_id),
+        "parentSpanId": span.parent.map(|parent| hex(&parent)).unwrap_or_default(),
+        "name": span.name,
+        "kind": span.kind as u8,
+        "startTimeUnixNano": nanos(span.start),
+        "endTimeUnixNano": nanos(span.end),
+        "attributes": attributes,
+    })
+}
+
+// an OTLP/HTTP json ExportTraceServiceRequest
+fn export_request(service_name: &str, spans: &[SpanData]) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": service_name } }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "rengo", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans.iter().map(span_json).collect::<Vec<Value>>(),
+            }],
+        }],
+    })
+}
+
+fn export(receiver: Receiver<Message>, url: String, service_name: String) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut failing = false;
+    loop {
+        let mut flushed = None;
+        let flush = match receiver.recv_timeout(FLUSH_INTERVAL) {
+            Ok(Message::Span(span)) => {
+                batch.push(span);
+                batch.len() >= BATCH_SIZE
+            }
+            Ok(Message::Flush(done)) => {
+                flushed = Some(done);
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => !batch.is_empty(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if !flush {
+            continue;
+        }
+        if batch.is_empty() {
+            flushed.map(|done| done.send(()));
+            continue;
+        }
+        let body = export_request(&service_name, &batch).to_string();
+        batch.clear();
+        // logged once per outage rather than on every batch
+        match http::post(&url, "application/json", body.as_bytes()) {
+            Ok(status) if (200..300).contains(&status) => {
+                if failing {
+                    info!("Exporting spans to {} again", url);
+                }
+                failing = false;
+            }
+            Ok(status) if !failing => {
+                error!("otlp export to {}: status {}", url, status);
+                failing = true;
+            }
+            Err(e) if !failing => {
+                error!("otlp export to {}: {}", url, e);
+                failing = true;
+            }
+            _ => {}
+        }
+        flushed.map(|done| done.send(()));
+    }
+}
+
+// exports spans to `otlp.endpoint` in batches, on a thread of its own
+pub fn start(config: &Config) {
+    let endpoint = match &config.otlp_endpoint {
+        Some(endpoint) => endpoint,
+        None => return,
+    };
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED);
+    let exporter = Exporter {
+        spans: sender,
+        sample_ratio: config.otlp_sample_ratio,
+        propagate: config.otlp_propagate,
+    };
+    if EXPORTER.set(exporter).is_err() {
+        return;
+    }
+    info!("Exporting spans to {}", url);
+    let service_name = config.otlp_service_name.clone();
+    thread::spawn(move || export(receiver, url, service_name));
+}
+
+fn send(span: SpanData) {
+    if let Some(exporter) = EXPORTER.get() {
+        // a full queue drops the span rather than slow the client down
+        let _ = exporter.spans.try_send(Message::Span(span));
+    }
+}
+
+// exports the spans still queued, waiting at most `timeout` for the collector
+pub fn flush(timeout: Duration) {
+    let exporter = match EXPORTER.get() {
+        Some(exporter) => exporter,
+        None => return,
+    };
+    let deadline = Instant::now() + timeout;
+    let (done, flushed) = mpsc::channel();
+    let mut message = Message::Flush(done);
+    // the queue may be full with the spans to flush
+    loop {
+        match exporter.spans.try_send(message) {
+            Ok(()) => break,
+            Err(TrySendError::Full(full)) if Instant::now() < deadline => message = full,
+            Err(_) => return,
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let _ = flushed.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+}
+
+// the command a worker thread is serving, one at a time per connection
+struct Current {
+    context: TraceContext,
+    parent: Option<[u8; 8]>,
+    start: SystemTime,
+    // unset for messages that are not traced, such as handshakes
+    name: Option<String>,
+    attributes: Vec<(&'static str, String)>,
+    cache_lookup: Option<SystemTime>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Current>> = const { RefCell::new(None) };
+}
+
+// when a client's message has been read, starts the span of its command
+pub fn begin(request_id: u32) {
+    if EXPORTER.get().is_none() {
+        return;
+    }
+    let current = Current {
+        context: TraceContext {
+            trace_id: rand::random(),
+            span_id: rand::random(),
+            sampled: false,
+        },
+        parent: None,
+        start: SystemTime::now(),
+        name: None,
+        attributes: vec![("rengo.request_id", request_id.to_string())],
+        cache_lookup: None,
+    };
+    CURRENT.with(|cell| *cell.borrow_mut() = Some(current));
+}
+
+// names the span after the command, it becomes a child of the client's
+// trace context when the comment carries one and is sampled otherwise
+pub fn command(command: &str, doc: &Document, client: SocketAddr) {
+    let sample_ratio = match EXPORTER.get() {
+        Some(exporter) => exporter.sample_ratio,
+        None => return,
+    };
+    CURRENT.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let current = match cell.as_mut() {
+            Some(current) => current,
+            None => return,
+        };
+        match client_context(doc) {
+            Some(parent) => {
+                current.context.trace_id = parent.trace_id;
+                current.context.sampled = parent.sampled;
+                current.parent = Some(parent.span_id);
+            }
+            None => current.context.sampled = rand::random::<f64>() < sample_ratio,
+        }
+        let db = doc.get_str("$db").unwrap_or("admin");
+        current.name = Some(format!("{} {}", command, db));
+        current.attributes.push(("db.system", "mongodb".to_string()));
+        current.attributes.push(("db.name", db.to_string()));
+        current.attributes.push(("db.operation", command.to_string()));
+        if let Ok(collection) = doc.get_str(command) {
+            current.attributes.push(("db.mongodb.collection", collection.to_string()));
+        }
+        current.attributes.push(("client.address", client.to_string()));
+    });
+}
+
+pub fn cache_lookup() {
+    CURRENT.with(|cell| {
+        if let Some(current) = cell.borrow_mut().as_mut() {
+            current.cache_lookup = Some(SystemTime::now());
+        }
+    });
+}
+
+// ends the cache lookup span once the cache has answered or the command is
+// on its way upstream
+pub fn cache_outcome(outcome: &str) {
+    CURRENT.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let current = match cell.as_mut() {
+            Some(current) => current,
+            None => return,
+        };
+        current.attributes.push(("rengo.cache", outcome.to_string()));
+        let start = match current.cache_lookup.take() {
+            Some(start) if current.context.sampled => start,
+            _ => return,
+        };
+        send(SpanData {
+            trace_id: current.context.trace_id,
+            span_id: rand::random(),
+            parent: Some(current.context.span_id),
+            name: "cache lookup".to_string(),
+            kind: Kind::Internal,
+            start,
+            end: SystemTime::now(),
+            attributes: vec![("rengo.cache", outcome.to_string())],
+        });
+    });
+}
+
+// a round trip to mongod within the current command, traced as the time
+// to send it and the time until its reply arrived
+pub struct Upstream {
+    trace_id: [u8; 16],
+    parent: [u8; 8],
+    // the span mongod's side is correlated with
+    context: TraceContext,
+    command: String,
+    start: SystemTime,
+    sent: Option<SystemTime>,
+}
+
+// None when the current command is not sampled
+pub fn upstream(doc: &Document) -> Option<Upstream> {
+    CURRENT.with(|cell| {
+        let cell = cell.borrow();
+        let current = cell.as_ref().filter(|current| current.context.sampled)?;
+        Some(Upstream {
+            trace_id: current.context.trace_id,
+            parent: current.context.span_id,
+            context: TraceContext {
+                trace_id: current.context.trace_id,
+                span_id: rand::random(),
+                sampled: true,
+            },
+            command: doc.keys().next().cloned().unwrap_or_default(),
+            start: SystemTime::now(),
+            sent: None,
+        })
+    })
+}
+
+impl Upstream {
+    // with otlp.propagate, the forwarded command carries the trace context
+    pub fn propagate(&self, doc: &mut Document) {
+        let enabled = EXPORTER.get().is_some_and(|exporter| exporter.propagate);
+        if enabled && commented(&self.command, health::upstream_wire_version()) {
+            propagate(doc, &self.context);
+        }
+    }
+    pub fn sent(&mut self) {
+        self.sent = Some(SystemTime::now());
+    }
+    pub fn replied(self) {
+        let sent = self.sent.unwrap_or(self.start);
+        let attributes = vec![("db.operation", self.command.clone())];
+        send(SpanData {
+            trace_id: self.trace_id,
+            span_id: self.context.span_id,
+            parent: Some(self.parent),
+            name: "upstream send".to_string(),
+            kind: Kind::Client,
+            start: self.start,
+            end: sent,
+            attributes: attributes.clone(),
+        });
+        send(SpanData {
+            trace_id: self.trace_id,
+            span_id: rand::random(),
+            parent: Some(self.parent),
+            name: "upstream reply".to_string(),
+            kind: Kind::Internal,
+            start: sent,
+            end: SystemTime::now(),
+            attributes,
+        });
+    }
+}
+
+// once the reply is written to the client, exports the command's span
+pub fn end() {
+    let current = match CURRENT.with(|cell| cell.borrow_mut().take()) {
+        Some(current) => current,
+        None => return,
+    };
+    let name = match current.name {
+        Some(name) if current.context.sampled => name,
+        _ => return,
+    };
+    send(SpanData {
+        trace_id: current.context.trace_id,
+        span_id: current.context.span_id,
+        parent: current.parent,
+        name,
+        kind: Kind::Server,
+        start: current.start,
+        end: SystemTime::now(),
+        attributes: current.attributes,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::doc;
+
+    use super::*;
+    use crate::http::testing::free_addr;
+    use crate::http::{HttpRequest, HttpResponse};
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    fn spans(request: &Value) -> Vec<Value> {
+        request["resourceSpans"][0]["scopeSpans"][0]["spans"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn parses_traceparents() {
+        let context = TraceContext::parse(TRACEPARENT).unwrap();
+        assert_eq!(hex(&context.trace_id), "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(hex(&context.span_id), "b7ad6b7169203331");
+        assert!(context.sampled);
+        assert_eq!(context.traceparent(), TRACEPARENT);
+        let unsampled = TraceContext::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00").unwrap();
+        assert!(!unsampled.sampled);
+        for invalid in [
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-00",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "00-0af7651916cd43dd8448eb211c8031-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319z-b7ad6b7169203331-01",
+            "a note from the client",
+        ] {
+            assert_eq!(TraceContext::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn finds_the_client_context() {
+        let context = TraceContext::parse(TRACEPARENT);
+        assert_eq!(client_context(&doc! { "find": "orders", "comment": TRACEPARENT }), context);
+        assert_eq!(client_context(&doc! { "comment": { "traceparent": TRACEPARENT, "app": "shop" } }), context);
+        assert_eq!(client_context(&doc! { "comment": "a note" }), None);
+        assert_eq!(client_context(&doc! { "comment": 7 }), None);
+        assert_eq!(client_context(&doc! { "find": "orders" }), None);
+    }
+
+    #[test]
+    fn propagates_into_comments() {
+        let context = TraceContext {
+            trace_id: [1; 16],
+            span_id: [2; 8],
+            sampled: true,
+        };
+        let traceparent = context.traceparent();
+        let mut command = doc! { "find": "orders" };
+        propagate(&mut command, &context);
+        assert_eq!(command.get_document("comment").unwrap(), &doc! { "traceparent": &traceparent });
+        let mut command = doc! { "find": "orders", "comment": { "app": "shop" } };
+        propagate(&mut command, &context);
+        assert_eq!(command.get_document("comment").unwrap(), &doc! { "app": "shop", "traceparent": &traceparent });
+        let mut command = doc! { "find": "orders", "comment": TRACEPARENT };
+        propagate(&mut command, &context);
+        assert_eq!(command.get_str("comment").unwrap(), traceparent);
+        let mut command = doc! { "find": "orders", "comment": "a note" };
+        propagate(&mut command, &context);
+        assert_eq!(command.get_str("comment").unwrap(), "a note");
+    }
+
+    #[test]
+    fn writes_are_commented_from_4_4() {
+        for read in ["find", "aggregate", "getMore"] {
+            assert!(commented(read, 0), "{}", read);
+            assert!(commented(read, 6), "{}", read);
+        }
+        for write in WRITES {
+            assert!(!commented(write, 0), "{}", write);
+            assert!(!commented(write, 8), "{}", write);
+            assert!(commented(write, 9), "{}", write);
+        }
+        assert!(!commented("hello", 17));
+    }
+
+    #[test]
+    fn exports_command_spans_to_the_collector() {
+        let addr = free_addr();
+        let (posted, requests) = mpsc::channel();
+        http::serve(&addr, move |request: &HttpRequest| {
+            assert_eq!(request.path, "/v1/traces");
+            assert_eq!(request.headers["content-type"], "application/json");
+            posted.send(serde_json::from_slice::<Value>(&request.body).unwrap()).unwrap();
+            HttpResponse::text(200, "")
+        })
+        .unwrap();
+        let mut config = Config {
+            otlp_endpoint: Some(format!("http://{}/", addr)),
+            otlp_service_name: "shop-proxy".to_string(),
+            ..Config::default()
+        };
+        // only commands that join a client's trace are sampled
+        config.otlp_sample_ratio = 0.0;
+        start(&config);
+        let client: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+
+        begin(9);
+        let untraced = doc! { "count": "orders", "$db": "shop" };
+        command("count", &untraced, client);
+        assert!(upstream(&untraced).is_none());
+        end();
+
+        begin(10);
+        let find = doc! { "find": "orders", "filter": {}, "comment": TRACEPARENT, "$db": "shop" };
+        command("find", &find, client);
+        cache_lookup();
+        cache_outcome("miss");
+        let mut forwarded = find.clone();
+        let mut round_trip = upstream(&find).unwrap();
+        round_trip.propagate(&mut forwarded);
+        round_trip.sent();
+        round_trip.replied();
+        end();
+        flush(Duration::from_secs(5));
+
+        let mut exported = vec![];
+        while exported.len() < 4 {
+            let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+            let resource = &request["resourceSpans"][0];
+            let service = &resource["resource"]["attributes"][0];
+            assert_eq!(service["key"], "service.name");
+            assert_eq!(service["value"]["stringValue"], "shop-proxy");
+            assert_eq!(resource["scopeSpans"][0]["scope"]["name"], "rengo");
+            exported.extend(spans(&request));
+        }
+        assert_eq!(exported.len(), 4, "{:?}", exported);
+        let span = |name: &str| exported.iter().find(|span| span["name"] == name).unwrap().clone();
+        let server = span("find shop");
+        assert_eq!(server["kind"], 2);
+        assert_eq!(server["traceId"], "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(server["parentSpanId"], "b7ad6b7169203331");
+        let attributes = server["attributes"].as_array().unwrap();
+        let attribute = |key: &str| {
+            let attribute = attributes.iter().find(|attribute| attribute["key"] == key).unwrap();
+            attribute["value"]["stringValue"].clone()
+        };
+        assert_eq!(attribute("rengo.request_id"), "10");
+        assert_eq!(attribute("db.mongodb.collection"), "orders");
+        assert_eq!(attribute("rengo.cache"), "miss");
+        assert_eq!(attribute("client.address"), "10.0.0.1:50000");
+        for name in ["cache lookup", "upstream send", "upstream reply"] {
+            let child = span(name);
+            assert_eq!(child["traceId"], server["traceId"], "{}", name);
+            assert_eq!(child["parentSpanId"], server["spanId"], "{}", name);
+            assert_ne!(child["spanId"], server["spanId"], "{}", name);
+        }
+        assert_eq!(span("upstream send")["kind"], 3);
+        // mongod sees the upstream send span as the parent of its work
+        let traceparent = format!(
+            "00-{}-{}-01",
+            server["traceId"].as_str().unwrap(),
+            span("upstream send")["spanId"].as_str().unwrap()
+        );
+        assert_eq!(forwarded.get_str("comment").unwrap(), traceparent);
+    }
+}
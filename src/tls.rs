@@ -0,0 +1,408 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use tracing::{error, info, warn};
+
+use crate::config::{ClientCertMode, Config};
+use crate::upstream::ConnectionString;
+
+// how often the listener certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", path));
+    }
+    Ok(certs)
+}
+
+pub fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    match rustls_pemfile::private_key(&mut BufReader::new(file)) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(format!("{}: no private key found (encrypted keys are not supported)", path)),
+        Err(e) => Err(format!("{}: {}", path, e)),
+    }
+}
+
+// the CAs we trust for the upstream: `tlsCAFile` when given, as drivers do,
+// otherwise the bundled webpki roots plus the system store so private CAs
+// installed on the host work
+fn root_store(uri: &ConnectionString) -> Result<RootCertStore, String> {
+    let mut root_cert_store = RootCertStore::empty();
+    if let Some(path) = uri.option("tlsCAFile") {
+        for cert in load_certs(path)? {
+            root_cert_store
+                .add(cert)
+                .map_err(|e| format!("{}: {}", path, e))?;
+        }
+        return Ok(root_cert_store);
+    }
+    root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            // a broken system certificate should not take the proxy down
+            root_cert_store.add_parsable_certificates(certs);
+        }
+        Err(e) => warn!("could not load system certificates: {}", e),
+    }
+    Ok(root_cert_store)
+}
+
+// client side tls for upstream connections, presents `tlsCertificateKeyFile`
+// when the uri has one (required for MONGODB-X509)
+pub fn client_config(uri: &ConnectionString) -> Result<Arc<ClientConfig>, String> {
+    let builder = ClientConfig::builder().with_root_certificates(root_store(uri)?);
+    let config = match uri.option("tlsCertificateKeyFile") {
+        Some(path) => {
+            if uri.option("tlsCertificateKeyFilePassword").is_some() {
+                return Err("tlsCertificateKeyFilePassword is not supported, decrypt the key first".to_string());
+            }
+            let certs = load_certs(path)?;
+            let key = load_private_key(path)?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| format!("{}: {}", path, e))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn certified_key(cert_file: &str, key_file: &str) -> Result<CertifiedKey, String> {
+    let certs = load_certs(cert_file)?;
+    let key = load_private_key(key_file)?;
+    let key = any_supported_type(&key).map_err(|e| format!("{}: {}", key_file, e))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// serves the listener certificate and swaps it when the files on disk change,
+// handshakes already done keep their certificate
+#[derive(Debug)]
+pub struct ReloadingCert {
+    cert_file: String,
+    key_file: String,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCert {
+    pub fn load(cert_file: &str, key_file: &str) -> Result<Arc<ReloadingCert>, String> {
+        let current = certified_key(cert_file, key_file)?;
+        Ok(Arc::new(ReloadingCert {
+            cert_file: cert_file.to_string(),
+            key_file: key_file.to_string(),
+            current: RwLock::new(Arc::new(current)),
+        }))
+    }
+    // polls the files' mtimes, a failed reload keeps serving the old pair so a
+    // half written renewal does not take the listener down
+    pub fn watch(self: &Arc<Self>) {
+        let cert = self.clone();
+        thread::spawn(move || {
+            let mut last = (modified(&cert.cert_file), modified(&cert.key_file));
+            loop {
+                thread::sleep(RELOAD_INTERVAL);
+                cert.reload(&mut last);
+            }
+        });
+    }
+    // loads the pair again when either file changed since `last`
+    fn reload(&self, last: &mut (Option<SystemTime>, Option<SystemTime>)) {
+        let now = (modified(&self.cert_file), modified(&self.key_file));
+        if now == *last {
+            return;
+        }
+        match certified_key(&self.cert_file, &self.key_file) {
+            Ok(key) => {
+                *self.current.write().unwrap() = Arc::new(key);
+                *last = now;
+                info!("Reloaded tls certificate {}", self.cert_file);
+            }
+            Err(e) => error!("tls certificate reload failed: {}", e),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+// server side tls for the client listener, None when no certificate is configured
+pub fn server_config(config: &Config) -> Result<Option<Arc<ServerConfig>>, String> {
+    let (cert_file, key_file) = match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+        (None, None) => return Ok(None),
+        _ => return Err("both a tls certificate and key file are required".to_string()),
+    };
+    let resolver = ReloadingCert::load(cert_file, key_file)?;
+    resolver.watch();
+    let builder = ServerConfig::builder();
+    let builder = match (&config.tls_ca_file, config.client_cert_mode()) {
+        (_, ClientCertMode::None) => builder.with_no_client_auth(),
+        (Some(ca_file), mode) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_file)? {
+                roots.add(cert).map_err(|e| format!("{}: {}", ca_file, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if mode == ClientCertMode::Optional {
+                verifier.allow_unauthenticated()
+            } else {
+                verifier
+            };
+            let verifier = verifier.build().map_err(|e| format!("{}: {}", ca_file, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        (None, _) => return Err("client certificate verification needs a tls ca file".to_string()),
+    };
+    Ok(Some(Arc::new(builder.with_cert_resolver(resolver))))
+}
+
+// a client connection, plain tcp or tls terminated by us
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl ClientStream {
+    pub fn accept(stream: TcpStream, tls: Option<&Arc<ServerConfig>>) -> io::Result<ClientStream> {
+        match tls {
+            Some(tls) => {
+                let connection = ServerConnection::new(tls.clone())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(ClientStream::Tls(Box::new(StreamOwned::new(connection, stream))))
+            }
+            None => Ok(ClientStream::Plain(stream)),
+        }
+    }
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            ClientStream::Plain(stream) => stream.peer_addr(),
+            ClientStream::Tls(stream) => stream.sock.peer_addr(),
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => stream.read(buf),
+            ClientStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => stream.write(buf),
+            ClientStream::Tls(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Plain(stream) => stream.flush(),
+            ClientStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::ServerName;
+    use rustls::ClientConnection;
+
+    use super::*;
+
+    // a CA and pem files for certificates it signs, in a directory per test
+    struct Pki {
+        dir: PathBuf,
+        ca: rcgen::Certificate,
+        ca_key: KeyPair,
+    }
+
+    impl Pki {
+        fn new(name: &str) -> Pki {
+            let dir = std::env::temp_dir().join(format!("rengo-tls-{}-{}", std::process::id(), name));
+            fs::create_dir_all(&dir).unwrap();
+            let ca_key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = params.self_signed(&ca_key).unwrap();
+            let pki = Pki { dir, ca, ca_key };
+            fs::write(pki.path("ca.pem"), pki.ca.pem()).unwrap();
+            pki
+        }
+        fn path(&self, file: &str) -> String {
+            self.dir.join(file).to_str().unwrap().to_string()
+        }
+        // writes `<name>.pem` and `<name>.key`, and `<name>.both.pem` with both
+        fn issue(&self, name: &str, host: &str) {
+            let key = KeyPair::generate().unwrap();
+            let params = CertificateParams::new(vec![host.to_string()]).unwrap();
+            let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+            fs::write(self.path(&format!("{}.pem", name)), cert.pem()).unwrap();
+            fs::write(self.path(&format!("{}.key", name)), key.serialize_pem()).unwrap();
+            let both = format!("{}{}", cert.pem(), key.serialize_pem());
+            fs::write(self.path(&format!("{}.both.pem", name)), both).unwrap();
+        }
+        fn listener_config(&self, client_cert: Option<ClientCertMode>) -> Config {
+            Config {
+                tls_cert_file: Some(self.path("server.pem")),
+                tls_key_file: Some(self.path("server.key")),
+                tls_ca_file: client_cert.map(|_| self.path("ca.pem")),
+                tls_client_cert: client_cert,
+                ..Config::default()
+            }
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // echoes one message through a listener terminating tls with `server`
+    fn echo(server: Arc<ServerConfig>, client: Arc<ClientConfig>) -> io::Result<Vec<u8>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = thread::spawn(move || -> io::Result<()> {
+            let (stream, _) = listener.accept()?;
+            let mut stream = ClientStream::accept(stream, Some(&server))?;
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf)?;
+            stream.write_all(&buf)?;
+            stream.flush()
+        });
+        let connection = ClientConnection::new(client, ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(addr)?);
+        let result = (|| {
+            stream.write_all(b"hello")?;
+            let mut buf = vec![0; 5];
+            stream.read_exact(&mut buf)?;
+            Ok(buf)
+        })();
+        drop(stream);
+        let _ = accepted.join();
+        result
+    }
+
+    fn upstream_config(uri: &str) -> Arc<ClientConfig> {
+        client_config(&ConnectionString::parse(uri).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn trusts_the_ca_file_from_the_uri() {
+        let pki = Pki::new("ca-file");
+        pki.issue("server", "localhost");
+        let server = server_config(&pki.listener_config(None)).unwrap().unwrap();
+        let trusting = upstream_config(&format!("mongodb://localhost/?tlsCAFile={}", pki.path("ca.pem")));
+        assert_eq!(echo(server.clone(), trusting).unwrap(), b"hello");
+        // the public roots don't know this CA
+        assert!(echo(server, upstream_config("mongodb://localhost/")).is_err());
+    }
+
+    #[test]
+    fn presents_the_client_certificate_for_x509() {
+        let pki = Pki::new("x509");
+        pki.issue("server", "localhost");
+        pki.issue("client", "app");
+        let server = server_config(&pki.listener_config(Some(ClientCertMode::Required))).unwrap().unwrap();
+        let ca = pki.path("ca.pem");
+        let with_cert = upstream_config(&format!(
+            "mongodb://localhost/?authMechanism=MONGODB-X509&tlsCAFile={}&tlsCertificateKeyFile={}",
+            ca,
+            pki.path("client.both.pem")
+        ));
+        assert_eq!(echo(server.clone(), with_cert).unwrap(), b"hello");
+        let without_cert = upstream_config(&format!("mongodb://localhost/?tlsCAFile={}", ca));
+        assert!(echo(server, without_cert).is_err());
+    }
+
+    #[test]
+    fn optional_client_certificates_let_anonymous_clients_in() {
+        let pki = Pki::new("optional");
+        pki.issue("server", "localhost");
+        let server = server_config(&pki.listener_config(Some(ClientCertMode::Optional))).unwrap().unwrap();
+        let client = upstream_config(&format!("mongodb://localhost/?tlsCAFile={}", pki.path("ca.pem")));
+        assert_eq!(echo(server, client).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn rejects_unusable_key_files() {
+        let pki = Pki::new("bad-keys");
+        pki.issue("client", "app");
+        let uri = |options: &str| ConnectionString::parse(&format!("mongodb://localhost/?{}", options)).unwrap();
+        let error = client_config(&uri(&format!(
+            "tlsCertificateKeyFile={}&tlsCertificateKeyFilePassword=x",
+            pki.path("client.both.pem")
+        )))
+        .unwrap_err();
+        assert!(error.contains("tlsCertificateKeyFilePassword"), "{}", error);
+        // a certificate without its key
+        let error = client_config(&uri(&format!("tlsCertificateKeyFile={}", pki.path("client.pem")))).unwrap_err();
+        assert!(error.contains("no private key found"), "{}", error);
+        let error = client_config(&uri("tlsCAFile=/nonexistent/ca.pem")).unwrap_err();
+        assert!(error.starts_with("/nonexistent/ca.pem"), "{}", error);
+    }
+
+    #[test]
+    fn listener_needs_a_ca_to_verify_clients() {
+        let pki = Pki::new("no-ca");
+        pki.issue("server", "localhost");
+        assert!(server_config(&Config::default()).unwrap().is_none());
+        let config = Config {
+            tls_ca_file: None,
+            ..pki.listener_config(Some(ClientCertMode::Required))
+        };
+        assert!(server_config(&config).is_err());
+        let config = Config {
+            tls_key_file: None,
+            ..pki.listener_config(None)
+        };
+        assert!(server_config(&config).is_err());
+    }
+
+    #[test]
+    fn reloads_the_certificate_when_it_changes() {
+        let pki = Pki::new("reload");
+        pki.issue("server", "localhost");
+        let cert = ReloadingCert::load(&pki.path("server.pem"), &pki.path("server.key")).unwrap();
+        let before = cert.current.read().unwrap().cert.clone();
+        let mut last = (modified(&cert.cert_file), modified(&cert.key_file));
+        cert.reload(&mut last);
+        assert_eq!(cert.current.read().unwrap().cert, before);
+        // a half written renewal keeps the old pair
+        thread::sleep(Duration::from_millis(20));
+        fs::write(pki.path("server.pem"), "").unwrap();
+        cert.reload(&mut last);
+        assert_eq!(cert.current.read().unwrap().cert, before);
+        pki.issue("server", "localhost");
+        cert.reload(&mut last);
+        assert_ne!(cert.current.read().unwrap().cert, before);
+    }
+}
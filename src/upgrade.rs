@@ -0,0 +1,241 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::TcpListener;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::ptr;
+use std::thread;
+
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::shutdown;
+
+// sent along with the descriptor, a message can't carry ancillary data alone
+const HANDOFF: &[u8] = b"L";
+
+// sends `fd` over `stream` as SCM_RIGHTS ancillary data
+fn send_fd(stream: &UnixStream, fd: RawFd) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: HANDOFF.as_ptr() as *mut libc::c_void,
+        iov_len: HANDOFF.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = space as _;
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(header) as *mut RawFd, fd);
+    }
+    if unsafe { libc::sendmsg(stream.as_raw_fd(), &message, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// the descriptor send_fd sent, owned by this process from here on
+fn receive_fd(stream: &UnixStream) -> io::Result<RawFd> {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = space as _;
+    let received = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut message, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&message);
+        if header.is_null() || (*header).cmsg_level != libc::SOL_SOCKET || (*header).cmsg_type != libc::SCM_RIGHTS {
+            return Err(io::Error::new(ErrorKind::InvalidData, "no listener in the handoff"));
+        }
+        Ok(ptr::read_unaligned(libc::CMSG_DATA(header) as *const RawFd))
+    }
+}
+
+// takes the listener over from the process serving `upgrade_socket`, None
+// when no process answers there
+fn take_over(path: &str) -> Option<TcpListener> {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        // nothing to take over, a first start or a stale socket file
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => return None,
+        Err(e) => {
+            warn!("could not reach the previous process on {}: {}", path, e);
+            return None;
+        }
+    };
+    match receive_fd(&stream) {
+        Ok(fd) => Some(unsafe { TcpListener::from_raw_fd(fd) }),
+        Err(e) => {
+            warn!("no listener handed over on {}: {}", path, e);
+            None
+        }
+    }
+}
+
+// the listener clients are accepted on: the previous process's when it hands
+// it over on `listen.upgrade_socket`, otherwise a newly bound one
+pub fn listener(config: &Config) -> io::Result<TcpListener> {
+    let addr = format!("{}:{}", config.listen_addr, config.port);
+    let taken = config.upgrade_socket.as_deref().and_then(take_over);
+    let listener = match taken {
+        Some(listener) => {
+            let local = listener.local_addr()?;
+            info!("Took the listener on {} over from the previous process", local);
+            if local.to_string() != addr {
+                warn!("the listener handed over is on {}, not on {}", local, addr);
+            }
+            listener
+        }
+        None => TcpListener::bind(&addr)?,
+    };
+    // both processes poll the listener while it's handed over, the one that
+    // loses the race for a client must not block in accept
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+// hands `listener` to the next process that connects to
+// `listen.upgrade_socket`, then shuts down like on SIGTERM
+pub fn serve(config: &Config, listener: &TcpListener) {
+    let path = match &config.upgrade_socket {
+        Some(path) => path.clone(),
+        None => return,
+    };
+    // the previous process's socket file, it doesn't listen on it anymore
+    let _ = fs::remove_file(&path);
+    let upgrades = match UnixListener::bind(&path) {
+        Ok(upgrades) => upgrades,
+        Err(e) => {
+            error!("upgrade socket on {}: {}", path, e);
+            return;
+        }
+    };
+    // whoever connects gets the listener, only rengo's own user may
+    if let Err(e) = fs::set_permissions(&path, fs::Permissions::from_mode(0o600)) {
+        error!("upgrade socket on {}: {}", path, e);
+        return;
+    }
+    let listener = match listener.try_clone() {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("upgrade socket on {}: {}", path, e);
+            return;
+        }
+    };
+    info!("Waiting for an upgrade on {}", path);
+    thread::spawn(move || {
+        for stream in upgrades.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("could not accept an upgrade: {}", e);
+                    continue;
+                }
+            };
+            match send_fd(&stream, listener.as_raw_fd()) {
+                Ok(()) => {
+                    info!("Handed the listener over to a new process, draining");
+                    shutdown::request();
+                    return;
+                }
+                Err(e) => error!("could not hand the listener over: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("rengo-upgrade-{}-{}.sock", std::process::id(), name));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn passes_a_listener_between_sockets() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        send_fd(&sender, listener.as_raw_fd()).unwrap();
+        let received = unsafe { TcpListener::from_raw_fd(receive_fd(&receiver).unwrap()) };
+        assert_ne!(received.as_raw_fd(), listener.as_raw_fd());
+        let addr = listener.local_addr().unwrap();
+        assert_eq!(received.local_addr().unwrap(), addr);
+        drop(listener);
+        let _client = TcpStream::connect(addr).unwrap();
+        assert!(received.accept().is_ok());
+    }
+
+    #[test]
+    fn a_message_without_a_listener_is_refused() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        sender.write_all(HANDOFF).unwrap();
+        assert_eq!(receive_fd(&receiver).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn nothing_to_take_over_without_a_process() {
+        assert!(take_over(&path("missing")).is_none());
+        // the socket file of a process that is gone
+        let stale = path("stale");
+        drop(UnixListener::bind(&stale).unwrap());
+        assert!(take_over(&stale).is_none());
+        let _ = fs::remove_file(stale);
+    }
+
+    #[test]
+    fn hands_the_listener_to_the_next_process() {
+        let path = path("handoff");
+        let config = Config {
+            listen_addr: "127.0.0.1".to_string(),
+            port: 0,
+            upgrade_socket: Some(path.clone()),
+            ..Config::default()
+        };
+        let old = listener(&config).unwrap();
+        serve(&config, &old);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let new = listener(&config).unwrap();
+        let addr = old.local_addr().unwrap();
+        assert_eq!(new.local_addr().unwrap(), addr);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !shutdown::requested() {
+            assert!(Instant::now() < deadline, "the old process didn't start draining");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // clients reach the new process once the old one stops accepting
+        drop(old);
+        new.set_nonblocking(false).unwrap();
+        let _client = TcpStream::connect(addr).unwrap();
+        assert!(new.accept().is_ok());
+        let _ = fs::remove_file(path);
+    }
+}